@@ -0,0 +1,73 @@
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Runs a command, returning what it printed if it succeeded.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Reruns the build script when the file changes, as long as it exists, since a missing file
+/// would rerun it on every build.
+fn rerun_if_changed(path: &str) {
+    if Path::new(path).exists() {
+        println!("cargo:rerun-if-changed={path}");
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // Committing changes the index, and the branch's ref if it isn't packed
+    rerun_if_changed(".git/HEAD");
+    rerun_if_changed(".git/index");
+    if let Some(head) = std::fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| Some(head.strip_prefix("ref: ")?.trim().to_string()))
+    {
+        rerun_if_changed(&format!(".git/{head}"));
+    }
+
+    // Builds without the repository, such as in Docker, can give the commit instead
+    let commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .or_else(|| output("git", &["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    let dirty = output("git", &["status", "--porcelain"]).is_some_and(|s| !s.is_empty());
+
+    // Reproducible builds fix the timestamp with SOURCE_DATE_EPOCH
+    let built_at = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .map(|epoch| UNIX_EPOCH + Duration::from_secs(epoch))
+        .unwrap_or_else(SystemTime::now);
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=GIT_COMMIT={commit}");
+    println!("cargo:rustc-env=GIT_DIRTY={dirty}");
+    println!(
+        "cargo:rustc-env=BUILD_TIMESTAMP={}",
+        humantime::format_rfc3339_seconds(built_at)
+    );
+    println!("cargo:rustc-env=RUSTC_VERSION={rustc_version}");
+    println!(
+        "cargo:rustc-env=BUILD_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+}
@@ -0,0 +1,391 @@
+use crate::plugins::parse_model;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Strips the brackets surrounding an IPv6 address as it would be written in a URL.
+fn unbracket(address: &str) -> &str {
+    address
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(address)
+}
+
+/// Parses an IP address, allowing IPv6 addresses to be surrounded by brackets as they are in URLs.
+pub fn parse_bind_address(address: &str) -> Result<IpAddr, String> {
+    unbracket(address)
+        .parse()
+        .map_err(|_| format!("{address} isn't an IP address"))
+}
+
+/// Whether every address the host resolves to is within one of the networks, which it isn't if it
+/// can't be resolved.
+pub async fn resolves_within(host: &str, networks: &[IpNet]) -> bool {
+    let addresses: Vec<IpAddr> = match unbracket(host).parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => match tokio::net::lookup_host((host, 80)).await {
+            Ok(addrs) => addrs.map(|a| a.ip()).collect(),
+            Err(_) => return false,
+        },
+    };
+
+    !addresses.is_empty()
+        && addresses
+            .iter()
+            .all(|ip| networks.iter().any(|net| net.contains(ip)))
+}
+
+/// Prefix of a device given by its MAC address, such as `mac:AA-BB-CC-DD-EE-FF`, rather than an IP
+/// address or DNS name.
+pub const MAC_PREFIX: &str = "mac:";
+
+/// Length of a MAC address written with separators, such as `AA-BB-CC-DD-EE-FF`.
+const MAC_LENGTH: usize = 17;
+
+/// Parses a MAC address separated by dashes or colons, such as `AA-BB-CC-DD-EE-FF`, formatting it
+/// as `aa:bb:cc:dd:ee:ff`.
+pub fn parse_mac_address(mac: &str) -> Result<String, String> {
+    let octets: Vec<&str> = mac.split(['-', ':']).collect();
+    if octets.len() != 6
+        || !octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return Err(format!("{mac} isn't a MAC address"));
+    }
+    Ok(octets.join(":").to_ascii_lowercase())
+}
+
+/// The MAC address of a device given by one rather than its IP address or DNS name.
+pub fn device_mac(address: &str) -> Option<&str> {
+    address.strip_prefix(MAC_PREFIX)
+}
+
+/// Checks a device address is an IP address or DNS name, returning it without any brackets so it
+/// can be used as a label value.
+pub fn parse_device_address(address: &str) -> Result<String, String> {
+    let unbracketed = unbracket(address);
+
+    if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+        return Ok(ip.to_string());
+    }
+
+    if unbracketed.contains('%') {
+        // The URL parser used to talk to devices doesn't support zone identifiers
+        Err(format!(
+            "{address} has a zone identifier, which isn't supported"
+        ))
+    } else if is_dns_name(unbracketed) {
+        Ok(unbracketed.to_string())
+    } else {
+        Err(format!("{address} isn't an IP address or DNS name"))
+    }
+}
+
+/// Checks the address of a device to read from is an IP address, DNS name or MAC address prefixed
+/// with `mac:`, such as `mac:AA-BB-CC-DD-EE-FF`, for its IP address to be found on the network.
+pub fn parse_configured_address(address: &str) -> Result<String, String> {
+    match address.strip_prefix(MAC_PREFIX) {
+        Some(mac) => Ok(format!("{MAC_PREFIX}{}", parse_mac_address(mac)?)),
+        None => parse_device_address(address),
+    }
+}
+
+/// A device given as an option, along with its model if given so it doesn't need to be asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceAddress {
+    pub address: String,
+    pub model: Option<String>,
+}
+
+/// The device addresses given in one occurrence of an option.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceAddresses(pub Vec<DeviceAddress>);
+
+/// Parses a device address optionally followed by its model, such as `10.0.0.5:P304M`, with IPv6
+/// addresses surrounded by brackets to give a model, such as `[fd00::10]:P304M`.
+fn parse_device_entry(entry: &str) -> Result<DeviceAddress, String> {
+    if let Some(mac) = entry.strip_prefix(MAC_PREFIX) {
+        // MAC addresses can be separated by colons too, so the model is whatever follows one
+        let (mac, model) = match mac.get(MAC_LENGTH..).and_then(|m| m.strip_prefix(':')) {
+            Some(model) => (&mac[..MAC_LENGTH], Some(parse_model(model)?)),
+            None => (mac, None),
+        };
+        return Ok(DeviceAddress {
+            address: format!("{MAC_PREFIX}{}", parse_mac_address(mac)?),
+            model,
+        });
+    }
+
+    let (address, model) = match entry.rsplit_once(':') {
+        // Any other colon is part of an IPv6 address
+        Some((address, model))
+            if !address.contains(':') || (address.starts_with('[') && address.ends_with(']')) =>
+        {
+            (address, Some(parse_model(model)?))
+        }
+        _ => (entry, None),
+    };
+
+    Ok(DeviceAddress {
+        address: parse_device_address(address)?,
+        model,
+    })
+}
+
+/// Parses a list of device addresses separated by commas or whitespace, such as
+/// `10.0.0.5,10.0.0.6` or `10.0.0.5 10.0.0.6`, each optionally followed by its model.
+pub fn parse_device_addresses(list: &str) -> Result<DeviceAddresses, String> {
+    let mut addresses = Vec::new();
+
+    for entry in list.split(',') {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            return Err(format!(
+                "{list:?} has an empty entry, check for a doubled or trailing comma"
+            ));
+        }
+
+        for address in entry.split_whitespace() {
+            addresses.push(parse_device_entry(address)?);
+        }
+    }
+
+    Ok(DeviceAddresses(addresses))
+}
+
+/// Parses a device's alias given as its address and alias separated by `=`, such as
+/// `10.0.0.5=garage-strip`.
+pub fn parse_device_alias(value: &str) -> Result<(String, String), String> {
+    let Some((address, alias)) = value.split_once('=') else {
+        return Err(format!("{value} isn't an address and alias separated by ="));
+    };
+
+    match alias.trim() {
+        "" => Err(format!("{value} has an empty alias")),
+        alias => Ok((parse_device_address(address.trim())?, alias.to_string())),
+    }
+}
+
+/// Combines the device addresses given in each occurrence of an option, skipping any given
+/// before, ignoring case as DNS names are case-insensitive.
+pub fn merge_device_addresses(lists: &[DeviceAddresses]) -> Vec<DeviceAddress> {
+    let mut merged: Vec<DeviceAddress> = Vec::new();
+    for device in lists.iter().flat_map(|list| list.0.iter()) {
+        if !merged
+            .iter()
+            .any(|d| d.address.eq_ignore_ascii_case(&device.address))
+        {
+            merged.push(device.clone());
+        }
+    }
+    merged
+}
+
+fn is_dns_name(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Formats a device address for use as the host of a URL, surrounding IPv6 addresses in brackets.
+pub fn url_host(address: &str) -> String {
+    match address.parse::<Ipv6Addr>() {
+        Ok(ip) => format!("[{ip}]"),
+        Err(_) => address.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DeviceAddress, DeviceAddresses, merge_device_addresses, parse_device_addresses};
+    use super::{device_mac, parse_configured_address, parse_mac_address};
+    use super::{parse_bind_address, parse_device_address, parse_device_alias, url_host};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    #[test]
+    fn bind_address() {
+        assert_eq!(
+            parse_bind_address("0.0.0.0"),
+            Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        );
+        assert_eq!(
+            parse_bind_address("::"),
+            Ok(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        );
+        assert_eq!(
+            parse_bind_address("[::]"),
+            Ok(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        );
+        assert!(parse_bind_address("localhost").is_err());
+    }
+
+    #[tokio::test]
+    async fn bind_to_address() {
+        for address in ["0.0.0.0", "::"] {
+            let address = SocketAddr::new(parse_bind_address(address).unwrap(), 0);
+            let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+
+            assert_eq!(listener.local_addr().unwrap().ip(), address.ip());
+        }
+    }
+
+    #[test]
+    fn device_address() {
+        assert_eq!(parse_device_address("10.0.0.1"), Ok("10.0.0.1".to_string()));
+        assert_eq!(parse_device_address("fd00::10"), Ok("fd00::10".to_string()));
+        assert_eq!(
+            parse_device_address("[FD00::10]"),
+            Ok("fd00::10".to_string())
+        );
+        assert_eq!(
+            parse_device_address("power-strip.local"),
+            Ok("power-strip.local".to_string())
+        );
+        assert!(parse_device_address("fe80::1%eth0").is_err());
+        assert!(parse_device_address("http://10.0.0.1").is_err());
+        assert!(parse_device_address("-strip.local").is_err());
+        assert!(parse_device_address("").is_err());
+        assert!(parse_device_address("mac:AA-BB-CC-DD-EE-FF").is_err());
+
+        assert_eq!(
+            parse_configured_address("mac:AA-BB-CC-DD-EE-FF"),
+            Ok("mac:aa:bb:cc:dd:ee:ff".to_string())
+        );
+        assert_eq!(
+            parse_configured_address("[FD00::10]"),
+            Ok("fd00::10".to_string())
+        );
+        assert!(parse_configured_address("mac:AA-BB-CC-DD-EE").is_err());
+    }
+
+    #[test]
+    fn mac_address() {
+        assert_eq!(
+            parse_mac_address("AA-BB-CC-DD-EE-0F"),
+            Ok("aa:bb:cc:dd:ee:0f".to_string())
+        );
+        assert_eq!(
+            parse_mac_address("aa:bb:cc:dd:ee:0f"),
+            Ok("aa:bb:cc:dd:ee:0f".to_string())
+        );
+        assert!(parse_mac_address("AA-BB-CC-DD-EE-0G").is_err());
+        assert!(parse_mac_address("AABBCCDDEE0F").is_err());
+        assert!(parse_mac_address("A-BB-CC-DD-EE-0FF").is_err());
+
+        assert_eq!(
+            device_mac("mac:aa:bb:cc:dd:ee:0f"),
+            Some("aa:bb:cc:dd:ee:0f")
+        );
+        assert_eq!(device_mac("10.0.0.1"), None);
+    }
+
+    #[test]
+    fn device_alias() {
+        assert_eq!(
+            parse_device_alias("[FD00::10]=garage-strip"),
+            Ok(("fd00::10".to_string(), "garage-strip".to_string()))
+        );
+        assert!(parse_device_alias("10.0.0.5").is_err());
+        assert!(parse_device_alias("10.0.0.5= ").is_err());
+        assert!(parse_device_alias("http://10.0.0.5=garage-strip").is_err());
+    }
+
+    #[test]
+    fn device_address_list() {
+        let addresses = |list: &str| {
+            parse_device_addresses(list).map(|a| a.0.into_iter().map(|d| d.address).collect())
+        };
+
+        assert_eq!(
+            addresses("10.0.0.5,10.0.0.6"),
+            Ok(vec!["10.0.0.5".to_string(), "10.0.0.6".to_string()])
+        );
+        assert_eq!(
+            addresses(" 10.0.0.5  10.0.0.6 , [fd00::10]"),
+            Ok(vec![
+                "10.0.0.5".to_string(),
+                "10.0.0.6".to_string(),
+                "fd00::10".to_string()
+            ])
+        );
+
+        let e = addresses("10.0.0.5,,10.0.0.6").unwrap_err();
+        assert!(e.contains("empty entry"), "{e}");
+        assert!(addresses("10.0.0.5,").is_err());
+        assert!(addresses("10.0.0.5;10.0.0.6").is_err());
+    }
+
+    #[test]
+    fn merge_address_lists() {
+        let list = |addresses: &[&str]| {
+            DeviceAddresses(
+                addresses
+                    .iter()
+                    .map(|a| DeviceAddress {
+                        address: a.to_string(),
+                        model: None,
+                    })
+                    .collect(),
+            )
+        };
+
+        let merged: Vec<String> = merge_device_addresses(&[
+            list(&["10.0.0.5", "Power-Strip.local"]),
+            list(&["power-strip.local", "10.0.0.6", "10.0.0.5"]),
+        ])
+        .into_iter()
+        .map(|d| d.address)
+        .collect();
+        assert_eq!(merged, ["10.0.0.5", "Power-Strip.local", "10.0.0.6"]);
+    }
+
+    #[test]
+    fn device_address_with_model() {
+        let device = |address: &str, model: Option<&str>| DeviceAddress {
+            address: address.to_string(),
+            model: model.map(|m| m.to_string()),
+        };
+
+        assert_eq!(
+            parse_device_addresses(
+                "10.0.0.5:p304m power-strip.local:P110M,[FD00::10]:P304M fd00::11"
+            )
+            .unwrap()
+            .0,
+            [
+                device("10.0.0.5", Some("P304M")),
+                device("power-strip.local", Some("P110M")),
+                device("fd00::10", Some("P304M")),
+                device("fd00::11", None),
+            ]
+        );
+        assert_eq!(
+            parse_device_addresses("mac:AA-BB-CC-DD-EE-FF:P304M,mac:aa:bb:cc:dd:ee:00")
+                .unwrap()
+                .0,
+            [
+                device("mac:aa:bb:cc:dd:ee:ff", Some("P304M")),
+                device("mac:aa:bb:cc:dd:ee:00", None),
+            ]
+        );
+
+        let e = parse_device_addresses("10.0.0.5:EP40").unwrap_err();
+        assert!(e.contains("expected one of P304M, P110M"), "{e}");
+        assert!(parse_device_addresses("10.0.0.5:").is_err());
+    }
+
+    #[test]
+    fn host_for_url() {
+        assert_eq!(url_host("10.0.0.1"), "10.0.0.1");
+        assert_eq!(url_host("fd00::10"), "[fd00::10]");
+        assert_eq!(url_host("power-strip.local"), "power-strip.local");
+    }
+}
@@ -0,0 +1,173 @@
+use reqwest::Url;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::Duration;
+use tracing::warn;
+
+/// Where and when to alert about a plug's power use.
+#[derive(Clone, Debug)]
+pub struct AlertConfig {
+    pub webhook_url: Url,
+    pub threshold_watts: u64,
+}
+
+/// The JSON posted to the webhook.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Alert {
+    pub device_id: String,
+    pub nickname: String,
+    pub current_power_watts: u64,
+    pub threshold_watts: u64,
+}
+
+/// Posts to a webhook when a plug's power use goes over the threshold, without alerting again
+/// until it has dropped below 90% of the threshold so readings hovering around it don't flood the
+/// webhook.
+pub struct PowerAlerts {
+    config: AlertConfig,
+    // Kept separate from the devices' clients, which are only for talking to devices
+    client: reqwest::Client,
+    firing: HashSet<String>,
+}
+
+impl PowerAlerts {
+    pub fn new(config: AlertConfig) -> Self {
+        PowerAlerts {
+            config,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            firing: HashSet::new(),
+        }
+    }
+
+    /// Records a plug's reading, returning the alert to send if it has just gone over the
+    /// threshold.
+    pub fn observe(
+        &mut self,
+        device_id: &str,
+        nickname: &str,
+        current_power_watts: u64,
+    ) -> Option<Alert> {
+        let threshold = self.config.threshold_watts;
+
+        if current_power_watts > threshold {
+            if !self.firing.insert(device_id.to_string()) {
+                return None;
+            }
+
+            Some(Alert {
+                device_id: device_id.to_string(),
+                nickname: nickname.to_string(),
+                current_power_watts,
+                threshold_watts: threshold,
+            })
+        } else {
+            if (current_power_watts as f64) < threshold as f64 * 0.9 {
+                self.firing.remove(device_id);
+            }
+            None
+        }
+    }
+
+    /// Posts the alert in the background, so a slow webhook doesn't hold up reading the devices.
+    pub fn send(&self, alert: Alert) {
+        let request = self
+            .client
+            .post(self.config.webhook_url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&alert).unwrap());
+
+        tokio::spawn(async move {
+            let result = request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            if let Err(e) = result {
+                warn!("Failed to send alert for {}: {e}", alert.device_id);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Alert, AlertConfig, PowerAlerts};
+    use axum::Router;
+    use axum::extract::State;
+    use axum::routing::post;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn alerts(webhook_url: &str) -> PowerAlerts {
+        PowerAlerts::new(AlertConfig {
+            webhook_url: webhook_url.parse().unwrap(),
+            threshold_watts: 100,
+        })
+    }
+
+    #[test]
+    fn alert_once_until_below_hysteresis() {
+        let mut alerts = alerts("http://localhost/");
+
+        assert_eq!(alerts.observe("plug-1", "Kettle", 100), None);
+        assert_eq!(
+            alerts.observe("plug-1", "Kettle", 101),
+            Some(Alert {
+                device_id: "plug-1".to_string(),
+                nickname: "Kettle".to_string(),
+                current_power_watts: 101,
+                threshold_watts: 100,
+            })
+        );
+
+        // Still over, or not far enough under, the threshold
+        assert_eq!(alerts.observe("plug-1", "Kettle", 150), None);
+        assert_eq!(alerts.observe("plug-1", "Kettle", 95), None);
+        assert_eq!(alerts.observe("plug-1", "Kettle", 120), None);
+
+        // Other plugs are tracked separately
+        assert!(alerts.observe("plug-2", "Heater", 120).is_some());
+
+        assert_eq!(alerts.observe("plug-1", "Kettle", 89), None);
+        assert!(alerts.observe("plug-1", "Kettle", 120).is_some());
+    }
+
+    #[tokio::test]
+    async fn send_alert() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let webhook = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State(tx): State<mpsc::Sender<String>>, body: String| async move {
+                        tx.send(body).await.unwrap();
+                    },
+                ),
+            )
+            .with_state(tx);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, webhook).await.unwrap() });
+
+        let mut alerts = alerts(&format!("http://{address}/hook"));
+        let alert = alerts.observe("plug-1", "Kettle", 120).unwrap();
+        alerts.send(alert);
+
+        let body = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "device_id": "plug-1",
+                "nickname": "Kettle",
+                "current_power_watts": 120,
+                "threshold_watts": 100,
+            })
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::exporter::PowerUse;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::collections::HashSet;
+use tracing::warn;
+
+/// Limits how many label combinations power use is recorded with, so plugs whose nicknames keep
+/// changing can't grow the series served without bound.
+pub struct CardinalityGuard {
+    limit: usize,
+    series: HashSet<PowerUse>,
+    current: Gauge,
+    limit_gauge: Gauge,
+}
+
+impl CardinalityGuard {
+    pub fn new(limit: usize) -> Self {
+        let limit_gauge = Gauge::default();
+        limit_gauge.set(limit as i64);
+
+        CardinalityGuard {
+            limit,
+            series: HashSet::new(),
+            current: Gauge::default(),
+            limit_gauge,
+        }
+    }
+
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "label_cardinality_current",
+            "Number of label combinations power use is recorded with",
+            self.current.clone(),
+        );
+        registry.register(
+            "label_cardinality_limit",
+            "Maximum number of label combinations power use is recorded with",
+            self.limit_gauge.clone(),
+        );
+    }
+
+    /// Whether power use can be recorded with the labels, which it always can if it already has
+    /// been, but otherwise only while under the limit.
+    pub fn admit(&mut self, labels: &PowerUse) -> bool {
+        if self.series.contains(labels) {
+            return true;
+        }
+
+        if self.series.len() >= self.limit {
+            warn!(
+                "Not recording power use for plug {} of {} as the limit of {} label combinations has been reached",
+                labels.position, labels.ip_address, self.limit
+            );
+            return false;
+        }
+
+        self.series.insert(labels.clone());
+        self.current.set(self.series.len() as i64);
+        true
+    }
+
+    pub fn contains(&self, labels: &PowerUse) -> bool {
+        self.series.contains(labels)
+    }
+
+    /// Forgets the labels once their series is no longer served, making room for another.
+    pub fn remove(&mut self, labels: &PowerUse) {
+        self.series.remove(labels);
+        self.current.set(self.series.len() as i64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CardinalityGuard;
+    use crate::exporter::{AliasLabel, PowerUse};
+
+    fn labels(nickname: &str) -> PowerUse {
+        PowerUse {
+            power_strip_id: "123".to_string(),
+            ip_address: "10.0.0.1".to_string(),
+            device_id: "456".to_string(),
+            nickname: nickname.to_string(),
+            group: String::new(),
+            position: 1,
+            strip_alias: AliasLabel::default(),
+        }
+    }
+
+    #[test]
+    fn admits_up_to_the_limit() {
+        let mut guard = CardinalityGuard::new(2);
+        assert_eq!(guard.limit_gauge.get(), 2);
+
+        assert!(guard.admit(&labels("Kettle")));
+        assert!(guard.admit(&labels("Kettle 2")));
+        assert!(!guard.admit(&labels("Kettle 3")));
+        assert_eq!(guard.current.get(), 2);
+
+        // Labels already recorded with are still admitted at the limit
+        assert!(guard.admit(&labels("Kettle")));
+
+        guard.remove(&labels("Kettle"));
+        assert_eq!(guard.current.get(), 1);
+        assert!(guard.admit(&labels("Kettle 3")));
+        assert!(guard.contains(&labels("Kettle 3")));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::config::{Credentials, DeviceConfig};
+use crate::connect::client_for_device;
+use crate::exporter::TapoClient;
+use std::time::Duration;
+use tapo::Error;
+
+/// What was found on a device that passed its check.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Checked {
+    pub model: String,
+    pub plugs: usize,
+}
+
+/// Connects to the device and checks it can be read from, giving up after the timeout.
+pub async fn check_device(
+    device: &DeviceConfig,
+    credentials: &Credentials,
+    timeout: Duration,
+) -> Result<Checked, String> {
+    let result = tokio::time::timeout(timeout, async {
+        let client =
+            client_for_device(credentials, &device.address, device.model.as_deref()).await?;
+        check_client(client.as_ref()).await
+    })
+    .await;
+
+    match result {
+        Ok(checked) => checked.map_err(|e| e.to_string()),
+        Err(_) => Err(format!(
+            "timed out after {}",
+            humantime::format_duration(timeout)
+        )),
+    }
+}
+
+/// Reads the device's information and the power use of each of its plugs once, as a scrape would.
+pub async fn check_client(client: &(dyn TapoClient + Send + Sync)) -> Result<Checked, Error> {
+    let info = client.device_info().await?;
+    let children = client.child_devices().await?;
+    if client.monitors_energy() {
+        let device_ids: Vec<String> = children.iter().map(|c| c.device_id.clone()).collect();
+        client.get_all_plug_powers(&device_ids).await?;
+    }
+
+    Ok(Checked {
+        model: info.model,
+        plugs: children.len(),
+    })
+}
+
+/// Describes the outcome of checking a device in a single line.
+pub fn summary(address: &str, result: &Result<Checked, String>) -> String {
+    match result {
+        Ok(checked) if checked.plugs == 1 => {
+            format!("PASS {address}: {} with 1 plug", checked.model)
+        }
+        Ok(checked) => format!(
+            "PASS {address}: {} with {} plugs",
+            checked.model, checked.plugs
+        ),
+        Err(e) => format!("FAIL {address}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Checked, check_client, summary};
+    use crate::fake::{FakeClient, plug};
+    use tapo::Error;
+
+    fn power_strip() -> FakeClient {
+        FakeClient::new("10.0.0.1")
+            .with_model("P304M", "1.0.5")
+            .with_plugs(vec![plug("456", "", 1), plug("789", "", 2)])
+    }
+
+    #[tokio::test]
+    async fn check_every_plug() {
+        let checked = check_client(&power_strip()).await;
+        assert_eq!(
+            checked.unwrap(),
+            Checked {
+                model: "P304M".to_string(),
+                plugs: 2,
+            }
+        );
+
+        let mut failing_plug = power_strip();
+        failing_plug.power_watts.remove("789");
+        let e = check_client(&failing_plug).await.unwrap_err();
+        assert!(matches!(e, Error::DeviceNotFound));
+    }
+
+    #[test]
+    fn summaries() {
+        let checked = |plugs| {
+            Ok(Checked {
+                model: "P304M".to_string(),
+                plugs,
+            })
+        };
+
+        assert_eq!(
+            summary("10.0.0.1", &checked(3)),
+            "PASS 10.0.0.1: P304M with 3 plugs"
+        );
+        assert_eq!(
+            summary("10.0.0.1", &checked(1)),
+            "PASS 10.0.0.1: P304M with 1 plug"
+        );
+        assert_eq!(
+            summary("10.0.0.2", &Err("timed out after 10s".to_string())),
+            "FAIL 10.0.0.2: timed out after 10s"
+        );
+    }
+}
@@ -0,0 +1,445 @@
+use crate::config::{Credentials, DeviceConfig};
+use crate::plugins;
+use crate::scan::{self, FoundDevice};
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The TP-Link cloud's API, which the Tapo app signs in to and lists the account's devices from,
+/// unless the account is in a region with its own.
+pub const DEFAULT_CLOUD_URL: &str = "https://eu-wap.tplinkcloud.com";
+
+/// How long to wait for the devices on the Tapo account to respond to being discovered.
+const DISCOVERY_DURATION: Duration = Duration::from_secs(5);
+
+/// Sends requests to the TP-Link cloud, returning the `result` of each response.
+#[async_trait]
+pub trait CloudTransport {
+    /// Sends the request, with the token from signing in for anything but signing in itself.
+    async fn request(
+        &self,
+        token: Option<&str>,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value, String>;
+}
+
+pub struct HttpTransport {
+    client: reqwest::Client,
+    url: Url,
+}
+
+impl HttpTransport {
+    /// Creates a transport for the cloud's API at the URL, such as [`DEFAULT_CLOUD_URL`].
+    pub fn new(url: Url) -> Self {
+        HttpTransport {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            url,
+        }
+    }
+}
+
+impl Default for HttpTransport {
+    fn default() -> Self {
+        HttpTransport::new(DEFAULT_CLOUD_URL.parse().unwrap())
+    }
+}
+
+#[async_trait]
+impl CloudTransport for HttpTransport {
+    async fn request(
+        &self,
+        token: Option<&str>,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
+        let mut url = self.url.clone();
+        if let Some(token) = token {
+            url.query_pairs_mut().append_pair("token", token);
+        }
+
+        let response: CloudResponse = self
+            .client
+            .post(url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Failed to reach the TP-Link cloud: {e}"))?
+            .json()
+            .await
+            .map_err(|e| format!("Unexpected response from the TP-Link cloud: {e}"))?;
+
+        match (response.error_code, response.result) {
+            (0, Some(result)) => Ok(result),
+            (0, None) => Err("The TP-Link cloud responded without a result".to_string()),
+            (code, _) => Err(format!(
+                "The TP-Link cloud responded with error {code}: {}",
+                response.msg.unwrap_or_default()
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CloudResponse {
+    error_code: i64,
+    msg: Option<String>,
+    result: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct LoginResult {
+    token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceListResult {
+    device_list: Vec<CloudRecord>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CloudRecord {
+    device_id: String,
+    device_model: String,
+    device_mac: String,
+}
+
+/// A device registered to the Tapo account.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CloudDevice {
+    pub device_id: String,
+    /// The model as the exporter names it, without the region the cloud gives it with.
+    pub model: String,
+    /// The MAC address, formatted as `aa:bb:cc:dd:ee:ff`.
+    pub mac: String,
+}
+
+/// Signs in to the Tapo account and lists the devices registered to it that are supported models.
+/// The cloud doesn't know the devices' local addresses, so they're found by [`resolve`].
+pub async fn cloud_devices(
+    transport: &(dyn CloudTransport + Send + Sync),
+    credentials: &Credentials,
+) -> Result<Vec<CloudDevice>, String> {
+    let login = transport
+        .request(
+            None,
+            json!({
+                "method": "login",
+                "params": {
+                    "appType": "Tapo_Android",
+                    "cloudUserName": credentials.username,
+                    "cloudPassword": credentials.password,
+                    "terminalUUID": terminal_uuid(&credentials.username),
+                },
+            }),
+        )
+        .await?;
+    let login: LoginResult = serde_json::from_value(login)
+        .map_err(|e| format!("Unexpected response signing in to the TP-Link cloud: {e}"))?;
+
+    let list = transport
+        .request(Some(&login.token), json!({"method": "getDeviceList"}))
+        .await?;
+    let list: DeviceListResult = serde_json::from_value(list)
+        .map_err(|e| format!("Unexpected device list from the TP-Link cloud: {e}"))?;
+
+    Ok(list
+        .device_list
+        .into_iter()
+        .filter_map(|record| {
+            let (_, model) = plugins::plugin_for(&record.device_model)?;
+            Some(CloudDevice {
+                device_id: record.device_id,
+                model,
+                mac: format_mac(&record.device_mac),
+            })
+        })
+        .collect())
+}
+
+/// Identifies the exporter to the cloud the same way each time it signs in to the account, as the
+/// app does with the phone it's on.
+fn terminal_uuid(username: &str) -> String {
+    let hash = format!(
+        "{:016x}{:016x}",
+        xxhash_rust::xxh3::xxh3_64(username.as_bytes()),
+        xxhash_rust::xxh3::xxh3_64(b"p304m-prometheus-exporter")
+    );
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hash[0..8],
+        &hash[8..12],
+        &hash[12..16],
+        &hash[16..20],
+        &hash[20..32]
+    )
+}
+
+/// Formats a MAC address given with or without separators, e.g. `AABBCCDDEEFF`, as
+/// `aa:bb:cc:dd:ee:ff`.
+fn format_mac(mac: &str) -> String {
+    let digits: Vec<char> = mac
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    digits
+        .chunks(2)
+        .map(|pair| pair.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Pairs each of the account's devices with the address of the device found on the local network
+/// with its MAC address, along with those that weren't found.
+pub fn resolve(
+    devices: Vec<CloudDevice>,
+    found: &[FoundDevice],
+) -> (Vec<(CloudDevice, String)>, Vec<CloudDevice>) {
+    let mut resolved = Vec::new();
+    let mut unreachable = Vec::new();
+    for device in devices {
+        match found.iter().find(|f| format_mac(&f.mac) == device.mac) {
+            Some(found) => resolved.push((device, found.address.clone())),
+            None => unreachable.push(device),
+        }
+    }
+    (resolved, unreachable)
+}
+
+/// Lists the supported devices on the Tapo account, and finds their addresses on the local network
+/// by their MAC addresses, along with those that weren't found.
+pub async fn find_devices(
+    cloud_url: &Url,
+    credentials: &Credentials,
+    duration: Duration,
+) -> Result<(Vec<(CloudDevice, String)>, Vec<CloudDevice>), String> {
+    let transport = HttpTransport::new(cloud_url.clone());
+    let devices = cloud_devices(&transport, credentials).await?;
+
+    let socket = scan::bind()
+        .await
+        .map_err(|e| format!("Failed to open socket for discovery: {e}"))?;
+    let found = scan::discover(&socket, &scan::broadcast_addresses(), duration)
+        .await
+        .map_err(|e| format!("Failed to send discovery packet: {e}"))?;
+    Ok(resolve(devices, &found))
+}
+
+/// The devices on the Tapo account to read from along with those given, leaving out any already
+/// given. Devices that can't be found on the local network are only warned about, as is failing
+/// to list them.
+pub async fn devices_to_read(
+    cloud_url: &Url,
+    credentials: &Credentials,
+    configured: &[DeviceConfig],
+) -> Vec<DeviceConfig> {
+    let (found, unreachable) = match find_devices(cloud_url, credentials, DISCOVERY_DURATION).await
+    {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("Failed to list the devices on the Tapo account: {e}");
+            return Vec::new();
+        }
+    };
+
+    for device in unreachable.iter() {
+        warn!(
+            "{} {} on the Tapo account wasn't found on the local network",
+            device.model, device.mac
+        );
+    }
+    let devices: Vec<DeviceConfig> = found
+        .into_iter()
+        .filter(|(_, address)| {
+            !configured
+                .iter()
+                .any(|d| d.address.eq_ignore_ascii_case(address))
+        })
+        .map(|(device, address)| DeviceConfig {
+            address,
+            model: Some(device.model),
+            ..DeviceConfig::default()
+        })
+        .collect();
+    info!(
+        "Found {} devices to read from on the Tapo account, and {} that weren't on the local network",
+        devices.len(),
+        unreachable.len()
+    );
+    devices
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CloudDevice, CloudTransport, HttpTransport, cloud_devices, format_mac, resolve};
+    use crate::config::Credentials;
+    use crate::scan::FoundDevice;
+    use async_trait::async_trait;
+    use axum::extract::Query;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Responds with the results recorded from the cloud, keeping the requests it was sent.
+    struct RecordedTransport {
+        requests: Mutex<Vec<(Option<String>, serde_json::Value)>>,
+    }
+
+    #[async_trait]
+    impl CloudTransport for RecordedTransport {
+        async fn request(
+            &self,
+            token: Option<&str>,
+            body: serde_json::Value,
+        ) -> Result<serde_json::Value, String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((token.map(str::to_string), body.clone()));
+            match (
+                body["method"].as_str(),
+                body["params"]["cloudPassword"].as_str(),
+            ) {
+                (Some("login"), Some("secret")) => Ok(json!({
+                    "accountId": "1234567",
+                    "regTime": "2023-01-02 03:04:05",
+                    "email": "me@example.com",
+                    "token": "recorded-token",
+                })),
+                (Some("login"), _) => Err(
+                    "The TP-Link cloud responded with error -20601: Incorrect email or password"
+                        .to_string(),
+                ),
+                (Some("getDeviceList"), _) => Ok(json!({
+                    "deviceList": [
+                        {
+                            "deviceType": "SMART.TAPOPLUG",
+                            "role": 0,
+                            "fwVer": "1.0.5 Build 230905 Rel.134850",
+                            "deviceId": "8022AA",
+                            "deviceName": "P304M",
+                            "deviceHwVer": "1.0",
+                            "deviceMac": "AABBCCDDEEFF",
+                            "deviceModel": "P304M(UK)",
+                            "status": 1,
+                        },
+                        {
+                            "deviceType": "SMART.TAPOBULB",
+                            "role": 0,
+                            "deviceId": "8022BB",
+                            "deviceName": "L530",
+                            "deviceMac": "AABBCCDDEE00",
+                            "deviceModel": "L530E(EU)",
+                            "status": 1,
+                        },
+                        {
+                            "deviceType": "SMART.TAPOPLUG",
+                            "role": 0,
+                            "deviceId": "8022CC",
+                            "deviceName": "P110M",
+                            "deviceMac": "AABBCCDDEE11",
+                            "deviceModel": "P110M(UK)",
+                            "status": 0,
+                        },
+                    ],
+                })),
+                _ => Err("Unexpected request".to_string()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_supported_devices() {
+        let transport = RecordedTransport {
+            requests: Mutex::new(Vec::new()),
+        };
+        let mut credentials = Credentials {
+            username: "me@example.com".to_string(),
+            password: "secret".to_string(),
+            account: None,
+        };
+
+        let devices = cloud_devices(&transport, &credentials).await.unwrap();
+        assert_eq!(
+            devices,
+            [
+                CloudDevice {
+                    device_id: "8022AA".to_string(),
+                    model: "P304M".to_string(),
+                    mac: "aa:bb:cc:dd:ee:ff".to_string(),
+                },
+                CloudDevice {
+                    device_id: "8022CC".to_string(),
+                    model: "P110M".to_string(),
+                    mac: "aa:bb:cc:dd:ee:11".to_string(),
+                },
+            ]
+        );
+        let requests = transport.requests.lock().unwrap().clone();
+        assert_eq!(requests[0].1["params"]["cloudUserName"], "me@example.com");
+        assert_eq!(requests[1].0.as_deref(), Some("recorded-token"));
+
+        credentials.password = "wrong".to_string();
+        let e = cloud_devices(&transport, &credentials).await.unwrap_err();
+        assert!(e.contains("Incorrect email or password"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn sends_requests_to_cloud_url() {
+        let cloud = Router::new().route(
+            "/",
+            post(|Query(query): Query<HashMap<String, String>>| async move {
+                Json(json!({
+                    "error_code": 0,
+                    "result": {"token": query.get("token").cloned().unwrap_or_default()},
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, cloud).await.unwrap() });
+
+        let transport = HttpTransport::new(format!("http://{address}").parse().unwrap());
+        let result = transport
+            .request(Some("abc"), json!({"method": "getDeviceList"}))
+            .await
+            .unwrap();
+        assert_eq!(result["token"], "abc");
+    }
+
+    #[test]
+    fn resolves_addresses_by_mac() {
+        let device = |mac: &str| CloudDevice {
+            device_id: mac.to_string(),
+            model: "P304M".to_string(),
+            mac: mac.to_string(),
+        };
+        let found = [FoundDevice {
+            address: "192.168.1.10".to_string(),
+            mac: "AA:BB:CC:DD:EE:FF".to_string(),
+            model: "P304M(UK)".to_string(),
+        }];
+
+        let (resolved, unreachable) = resolve(
+            vec![device("aa:bb:cc:dd:ee:ff"), device("aa:bb:cc:dd:ee:11")],
+            &found,
+        );
+        assert_eq!(
+            resolved,
+            [(device("aa:bb:cc:dd:ee:ff"), "192.168.1.10".to_string())]
+        );
+        assert_eq!(unreachable, [device("aa:bb:cc:dd:ee:11")]);
+
+        assert_eq!(format_mac("AA-BB-CC-DD-EE-FF"), "aa:bb:cc:dd:ee:ff");
+    }
+}
@@ -0,0 +1,982 @@
+use crate::address::{parse_bind_address, parse_configured_address};
+use crate::exporter::{AliasMode, ReadinessPolicy};
+use crate::labels::is_valid_label_name;
+use crate::otlp::OtlpProtocol;
+use crate::plugins::parse_model;
+use crate::plugs::PlugMatcher;
+use crate::power_histogram::{PowerBuckets, parse_buckets};
+use axum::http::HeaderValue;
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use ipnet::IpNet;
+use reqwest::Url;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings read from a TOML file, which are overridden by any given on the command line or by
+/// environment variables.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: Option<u16>,
+    #[serde(default, deserialize_with = "bind_address")]
+    pub bind_address: Option<IpAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub devices_file: Option<PathBuf>,
+    #[serde(default, deserialize_with = "mode")]
+    pub unix_socket_mode: Option<u32>,
+    pub health_port: Option<u16>,
+    #[serde(default, deserialize_with = "bind_address")]
+    pub health_bind_address: Option<IpAddr>,
+    pub username: Option<String>,
+    pub username_file: Option<PathBuf>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub discover_mdns: Option<bool>,
+    pub discover_from_cloud: Option<bool>,
+    #[serde(default, deserialize_with = "url")]
+    pub cloud_url: Option<Url>,
+    pub strict_models: Option<bool>,
+    pub watch_config: Option<bool>,
+    pub connect_attempts: Option<u32>,
+    #[serde(default, deserialize_with = "duration")]
+    pub connect_retry_delay: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub mac_resolve_interval: Option<Duration>,
+    pub enable_management_api: Option<bool>,
+    pub management_api_token: Option<String>,
+    #[serde(default, deserialize_with = "networks")]
+    pub probe_allow_cidr: Option<Vec<IpNet>>,
+    #[serde(default, deserialize_with = "duration")]
+    pub probe_client_ttl: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub probe_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub http_timeout: Option<Duration>,
+    pub http_max_concurrent: Option<usize>,
+    #[serde(default, deserialize_with = "duration")]
+    pub min_scrape_interval: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub info_refresh_interval: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub scrape_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "origins")]
+    pub cors_allowed_origins: Option<Vec<HeaderValue>>,
+    #[serde(default, deserialize_with = "url")]
+    pub alert_webhook_url: Option<Url>,
+    pub alert_threshold_watts: Option<u64>,
+    #[serde(default, deserialize_with = "url")]
+    pub otlp_endpoint: Option<Url>,
+    pub otlp_protocol: Option<OtlpProtocol>,
+    #[serde(default, deserialize_with = "duration")]
+    pub push_interval: Option<Duration>,
+    pub statsd_address: Option<String>,
+    pub statsd_tags: Option<bool>,
+    #[serde(default, deserialize_with = "url")]
+    pub push_gateway_url: Option<Url>,
+    pub push_gateway_username: Option<String>,
+    pub push_gateway_password: Option<String>,
+    pub push_job: Option<String>,
+    #[serde(default, deserialize_with = "labels")]
+    pub push_grouping_labels: BTreeMap<String, String>,
+    #[serde(default, deserialize_with = "url")]
+    pub remote_write_url: Option<Url>,
+    pub remote_write_bearer_token: Option<String>,
+    pub remote_write_username: Option<String>,
+    pub remote_write_password: Option<String>,
+    #[serde(default, deserialize_with = "url")]
+    pub influx_url: Option<Url>,
+    pub influx_bucket: Option<String>,
+    pub influx_org: Option<String>,
+    pub influx_token: Option<String>,
+    pub no_listen: Option<bool>,
+    pub history_size: Option<usize>,
+    #[serde(default, deserialize_with = "power_buckets")]
+    pub power_histogram_buckets: Option<PowerBuckets>,
+    pub max_label_cardinality: Option<usize>,
+    pub state_file: Option<PathBuf>,
+    #[serde(default, deserialize_with = "metric_prefix")]
+    pub metric_prefix: Option<String>,
+    pub pid_file: Option<PathBuf>,
+    pub readiness_policy: Option<ReadinessPolicy>,
+    pub alias_mode: Option<AliasMode>,
+    pub include_plugs: Option<Vec<PlugMatcher>>,
+    pub exclude_plugs: Option<Vec<PlugMatcher>>,
+    #[serde(default, deserialize_with = "duration")]
+    pub readiness_window: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub shutdown_timeout: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    #[serde(deserialize_with = "device_address")]
+    pub address: String,
+    /// The model of the device, rather than asking the device for it.
+    #[serde(default, deserialize_with = "model")]
+    pub model: Option<String>,
+    /// Labels added to the device's target in service discovery.
+    #[serde(default, deserialize_with = "labels")]
+    pub labels: BTreeMap<String, String>,
+    /// Username for the Tapo account the device is on, if not the one given by `--username`.
+    pub username: Option<String>,
+    /// Password for the Tapo account the device is on, if not the one given by `--password`.
+    pub password: Option<String>,
+    /// Path of a TOML file with the `username` and `password` for the account the device is on.
+    pub credentials_file: Option<PathBuf>,
+    /// Name for the account the device is on, used in metrics instead of a hash of the username.
+    pub account: Option<String>,
+    /// Name for the device used in the labels of its plugs' power use, in place of or along with
+    /// their nicknames.
+    pub alias: Option<String>,
+    /// Group the device is in, such as `server-room`, added as a `group` label to its metrics.
+    #[serde(default)]
+    pub group: String,
+}
+
+/// The Tapo account a device is read with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    /// Identifies the account in metrics without revealing the username, if the device isn't on
+    /// the account given by `--username`.
+    pub account: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialsFile {
+    username: String,
+    password: String,
+}
+
+impl DeviceConfig {
+    /// Picks the device's own credentials, falling back to those given by `--username` and
+    /// `--password`.
+    pub fn credentials(
+        &self,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<Credentials, String> {
+        let (own_username, own_password) = match &self.credentials_file {
+            Some(_) if self.username.is_some() || self.password.is_some() => {
+                return Err(format!(
+                    "{} has both a credentials file and a username or password",
+                    self.address
+                ));
+            }
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+                let file: CredentialsFile = toml::from_str(&contents)
+                    .map_err(|e| format!("Invalid credentials in {}: {e}", path.display()))?;
+                (Some(file.username), Some(file.password))
+            }
+            None => (self.username.clone(), self.password.clone()),
+        };
+
+        let account = match (&self.account, &own_username) {
+            (Some(account), _) => Some(account.clone()),
+            (None, Some(username)) => Some(format!(
+                "{:016x}",
+                xxhash_rust::xxh3::xxh3_64(username.as_bytes())
+            )),
+            (None, None) => None,
+        };
+
+        match (
+            own_username.or(username.map(str::to_string)),
+            own_password.or(password.map(str::to_string)),
+        ) {
+            (Some(username), Some(password)) => Ok(Credentials {
+                username,
+                password,
+                account,
+            }),
+            _ => Err(format!(
+                "{} has no username and password, either of its own or given by --username and --password",
+                self.address
+            )),
+        }
+    }
+}
+
+/// Picks the credentials for each device, failing with every device missing credentials at once,
+/// rather than one per attempt.
+pub fn device_credentials(
+    devices: &[DeviceConfig],
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<Vec<Credentials>, String> {
+    let (credentials, missing): (Vec<_>, Vec<_>) = devices
+        .iter()
+        .map(|device| device.credentials(username, password))
+        .partition(Result::is_ok);
+    if !missing.is_empty() {
+        let missing: Vec<String> = missing.into_iter().filter_map(Result::err).collect();
+        return Err(missing.join("\n"));
+    }
+
+    Ok(credentials.into_iter().flatten().collect())
+}
+
+/// Reads and validates a configuration file.
+pub fn load(path: &Path) -> Result<ConfigFile, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+    parse(&contents).map_err(|e| format!("Invalid configuration in {}: {e}", path.display()))
+}
+
+fn parse(contents: &str) -> Result<ConfigFile, String> {
+    toml::from_str(contents).map_err(|e| e.to_string())
+}
+
+/// Reads a file listing one device per line.
+pub fn load_devices_file(path: &Path) -> Result<Vec<DeviceConfig>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+    parse_devices_file(&contents).map_err(|e| format!("Invalid devices in {}, {e}", path.display()))
+}
+
+/// Parses a device's address on each line, optionally followed by its model, ignoring blank lines
+/// and anything after a `#`.
+fn parse_devices_file(contents: &str) -> Result<Vec<DeviceConfig>, String> {
+    let mut devices = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+        let (address, model) = match fields[..] {
+            [] => continue,
+            [address] => (address, None),
+            [address, model] => (address, Some(model)),
+            _ => {
+                return Err(format!(
+                    "line {}: expected an address optionally followed by a model",
+                    i + 1
+                ));
+            }
+        };
+
+        devices.push(DeviceConfig {
+            address: parse_configured_address(address)
+                .map_err(|e| format!("line {}: {e}", i + 1))?,
+            model: model
+                .map(parse_model)
+                .transpose()
+                .map_err(|e| format!("line {}: {e}", i + 1))?,
+            ..Default::default()
+        });
+    }
+
+    Ok(merge_devices(Vec::new(), devices))
+}
+
+/// Adds devices to those already given, skipping any with the same address as one before it,
+/// ignoring case as DNS names are case-insensitive.
+pub fn merge_devices(devices: Vec<DeviceConfig>, more: Vec<DeviceConfig>) -> Vec<DeviceConfig> {
+    let mut merged: Vec<DeviceConfig> = Vec::with_capacity(devices.len() + more.len());
+    for device in devices.into_iter().chain(more) {
+        if !merged
+            .iter()
+            .any(|d| d.address.eq_ignore_ascii_case(&device.address))
+        {
+            merged.push(device);
+        }
+    }
+    merged
+}
+
+/// Where the devices to read from were given, so they can be read again when reloading.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceSources {
+    /// Devices given by `--device-addresses`, which replace those in the configuration file.
+    pub addresses: Option<Vec<DeviceConfig>>,
+    pub config_file: Option<PathBuf>,
+    pub devices_file: Option<PathBuf>,
+    /// Aliases given by `--device-alias`, by address, which replace those in the configuration
+    /// file.
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl DeviceSources {
+    /// Whether reading the devices again could find different devices.
+    pub fn reloadable(&self) -> bool {
+        (self.addresses.is_none() && self.config_file.is_some()) || self.devices_file.is_some()
+    }
+
+    /// Reads the devices from the files again.
+    pub fn load(&self) -> Result<Vec<DeviceConfig>, String> {
+        let config_devices = match (&self.addresses, &self.config_file) {
+            (None, Some(path)) => load(path)?.devices,
+            _ => Vec::new(),
+        };
+        self.resolve(config_devices)
+    }
+
+    /// Combines the devices given as options and in the devices file with those already read
+    /// from the configuration file.
+    pub fn resolve(&self, config_devices: Vec<DeviceConfig>) -> Result<Vec<DeviceConfig>, String> {
+        let devices = match &self.addresses {
+            Some(addresses) => addresses.clone(),
+            None => config_devices,
+        };
+        let mut devices = match &self.devices_file {
+            Some(path) => merge_devices(devices, load_devices_file(path)?),
+            None => devices,
+        };
+
+        for device in devices.iter_mut() {
+            if let Some((_, alias)) = self
+                .aliases
+                .iter()
+                .find(|(address, _)| address.eq_ignore_ascii_case(&device.address))
+            {
+                device.alias = Some(alias.clone());
+            }
+        }
+        Ok(devices)
+    }
+}
+
+/// Picks the value given on the command line or by an environment variable, falling back to the
+/// value from the configuration file before the default.
+pub fn merge<T>(matches: &ArgMatches, id: &str, value: T, file_value: Option<T>) -> T {
+    match (matches.value_source(id), file_value) {
+        (None | Some(ValueSource::DefaultValue), Some(file_value)) => file_value,
+        _ => value,
+    }
+}
+
+/// Picks a secret given either directly or as the path of a file holding it, on the command line or
+/// by an environment variable, falling back to either form in the configuration file. The file's
+/// path is given by the option with `_file` added to the secret's ID.
+pub fn merge_secret(
+    matches: &ArgMatches,
+    id: &str,
+    value: Option<String>,
+    path: Option<PathBuf>,
+    file_value: Option<String>,
+    file_path: Option<PathBuf>,
+) -> Result<Option<String>, String> {
+    let path_id = format!("{id}_file");
+    let given = |id: &str| {
+        !matches!(
+            matches.value_source(id),
+            None | Some(ValueSource::DefaultValue)
+        )
+    };
+
+    if given(id) {
+        return Ok(value);
+    }
+    if given(&path_id) {
+        return path.map(|path| read_secret(&path)).transpose();
+    }
+    match (file_value, file_path) {
+        (Some(_), Some(_)) => Err(format!(
+            "Only one of {id} and {path_id} can be given in the configuration file"
+        )),
+        (Some(value), None) => Ok(Some(value)),
+        (None, Some(path)) => read_secret(&path).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Reads a secret from a file, such as a Docker or Kubernetes secret, ignoring any whitespace
+/// around it.
+pub fn read_secret(path: &Path) -> Result<String, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+    match contents.trim() {
+        "" => Err(format!("{} is empty", path.display())),
+        secret => Ok(secret.to_string()),
+    }
+}
+
+pub fn parse_origin(origin: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(origin.trim()).map_err(|_| format!("{origin} isn't a valid origin"))
+}
+
+/// Checks a metric prefix is lower case letters, digits and underscores, starting with a letter.
+pub fn parse_metric_prefix(prefix: &str) -> Result<String, String> {
+    let mut chars = prefix.chars();
+    if chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        Ok(prefix.to_string())
+    } else {
+        Err(format!(
+            "{prefix} isn't a valid metric prefix, which must match [a-z][a-z0-9_]*"
+        ))
+    }
+}
+
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8).map_err(|_| format!("{mode} isn't an octal file mode"))
+}
+
+/// Deserializes a string with the parser used for the equivalent command line option.
+fn parse_with<'de, D, T>(
+    deserializer: D,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| parse(&value))
+        .transpose()
+        .map_err(D::Error::custom)
+}
+
+/// Deserializes a list of strings with the parser used for the equivalent command line option.
+fn parse_list_with<'de, D, T>(
+    deserializer: D,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<Vec<String>>::deserialize(deserializer)?
+        .map(|values| values.iter().map(|v| parse(v)).collect())
+        .transpose()
+        .map_err(D::Error::custom)
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    parse_with(deserializer, |value| {
+        humantime::parse_duration(value).map_err(|e| format!("{value} isn't a duration: {e}"))
+    })
+}
+
+fn bind_address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<IpAddr>, D::Error> {
+    parse_with(deserializer, parse_bind_address)
+}
+
+fn mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    parse_with(deserializer, parse_mode)
+}
+
+fn networks<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<IpNet>>, D::Error> {
+    parse_list_with(deserializer, |value| {
+        value
+            .parse()
+            .map_err(|_| format!("{value} isn't a network in CIDR notation"))
+    })
+}
+
+fn origins<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<HeaderValue>>, D::Error> {
+    parse_list_with(deserializer, parse_origin)
+}
+
+fn metric_prefix<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    parse_with(deserializer, parse_metric_prefix)
+}
+
+fn power_buckets<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<PowerBuckets>, D::Error> {
+    parse_with(deserializer, parse_buckets)
+}
+
+fn url<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Url>, D::Error> {
+    parse_with(deserializer, |value| {
+        value
+            .parse()
+            .map_err(|e| format!("{value} isn't a URL: {e}"))
+    })
+}
+
+fn device_address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    parse_configured_address(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+fn model<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    parse_with(deserializer, parse_model)
+}
+
+fn labels<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+    let labels = BTreeMap::<String, String>::deserialize(deserializer)?;
+
+    for name in labels.keys() {
+        if !is_valid_label_name(name) {
+            return Err(D::Error::custom(format!("{name} isn't a valid label name")));
+        }
+    }
+
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConfigFile, Credentials, DeviceConfig, merge, merge_devices, merge_secret, parse};
+    use super::{DeviceSources, parse_devices_file};
+    use crate::exporter::ReadinessPolicy;
+    use clap::{Arg, Command};
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_config() {
+        let config = parse(
+            r#"
+            [server]
+            port = 9090
+            bind_address = "[::]"
+            http_timeout = "1m"
+            readiness_policy = "all"
+
+            [[devices]]
+            address = "10.0.0.1"
+            model = "p304m"
+            labels = { room = "office" }
+            group = "server-room"
+
+            [[devices]]
+            address = "fd00::10"
+
+            [[devices]]
+            address = "mac:AA-BB-CC-DD-EE-FF"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port, Some(9090));
+        assert_eq!(config.server.bind_address, Some("::".parse().unwrap()));
+        assert_eq!(config.server.http_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(config.server.readiness_policy, Some(ReadinessPolicy::All));
+        assert_eq!(config.server.username, None);
+
+        assert_eq!(config.devices.len(), 3);
+        assert_eq!(config.devices[0].address, "10.0.0.1");
+        assert_eq!(config.devices[0].model.as_deref(), Some("P304M"));
+        assert_eq!(config.devices[0].labels["room"], "office");
+        assert_eq!(config.devices[0].group, "server-room");
+        assert_eq!(config.devices[1].address, "fd00::10");
+        assert_eq!(config.devices[1].model, None);
+        assert!(config.devices[1].labels.is_empty());
+        assert!(config.devices[1].group.is_empty());
+        assert_eq!(config.devices[2].address, "mac:aa:bb:cc:dd:ee:ff");
+    }
+
+    #[test]
+    fn parse_empty_config() {
+        let config = parse("").unwrap();
+
+        assert_eq!(config.server.port, None);
+        assert!(config.devices.is_empty());
+    }
+
+    #[test]
+    fn malformed_config() {
+        let cases = [
+            ("[server]\nport = \"eighty\"", "port"),
+            ("[server]\nhttp_timeout = \"soon\"", "http_timeout"),
+            ("[server]\nprot = 8080", "prot"),
+            (
+                "[server]\npower_histogram_buckets = \"100,50\"",
+                "power_histogram_buckets",
+            ),
+            ("[server]\nmetric_prefix = \"Tapo\"", "metric_prefix"),
+            ("[server]\nmetric_prefix = \"2tapo\"", "metric_prefix"),
+            ("[[devices]]\naddress = \"fe80::1%eth0\"", "address"),
+            (
+                "[[devices]]\naddress = \"10.0.0.1\"\nmodel = \"EP40\"",
+                "model",
+            ),
+            (
+                "[[devices]]\naddress = \"10.0.0.1\"\nlabels = { \"my-room\" = \"office\" }",
+                "my-room",
+            ),
+            ("[server\nport = 8080", "server"),
+        ];
+
+        for (contents, key) in cases {
+            let e = parse(contents).unwrap_err();
+            assert!(e.contains(key), "{e} should name {key}");
+        }
+    }
+
+    #[test]
+    fn parse_devices() {
+        let devices = parse_devices_file(
+            "# Downstairs\n\
+            10.0.0.1\n\
+            \n\
+            \t10.0.0.2   p110m  # Kettle\n\
+            [fd00::10]\n\
+            power-strip.local P304M\n\
+            10.0.0.1 P304M\n",
+        )
+        .unwrap();
+
+        let devices: Vec<(&str, Option<&str>)> = devices
+            .iter()
+            .map(|d| (d.address.as_str(), d.model.as_deref()))
+            .collect();
+        assert_eq!(
+            devices,
+            [
+                ("10.0.0.1", None),
+                ("10.0.0.2", Some("P110M")),
+                ("fd00::10", None),
+                ("power-strip.local", Some("P304M")),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_devices_file() {
+        let cases = [
+            ("10.0.0.1\n10.0.0.2 EP40\n", "line 2"),
+            ("# Devices\n\nfe80::1%eth0\n", "line 3"),
+            ("10.0.0.1 P304M extra\n", "line 1"),
+        ];
+
+        for (contents, line) in cases {
+            let e = parse_devices_file(contents).unwrap_err();
+            assert!(e.starts_with(line), "{e} should name {line}");
+        }
+    }
+
+    #[test]
+    fn devices_from_every_source() {
+        let path = std::env::temp_dir().join(format!("devices-{}", std::process::id()));
+        std::fs::write(&path, "10.0.0.2\n10.0.0.3 P110M\n").unwrap();
+        let device = |address: &str| DeviceConfig {
+            address: address.to_string(),
+            ..Default::default()
+        };
+        let addresses = |devices: Vec<DeviceConfig>| -> Vec<String> {
+            devices.into_iter().map(|d| d.address).collect()
+        };
+
+        let sources = DeviceSources {
+            addresses: Some(vec![device("10.0.0.1"), device("10.0.0.2")]),
+            config_file: None,
+            devices_file: Some(path.clone()),
+            aliases: BTreeMap::new(),
+        };
+        assert_eq!(
+            addresses(sources.resolve(vec![device("10.0.0.9")]).unwrap()),
+            ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+
+        // Devices in the configuration file are only replaced by those given as options
+        let sources = DeviceSources {
+            addresses: None,
+            ..sources
+        };
+        assert_eq!(
+            addresses(sources.resolve(vec![device("10.0.0.9")]).unwrap()),
+            ["10.0.0.9", "10.0.0.2", "10.0.0.3"]
+        );
+        assert!(sources.reloadable());
+
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            addresses(merge_devices(
+                vec![device("10.0.0.1")],
+                vec![device("10.0.0.1"), device("10.0.0.2")]
+            )),
+            ["10.0.0.1", "10.0.0.2"]
+        );
+    }
+
+    #[test]
+    fn device_aliases() {
+        let config = parse(
+            "[[devices]]\naddress = \"10.0.0.1\"\nalias = \"desk\"\n\n[[devices]]\naddress = \"10.0.0.2\"\nalias = \"garage\"",
+        )
+        .unwrap();
+        assert_eq!(config.devices[0].alias.as_deref(), Some("desk"));
+
+        // Aliases given as options replace those in the configuration file
+        let sources = DeviceSources {
+            addresses: None,
+            config_file: None,
+            devices_file: None,
+            aliases: BTreeMap::from([("10.0.0.2".to_string(), "shed".to_string())]),
+        };
+        let aliases: Vec<_> = sources
+            .resolve(config.devices)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.alias)
+            .collect();
+        assert_eq!(
+            aliases,
+            [Some("desk".to_string()), Some("shed".to_string())]
+        );
+    }
+
+    #[test]
+    fn device_credentials() {
+        let device = |contents: &str| parse(contents).unwrap().devices.remove(0);
+
+        // Devices without their own credentials use the global ones, without an account label
+        assert_eq!(
+            device("[[devices]]\naddress = \"10.0.0.1\"").credentials(Some("me"), Some("secret")),
+            Ok(Credentials {
+                username: "me".to_string(),
+                password: "secret".to_string(),
+                account: None,
+            })
+        );
+
+        let own = device(
+            "[[devices]]\naddress = \"10.0.0.1\"\nusername = \"parents\"\npassword = \"theirs\"",
+        )
+        .credentials(Some("me"), Some("secret"))
+        .unwrap();
+        assert_eq!(own.username, "parents");
+        assert_eq!(own.password, "theirs");
+        let account = own.account.unwrap();
+        assert_eq!(account.len(), 16);
+        assert!(!account.contains("parents"));
+
+        let aliased = device(
+            "[[devices]]\naddress = \"10.0.0.1\"\nusername = \"parents\"\npassword = \"theirs\"\naccount = \"parents\"",
+        )
+        .credentials(None, None)
+        .unwrap();
+        assert_eq!(aliased.account.as_deref(), Some("parents"));
+
+        // The password can fall back to the global one
+        let partial = device("[[devices]]\naddress = \"10.0.0.1\"\nusername = \"parents\"")
+            .credentials(Some("me"), Some("secret"))
+            .unwrap();
+        assert_eq!(partial.password, "secret");
+
+        let e = device("[[devices]]\naddress = \"10.0.0.1\"")
+            .credentials(Some("me"), None)
+            .unwrap_err();
+        assert!(e.contains("10.0.0.1"), "{e}");
+    }
+
+    #[test]
+    fn device_credentials_file() {
+        let path = std::env::temp_dir().join(format!("credentials-{}.toml", std::process::id()));
+        std::fs::write(&path, "username = \"parents\"\npassword = \"theirs\"\n").unwrap();
+
+        let device = DeviceConfig {
+            address: "10.0.0.1".to_string(),
+            credentials_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let credentials = device.credentials(Some("me"), Some("secret")).unwrap();
+        assert_eq!(credentials.username, "parents");
+        assert_eq!(credentials.password, "theirs");
+        assert!(credentials.account.is_some());
+
+        let device = DeviceConfig {
+            username: Some("me".to_string()),
+            ..device
+        };
+        assert!(device.credentials(None, None).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let command = Command::new("test")
+            .arg(Arg::new("port").long("port").default_value("8080"))
+            .arg(Arg::new("username").long("username"));
+        let file = ConfigFile {
+            server: super::ServerConfig {
+                port: Some(9090),
+                username: Some("file".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Values from the file are used over defaults and missing values
+        let matches = command.clone().get_matches_from(["test"]);
+        assert_eq!(merge(&matches, "port", 8080, file.server.port), 9090);
+        assert_eq!(merge(&matches, "port", 8080, None), 8080);
+        assert_eq!(
+            merge(
+                &matches,
+                "username",
+                None,
+                file.server.username.clone().map(Some)
+            ),
+            Some("file".to_string())
+        );
+
+        let matches = command.get_matches_from(["test", "--port", "7070", "--username", "flag"]);
+        assert_eq!(merge(&matches, "port", 7070, file.server.port), 7070);
+        assert_eq!(
+            merge(
+                &matches,
+                "username",
+                Some("flag".to_string()),
+                file.server.username.map(Some)
+            ),
+            Some("flag".to_string())
+        );
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        // Cargo sets the variable when running tests. Setting one here would race with the other
+        // tests reading the environment
+        let command = Command::new("test").arg(
+            Arg::new("directory")
+                .long("directory")
+                .env("CARGO_MANIFEST_DIR")
+                .default_value("."),
+        );
+
+        let matches = command.get_matches_from(["test"]);
+        assert_eq!(
+            merge(&matches, "directory", "environment", Some("file")),
+            "environment"
+        );
+    }
+
+    #[test]
+    fn secret_precedence() {
+        let dir = std::env::temp_dir().join(format!("secrets-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let secret = dir.join("password");
+        std::fs::write(&secret, "from file\n").unwrap();
+        let empty = dir.join("empty");
+        std::fs::write(&empty, " \n").unwrap();
+        let missing = dir.join("missing");
+
+        let command = Command::new("test")
+            .arg(Arg::new("password").long("password"))
+            .arg(
+                Arg::new("password_file")
+                    .long("password-file")
+                    .conflicts_with("password"),
+            );
+        let from_env = Command::new("test")
+            .arg(Arg::new("password").long("password"))
+            .arg(
+                Arg::new("password_file")
+                    .long("password-file")
+                    .env("CARGO_MANIFEST_DIR")
+                    .conflicts_with("password"),
+            );
+
+        let merge = |args: &[&str],
+                     value: Option<&str>,
+                     path: Option<&Path>,
+                     file_value: Option<&str>,
+                     file_path: Option<&Path>| {
+            let matches = command.clone().try_get_matches_from(args)?;
+            Ok::<_, clap::Error>(merge_secret(
+                &matches,
+                "password",
+                value.map(str::to_string),
+                path.map(Path::to_path_buf),
+                file_value.map(str::to_string),
+                file_path.map(Path::to_path_buf),
+            ))
+        };
+
+        // Given directly or as a file on the command line, over either in the configuration file
+        assert_eq!(
+            merge(
+                &["test", "--password", "flag"],
+                Some("flag"),
+                None,
+                Some("config"),
+                None
+            )
+            .unwrap(),
+            Ok(Some("flag".to_string()))
+        );
+        let path = secret.to_str().unwrap();
+        assert_eq!(
+            merge(
+                &["test", "--password-file", path],
+                None,
+                Some(&secret),
+                Some("config"),
+                None
+            )
+            .unwrap(),
+            Ok(Some("from file".to_string()))
+        );
+        assert!(
+            merge(
+                &["test", "--password", "flag", "--password-file", path],
+                None,
+                None,
+                None,
+                None
+            )
+            .is_err()
+        );
+
+        // Falling back to the configuration file
+        assert_eq!(
+            merge(&["test"], None, None, Some("config"), None).unwrap(),
+            Ok(Some("config".to_string()))
+        );
+        assert_eq!(
+            merge(&["test"], None, None, None, Some(&secret)).unwrap(),
+            Ok(Some("from file".to_string()))
+        );
+        assert_eq!(merge(&["test"], None, None, None, None).unwrap(), Ok(None));
+        assert!(
+            merge(&["test"], None, None, Some("config"), Some(&secret))
+                .unwrap()
+                .is_err()
+        );
+
+        // The file given by an environment variable overrides the configuration file. Cargo sets
+        // the variable when running tests, and the path it's read from is passed to the merge
+        let matches = from_env.clone().get_matches_from(["test"]);
+        assert_eq!(
+            merge_secret(
+                &matches,
+                "password",
+                None,
+                Some(secret.clone()),
+                Some("config".to_string()),
+                None
+            ),
+            Ok(Some("from file".to_string()))
+        );
+
+        // Files that can't be used are named in the error
+        for path in [&empty, &missing] {
+            let e = merge(&["test"], None, None, None, Some(path))
+                .unwrap()
+                .unwrap_err();
+            assert!(e.contains(path.to_str().unwrap()), "{e}");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long to wait after the configuration file last changed before reloading it, so it isn't
+/// read while it's still being written.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The symlink Kubernetes swaps for another to update every file of a mounted ConfigMap at once.
+const KUBERNETES_DATA: &str = "..data";
+
+/// Sends on `reloads` once changes to the file have settled, until the watcher is dropped. The
+/// directory the file is in is watched rather than the file itself, as Kubernetes updates a
+/// ConfigMap by replacing a symlink next to it rather than writing to the file. Changes to
+/// anything else in the directory are ignored.
+pub fn watch_config(path: &Path, reloads: mpsc::Sender<()>) -> notify::Result<RecommendedWatcher> {
+    let file_name = path.file_name().map(OsStr::to_os_string);
+    let (changes_tx, mut changes) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        let changed = event
+            .paths
+            .iter()
+            .filter_map(|p| p.file_name())
+            .any(|name| file_name.as_deref() == Some(name) || name == OsStr::new(KUBERNETES_DATA));
+        if changed && !event.kind.is_access() {
+            let _ = changes_tx.send(());
+        }
+    })?;
+    let directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        while changes.recv().await.is_some() {
+            loop {
+                match tokio::time::timeout(DEBOUNCE, changes.recv()).await {
+                    Ok(Some(())) => continue,
+                    // The watcher was dropped
+                    Ok(None) => return,
+                    Err(_) => break,
+                }
+            }
+            if reloads.send(()).await.is_err() {
+                return;
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod test {
+    use super::watch_config;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn reloads_once_changes_settle() {
+        let dir = std::env::temp_dir().join(format!("config-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "[[devices]]\naddress = \"10.0.0.1\"\n").unwrap();
+
+        let (tx, mut reloads) = mpsc::channel(1);
+        let watcher = watch_config(&path, tx).unwrap();
+
+        // Written in several goes, as an editor might
+        for address in ["10.0.0.2", "10.0.0.3"] {
+            let mut contents = std::fs::read_to_string(&path).unwrap();
+            contents.push_str(&format!("[[devices]]\naddress = \"{address}\"\n"));
+            std::fs::write(&path, contents).unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), reloads.recv())
+            .await
+            .expect("the change should have been noticed")
+            .unwrap();
+        let devices = crate::config::load(&path).unwrap().devices;
+        assert_eq!(devices.len(), 3);
+        // The writes were close enough together to reload once
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), reloads.recv())
+                .await
+                .is_err()
+        );
+
+        drop(watcher);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_other_files() {
+        let dir = std::env::temp_dir().join(format!("config-watch-other-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "[[devices]]\naddress = \"10.0.0.1\"\n").unwrap();
+
+        let (tx, mut reloads) = mpsc::channel(1);
+        let watcher = watch_config(&path, tx).unwrap();
+
+        std::fs::write(dir.join("notes.txt"), "not the configuration").unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), reloads.recv())
+                .await
+                .is_err()
+        );
+
+        // Whereas Kubernetes swapping the data it links to is
+        std::os::unix::fs::symlink(&dir, dir.join("..data")).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), reloads.recv())
+            .await
+            .expect("the swap should have been noticed")
+            .unwrap();
+
+        drop(watcher);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
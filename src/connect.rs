@@ -0,0 +1,805 @@
+use crate::address::url_host;
+use crate::config::{Credentials, DeviceConfig};
+use crate::error::{AppError, describe_connect_error};
+use crate::exporter::{ChildDevice, DeviceIdentity, DeviceInfo, GenericClient, TapoClient};
+use crate::plugins;
+use crate::retry;
+use crate::sensors::SensorReading;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tapo::responses::CurrentPowerResult;
+use tapo::{ApiClient, Error, TapoResponseError};
+use tracing::{info, warn};
+
+/// Models of Tapo plug and power strip, not all of which report their power use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "UPPER")]
+pub enum KnownModel {
+    P304M,
+    P110M,
+    P115,
+    EP25,
+    P100,
+    P105,
+    P300,
+    P306,
+    H100,
+    EP40,
+}
+
+impl KnownModel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KnownModel::P304M => "P304M",
+            KnownModel::P110M => "P110M",
+            KnownModel::P115 => "P115",
+            KnownModel::EP25 => "EP25",
+            KnownModel::P100 => "P100",
+            KnownModel::P105 => "P105",
+            KnownModel::P300 => "P300",
+            KnownModel::P306 => "P306",
+            KnownModel::H100 => "H100",
+            KnownModel::EP40 => "EP40",
+        }
+    }
+}
+
+impl FromStr for KnownModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <KnownModel as clap::ValueEnum>::from_str(s, true)
+            .map_err(|_| format!("{s} is not a known model"))
+    }
+}
+
+impl fmt::Display for KnownModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Connects to devices, so what connects to them can be swapped for fakes where there are no
+/// devices to connect to, such as in tests.
+#[async_trait]
+pub trait ClientFactory: Send + Sync {
+    /// Asks the device for its model, as it reports it, then connects to it with the plugin for
+    /// that model, failing if there isn't one.
+    async fn detect(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+    ) -> Result<(String, Box<dyn TapoClient + Send + Sync>), Error>;
+
+    /// Connects to the device as the model, which is named as the plugin for it names it.
+    async fn build(
+        &self,
+        credentials: &Credentials,
+        model: &str,
+        address: &str,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error>;
+
+    /// Connects to a device of a model no plugin supports, only to read its device information.
+    async fn build_generic(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error>;
+}
+
+/// Connects to devices with the tapo crate, using the plugin registered for each model.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TapoClientFactory;
+
+#[async_trait]
+impl ClientFactory for TapoClientFactory {
+    async fn detect(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+    ) -> Result<(String, Box<dyn TapoClient + Send + Sync>), Error> {
+        let device = ApiClient::new(&credentials.username, &credentials.password)
+            .generic_device(url_host(address))
+            .await?;
+        let model = device.get_device_info().await?.model;
+
+        // Carry on with the session the model was asked over, rather than logging in again
+        let (plugin, _) = plugins::plugin_for(&model).ok_or_else(|| unsupported_model(&model))?;
+        let client = plugin.take_over(credentials, address, device).await?;
+        Ok((model, client))
+    }
+
+    async fn build(
+        &self,
+        credentials: &Credentials,
+        model: &str,
+        address: &str,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+        let (plugin, _) = plugins::plugin_for(model).ok_or_else(|| unsupported_model(model))?;
+        plugin.build_client(credentials, address).await
+    }
+
+    async fn build_generic(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+        let client = ApiClient::new(&credentials.username, &credentials.password)
+            .generic_device(url_host(address))
+            .await?;
+
+        Ok(Box::new(GenericClient {
+            address: address.to_string(),
+            account: credentials.account.clone(),
+            client,
+        }))
+    }
+}
+
+/// Creates a client for the device with the plugin for its model, asking the device for its model
+/// if it isn't known.
+pub async fn client_for_device(
+    credentials: &Credentials,
+    device_address: &str,
+    model: Option<&str>,
+) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+    connect_device(&TapoClientFactory, credentials, device_address, model).await
+}
+
+/// Creates a client for the device with the factory, asking the device for its model if it isn't
+/// known and failing if no plugin supports it.
+pub async fn connect_device(
+    factory: &dyn ClientFactory,
+    credentials: &Credentials,
+    device_address: &str,
+    model: Option<&str>,
+) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+    let (model, client): (_, Box<dyn TapoClient + Send + Sync>) = match model {
+        Some(model) => {
+            let (_, name) = plugins::plugin_for(model).ok_or_else(|| unsupported_model(model))?;
+            let client = factory.build(credentials, &name, device_address).await?;
+            (
+                model.to_string(),
+                Box::new(DeclaredModelClient {
+                    client,
+                    model: model.to_string(),
+                }),
+            )
+        }
+        None => factory.detect(credentials, device_address).await?,
+    };
+    Ok(Box::new(IdentifiedClient::new(client, Some(model))))
+}
+
+/// How the error [`client_for_device`] fails with for a model no plugin supports ends.
+const UNSUPPORTED_MODEL: &str = " is not a supported model";
+
+fn unsupported_model(model: &str) -> Error {
+    Error::Validation {
+        field: "model".to_string(),
+        message: format!("{model}{UNSUPPORTED_MODEL}"),
+    }
+}
+
+/// Whether [`client_for_device`] failed because the device is a model no plugin supports, rather
+/// than because it couldn't be connected to.
+pub fn is_unsupported_model(e: &Error) -> bool {
+    matches!(e, Error::Validation { field, message } if field == "model" && message.ends_with(UNSUPPORTED_MODEL))
+}
+
+/// Creates a client for a device of a model no plugin supports, which only reads its device
+/// information.
+pub async fn generic_client(
+    factory: &dyn ClientFactory,
+    credentials: &Credentials,
+    device_address: &str,
+) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+    let client = factory.build_generic(credentials, device_address).await?;
+    Ok(Box::new(IdentifiedClient::new(client, None)))
+}
+
+/// The client for a device that keeps what it's told about the device and what the device last
+/// reported, so it can be named when it can't be read.
+struct IdentifiedClient {
+    client: Box<dyn TapoClient + Send + Sync>,
+    identity: Mutex<DeviceIdentity>,
+}
+
+impl IdentifiedClient {
+    fn new(client: Box<dyn TapoClient + Send + Sync>, model: Option<String>) -> Self {
+        let identity = DeviceIdentity {
+            address: client.address().to_string(),
+            device_id: None,
+            model,
+        };
+        IdentifiedClient {
+            client,
+            identity: Mutex::new(identity),
+        }
+    }
+}
+
+#[async_trait]
+impl TapoClient for IdentifiedClient {
+    fn address(&self) -> &str {
+        self.client.address()
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        self.identity.lock().unwrap().clone()
+    }
+
+    async fn refresh_session(&mut self) -> Result<(), Error> {
+        self.client.refresh_session().await
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo, Error> {
+        let info = self.client.device_info().await?;
+
+        let mut identity = self.identity.lock().unwrap();
+        identity.device_id = Some(info.power_strip_id.clone());
+        identity.model = Some(info.model.clone());
+        Ok(info)
+    }
+
+    async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+        self.client.child_devices().await
+    }
+
+    async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
+        self.client.get_power_for_plug(device_id).await
+    }
+
+    async fn get_all_plug_powers(
+        &self,
+        device_ids: &[String],
+    ) -> Result<HashMap<String, CurrentPowerResult>, Error> {
+        self.client.get_all_plug_powers(device_ids).await
+    }
+
+    fn reads_all_plug_powers_at_once(&self) -> bool {
+        self.client.reads_all_plug_powers_at_once()
+    }
+
+    fn monitors_energy(&self) -> bool {
+        self.client.monitors_energy()
+    }
+
+    async fn sensor_readings(&self) -> Result<Vec<SensorReading>, Error> {
+        self.client.sensor_readings().await
+    }
+}
+
+/// The client for a device whose model was given rather than asked for, so reading from a device
+/// that's a different model fails saying so, rather than with whatever the device responded.
+struct DeclaredModelClient {
+    client: Box<dyn TapoClient + Send + Sync>,
+    model: String,
+}
+
+impl DeclaredModelClient {
+    /// Adds the likely cause to errors from the device not understanding a request, or responding
+    /// with something other than expected.
+    fn explain(&self, e: Error) -> Error {
+        match e {
+            Error::Serde(_)
+            | Error::Tapo(
+                TapoResponseError::InvalidRequest
+                | TapoResponseError::InvalidResponse
+                | TapoResponseError::MalformedRequest
+                | TapoResponseError::InvalidParameters
+                | TapoResponseError::Unknown(_),
+            ) => Error::Other(anyhow::anyhow!(
+                "{e}, which may be because {} isn't the {} it was given as",
+                self.client.address(),
+                self.model
+            )),
+            e => e,
+        }
+    }
+}
+
+#[async_trait]
+impl TapoClient for DeclaredModelClient {
+    fn address(&self) -> &str {
+        self.client.address()
+    }
+
+    async fn refresh_session(&mut self) -> Result<(), Error> {
+        self.client.refresh_session().await
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo, Error> {
+        let info = self
+            .client
+            .device_info()
+            .await
+            .map_err(|e| self.explain(e))?;
+
+        // Models can be reported with their region, such as `P110M(UK)`
+        if !plugins::normalize_model(&info.model).eq_ignore_ascii_case(&self.model) {
+            return Err(Error::Validation {
+                field: "model".to_string(),
+                message: format!(
+                    "{} was given as a {} but is a {}",
+                    self.address(),
+                    self.model,
+                    info.model
+                ),
+            });
+        }
+        Ok(info)
+    }
+
+    async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+        self.client
+            .child_devices()
+            .await
+            .map_err(|e| self.explain(e))
+    }
+
+    async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
+        self.client
+            .get_power_for_plug(device_id)
+            .await
+            .map_err(|e| self.explain(e))
+    }
+
+    async fn get_all_plug_powers(
+        &self,
+        device_ids: &[String],
+    ) -> Result<HashMap<String, CurrentPowerResult>, Error> {
+        self.client
+            .get_all_plug_powers(device_ids)
+            .await
+            .map_err(|e| self.explain(e))
+    }
+
+    fn reads_all_plug_powers_at_once(&self) -> bool {
+        self.client.reads_all_plug_powers_at_once()
+    }
+
+    fn monitors_energy(&self) -> bool {
+        self.client.monitors_energy()
+    }
+
+    async fn sensor_readings(&self) -> Result<Vec<SensorReading>, Error> {
+        self.client
+            .sensor_readings()
+            .await
+            .map_err(|e| self.explain(e))
+    }
+}
+
+/// Connects to each device being started with using the factory, trying again with a growing
+/// delay in case it's rebooting, and skipping it if it still can't be connected to, so it can be
+/// added by reloading the devices later. Devices of models that aren't supported are only read for
+/// their device information, or stop the exporter from starting if `strict_models`.
+pub async fn build_clients(
+    factory: &dyn ClientFactory,
+    devices: &[DeviceConfig],
+    credentials: Vec<Credentials>,
+    strict_models: bool,
+    attempts: u32,
+    retry_delay: Duration,
+) -> Result<Vec<Box<dyn TapoClient + Send + Sync>>, AppError> {
+    let mut clients: Vec<Box<dyn TapoClient + Send + Sync>> = Vec::new();
+    let mut unsupported = Vec::new();
+    let mut skipped = Vec::new();
+
+    for (device, credentials) in devices.iter().zip(credentials) {
+        let description = format!("connect to {}", device.address);
+        let connect = retry::with_backoff(
+            &description,
+            attempts,
+            retry_delay,
+            // The device's model won't have changed by the next attempt
+            |e| !is_unsupported_model(e),
+            || {
+                connect_device(
+                    factory,
+                    &credentials,
+                    &device.address,
+                    device.model.as_deref(),
+                )
+            },
+        );
+        let e = match connect.await {
+            Ok(client) => {
+                clients.push(client);
+                continue;
+            }
+            Err(e) => e,
+        };
+        if !is_unsupported_model(&e) {
+            let reason = describe_connect_error(&e);
+            warn!("Skipping {}: {reason}", device.address);
+            skipped.push(format!("{} ({reason})", device.address));
+            continue;
+        }
+        if strict_models {
+            return Err(AppError::connect(&device.address, &e));
+        }
+
+        warn!(
+            "{} isn't supported ({e}), so only its device information will be served",
+            device.address
+        );
+        match generic_client(factory, &credentials, &device.address).await {
+            Ok(client) => {
+                clients.push(client);
+                unsupported.push(format!("{} ({e})", device.address));
+            }
+            Err(generic_error) => {
+                let reason = describe_connect_error(&generic_error);
+                warn!("Skipping {}: {reason}", device.address);
+                skipped.push(format!("{} ({reason})", device.address));
+            }
+        }
+    }
+
+    info!(
+        "Connected to {} of {} configured devices, {} of which aren't supported models, skipping {}",
+        clients.len(),
+        devices.len(),
+        unsupported.len(),
+        skipped.len()
+    );
+    if !unsupported.is_empty() {
+        info!(
+            "Only serving the device information of {}",
+            unsupported.join(", ")
+        );
+    }
+    if !skipped.is_empty() {
+        info!("Skipped {}", skipped.join(", "));
+    }
+    Ok(clients)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ClientFactory, DeclaredModelClient, IdentifiedClient, KnownModel, build_clients,
+        client_for_device, connect_device, is_unsupported_model, unsupported_model,
+    };
+    use crate::config::{Credentials, DeviceConfig};
+    use crate::exporter::{AccountLabel, ChildDevice, DeviceIdentity, DeviceInfo, TapoClient};
+    use crate::fake::FakeClient;
+    use crate::plugins;
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tapo::responses::CurrentPowerResult;
+    use tapo::{Error, TapoResponseError};
+
+    #[test]
+    fn known_models() {
+        assert_eq!("P304M".parse(), Ok(KnownModel::P304M));
+        assert_eq!("p110m".parse(), Ok(KnownModel::P110M));
+        assert_eq!("EP40".parse(), Ok(KnownModel::EP40));
+        assert_eq!(
+            "P999".parse::<KnownModel>(),
+            Err("P999 is not a known model".to_string())
+        );
+
+        // Names round trip through parsing
+        for model in <KnownModel as clap::ValueEnum>::value_variants() {
+            assert_eq!(model.name().parse(), Ok(*model));
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_models() {
+        let credentials = Credentials {
+            username: "me".to_string(),
+            password: "secret".to_string(),
+            account: None,
+        };
+        let e = client_for_device(&credentials, "10.0.0.1", Some("L530"))
+            .await
+            .err()
+            .unwrap();
+        assert!(is_unsupported_model(&e), "{e}");
+
+        // Nor is a device giving the wrong model
+        let e = Error::Validation {
+            field: "model".to_string(),
+            message: "10.0.0.1 was given as a P110M but is a P304M".to_string(),
+        };
+        assert!(!is_unsupported_model(&e));
+        assert!(!is_unsupported_model(&Error::Tapo(
+            TapoResponseError::InvalidCredentials(String::new())
+        )));
+    }
+
+    /// A P110M, which doesn't understand requests for its plugs.
+    struct PlugClient {}
+
+    #[async_trait]
+    impl TapoClient for PlugClient {
+        fn address(&self) -> &str {
+            "10.0.0.1"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            Ok(DeviceInfo {
+                power_strip_id: "123".to_string(),
+                ip_address: "10.0.0.1".to_string(),
+                model: "P110M(UK)".to_string(),
+                firmware_version: "1.0".to_string(),
+                hardware_version: "1.0".to_string(),
+                mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+                account: AccountLabel::default(),
+                group: String::new(),
+                master_on: None,
+            })
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            Err(Error::Tapo(TapoResponseError::InvalidRequest))
+        }
+
+        async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+            Err(Error::Tapo(TapoResponseError::SessionTimeout))
+        }
+    }
+
+    #[tokio::test]
+    async fn identity_is_kept_from_the_device() {
+        let client = IdentifiedClient::new(Box::new(PlugClient {}), Some("P110M".to_string()));
+        assert_eq!(
+            client.identity(),
+            DeviceIdentity {
+                address: "10.0.0.1".to_string(),
+                device_id: None,
+                model: Some("P110M".to_string()),
+            }
+        );
+
+        client.device_info().await.unwrap();
+        assert_eq!(
+            client.identity(),
+            DeviceIdentity {
+                address: "10.0.0.1".to_string(),
+                device_id: Some("123".to_string()),
+                model: Some("P110M(UK)".to_string()),
+            }
+        );
+
+        // What was last read is still known when the device can't be read
+        client.get_power_for_plug("456").await.unwrap_err();
+        assert_eq!(client.identity().device_id, Some("123".to_string()));
+    }
+
+    #[tokio::test]
+    async fn declared_model_mismatch() {
+        let client = DeclaredModelClient {
+            client: Box::new(PlugClient {}),
+            model: "P304M".to_string(),
+        };
+        assert_eq!(
+            client.device_info().await.unwrap_err().to_string(),
+            "Validation: model 10.0.0.1 was given as a P304M but is a P110M(UK)"
+        );
+        assert_eq!(
+            client.child_devices().await.unwrap_err().to_string(),
+            "Invalid request, which may be because 10.0.0.1 isn't the P304M it was given as"
+        );
+        // Errors unrelated to the model are left alone
+        assert_eq!(
+            client
+                .get_power_for_plug("456")
+                .await
+                .unwrap_err()
+                .to_string(),
+            "Session timeout"
+        );
+
+        let client = DeclaredModelClient {
+            client: Box::new(PlugClient {}),
+            model: "P110M".to_string(),
+        };
+        assert!(client.device_info().await.is_ok());
+    }
+
+    fn credentials() -> Credentials {
+        Credentials {
+            username: "me".to_string(),
+            password: "secret".to_string(),
+            account: None,
+        }
+    }
+
+    /// Connects to devices that report the models they're listed with, keeping what it's asked to
+    /// do. Devices that aren't listed can't be reached.
+    #[derive(Default)]
+    struct FakeFactory {
+        models: HashMap<&'static str, &'static str>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl FakeFactory {
+        fn new(models: &[(&'static str, &'static str)]) -> Self {
+            FakeFactory {
+                models: models.iter().copied().collect(),
+                calls: Mutex::default(),
+            }
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn reach(&self, address: &str) -> Result<(), Error> {
+            match self.models.contains_key(address) {
+                true => Ok(()),
+                false => Err(Error::Other(anyhow::anyhow!("connection refused"))),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ClientFactory for FakeFactory {
+        async fn detect(
+            &self,
+            _: &Credentials,
+            address: &str,
+        ) -> Result<(String, Box<dyn TapoClient + Send + Sync>), Error> {
+            self.calls.lock().unwrap().push(format!("detect {address}"));
+            self.reach(address)?;
+            let model = self.models[address];
+            let (_, name) = plugins::plugin_for(model).ok_or_else(|| unsupported_model(model))?;
+            self.calls.lock().unwrap().push(format!("{name} {address}"));
+            Ok((model.to_string(), Box::new(FakeClient::new(address))))
+        }
+
+        async fn build(
+            &self,
+            _: &Credentials,
+            model: &str,
+            address: &str,
+        ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{model} {address}"));
+            self.reach(address)?;
+            Ok(Box::new(FakeClient::new(address)))
+        }
+
+        async fn build_generic(
+            &self,
+            _: &Credentials,
+            address: &str,
+        ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("generic {address}"));
+            self.reach(address)?;
+            Ok(Box::new(FakeClient::new(address)))
+        }
+    }
+
+    #[tokio::test]
+    async fn route_models_to_their_plugins() {
+        for (reported, model) in [
+            ("P304M", "P304M"),
+            ("P304M(UK)", "P304M"),
+            ("P110M", "P110M"),
+            ("p110m(EU)", "P110M"),
+            ("P115", "P115"),
+            ("EP25", "EP25"),
+            ("P100", "P100"),
+            ("P105", "P105"),
+            ("P300", "P300"),
+            ("P306", "P306"),
+            ("H100", "H100"),
+        ] {
+            let factory = FakeFactory::new(&[("10.0.0.1", reported)]);
+            let client = connect_device(&factory, &credentials(), "10.0.0.1", None)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                factory.calls(),
+                ["detect 10.0.0.1".to_string(), format!("{model} 10.0.0.1")]
+            );
+            assert_eq!(client.identity().model.as_deref(), Some(reported));
+        }
+    }
+
+    #[tokio::test]
+    async fn route_unknown_model() {
+        let factory = FakeFactory::new(&[("10.0.0.1", "L530")]);
+
+        let e = connect_device(&factory, &credentials(), "10.0.0.1", None)
+            .await
+            .err()
+            .unwrap();
+        assert!(is_unsupported_model(&e), "{e}");
+        assert_eq!(factory.calls(), ["detect 10.0.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn route_unreachable_device() {
+        let factory = FakeFactory::default();
+
+        let e = connect_device(&factory, &credentials(), "10.0.0.1", None)
+            .await
+            .err()
+            .unwrap();
+        assert!(!is_unsupported_model(&e));
+        assert_eq!(e.to_string(), "connection refused");
+        assert_eq!(factory.calls(), ["detect 10.0.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn route_declared_model() {
+        let factory = FakeFactory::new(&[("10.0.0.1", "P304M")]);
+
+        let client = connect_device(&factory, &credentials(), "10.0.0.1", Some("p110m"))
+            .await
+            .unwrap();
+        // The device isn't asked for its model
+        assert_eq!(factory.calls(), ["P110M 10.0.0.1"]);
+        assert_eq!(client.identity().model.as_deref(), Some("p110m"));
+    }
+
+    #[tokio::test]
+    async fn build_clients_for_each_device() {
+        let factory = FakeFactory::new(&[("10.0.0.1", "P304M"), ("10.0.0.2", "L530")]);
+        let devices = ["10.0.0.1", "10.0.0.2", "10.0.0.3"].map(|address| DeviceConfig {
+            address: address.to_string(),
+            ..DeviceConfig::default()
+        });
+
+        let clients = build_clients(
+            &factory,
+            &devices,
+            vec![credentials(); 3],
+            false,
+            1,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+        let addresses: Vec<&str> = clients.iter().map(|c| c.address()).collect();
+        // The unsupported device is only read for its device information, and the unreachable one
+        // skipped
+        assert_eq!(addresses, ["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(
+            factory.calls(),
+            [
+                "detect 10.0.0.1",
+                "P304M 10.0.0.1",
+                "detect 10.0.0.2",
+                "generic 10.0.0.2",
+                "detect 10.0.0.3",
+            ]
+        );
+
+        let e = build_clients(
+            &factory,
+            &devices,
+            vec![credentials(); 3],
+            true,
+            1,
+            Duration::ZERO,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(e.to_string().contains("10.0.0.2"), "{e}");
+    }
+}
@@ -0,0 +1,124 @@
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// The service devices announce themselves as over mDNS.
+pub const SERVICE_TYPE: &str = "_tapo._tcp.local.";
+
+/// A device that has announced itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredDevice {
+    pub name: String,
+    pub address: String,
+}
+
+/// Browses for devices announcing themselves on the local network.
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    events: Receiver<ServiceEvent>,
+    seen: HashSet<String>,
+}
+
+impl Discovery {
+    pub fn start() -> Result<Self, mdns_sd::Error> {
+        let daemon = ServiceDaemon::new()?;
+        let events = daemon.browse(SERVICE_TYPE)?;
+
+        Ok(Discovery {
+            daemon,
+            events,
+            seen: HashSet::new(),
+        })
+    }
+
+    /// Waits for a device that hasn't been seen before, returning `None` if browsing stops.
+    pub async fn next(&mut self) -> Option<DiscoveredDevice> {
+        while let Ok(event) = self.events.recv_async().await {
+            let ServiceEvent::ServiceResolved(info) = event else {
+                continue;
+            };
+
+            // Devices are announced again periodically, and whenever their address changes
+            if let Some(device) = discovered_device(&info) {
+                if self.seen.insert(device.address.clone()) {
+                    return Some(device);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Forgets the device was seen, so it's returned again the next time it announces itself,
+    /// such as after failing to connect to it.
+    pub fn forget(&mut self, address: &str) {
+        self.seen.remove(address);
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Picks the address to connect to the device on, preferring IPv4. IPv6 link-local addresses are
+/// skipped as they'd need a zone identifier.
+fn discovered_device(info: &ServiceInfo) -> Option<DiscoveredDevice> {
+    let address = info
+        .get_addresses()
+        .iter()
+        .filter(|ip| match ip {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(ip) => !ip.is_unicast_link_local(),
+        })
+        .min_by_key(|ip| (ip.is_ipv6(), **ip))?;
+
+    Some(DiscoveredDevice {
+        name: info.get_fullname().to_string(),
+        address: address.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SERVICE_TYPE, discovered_device};
+    use mdns_sd::ServiceInfo;
+    use std::collections::HashMap;
+
+    fn service(addresses: &str) -> ServiceInfo {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            "office",
+            "office.local.",
+            addresses,
+            80,
+            None::<HashMap<String, String>>,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn discovered_device_address() {
+        let cases = [
+            ("192.168.0.10", Some("192.168.0.10")),
+            ("fd00::10,192.168.0.10", Some("192.168.0.10")),
+            ("fe80::1,fd00::10", Some("fd00::10")),
+            ("fe80::1", None),
+        ];
+
+        for (addresses, expected) in cases {
+            let device = discovered_device(&service(addresses));
+            assert_eq!(
+                device.as_ref().map(|d| d.address.as_str()),
+                expected,
+                "{addresses}"
+            );
+        }
+
+        assert_eq!(
+            discovered_device(&service("192.168.0.10")).unwrap().name,
+            "office._tapo._tcp.local."
+        );
+    }
+}
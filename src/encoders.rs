@@ -0,0 +1,277 @@
+use crate::energy::EnergyTotals;
+use crate::exporter::{ChildDevice, Inventory, PowerUse, device_info_labels, power_use_labels};
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const INFLUX_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Encodes the power use recorded for each plug as InfluxDB line protocol, with the measurement
+/// named after the metric prefix, e.g.
+/// `tapo_power_use_watts,power_strip_id=X,device_id=Y,nickname=Z,position=1 value=45i 1700000000000000000`.
+pub struct InfluxLineEncoder<'a> {
+    power_use: &'a Family<PowerUse, Gauge>,
+    prefix: &'a str,
+    timestamp: SystemTime,
+}
+
+impl<'a> InfluxLineEncoder<'a> {
+    pub fn new(
+        power_use: &'a Family<PowerUse, Gauge>,
+        prefix: &'a str,
+        timestamp: SystemTime,
+    ) -> Self {
+        InfluxLineEncoder {
+            power_use,
+            prefix,
+            timestamp,
+        }
+    }
+
+    /// Writes a line for each of the device's plugs, using what was last read from it to find the
+    /// plugs' series.
+    pub fn encode(&self, out: &mut String, address: &str, inventory: &Inventory) {
+        let escaped_info = device_info_labels(&inventory.device_info);
+        let timestamp = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        for child in inventory.children.iter() {
+            let Some(gauge) = self.power_use.get(&power_use_labels(
+                &escaped_info,
+                inventory.labelled_address(address),
+                child,
+                inventory.alias.as_ref(),
+            )) else {
+                continue;
+            };
+
+            write!(out, "{}_power_use_watts", self.prefix).unwrap();
+            write_tags(out, inventory, child);
+            writeln!(out, " value={}i {timestamp}", gauge.get()).unwrap();
+        }
+    }
+}
+
+/// Writes a `{prefix}_power` line for each of the device's plugs that was read, with the energy
+/// it's used so far if that's being kept, e.g.
+/// `tapo_power,power_strip_id=X,device_id=Y,nickname=Z,position=1 watts=45i,energy_wh=12.5 1700000000000000000`.
+pub fn encode_readings(
+    out: &mut String,
+    prefix: &str,
+    inventory: &Inventory,
+    energy_totals: Option<&EnergyTotals>,
+    timestamp: SystemTime,
+) {
+    let timestamp = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    for child in inventory.children.iter() {
+        let Some(watts) = inventory.power_watts.get(&child.device_id) else {
+            continue;
+        };
+
+        write!(out, "{prefix}_power").unwrap();
+        write_tags(out, inventory, child);
+        write!(out, " watts={watts}i").unwrap();
+        if let Some(total) = energy_totals
+            .and_then(|e| e.total(&inventory.device_info.power_strip_id, &child.device_id))
+        {
+            write!(out, ",energy_wh={total}").unwrap();
+        }
+        writeln!(out, " {timestamp}").unwrap();
+    }
+}
+
+/// Writes the tags identifying the plug, each preceded by a comma.
+fn write_tags(out: &mut String, inventory: &Inventory, child: &ChildDevice) {
+    for (key, value) in [
+        (
+            "power_strip_id",
+            inventory.device_info.power_strip_id.as_str(),
+        ),
+        ("device_id", child.device_id.as_str()),
+        ("nickname", child.nickname.as_str()),
+        ("group", inventory.device_info.group.as_str()),
+        ("position", &child.position.to_string()),
+    ] {
+        // Tags can't be empty, so are left out instead
+        if !value.is_empty() {
+            write!(out, ",{key}={}", escape_tag_value(value)).unwrap();
+        }
+    }
+}
+
+/// Escapes the characters that separate tags and fields in line protocol, which in tag values are
+/// commas, equals signs and spaces.
+fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InfluxLineEncoder, encode_readings, escape_tag_value};
+    use crate::energy::EnergyTotals;
+    use crate::exporter::{
+        AccountLabel, ChildDevice, DeviceInfo, Inventory, PowerUse, device_info_labels,
+        power_use_labels,
+    };
+    use prometheus_client::metrics::family::Family;
+    use prometheus_client::metrics::gauge::Gauge;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    #[test]
+    fn tag_values_are_escaped() {
+        assert_eq!(escape_tag_value("Living room"), "Living\\ room");
+        assert_eq!(escape_tag_value("a,b=c"), "a\\,b\\=c");
+        assert_eq!(escape_tag_value("kettle"), "kettle");
+        assert_eq!(escape_tag_value("Desk = 1, 2"), "Desk\\ \\=\\ 1\\,\\ 2");
+    }
+
+    #[test]
+    fn encode_readings_with_energy() {
+        let child = |device_id: &str, nickname: &str, position| ChildDevice {
+            device_id: device_id.to_string(),
+            nickname: nickname.to_string(),
+            device_on: true,
+            on_time_seconds: None,
+            overloaded: None,
+            position,
+        };
+        let inventory = Inventory {
+            device_info: DeviceInfo {
+                power_strip_id: "123".to_string(),
+                ip_address: "10.0.0.1".to_string(),
+                model: "P304M".to_string(),
+                firmware_version: "1.0".to_string(),
+                hardware_version: "1.0".to_string(),
+                mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+                account: AccountLabel::default(),
+                group: String::new(),
+                master_on: None,
+            },
+            alias: None,
+            given_mac: None,
+            sensors: vec![],
+            children: vec![
+                child("456", "Desk, left=1", 1),
+                child("789", "", 2),
+                child("unread", "", 3),
+            ],
+            power_watts: HashMap::from([("456".to_string(), 45), ("789".to_string(), 0)]),
+        };
+        // Never saved, so the file doesn't need to exist
+        let mut energy =
+            EnergyTotals::load(&std::env::temp_dir().join("no-energy-totals.json")).unwrap();
+        let start = Instant::now();
+        energy.record("123", "456", 40, start);
+        energy.record("123", "456", 50, start + Duration::from_secs(3600));
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let mut out = String::new();
+        encode_readings(&mut out, "tapo", &inventory, Some(&energy), timestamp);
+        assert_eq!(
+            out,
+            "tapo_power,power_strip_id=123,device_id=456,nickname=Desk\\,\\ left\\=1,position=1 watts=45i,energy_wh=45 1700000000000000000\n\
+            tapo_power,power_strip_id=123,device_id=789,position=2 watts=0i 1700000000000000000\n"
+        );
+
+        let mut out = String::new();
+        encode_readings(&mut out, "tapo", &inventory, None, timestamp);
+        assert!(out.starts_with(
+            "tapo_power,power_strip_id=123,device_id=456,nickname=Desk\\,\\ left\\=1,position=1 watts=45i 1700000000000000000\n"
+        ));
+    }
+
+    #[test]
+    fn encode_power_use() {
+        let inventory = Inventory {
+            device_info: DeviceInfo {
+                power_strip_id: "123".to_string(),
+                ip_address: "10.0.0.1".to_string(),
+                model: "P304M".to_string(),
+                firmware_version: "1.0".to_string(),
+                hardware_version: "1.0".to_string(),
+                mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+                account: AccountLabel::default(),
+                group: String::new(),
+                master_on: None,
+            },
+            alias: None,
+            given_mac: None,
+            sensors: vec![],
+            children: vec![
+                ChildDevice {
+                    device_id: "456".to_string(),
+                    nickname: "Living room".to_string(),
+                    device_on: true,
+                    on_time_seconds: None,
+                    overloaded: None,
+                    position: 1,
+                },
+                ChildDevice {
+                    device_id: "789".to_string(),
+                    nickname: "".to_string(),
+                    device_on: true,
+                    on_time_seconds: None,
+                    overloaded: None,
+                    position: 2,
+                },
+                ChildDevice {
+                    device_id: "unread".to_string(),
+                    nickname: "".to_string(),
+                    device_on: true,
+                    on_time_seconds: None,
+                    overloaded: None,
+                    position: 3,
+                },
+            ],
+            power_watts: HashMap::new(),
+        };
+        let power_use = Family::<PowerUse, Gauge>::default();
+        let escaped_info = device_info_labels(&inventory.device_info);
+        power_use
+            .get_or_create(&power_use_labels(
+                &escaped_info,
+                "10.0.0.1",
+                &inventory.children[0],
+                None,
+            ))
+            .set(45);
+        power_use
+            .get_or_create(&power_use_labels(
+                &escaped_info,
+                "10.0.0.1",
+                &inventory.children[1],
+                None,
+            ))
+            .set(0);
+
+        let mut out = String::new();
+        InfluxLineEncoder::new(
+            &power_use,
+            "tapo",
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        )
+        .encode(&mut out, "10.0.0.1", &inventory);
+
+        assert_eq!(
+            out,
+            "tapo_power_use_watts,power_strip_id=123,device_id=456,nickname=Living\\ room,position=1 value=45i 1700000000000000000\n\
+            tapo_power_use_watts,power_strip_id=123,device_id=789,position=2 value=0i 1700000000000000000\n"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use crate::labels::escape_label_value;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::registry::Registry;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::time::Instant;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct EnergyTotal {
+    pub power_strip_id: String,
+    pub device_id: String,
+}
+
+/// Watt-hours used by each plug, by the ID of the device it's part of then its own ID, as saved
+/// in the state file.
+type Totals = BTreeMap<String, BTreeMap<String, f64>>;
+
+/// Adds up the energy each plug has used from its power readings, saving the totals to a file so
+/// they carry on from where they were when the exporter restarts.
+#[derive(Clone, Debug)]
+pub struct EnergyTotals {
+    path: PathBuf,
+    totals: Totals,
+    /// The last reading of each plug, by the ID of the device it's part of and its own ID.
+    last_readings: HashMap<(String, String), (Instant, u64)>,
+    counter: Family<EnergyTotal, Counter<f64, AtomicU64>>,
+}
+
+impl EnergyTotals {
+    /// Reads the totals saved in the state file, starting from nothing if it doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let totals: Totals = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Failed to parse {}: {e}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Totals::new(),
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+        };
+
+        let energy = EnergyTotals {
+            path: path.to_path_buf(),
+            totals,
+            last_readings: HashMap::new(),
+            counter: Family::default(),
+        };
+        // Serve the totals carried over before the plugs are next read
+        for (power_strip_id, plugs) in energy.totals.iter() {
+            for device_id in plugs.keys() {
+                energy.update_counter(power_strip_id, device_id);
+            }
+        }
+        Ok(energy)
+    }
+
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "energy_total_wh_since_epoch",
+            "Energy used by the plug in watt-hours, from its power use since it was first read",
+            self.counter.clone(),
+        );
+    }
+
+    /// Adds the energy used since the plug's last reading, taking its power use to have changed
+    /// steadily between the two.
+    pub fn record(&mut self, power_strip_id: &str, device_id: &str, watts: u64, at: Instant) {
+        let key = (power_strip_id.to_string(), device_id.to_string());
+        let total = self
+            .totals
+            .entry(key.0.clone())
+            .or_default()
+            .entry(key.1.clone())
+            .or_default();
+        if let Some((last_at, last_watts)) = self.last_readings.insert(key, (at, watts)) {
+            let hours = at.duration_since(last_at).as_secs_f64() / 3600.0;
+            *total += (last_watts + watts) as f64 / 2.0 * hours;
+        }
+
+        self.update_counter(power_strip_id, device_id);
+    }
+
+    /// Brings the plug's counter up to its total, as counters can only be added to.
+    fn update_counter(&self, power_strip_id: &str, device_id: &str) {
+        let total = self.totals[power_strip_id][device_id];
+        let counter = self.counter.get_or_create(&EnergyTotal {
+            power_strip_id: escape_label_value(power_strip_id),
+            device_id: escape_label_value(device_id),
+        });
+        let added = total - counter.get();
+        if added > 0.0 {
+            counter.inc_by(added);
+        }
+    }
+
+    /// The watt-hours the plug has used so far, if it's been read.
+    pub fn total(&self, power_strip_id: &str, device_id: &str) -> Option<f64> {
+        self.totals.get(power_strip_id)?.get(device_id).copied()
+    }
+
+    /// The watt-hours used by each plug so far, with the ID of the device it's part of and its own
+    /// ID.
+    pub fn totals(&self) -> impl Iterator<Item = (&str, &str, f64)> {
+        self.totals.iter().flat_map(|(power_strip_id, plugs)| {
+            plugs
+                .iter()
+                .map(|(device_id, total)| (power_strip_id.as_str(), device_id.as_str(), *total))
+        })
+    }
+
+    /// Writes the totals to the state file, replacing it in one go so it's never left half written.
+    pub async fn save(&self) -> io::Result<()> {
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let contents = serde_json::to_string_pretty(&self.totals).map_err(io::Error::other)?;
+
+        tokio::fs::write(&temporary, contents).await?;
+        tokio::fs::rename(&temporary, &self.path).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EnergyTotal, EnergyTotals};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn totals_carry_on_after_restarting() {
+        let dir = std::env::temp_dir().join(format!("energy-totals-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let _ = std::fs::remove_file(&path);
+
+        let mut energy = EnergyTotals::load(&path).unwrap();
+        let start = Instant::now();
+        energy.record("123", "456", 100, start);
+        energy.record("123", "456", 200, start + Duration::from_secs(1800));
+        // Half an hour going steadily from 100W to 200W
+        assert_eq!(energy.totals["123"]["456"], 75.0);
+        energy.save().await.unwrap();
+
+        let mut energy = EnergyTotals::load(&path).unwrap();
+        let total = |energy: &EnergyTotals| {
+            energy
+                .counter
+                .get_or_create(&EnergyTotal {
+                    power_strip_id: "123".to_string(),
+                    device_id: "456".to_string(),
+                })
+                .get()
+        };
+        assert_eq!(total(&energy), 75.0);
+
+        // The first reading after restarting has nothing to add to
+        let start = Instant::now();
+        energy.record("123", "456", 50, start);
+        assert_eq!(total(&energy), 75.0);
+        energy.record("123", "456", 50, start + Duration::from_secs(3600));
+        assert_eq!(total(&energy), 125.0);
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(EnergyTotals::load(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
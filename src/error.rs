@@ -0,0 +1,315 @@
+use crate::health::HealthError;
+use clap::error::ErrorKind;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::time::Duration;
+use tapo::{Error, TapoResponseError};
+
+/// Why the exporter stopped, which decides the code it exits with so scripts and process managers
+/// can tell a mistake in its configuration apart from a device it can't reach.
+#[derive(Debug)]
+pub enum AppError {
+    /// The command line was incomplete, with the help or the missing option to show for it.
+    Usage(String),
+    /// The options or configuration file are wrong, so trying again won't help.
+    Config(String),
+    /// A device or the TP-Link cloud couldn't be connected to, which may work if tried again.
+    Connection(String),
+    /// Anything else failing, such as a port being in use or a file not being writable.
+    Runtime(String),
+    /// The `health` subcommand's check failing, exiting with the check's own code. The reason's
+    /// already been printed when it was reported as JSON.
+    Health { error: HealthError, reported: bool },
+}
+
+impl AppError {
+    /// The code to exit with. Usage errors exit with 2, as clap does for them.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            AppError::Runtime(_) => 1,
+            AppError::Usage(_) => 2,
+            AppError::Config(_) => 3,
+            AppError::Connection(_) => 4,
+            AppError::Health { error, .. } => error.exit_code(),
+        }
+    }
+
+    /// Whether the reason has already been printed, so shouldn't be again.
+    pub fn reported(&self) -> bool {
+        matches!(self, AppError::Health { reported: true, .. })
+    }
+
+    /// An option given neither on the command line nor in the configuration file, reported as clap
+    /// reports one missing from the command line.
+    pub fn missing_option(name: &str) -> Self {
+        AppError::missing(&format!(
+            "--{name} must be given, either as an option or in the configuration file"
+        ))
+    }
+
+    /// Options missing as explained by the message, reported as clap reports them.
+    pub fn missing(message: &str) -> Self {
+        let error = clap::Error::raw(ErrorKind::MissingRequiredArgument, message);
+        AppError::Usage(error.render().to_string().trim_end().to_string())
+    }
+
+    /// Failing to listen on an address, explaining the most common reasons.
+    pub fn listen(address: impl Display, e: io::Error) -> Self {
+        AppError::Runtime(match e.kind() {
+            io::ErrorKind::AddrInUse => {
+                format!(
+                    "Failed to listen on {address} as something else already is, such as another exporter"
+                )
+            }
+            io::ErrorKind::AddrNotAvailable => {
+                format!("Failed to listen on {address} as it isn't an address of this machine")
+            }
+            io::ErrorKind::PermissionDenied => {
+                format!(
+                    "Failed to listen on {address} as it isn't permitted, ports below 1024 need root"
+                )
+            }
+            _ => format!("Failed to listen on {address}: {e}"),
+        })
+    }
+
+    /// Failing to connect to a device.
+    pub fn connect(address: &str, e: &Error) -> Self {
+        AppError::Connection(format!(
+            "Failed to connect to {address}: {}",
+            describe_connect_error(e)
+        ))
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Usage(message)
+            | AppError::Config(message)
+            | AppError::Connection(message)
+            | AppError::Runtime(message) => f.write_str(message),
+            AppError::Health { error, .. } => write!(f, "Health check failed: {error}"),
+        }
+    }
+}
+
+/// What was being done with a device when it failed, named as it appears in errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Connect,
+    Refresh,
+    DeviceInfo,
+    ChildList,
+    PowerRead,
+    SensorRead,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Operation::Connect => "connect",
+            Operation::Refresh => "refresh",
+            Operation::DeviceInfo => "device_info",
+            Operation::ChildList => "child_list",
+            Operation::PowerRead => "power_read",
+            Operation::SensorRead => "sensor_read",
+        })
+    }
+}
+
+/// The device an error happened on, by its address along with its ID once it's been read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceContext {
+    pub address: String,
+    pub device_id: Option<String>,
+}
+
+impl DeviceContext {
+    pub fn new(address: &str, device_id: Option<String>) -> Self {
+        DeviceContext {
+            address: address.to_string(),
+            device_id,
+        }
+    }
+}
+
+impl Display for DeviceContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.device_id {
+            Some(device_id) => write!(f, "{} ({device_id})", self.address),
+            None => f.write_str(&self.address),
+        }
+    }
+}
+
+/// Why reading the metrics failed, saying which device and plug it was and what was being done
+/// with it.
+#[derive(Debug, thiserror::Error)]
+pub enum ExporterError {
+    #[error("{device}: {operation} failed: {source}")]
+    Device {
+        device: DeviceContext,
+        operation: Operation,
+        source: Error,
+    },
+    #[error("{device}: {operation} failed for plug {position} ({nickname}): {source}")]
+    Plug {
+        device: DeviceContext,
+        operation: Operation,
+        position: u8,
+        nickname: String,
+        source: Error,
+    },
+    #[error("{device}: timed out after {}", humantime::format_duration(*timeout))]
+    Timeout {
+        device: DeviceContext,
+        timeout: Duration,
+    },
+    #[error("Reading the devices timed out after {}", humantime::format_duration(*.0))]
+    ScrapeTimeout(Duration),
+    #[error("{device}: reading it panicked: {message}")]
+    DevicePanic {
+        device: DeviceContext,
+        message: String,
+    },
+    #[error("Reading the devices panicked: {0}")]
+    Panic(String),
+    #[error("Failed to encode the metrics: {0}")]
+    Encode(std::fmt::Error),
+    #[error("No metrics have been read")]
+    NoMetrics,
+}
+
+impl ExporterError {
+    /// A call to the device failing.
+    pub fn device(device: &DeviceContext, operation: Operation, source: Error) -> Self {
+        ExporterError::Device {
+            device: device.clone(),
+            operation,
+            source,
+        }
+    }
+
+    /// The error from the device, for errors that came from one.
+    pub fn tapo_error(&self) -> Option<&Error> {
+        match self {
+            ExporterError::Device { source, .. } | ExporterError::Plug { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+
+    /// What was being done with the device, for errors that came from one.
+    pub fn operation(&self) -> Option<Operation> {
+        match self {
+            ExporterError::Device { operation, .. } | ExporterError::Plug { operation, .. } => {
+                Some(*operation)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Describes why connecting to a device failed, in words for the most common reasons.
+pub fn describe_connect_error(e: &Error) -> String {
+    match e {
+        Error::Tapo(TapoResponseError::InvalidCredentials(_)) => {
+            "the username or password is wrong".to_string()
+        }
+        Error::Http(e) if e.is_connect() || e.is_timeout() => {
+            format!("it couldn't be reached, check it's on and its address is right ({e})")
+        }
+        e => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AppError, DeviceContext, ExporterError, Operation, describe_connect_error};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+    use tapo::{Error, TapoResponseError};
+
+    #[tokio::test]
+    async fn listening_on_a_port_in_use() {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let e = tokio::net::TcpListener::bind(address).await.unwrap_err();
+        let e = AppError::listen(address, e);
+        assert_eq!(e.exit_code(), 1);
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "Failed to listen on {address} as something else already is, such as another exporter"
+            )
+        );
+
+        let unavailable = SocketAddr::from(([192, 0, 2, 1], 0));
+        let e = tokio::net::TcpListener::bind(unavailable)
+            .await
+            .unwrap_err();
+        assert!(
+            AppError::listen(unavailable, e)
+                .to_string()
+                .contains("isn't an address of this machine")
+        );
+    }
+
+    #[test]
+    fn connection_errors() {
+        let e = AppError::connect(
+            "10.0.0.1",
+            &Error::Tapo(TapoResponseError::InvalidCredentials(String::new())),
+        );
+        assert_eq!(e.exit_code(), 4);
+        assert_eq!(
+            e.to_string(),
+            "Failed to connect to 10.0.0.1: the username or password is wrong"
+        );
+        assert_eq!(
+            describe_connect_error(&Error::Tapo(TapoResponseError::SessionTimeout)),
+            Error::Tapo(TapoResponseError::SessionTimeout).to_string()
+        );
+        assert_eq!(AppError::Config(String::new()).exit_code(), 3);
+        assert_eq!(AppError::Usage(String::new()).exit_code(), 2);
+    }
+
+    #[test]
+    fn exporter_errors_name_the_device() {
+        let unread = DeviceContext::new("10.0.0.1", None);
+        let read = DeviceContext::new("10.0.0.1", Some("123".to_string()));
+
+        let e = ExporterError::device(
+            &unread,
+            Operation::Refresh,
+            Error::Tapo(TapoResponseError::SessionTimeout),
+        );
+        assert_eq!(e.to_string(), "10.0.0.1: refresh failed: Session timeout");
+        assert_eq!(e.operation(), Some(Operation::Refresh));
+
+        let e = ExporterError::Plug {
+            device: read.clone(),
+            operation: Operation::PowerRead,
+            position: 2,
+            nickname: "Kettle".to_string(),
+            source: Error::DeviceNotFound,
+        };
+        assert_eq!(
+            e.to_string(),
+            "10.0.0.1 (123): power_read failed for plug 2 (Kettle): Device not found"
+        );
+        assert!(matches!(e.tapo_error(), Some(Error::DeviceNotFound)));
+
+        let e = ExporterError::Timeout {
+            device: read,
+            timeout: Duration::from_secs(5),
+        };
+        assert_eq!(e.to_string(), "10.0.0.1 (123): timed out after 5s");
+        assert_eq!(e.operation(), None);
+    }
+}
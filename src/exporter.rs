@@ -1,43 +1,161 @@
+use crate::alert::{AlertConfig, PowerAlerts};
+use crate::cardinality::CardinalityGuard;
+use crate::config::DeviceConfig;
+use crate::encoders::{INFLUX_CONTENT_TYPE, InfluxLineEncoder, encode_readings};
+use crate::energy::EnergyTotals;
+use crate::error::{DeviceContext, ExporterError, Operation};
+use crate::firmware::FirmwareMetrics;
+use crate::history::PowerHistory;
+use crate::influx::InfluxWriter;
+use crate::labels::{escape_label_value, sanitize_label_value};
+use crate::mac::ResolvedMacs;
+use crate::otlp::OtlpExporter;
+use crate::overload::OverloadEvents;
+use crate::plugs::PlugFilter;
+use crate::power_histogram::{PowerBuckets, PowerHistogram};
+use crate::pushgateway::Pushgateway;
+use crate::remote_write::RemoteWriter;
+use crate::sensors::{SensorMetrics, SensorReading};
+use crate::statsd::StatsdSender;
 use async_trait::async_trait;
 use axum::Router;
-use axum::body::Body;
+use axum::body::Bytes;
+use axum::error_handling::HandleErrorLayer;
 use axum::extract::State;
 use axum::http::StatusCode;
-use axum::http::header::CONTENT_TYPE;
+use axum::http::header::{ACCEPT, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderMap, HeaderValue, Method};
 use axum::response::{IntoResponse, Response};
 use axum::routing::get;
+use axum::{BoxError, Json};
+use futures_util::FutureExt;
 use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::{EncodeLabelSet, LabelSetEncoder};
+use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::registry::Registry;
-use prometheus_client_derive_encode::EncodeLabelSet;
-use std::sync::Arc;
-use tapo::responses::CurrentPowerResult;
-use tapo::{Error, PowerStripEnergyMonitoringHandler};
-use tapo::{Plug, PlugEnergyMonitoringHandler};
-use tokio::sync::RwLock;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tapo::TapoResponseError;
+use tapo::responses::{
+    ChildDeviceHubResult, CurrentPowerResult, DeviceInfoPowerStripResult, PowerProtectionStatus,
+};
+use tapo::{Error, GenericDeviceHandler, HubHandler};
+use tapo::{Plug, PlugEnergyMonitoringHandler, PlugHandler};
+use tapo::{PowerStripEnergyMonitoringHandler, PowerStripHandler};
+use tokio::sync::{RwLock, Semaphore};
+use tower::ServiceBuilder;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::error::Overloaded;
+use tower::timeout::error::Elapsed;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{error, warn};
 
+pub(crate) const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+const SERVED_FROM_CACHE_HEADER: &str = "x-served-from-cache";
+
+/// What the name of every metric starts with unless configured otherwise.
+pub const DEFAULT_METRIC_PREFIX: &str = "tapo";
+
+/// A plug on a device, as listed by the device.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ChildDevice {
-    device_id: String,
-    nickname: String,
-    position: u8,
+    pub device_id: String,
+    pub nickname: String,
+    /// Whether the plug is switched on.
+    pub device_on: bool,
+    /// How long the plug has been switched on for, if it's on and reports it.
+    pub on_time_seconds: Option<u64>,
+    /// Whether the plug's overload protection has tripped, for plugs that report it.
+    pub overloaded: Option<bool>,
+    pub position: u8,
+}
+
+/// What's known about a device without asking it, from when its client was created and from what
+/// it last reported.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub address: String,
+    /// The device's ID, once it's been read.
+    pub device_id: Option<String>,
+    /// The device's model, once it's been given or read.
+    pub model: Option<String>,
 }
 
+/// Reads from a Tapo device, with an implementation for each kind of device.
 #[async_trait]
 pub trait TapoClient {
+    /// The address the device was connected to with, which identifies it.
+    fn address(&self) -> &str;
+
+    /// What's known about the device without asking it, so it can be named when it can't be read.
+    /// Clients that don't keep track of what the device reported only know its address.
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            address: self.address().to_string(),
+            ..DeviceIdentity::default()
+        }
+    }
+
+    /// Logs in to the device again, as sessions expire.
     async fn refresh_session(&mut self) -> Result<(), Error>;
     async fn device_info(&self) -> Result<DeviceInfo, Error>;
     async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error>;
     async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error>;
+
+    /// The power use of each of the plugs, by device ID. Clients for devices that can be asked for
+    /// every plug's power use in one request override this, as the tapo crate doesn't have a way
+    /// to do so for any built-in model yet.
+    async fn get_all_plug_powers(
+        &self,
+        device_ids: &[String],
+    ) -> Result<HashMap<String, CurrentPowerResult>, Error> {
+        let mut powers = HashMap::with_capacity(device_ids.len());
+        for device_id in device_ids {
+            powers.insert(device_id.clone(), self.get_power_for_plug(device_id).await?);
+        }
+        Ok(powers)
+    }
+
+    /// Whether [`get_all_plug_powers`](TapoClient::get_all_plug_powers) asks the device for every
+    /// plug's power use in one request, rather than asking each plug in turn.
+    fn reads_all_plug_powers_at_once(&self) -> bool {
+        false
+    }
+
+    /// Whether the device reports its plugs' power use, so it's only asked for when it does.
+    fn monitors_energy(&self) -> bool {
+        true
+    }
+
+    /// What the sensors connected to the device, such as a hub's temperature sensors, last
+    /// reported. Devices without sensors have none.
+    async fn sensor_readings(&self) -> Result<Vec<SensorReading>, Error> {
+        Ok(Vec::new())
+    }
 }
 
+/// A plug that reports its power use, such as the P110M, listed as its own only plug.
 #[derive(Debug)]
 pub struct PlugClient {
+    pub address: String,
+    pub account: Option<String>,
     pub client: PlugEnergyMonitoringHandler,
 }
 
 #[async_trait]
 impl TapoClient for PlugClient {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
     async fn refresh_session(&mut self) -> Result<(), Error> {
         match self.client.refresh_session().await {
             Ok(_) => Ok(()),
@@ -49,8 +167,14 @@ impl TapoClient for PlugClient {
         let result = self.client.get_device_info().await?;
         Ok(DeviceInfo {
             power_strip_id: result.device_id,
+            ip_address: self.address.clone(),
             model: result.model,
             firmware_version: result.fw_ver,
+            hardware_version: result.hw_ver,
+            mac_address: format_mac_address(&result.mac),
+            account: AccountLabel(self.account.clone()),
+            group: String::new(),
+            master_on: None,
         })
     }
 
@@ -58,7 +182,10 @@ impl TapoClient for PlugClient {
         let result = self.client.get_device_info().await?;
         Ok(vec![ChildDevice {
             device_id: result.device_id,
-            nickname: result.nickname,
+            nickname: sanitize_label_value(&result.nickname),
+            device_on: result.device_on,
+            on_time_seconds: result.device_on.then_some(result.on_time),
+            overloaded: Some(result.power_protection_status == PowerProtectionStatus::Overloaded),
             position: 0,
         }])
     }
@@ -68,13 +195,21 @@ impl TapoClient for PlugClient {
     }
 }
 
+/// A plug that doesn't report its power use, such as the P100, listed as its own only plug along
+/// with whether it's switched on.
 #[derive(Debug)]
-pub struct PowerStripClient {
-    pub client: PowerStripEnergyMonitoringHandler,
+pub struct PlugNonEmClient {
+    pub address: String,
+    pub account: Option<String>,
+    pub client: PlugHandler,
 }
 
 #[async_trait]
-impl TapoClient for PowerStripClient {
+impl TapoClient for PlugNonEmClient {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
     async fn refresh_session(&mut self) -> Result<(), Error> {
         match self.client.refresh_session().await {
             Ok(_) => Ok(()),
@@ -86,18 +221,77 @@ impl TapoClient for PowerStripClient {
         let result = self.client.get_device_info().await?;
         Ok(DeviceInfo {
             power_strip_id: result.device_id,
+            ip_address: self.address.clone(),
             model: result.model,
             firmware_version: result.fw_ver,
+            hardware_version: result.hw_ver,
+            mac_address: format_mac_address(&result.mac),
+            account: AccountLabel(self.account.clone()),
+            group: String::new(),
+            master_on: None,
+        })
+    }
+
+    async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+        let result = self.client.get_device_info().await?;
+        Ok(vec![ChildDevice {
+            device_id: result.device_id,
+            nickname: sanitize_label_value(&result.nickname),
+            device_on: result.device_on,
+            on_time_seconds: result.device_on.then_some(result.on_time),
+            overloaded: None,
+            position: 0,
+        }])
+    }
+
+    async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+        Err(Error::Validation {
+            field: "model".to_string(),
+            message: format!("{} doesn't report its power use", self.address),
         })
     }
 
+    fn monitors_energy(&self) -> bool {
+        false
+    }
+}
+
+/// A power strip that reports each of its plugs' power use, such as the P304M.
+#[derive(Debug)]
+pub struct PowerStripClient {
+    pub address: String,
+    pub account: Option<String>,
+    pub client: PowerStripEnergyMonitoringHandler,
+}
+
+#[async_trait]
+impl TapoClient for PowerStripClient {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    async fn refresh_session(&mut self) -> Result<(), Error> {
+        match self.client.refresh_session().await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo, Error> {
+        let result = self.client.get_device_info_json().await?;
+        power_strip_info(result, &self.address, &self.account)
+    }
+
     async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
         let devices = self.client.get_child_device_list().await?;
         Ok(devices
             .iter()
             .map(|d| ChildDevice {
                 device_id: d.device_id.clone(),
-                nickname: d.nickname.clone(),
+                nickname: sanitize_label_value(&d.nickname),
+                device_on: d.device_on,
+                on_time_seconds: d.device_on.then_some(d.on_time),
+                overloaded: Some(d.power_protection_status == PowerProtectionStatus::Overloaded),
                 position: d.position,
             })
             .collect())
@@ -113,200 +307,4779 @@ impl TapoClient for PowerStripClient {
     }
 }
 
+/// A power strip that doesn't report its plugs' power use, such as the P300, whose plugs are still
+/// listed along with whether they're switched on.
+#[derive(Debug)]
+pub struct PowerStripNonEmClient {
+    pub address: String,
+    pub account: Option<String>,
+    pub client: PowerStripHandler,
+}
+
+#[async_trait]
+impl TapoClient for PowerStripNonEmClient {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    async fn refresh_session(&mut self) -> Result<(), Error> {
+        match self.client.refresh_session().await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo, Error> {
+        let result = self.client.get_device_info_json().await?;
+        power_strip_info(result, &self.address, &self.account)
+    }
+
+    async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+        let devices = self.client.get_child_device_list().await?;
+        Ok(devices
+            .iter()
+            .map(|d| ChildDevice {
+                device_id: d.device_id.clone(),
+                nickname: sanitize_label_value(&d.nickname),
+                device_on: d.device_on,
+                on_time_seconds: d.device_on.then_some(d.on_time),
+                overloaded: None,
+                position: d.position,
+            })
+            .collect())
+    }
+
+    async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+        Err(Error::Validation {
+            field: "model".to_string(),
+            message: format!("{} doesn't report its plugs' power use", self.address),
+        })
+    }
+
+    fn monitors_energy(&self) -> bool {
+        false
+    }
+}
+
+/// An H100 hub, which has no plugs of its own but reports the readings of the temperature and
+/// humidity sensors connected to it.
+#[derive(Debug)]
+pub struct H100Client {
+    pub address: String,
+    pub account: Option<String>,
+    pub client: HubHandler,
+}
+
+#[async_trait]
+impl TapoClient for H100Client {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    async fn refresh_session(&mut self) -> Result<(), Error> {
+        match self.client.refresh_session().await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo, Error> {
+        let result = self.client.get_device_info().await?;
+        Ok(DeviceInfo {
+            power_strip_id: result.device_id,
+            ip_address: self.address.clone(),
+            model: result.model,
+            firmware_version: result.fw_ver,
+            hardware_version: result.hw_ver,
+            mac_address: format_mac_address(&result.mac),
+            account: AccountLabel(self.account.clone()),
+            group: String::new(),
+            master_on: None,
+        })
+    }
+
+    async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+        Ok(Vec::new())
+    }
+
+    async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+        Err(Error::Validation {
+            field: "model".to_string(),
+            message: format!("{} is a hub, which has no plugs", self.address),
+        })
+    }
+
+    fn monitors_energy(&self) -> bool {
+        false
+    }
+
+    async fn sensor_readings(&self) -> Result<Vec<SensorReading>, Error> {
+        let devices = self.client.get_child_device_list().await?;
+        // Only the temperature and humidity sensors have readings to report, so buttons, contact
+        // sensors and the like are skipped
+        Ok(devices
+            .into_iter()
+            .filter_map(|d| {
+                let (sensor_type, sensor) = match d {
+                    ChildDeviceHubResult::T310(sensor) => ("T310", sensor),
+                    ChildDeviceHubResult::T315(sensor) => ("T315", sensor),
+                    _ => return None,
+                };
+                Some(SensorReading {
+                    device_id: sensor.device_id,
+                    nickname: sanitize_label_value(&sensor.nickname),
+                    sensor_type: sensor_type.to_string(),
+                    temperature_celsius: Some(sensor.current_temperature.into()),
+                    humidity_percent: Some(sensor.current_humidity.into()),
+                    battery_low: Some(sensor.at_low_battery),
+                })
+            })
+            .collect())
+    }
+}
+
+/// A device of a model that isn't supported, read with the handler every model understands so it's
+/// at least served in `tapo_device_info`, without any plugs or sensors.
+#[derive(Debug)]
+pub struct GenericClient {
+    pub address: String,
+    pub account: Option<String>,
+    pub client: GenericDeviceHandler,
+}
+
+#[async_trait]
+impl TapoClient for GenericClient {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    async fn refresh_session(&mut self) -> Result<(), Error> {
+        match self.client.refresh_session().await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo, Error> {
+        let result = self.client.get_device_info().await?;
+        Ok(DeviceInfo {
+            power_strip_id: result.device_id,
+            ip_address: self.address.clone(),
+            model: result.model,
+            firmware_version: result.fw_ver,
+            hardware_version: result.hw_ver,
+            mac_address: format_mac_address(&result.mac),
+            account: AccountLabel(self.account.clone()),
+            group: String::new(),
+            master_on: None,
+        })
+    }
+
+    async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+        Ok(Vec::new())
+    }
+
+    async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+        Err(Error::Validation {
+            field: "model".to_string(),
+            message: format!("{} isn't a supported model", self.address),
+        })
+    }
+
+    fn monitors_energy(&self) -> bool {
+        false
+    }
+}
+
+/// The information a power strip reports about itself, along with whether its master switch is on,
+/// which the tapo crate leaves out, if it has one.
+fn power_strip_info(
+    result: serde_json::Value,
+    address: &str,
+    account: &Option<String>,
+) -> Result<DeviceInfo, Error> {
+    let master_on = result.get("device_on").and_then(serde_json::Value::as_bool);
+    let result: DeviceInfoPowerStripResult = serde_json::from_value(result)?;
+
+    Ok(DeviceInfo {
+        power_strip_id: result.device_id,
+        ip_address: address.to_string(),
+        model: result.model,
+        firmware_version: result.fw_ver,
+        hardware_version: result.hw_ver,
+        mac_address: format_mac_address(&result.mac),
+        account: AccountLabel(account.clone()),
+        group: String::new(),
+        master_on,
+    })
+}
+
+/// What a panic was given to say, such as the message passed to `panic!`.
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|m| m.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown cause".to_string())
+}
+
+/// Formats a MAC address as reported by a device, e.g. `AA-BB-CC-DD-EE-FF`, as `aa:bb:cc:dd:ee:ff`.
+fn format_mac_address(mac: &str) -> String {
+    mac.to_ascii_lowercase().replace('-', ":")
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct PowerUse {
     pub power_strip_id: String,
+    pub ip_address: String,
     pub device_id: String,
     pub nickname: String,
+    /// The group the device is in, or empty if it isn't in one.
+    pub group: String,
+    // Labels are encoded in field order, so this is kept after the plug's other labels, followed
+    // only by the power strip's alias when it's given one. Label values are always strings in
+    // OpenMetrics, so this is encoded as e.g. `position="1"` and can't be used in arithmetic.
     pub position: u8,
+    #[prometheus(flatten)]
+    pub strip_alias: AliasLabel,
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+/// What the device reports about itself, served as the labels of `tapo_device_info`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct DeviceInfo {
     pub power_strip_id: String,
+    pub ip_address: String,
     pub model: String,
     pub firmware_version: String,
+    pub hardware_version: String,
+    pub mac_address: String,
+    pub account: AccountLabel,
+    /// The group the device is given in its configuration, such as `server-room`, as devices don't
+    /// report one, or empty if it isn't in one.
+    pub group: String,
+    /// Whether the power strip's master switch is on, for devices that have one. It isn't a label,
+    /// so is always left out of the labels the information is recorded with.
+    pub master_on: Option<bool>,
 }
 
-struct AppState {
-    pub registry: Registry,
-    power_use: Family<PowerUse, Gauge>,
-    device_info: Family<DeviceInfo, Gauge>,
-    clients: Vec<Box<dyn TapoClient + Send + Sync>>,
+impl EncodeLabelSet for DeviceInfo {
+    fn encode(&self, encoder: &mut LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        [
+            ("power_strip_id", self.power_strip_id.as_str()),
+            ("ip_address", self.ip_address.as_str()),
+            ("model", self.model.as_str()),
+            ("firmware_version", self.firmware_version.as_str()),
+            ("hardware_version", self.hardware_version.as_str()),
+            ("mac_address", self.mac_address.as_str()),
+            ("group", self.group.as_str()),
+        ]
+        .encode(encoder)?;
+        self.account.encode(encoder)
+    }
 }
 
-impl AppState {
-    pub async fn update_metrics(&mut self) -> Result<(), Error> {
-        for c in self.clients.iter_mut() {
-            if let Err(e) = c.refresh_session().await {
-                panic!("Failed to refresh session: {e}");
-            }
+/// The account a device is read with, only labelled when the device has its own credentials.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct AccountLabel(pub Option<String>);
+
+impl EncodeLabelSet for AccountLabel {
+    fn encode(&self, encoder: &mut LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        match &self.0 {
+            Some(account) => [("account", account.as_str())].encode(encoder),
+            None => Ok(()),
         }
+    }
+}
 
-        for c in self.clients.iter_mut() {
-            let device_info = c.device_info().await?;
+/// The alias of the device a plug is part of, only labelled when the device has one and it isn't
+/// replacing the nickname of a plug that's a device of its own.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct AliasLabel(pub Option<String>);
+
+impl EncodeLabelSet for AliasLabel {
+    fn encode(&self, encoder: &mut LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        match &self.0 {
+            Some(alias) => [("strip_alias", alias.as_str())].encode(encoder),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How a device's alias is added to the labels of its plugs' power use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AliasMode {
+    /// As a `strip_alias` label, keeping the plugs' nicknames
+    #[default]
+    Label,
+    /// In place of the nickname of a plug that's a device of its own, while a power strip's plugs
+    /// keep theirs and get a `strip_alias` label
+    Nickname,
+}
 
-            self.device_info.get_or_create(&device_info).set(1);
+/// A name given to a device in place of, or along with, the nicknames of its plugs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alias {
+    pub name: String,
+    pub mode: AliasMode,
+}
 
-            let child_device_list = c.child_devices().await?;
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ScrapeErrors {
+    pub ip_address: String,
+}
 
-            for child in child_device_list.into_iter() {
-                let current_power = c.get_power_for_plug(child.device_id.as_ref()).await?;
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ApiCalls {
+    pub power_strip_id: String,
+    pub ip_address: String,
+    pub method: String,
+}
 
-                self.power_use
-                    .get_or_create(&PowerUse {
-                        power_strip_id: device_info.power_strip_id.clone(),
-                        device_id: child.device_id.clone(),
-                        nickname: child.nickname,
-                        position: child.position,
-                    })
-                    .set(current_power.current_power as i64);
-            }
-        }
+/// The methods of the devices' API whose calls are counted, named as the devices name them.
+const API_METHODS: [&str; 4] = [
+    "refresh_session",
+    "get_device_info",
+    "get_child_device_list",
+    "get_current_power",
+];
 
-        Ok(())
+/// Counts calls made to a device with a method of its API, if they're being counted.
+fn count_api_calls(
+    api_calls: Option<&Family<ApiCalls, Counter>>,
+    power_strip_id: &str,
+    address: &str,
+    method: &str,
+    calls: u64,
+) {
+    if let Some(api_calls) = api_calls {
+        api_calls
+            .get_or_create(&ApiCalls {
+                power_strip_id: power_strip_id.to_string(),
+                ip_address: escape_label_value(address),
+                method: method.to_string(),
+            })
+            .inc_by(calls);
     }
 }
 
-async fn metrics_handler(State(state): State<Arc<RwLock<AppState>>>) -> impl IntoResponse {
-    let mut state = state.write().await;
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct SessionRefreshErrors {
+    pub power_strip_id: String,
+    pub ip_address: String,
+    pub error_kind: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Reachable {
+    pub power_strip_id: String,
+    pub ip_address: String,
+}
 
-    match state.update_metrics().await {
-        Ok(_) => {
-            let mut buffer = String::new();
-            encode(&mut buffer, &state.registry).unwrap();
+/// A device given by its MAC address rather than its IP address.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MacAddress {
+    pub mac_address: String,
+}
 
-            Response::builder()
-                .status(StatusCode::OK)
-                .header(
-                    CONTENT_TYPE,
-                    "application/openmetrics-text; version=1.0.0; charset=utf-8",
-                )
-                .body(Body::from(buffer))
-                .unwrap()
-        }
-        Err(e) => Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from(e.to_string()))
-            .unwrap(),
+/// What was last read from a device.
+#[derive(Clone, Debug)]
+pub struct Inventory {
+    pub device_info: DeviceInfo,
+    pub children: Vec<ChildDevice>,
+    /// Power use in watts, by child device ID.
+    pub power_watts: HashMap<String, u64>,
+    /// The alias the plugs' power use was labelled with, if any.
+    pub alias: Option<Alias>,
+    /// The MAC address the device was given by, if it was.
+    pub given_mac: Option<String>,
+    pub sensors: Vec<SensorReading>,
+}
+
+impl Inventory {
+    /// The address the plugs' power use is labelled with, which is the MAC address the device was
+    /// given by if it was, rather than the address it was found at.
+    pub fn labelled_address<'a>(&'a self, address: &'a str) -> &'a str {
+        self.given_mac.as_deref().unwrap_or(address)
     }
 }
 
-async fn health() -> impl IntoResponse {
-    Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::empty())
-        .unwrap()
+/// How reading from a device has been going.
+#[derive(Clone, Debug, Default)]
+struct DeviceStatus {
+    device_id: Option<String>,
+    model: Option<String>,
+    last_success: Option<SystemTime>,
+    consecutive_failures: u64,
+    last_error: Option<String>,
 }
 
-pub fn app(power_strips: Vec<Box<dyn TapoClient + Send + Sync>>) -> Router {
-    let mut state = AppState {
-        registry: Registry::default(),
-        power_use: Family::default(),
-        device_info: Family::default(),
-        clients: power_strips,
-    };
-    state.registry.register(
-        "tapo_power_use_watts",
-        "Current power use in watts",
-        state.power_use.clone(),
-    );
-    state.registry.register(
-        "tapo_device_info",
-        "Device information",
-        state.device_info.clone(),
-    );
-    let state = Arc::new(RwLock::new(state));
+impl DeviceStatus {
+    /// Records what's known about the device, keeping what was known before of anything that isn't.
+    fn identify(&mut self, identity: DeviceIdentity) {
+        if identity.device_id.is_some() {
+            self.device_id = identity.device_id;
+        }
+        if identity.model.is_some() {
+            self.model = identity.model;
+        }
+    }
+}
 
-    Router::new()
-        .route("/metrics", get(metrics_handler))
-        .route("/health", get(health))
-        .with_state(state)
+/// The devices being read from, in the order they were added, with the status of each by address.
+#[derive(Debug, Default)]
+struct DeviceStatuses {
+    addresses: Vec<String>,
+    by_address: HashMap<String, DeviceStatus>,
 }
 
-#[cfg(test)]
-mod test {
-    use super::app;
-    use super::{ChildDevice, DeviceInfo, TapoClient};
-    use async_trait::async_trait;
+/// Kept outside of the lock held while devices are being read so health and readiness can be
+/// checked without waiting for a scrape.
+type Statuses = Arc<Mutex<DeviceStatuses>>;
 
-    use axum::body::Body;
-    use axum::http::Request;
-    use axum::http::StatusCode;
-    use http_body_util::BodyExt;
-    use tapo::Error;
-    use tapo::responses::CurrentPowerResult;
-    use tower::ServiceExt; // for `collect`
+struct AppState {
+    pub registry: Registry,
+    power_use: Family<PowerUse, Gauge>,
+    plug_on: Family<PowerUse, Gauge>,
+    plug_on_since: Family<PowerUse, Gauge>,
+    device_info: Family<DeviceInfo, Gauge>,
+    scrape_errors: Family<ScrapeErrors, Counter>,
+    session_refresh_errors: Family<SessionRefreshErrors, Counter>,
+    api_calls: Family<ApiCalls, Counter>,
+    reachable: Family<Reachable, Gauge>,
+    master_on: Family<Reachable, Gauge>,
+    mac_resolved: Family<MacAddress, Gauge>,
+    internal_errors: Counter,
+    clients: Vec<Box<dyn TapoClient + Send + Sync>>,
+    inventory: HashMap<String, Inventory>,
+    /// The devices read by the last update, whose inventory is of that update rather than left
+    /// from an earlier one.
+    fresh: HashSet<String>,
+    statuses: Statuses,
+    min_scrape_interval: Duration,
+    last_scrape: Option<Scrape>,
+    /// The metrics as they were after the devices were last read, once they've been encoded.
+    encoded: Mutex<Option<EncodedMetrics>>,
+    info_refresh_interval: Duration,
+    /// When each device's information was last read from it, by address.
+    info_read: HashMap<String, Instant>,
+    device_labels: HashMap<String, BTreeMap<String, String>>,
+    aliases: HashMap<String, Alias>,
+    groups: HashMap<String, String>,
+    given_macs: HashMap<String, String>,
+    alerts: Option<PowerAlerts>,
+    statsd: Option<StatsdSender>,
+    remote_write_errors: Option<Counter>,
+    influx_write_errors: Option<Counter>,
+    otlp_push_errors: Option<Counter>,
+    pushgateway_push_errors: Option<Counter>,
+    history: PowerHistory,
+    power_histogram: Option<PowerHistogram>,
+    overloads: OverloadEvents,
+    firmware: FirmwareMetrics,
+    sensors: SensorMetrics,
+    energy_totals: Option<EnergyTotals>,
+    cardinality: CardinalityGuard,
+    device_timeout: Option<Duration>,
+    scrape_timeout: Option<Duration>,
+    plug_filter: PlugFilter,
+    metric_prefix: String,
+}
 
-    struct TestClient {}
+/// When the devices were last read successfully.
+#[derive(Clone, Copy)]
+struct Scrape {
+    at: Instant,
+    time: SystemTime,
+}
 
-    #[async_trait]
-    impl TapoClient for TestClient {
-        async fn refresh_session(&mut self) -> Result<(), Error> {
-            Ok(())
-        }
+/// The metrics as they're served, which are shared by every request until the devices are read
+/// again rather than copied for each.
+#[derive(Clone)]
+struct EncodedMetrics {
+    body: Bytes,
+    etag: String,
+}
 
-        async fn device_info(&self) -> Result<DeviceInfo, Error> {
-            Ok(DeviceInfo {
-                power_strip_id: "123".to_string(),
-                firmware_version: "".to_string(),
-                model: "catwalk".to_string(),
-            })
+impl EncodedMetrics {
+    /// Tags the metrics by what was read from the devices, leaving out the lines starting with
+    /// `uncounted`, which change on every read regardless.
+    fn new(body: String, uncounted: &str) -> Self {
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        for line in body.lines().filter(|line| !line.starts_with(uncounted)) {
+            hasher.update(line.as_bytes());
+            hasher.update(b"\n");
+        }
+        let etag = format!("\"{:016x}\"", hasher.digest());
+        EncodedMetrics {
+            body: Bytes::from(body),
+            etag,
+        }
+    }
+
+    /// Whether the scraper already has these metrics, going by the `If-None-Match` header.
+    fn is_unmodified(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag)
+    }
+
+    fn response(&self, headers: &HeaderMap, from_cache: bool) -> Response {
+        let mut response = if self.is_unmodified(headers) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            (
+                [(CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)],
+                self.body.clone(),
+            )
+                .into_response()
+        };
+
+        // The ETag is a quoted hex digest, so is always a valid header value
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            response.headers_mut().insert(ETAG, etag);
+        }
+        if from_cache {
+            response
+                .headers_mut()
+                .insert(SERVED_FROM_CACHE_HEADER, HeaderValue::from_static("true"));
+        }
+        response
+    }
+}
+
+impl AppState {
+    /// Creates the state for reading from the devices, with every metric registered.
+    fn new(power_strips: Vec<Box<dyn TapoClient + Send + Sync>>, config: &AppConfig) -> Self {
+        let statuses = Arc::new(Mutex::new(DeviceStatuses {
+            addresses: power_strips
+                .iter()
+                .map(|c| c.address().to_string())
+                .collect(),
+            by_address: power_strips
+                .iter()
+                .map(|c| {
+                    let mut status = DeviceStatus::default();
+                    status.identify(c.identity());
+                    (c.address().to_string(), status)
+                })
+                .collect(),
+        }));
+
+        let mut state = AppState {
+            registry: Registry::default(),
+            power_use: Family::default(),
+            plug_on: Family::default(),
+            plug_on_since: Family::default(),
+            device_info: Family::default(),
+            scrape_errors: Family::default(),
+            session_refresh_errors: Family::default(),
+            api_calls: Family::default(),
+            reachable: Family::default(),
+            master_on: Family::default(),
+            mac_resolved: Family::default(),
+            internal_errors: Counter::default(),
+            clients: power_strips,
+            inventory: HashMap::new(),
+            fresh: HashSet::new(),
+            statuses,
+            min_scrape_interval: config.min_scrape_interval,
+            last_scrape: None,
+            encoded: Mutex::new(None),
+            info_refresh_interval: config.info_refresh_interval,
+            info_read: HashMap::new(),
+            device_labels: config.device_labels.clone(),
+            aliases: config.aliases.clone(),
+            groups: config.groups.clone(),
+            given_macs: config.given_macs.clone(),
+            alerts: config.alert.clone().map(PowerAlerts::new),
+            statsd: config.statsd.clone(),
+            remote_write_errors: config.remote_write_errors.clone(),
+            influx_write_errors: config.influx_write_errors.clone(),
+            otlp_push_errors: config.otlp_push_errors.clone(),
+            pushgateway_push_errors: config.pushgateway_push_errors.clone(),
+            history: PowerHistory::new(config.history_size),
+            power_histogram: config
+                .power_histogram_buckets
+                .clone()
+                .map(PowerHistogram::new),
+            overloads: OverloadEvents::default(),
+            firmware: FirmwareMetrics::default(),
+            sensors: SensorMetrics::default(),
+            energy_totals: config.energy_totals.clone(),
+            cardinality: CardinalityGuard::new(config.max_label_cardinality),
+            device_timeout: config.device_timeout,
+            scrape_timeout: config.scrape_timeout,
+            plug_filter: config.plug_filter.clone(),
+            metric_prefix: config.metric_prefix.clone(),
+        };
+        for c in state.clients.iter() {
+            state
+                .reachable
+                .get_or_create(&reachable_labels(&state.inventory, c.address()))
+                .set(0);
+        }
+        let mut registry = Registry::default();
+        state.register(&mut registry);
+        state.registry = registry;
+        state
+    }
+
+    /// Registers every metric with the registry, their names prefixed with the metric prefix.
+    fn register(&self, registry: &mut Registry) {
+        let registry = registry.sub_registry_with_prefix(&self.metric_prefix);
+        registry.register(
+            "power_use_watts",
+            "Current power use in watts",
+            self.power_use.clone(),
+        );
+        if let Some(power_histogram) = self.power_histogram.as_ref() {
+            power_histogram.register(registry);
+        }
+        registry.register(
+            "plug_on",
+            "Whether the plug is switched on",
+            self.plug_on.clone(),
+        );
+        registry.register(
+            "plug_on_since_seconds",
+            "How long the plug has been switched on for in seconds, while it's on",
+            self.plug_on_since.clone(),
+        );
+        registry.register(
+            "device_info",
+            "Device information",
+            self.device_info.clone(),
+        );
+        registry.register(
+            "scrape_errors",
+            "Number of failed attempts to read metrics from a device",
+            self.scrape_errors.clone(),
+        );
+        registry.register(
+            "session_refresh_errors",
+            "Number of failed attempts to refresh the session with a device, by kind of error",
+            self.session_refresh_errors.clone(),
+        );
+        registry.register(
+            "api_calls",
+            "Number of calls made to a device, by method of its API",
+            self.api_calls.clone(),
+        );
+        registry.register(
+            "device_reachable",
+            "Whether the device responded when last read from",
+            self.reachable.clone(),
+        );
+        registry.register(
+            "power_strip_master_on",
+            "Whether the power strip's master switch is on, for power strips that have one",
+            self.master_on.clone(),
+        );
+        registry.register(
+            "device_mac_resolved",
+            "Whether the address of the device given by its MAC address has been found",
+            self.mac_resolved.clone(),
+        );
+        registry.register(
+            "exporter_internal_errors",
+            "Number of times reading the devices panicked, which was recovered from",
+            self.internal_errors.clone(),
+        );
+        self.history.register(registry);
+        self.overloads.register(registry);
+        self.firmware.register(registry);
+        self.sensors.register(registry);
+        if let Some(energy_totals) = self.energy_totals.as_ref() {
+            energy_totals.register(registry);
+        }
+        self.cardinality.register(registry);
+        if let Some(statsd) = self.statsd.as_ref() {
+            registry.register(
+                "statsd_send_errors",
+                "Number of StatsD packets that failed to send",
+                statsd.errors(),
+            );
+        }
+        if let Some(remote_write_errors) = self.remote_write_errors.as_ref() {
+            registry.register(
+                "remote_write_errors",
+                "Number of remote write requests that failed, having been tried again",
+                remote_write_errors.clone(),
+            );
+        }
+        if let Some(influx_write_errors) = self.influx_write_errors.as_ref() {
+            registry.register(
+                "influx_write_errors",
+                "Number of writes to InfluxDB that failed",
+                influx_write_errors.clone(),
+            );
+        }
+        if let Some(otlp_push_errors) = self.otlp_push_errors.as_ref() {
+            registry.register(
+                "otlp_push_errors",
+                "Number of pushes to the OTLP endpoint that failed",
+                otlp_push_errors.clone(),
+            );
+        }
+        if let Some(pushgateway_push_errors) = self.pushgateway_push_errors.as_ref() {
+            registry.register(
+                "pushgateway_push_errors",
+                "Number of pushes to the Pushgateway that failed",
+                pushgateway_push_errors.clone(),
+            );
+        }
+    }
+
+    /// Updates the metrics for every device, isolating failures so that one unreachable device
+    /// doesn't prevent the others from being reported. Only fails if every device failed.
+    pub async fn update_metrics(&mut self) -> Result<(), ExporterError> {
+        let mut last_error = None;
+        let mut succeeded = false;
+        // Every reading of this scrape is given the same time, so they can be told apart from others
+        let scraped_at = SystemTime::now();
+        self.fresh.clear();
+        // The series of plugs that were renamed or have gone, only removed once every device has
+        // been read as the clients are borrowed until then
+        let mut stale = Vec::new();
+
+        for c in self.clients.iter_mut() {
+            let alias = self.aliases.get(c.address());
+            let group = self.groups.get(c.address()).map_or("", String::as_str);
+            let given_mac = self.given_macs.get(c.address()).map(String::as_str);
+            // The ID last read from the device, as it's only read again once the session's refreshed
+            let device_id = c.identity().device_id.or_else(|| {
+                self.inventory
+                    .get(c.address())
+                    .map(|i| i.device_info.power_strip_id.clone())
+            });
+            let device = DeviceContext::new(c.address(), device_id);
+            // Device information rarely changes, so is only read again once the interval's passed.
+            // Whether a power strip's master switch is on is only reported with it, so is as old
+            let known_info = self
+                .info_read
+                .get(c.address())
+                .filter(|read| read.elapsed() < self.info_refresh_interval)
+                .and_then(|_| self.inventory.get(c.address()))
+                .map(|i| i.device_info.clone());
+            let info_known = known_info.is_some();
+            let update = async {
+                count_api_calls(
+                    Some(&self.api_calls),
+                    device.device_id.as_deref().unwrap_or_default(),
+                    c.address(),
+                    "refresh_session",
+                    1,
+                );
+                c.refresh_session()
+                    .await
+                    .map_err(|e| ExporterError::device(&device, Operation::Refresh, e))?;
+                update_device(
+                    c.as_mut(),
+                    &self.power_use,
+                    &self.device_info,
+                    alias,
+                    given_mac,
+                    group,
+                    known_info,
+                    Some(&self.api_calls),
+                    Some(&mut self.cardinality),
+                    Some(&mut self.plug_filter),
+                )
+                .await
+            };
+            let update = AssertUnwindSafe(update).catch_unwind();
+            let result = match self.device_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, update)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Ok(Err(ExporterError::Timeout {
+                            device: device.clone(),
+                            timeout,
+                        }))
+                    }),
+                None => update.await,
+            };
+            // A panic is only that device failing, so the others are still reported
+            let result = result.unwrap_or_else(|panic| {
+                self.internal_errors.inc();
+                Err(ExporterError::DevicePanic {
+                    device: device.clone(),
+                    message: panic_message(panic),
+                })
+            });
+
+            match result {
+                Ok(inventory) => {
+                    let mut statuses = self.statuses.lock().unwrap();
+                    let status = statuses
+                        .by_address
+                        .entry(c.address().to_string())
+                        .or_default();
+                    status.device_id = Some(inventory.device_info.power_strip_id.clone());
+                    status.model = Some(inventory.device_info.model.clone());
+                    status.last_success = Some(SystemTime::now());
+                    status.consecutive_failures = 0;
+                    status.last_error = None;
+                    drop(statuses);
+                    if !info_known {
+                        self.info_read
+                            .insert(c.address().to_string(), Instant::now());
+                    }
+
+                    let escaped_info = device_info_labels(&inventory.device_info);
+                    let current: HashSet<PowerUse> = inventory
+                        .children
+                        .iter()
+                        .map(|child| {
+                            power_use_labels(
+                                &escaped_info,
+                                inventory.labelled_address(c.address()),
+                                child,
+                                inventory.alias.as_ref(),
+                            )
+                        })
+                        .collect();
+                    stale.extend(
+                        power_use_labels_of(&self.inventory, c.address())
+                            .filter(|labels| !current.contains(labels)),
+                    );
+
+                    let read_at = Instant::now();
+                    for child in inventory.children.iter() {
+                        let labels = power_use_labels(
+                            &escaped_info,
+                            inventory.labelled_address(c.address()),
+                            child,
+                            inventory.alias.as_ref(),
+                        );
+                        if !self.cardinality.contains(&labels) {
+                            continue;
+                        }
+                        self.plug_on
+                            .get_or_create(&labels)
+                            .set(child.device_on as i64);
+                        match child.on_time_seconds {
+                            Some(seconds) => {
+                                self.plug_on_since
+                                    .get_or_create(&labels)
+                                    .set(seconds as i64);
+                            }
+                            None => {
+                                self.plug_on_since.remove(&labels);
+                            }
+                        }
+                        if let Some(overloaded) = child.overloaded {
+                            self.overloads.record(&labels, overloaded);
+                        }
+                        if let Some(&watts) = inventory.power_watts.get(&child.device_id) {
+                            self.history.record(&labels, watts as i64, read_at);
+                            if let Some(power_histogram) = self.power_histogram.as_ref() {
+                                power_histogram.observe(&labels, watts, scraped_at);
+                            }
+                        }
+                    }
+
+                    if let Some(energy_totals) = self.energy_totals.as_mut() {
+                        for child in inventory.children.iter() {
+                            if let Some(&watts) = inventory.power_watts.get(&child.device_id) {
+                                energy_totals.record(
+                                    &inventory.device_info.power_strip_id,
+                                    &child.device_id,
+                                    watts,
+                                    read_at,
+                                );
+                            }
+                        }
+                    }
+
+                    // Plugs use nothing while the master switch is off, which isn't worth alerting on
+                    let master_off = inventory.device_info.master_on == Some(false);
+                    if let Some(alerts) = self.alerts.as_mut().filter(|_| !master_off) {
+                        for child in inventory.children.iter() {
+                            let Some(&watts) = inventory.power_watts.get(&child.device_id) else {
+                                continue;
+                            };
+                            if let Some(alert) =
+                                alerts.observe(&child.device_id, &child.nickname, watts)
+                            {
+                                alerts.send(alert);
+                            }
+                        }
+                    }
+
+                    // The device's ID isn't known until it's first read
+                    let previous = reachable_labels(&self.inventory, c.address());
+                    let reachable = Reachable {
+                        power_strip_id: escaped_info.power_strip_id.clone(),
+                        ip_address: escape_label_value(c.address()),
+                    };
+                    if previous != reachable {
+                        self.reachable.remove(&previous);
+                        self.firmware.remove(&previous);
+                        self.master_on.remove(&previous);
+                    }
+                    self.reachable.get_or_create(&reachable).set(1);
+                    if let Some(on) = inventory.device_info.master_on {
+                        self.master_on.get_or_create(&reachable).set(on as i64);
+                    }
+                    self.firmware
+                        .record(&reachable, &inventory.device_info.firmware_version);
+                    self.sensors.record(
+                        c.address(),
+                        &inventory.device_info.power_strip_id,
+                        &inventory.sensors,
+                    );
+
+                    self.inventory.insert(c.address().to_string(), inventory);
+                    self.fresh.insert(c.address().to_string());
+                    succeeded = true;
+                }
+                Err(e) => {
+                    warn!("Failed to update metrics: {e}");
+                    let mut statuses = self.statuses.lock().unwrap();
+                    let status = statuses
+                        .by_address
+                        .entry(c.address().to_string())
+                        .or_default();
+                    status.identify(c.identity());
+                    status.consecutive_failures += 1;
+                    status.last_error = Some(e.to_string());
+                    drop(statuses);
+                    // It may have failed from being updated, so its information's read again
+                    self.info_read.remove(c.address());
+
+                    self.scrape_errors
+                        .get_or_create(&ScrapeErrors {
+                            ip_address: c.address().to_string(),
+                        })
+                        .inc();
+                    let reachable = reachable_labels(&self.inventory, c.address());
+                    if e.operation() == Some(Operation::Refresh) {
+                        self.session_refresh_errors
+                            .get_or_create(&SessionRefreshErrors {
+                                power_strip_id: reachable.power_strip_id.clone(),
+                                ip_address: reachable.ip_address.clone(),
+                                error_kind: error_kind(&e).to_string(),
+                            })
+                            .inc();
+                    }
+                    self.reachable.get_or_create(&reachable).set(0);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        for labels in stale.iter() {
+            self.remove_plug_series(labels);
+        }
+
+        // Only once every device has been read can a filter be known not to match any plug
+        if last_error.is_none() {
+            self.plug_filter.warn_unmatched();
+        }
+
+        match last_error {
+            Some(e) if !succeeded => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Stops serving the series last recorded for the device.
+    fn remove_series(&mut self, address: &str) {
+        let reachable = reachable_labels(&self.inventory, address);
+        // The session may have failed to refresh before the device's ID was known
+        for power_strip_id in [reachable.power_strip_id.as_str(), ""] {
+            for kind in ERROR_KINDS {
+                self.session_refresh_errors.remove(&SessionRefreshErrors {
+                    power_strip_id: power_strip_id.to_string(),
+                    ip_address: reachable.ip_address.clone(),
+                    error_kind: kind.to_string(),
+                });
+            }
+        }
+        for method in API_METHODS {
+            for power_strip_id in [reachable.power_strip_id.as_str(), ""] {
+                self.api_calls.remove(&ApiCalls {
+                    power_strip_id: power_strip_id.to_string(),
+                    ip_address: reachable.ip_address.clone(),
+                    method: method.to_string(),
+                });
+            }
+        }
+        self.reachable.remove(&reachable);
+        self.firmware.remove(&reachable);
+        self.master_on.remove(&reachable);
+        self.sensors.remove(address);
+        self.remove_power_use(address);
+        if let Some(inventory) = self.inventory.remove(address) {
+            self.device_info
+                .remove(&device_info_labels(&inventory.device_info));
+        }
+        self.info_read.remove(address);
+
+        self.scrape_errors.remove(&ScrapeErrors {
+            ip_address: address.to_string(),
+        });
+    }
+}
+
+impl AppState {
+    /// Stops serving the power use last recorded for the device's plugs.
+    fn remove_power_use(&mut self, address: &str) {
+        let labels: Vec<PowerUse> = power_use_labels_of(&self.inventory, address).collect();
+        for labels in labels.iter() {
+            self.remove_plug_series(labels);
+        }
+    }
+
+    /// Stops serving the series of a plug, freeing its labels for another.
+    fn remove_plug_series(&mut self, labels: &PowerUse) {
+        self.power_use.remove(labels);
+        self.plug_on.remove(labels);
+        self.plug_on_since.remove(labels);
+        self.history.remove(labels);
+        if let Some(power_histogram) = self.power_histogram.as_ref() {
+            power_histogram.remove(labels);
+        }
+        self.overloads.remove(labels);
+        self.cardinality.remove(labels);
+    }
+}
+
+/// The labels the power use of each of the device's plugs was last recorded with, if it's been read.
+fn power_use_labels_of<'a>(
+    inventory: &'a HashMap<String, Inventory>,
+    address: &'a str,
+) -> impl Iterator<Item = PowerUse> + 'a {
+    inventory
+        .get(address)
+        .into_iter()
+        .flat_map(move |inventory| {
+            let escaped_info = device_info_labels(&inventory.device_info);
+            inventory.children.iter().map(move |child| {
+                power_use_labels(
+                    &escaped_info,
+                    inventory.labelled_address(address),
+                    child,
+                    inventory.alias.as_ref(),
+                )
+            })
+        })
+}
+
+/// The labels a device's reachability is recorded with, using the ID last read from it, or none if
+/// it hasn't been read yet.
+fn reachable_labels(inventory: &HashMap<String, Inventory>, address: &str) -> Reachable {
+    Reachable {
+        power_strip_id: inventory
+            .get(address)
+            .map(|i| escape_label_value(&i.device_info.power_strip_id))
+            .unwrap_or_default(),
+        ip_address: escape_label_value(address),
+    }
+}
+
+/// The labels a device's information is recorded with.
+pub(crate) fn device_info_labels(info: &DeviceInfo) -> DeviceInfo {
+    DeviceInfo {
+        power_strip_id: escape_label_value(&info.power_strip_id),
+        ip_address: escape_label_value(&info.ip_address),
+        model: escape_label_value(&info.model),
+        firmware_version: escape_label_value(&info.firmware_version),
+        hardware_version: escape_label_value(&info.hardware_version),
+        mac_address: escape_label_value(&info.mac_address),
+        account: AccountLabel(info.account.0.as_deref().map(escape_label_value)),
+        group: escape_label_value(&info.group),
+        master_on: None,
+    }
+}
+
+/// The labels a plug's power use is recorded with, given the labels of the device it's part of and
+/// the device's alias.
+pub(crate) fn power_use_labels(
+    escaped_info: &DeviceInfo,
+    address: &str,
+    child: &ChildDevice,
+    alias: Option<&Alias>,
+) -> PowerUse {
+    // Only a plug that's a device of its own has its nickname replaced, as the alias names the
+    // device rather than any one of a power strip's plugs
+    let (nickname, strip_alias) = match alias {
+        Some(Alias {
+            name,
+            mode: AliasMode::Nickname,
+        }) if child.position == 0 => (name, None),
+        Some(Alias { name, .. }) => (&child.nickname, Some(escape_label_value(name))),
+        None => (&child.nickname, None),
+    };
+
+    PowerUse {
+        power_strip_id: escaped_info.power_strip_id.clone(),
+        ip_address: escape_label_value(address),
+        device_id: escape_label_value(&child.device_id),
+        nickname: escape_label_value(nickname),
+        group: escaped_info.group.clone(),
+        position: child.position,
+        strip_alias: AliasLabel(strip_alias),
+    }
+}
+
+/// Every kind of error given by [`error_kind`].
+const ERROR_KINDS: [&str; 6] = [
+    "invalid_credentials",
+    "session_timeout",
+    "device",
+    "http",
+    "invalid_response",
+    "other",
+];
+
+/// What kind of error reading from a device failed with, for labelling.
+fn error_kind(e: &ExporterError) -> &'static str {
+    match e.tapo_error() {
+        Some(Error::Tapo(TapoResponseError::InvalidCredentials(_))) => "invalid_credentials",
+        Some(Error::Tapo(TapoResponseError::SessionTimeout)) => "session_timeout",
+        Some(Error::Tapo(_)) => "device",
+        Some(Error::Http(_)) => "http",
+        Some(Error::Serde(_)) => "invalid_response",
+        _ => "other",
+    }
+}
+
+/// Reads the current metrics from a single device into the given families, once its session has
+/// been refreshed. Nothing is recorded unless every call to the device succeeds, power use is only
+/// recorded with labels the cardinality guard, if given, admits, plugs the filter, if given, skips
+/// aren't read, and devices that don't monitor their energy use are read without their power use.
+/// The device's information is only read if what was last read isn't given.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn update_device(
+    c: &mut (dyn TapoClient + Send + Sync),
+    power_use: &Family<PowerUse, Gauge>,
+    device_info: &Family<DeviceInfo, Gauge>,
+    alias: Option<&Alias>,
+    given_mac: Option<&str>,
+    group: &str,
+    known_info: Option<DeviceInfo>,
+    api_calls: Option<&Family<ApiCalls, Counter>>,
+    mut cardinality: Option<&mut CardinalityGuard>,
+    mut plugs: Option<&mut PlugFilter>,
+) -> Result<Inventory, ExporterError> {
+    let mut device = DeviceContext::new(c.address(), None);
+    let mut info = match known_info {
+        Some(info) => info,
+        None => {
+            // The device's ID is only known before it's asked if it's been read already
+            let device_id = c.identity().device_id.unwrap_or_default();
+            count_api_calls(api_calls, &device_id, c.address(), "get_device_info", 1);
+            c.device_info()
+                .await
+                .map_err(|e| ExporterError::device(&device, Operation::DeviceInfo, e))?
+        }
+    };
+    info.group = group.to_string();
+    device.device_id = Some(info.power_strip_id.clone());
+
+    count_api_calls(
+        api_calls,
+        &info.power_strip_id,
+        c.address(),
+        "get_child_device_list",
+        1,
+    );
+    let child_device_list = c
+        .child_devices()
+        .await
+        .map_err(|e| ExporterError::device(&device, Operation::ChildList, e))?;
+
+    // Skipped plugs aren't asked for their power use at all, saving a call to the device
+    let children: Vec<ChildDevice> = child_device_list
+        .into_iter()
+        .filter(|child| plugs.as_mut().is_none_or(|filter| filter.keeps(child)))
+        .collect();
+    let mut powers = match c.monitors_energy() {
+        true => {
+            let device_ids: Vec<String> = children.iter().map(|c| c.device_id.clone()).collect();
+            // Each plug's asked for its power use in turn, unless the device can be asked for all
+            // of them at once
+            let calls = match c.reads_all_plug_powers_at_once() {
+                true => 1,
+                false => device_ids.len() as u64,
+            };
+            count_api_calls(
+                api_calls,
+                &info.power_strip_id,
+                c.address(),
+                "get_current_power",
+                calls,
+            );
+            let powers = c.get_all_plug_powers(&device_ids).await;
+            Some(powers.map_err(|e| match &children[..] {
+                // The failure can only be put down to a plug when there's just the one
+                [child] => ExporterError::Plug {
+                    device: device.clone(),
+                    operation: Operation::PowerRead,
+                    position: child.position,
+                    nickname: child.nickname.clone(),
+                    source: e,
+                },
+                _ => ExporterError::device(&device, Operation::PowerRead, e),
+            })?)
+        }
+        false => None,
+    };
+
+    let mut readings = Vec::with_capacity(children.len());
+    for child in children.into_iter() {
+        let current_power = match powers.as_mut() {
+            Some(powers) => {
+                Some(
+                    powers
+                        .remove(&child.device_id)
+                        .ok_or_else(|| ExporterError::Plug {
+                            device: device.clone(),
+                            operation: Operation::PowerRead,
+                            position: child.position,
+                            nickname: child.nickname.clone(),
+                            source: Error::Other(anyhow::anyhow!(
+                                "its power use wasn't reported by the device"
+                            )),
+                        })?,
+                )
+            }
+            None => None,
+        };
+        readings.push((child, current_power));
+    }
+    let sensors = c
+        .sensor_readings()
+        .await
+        .map_err(|e| ExporterError::device(&device, Operation::SensorRead, e))?;
+
+    let escaped_info = device_info_labels(&info);
+    device_info.get_or_create(&escaped_info).set(1);
+
+    for (child, current_power) in readings.iter() {
+        let labels = power_use_labels(
+            &escaped_info,
+            given_mac.unwrap_or(c.address()),
+            child,
+            alias,
+        );
+        if cardinality
+            .as_mut()
+            .is_some_and(|guard| !guard.admit(&labels))
+        {
+            continue;
+        }
+        if let Some(current_power) = current_power {
+            power_use
+                .get_or_create(&labels)
+                .set(current_power.current_power as i64);
+        }
+    }
+
+    Ok(Inventory {
+        device_info: info,
+        power_watts: readings
+            .iter()
+            .filter_map(|(child, power)| {
+                Some((child.device_id.clone(), power.as_ref()?.current_power))
+            })
+            .collect(),
+        children: readings.into_iter().map(|(child, _)| child).collect(),
+        alias: alias.cloned(),
+        given_mac: given_mac.map(str::to_string),
+        sensors,
+    })
+}
+
+impl AppState {
+    /// Reads from the devices, unless they were read within the minimum scrape interval, returning
+    /// when they were last read along with whether it's from the cache.
+    async fn scrape(&mut self) -> Result<(Scrape, bool), ExporterError> {
+        // Avoid reading from the devices too often if scraped more frequently than expected
+        let fresh = self
+            .last_scrape
+            .as_ref()
+            .is_some_and(|scrape| scrape.at.elapsed() < self.min_scrape_interval);
+
+        if !fresh {
+            self.read_devices().await?;
+            self.send_to_statsd();
+            self.save_energy_totals().await;
+
+            self.last_scrape = Some(Scrape {
+                at: Instant::now(),
+                time: SystemTime::now(),
+            });
+            *self.encoded.get_mut().unwrap() = None;
+        }
+
+        match self.last_scrape {
+            Some(scrape) => Ok((scrape, fresh)),
+            None => Err(ExporterError::NoMetrics),
+        }
+    }
+
+    /// The metrics as they were after the devices were last read, encoded the first time they're
+    /// asked for. Only needing to read the state, other requests can read it while encoding.
+    fn encoded(&self) -> Result<EncodedMetrics, ExporterError> {
+        if let Some(encoded) = self.encoded.lock().unwrap().as_ref() {
+            return Ok(encoded.clone());
+        }
+
+        // The calls made to the devices to read them would otherwise change the tag every time
+        let api_calls = format!("{}_api_calls_total{{", self.metric_prefix);
+        let encoded = EncodedMetrics::new(encode_metrics(&self.registry)?, &api_calls);
+        *self.encoded.lock().unwrap() = Some(encoded.clone());
+        Ok(encoded)
+    }
+
+    /// Updates the metrics within the scrape timeout, recovering from a panic outside of reading any
+    /// one device by counting it and failing the scrape, so the server carries on serving.
+    async fn read_devices(&mut self) -> Result<(), ExporterError> {
+        let internal_errors = self.internal_errors.clone();
+        let scrape_timeout = self.scrape_timeout;
+        let update = AssertUnwindSafe(self.update_metrics()).catch_unwind();
+        let result = match scrape_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, update)
+                .await
+                .map_err(|_| ExporterError::ScrapeTimeout(timeout))?,
+            None => update.await,
+        };
+
+        result.unwrap_or_else(|panic| {
+            internal_errors.inc();
+            Err(ExporterError::Panic(panic_message(panic)))
+        })
+    }
+
+    /// Saves the energy used by each plug to the state file, if configured, so the totals carry on
+    /// from there after restarting.
+    async fn save_energy_totals(&self) {
+        let Some(energy_totals) = self.energy_totals.as_ref() else {
+            return;
+        };
+
+        if let Err(e) = energy_totals.save().await {
+            warn!("Failed to save the energy totals: {e}");
+        }
+    }
+
+    /// What was read from each device by the last update, leaving out those that couldn't be read
+    /// so their last readings aren't passed on as if they were new.
+    fn fresh_inventories(&self) -> impl Iterator<Item = (&str, &Inventory)> {
+        self.clients
+            .iter()
+            .filter(|c| self.fresh.contains(c.address()))
+            .filter_map(|c| Some((c.address(), self.inventory.get(c.address())?)))
+    }
+
+    /// Sends what was just read from the devices to StatsD, if configured.
+    fn send_to_statsd(&self) {
+        let Some(statsd) = self.statsd.as_ref() else {
+            return;
+        };
+
+        for (address, inventory) in self.fresh_inventories() {
+            statsd.send_power_use(address, inventory);
+        }
+        for c in self.clients.iter() {
+            if let Some(errors) = self.scrape_errors.get(&ScrapeErrors {
+                ip_address: c.address().to_string(),
+            }) {
+                statsd.send_scrape_errors(c.address(), errors.get());
+            }
+        }
+    }
+}
+
+/// Encodes the metrics in the registry as they're served.
+pub(crate) fn encode_metrics(registry: &Registry) -> Result<String, ExporterError> {
+    let mut buffer = String::new();
+    encode(&mut buffer, registry).map_err(ExporterError::Encode)?;
+    Ok(buffer)
+}
+
+/// The body of a request that failed, which doesn't give the reason as it can include the
+/// devices' addresses and details of how they were talked to.
+const ERROR_BODY: &str = "Failed to read the metrics, see the exporter's logs for why\n";
+
+/// Logs why a request failed, answering it as a server error, or as unavailable if reading the
+/// devices took too long.
+pub(crate) fn error_response(e: ExporterError) -> Response {
+    error!("{e}");
+    let status = match e {
+        ExporterError::ScrapeTimeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, ERROR_BODY).into_response()
+}
+
+async fn metrics_handler(
+    State(state): State<Arc<RwLock<AppState>>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    // The lock's only held for writing while reading from the devices, then for reading while
+    // encoding, with the response built once it's released
+    let encoded = {
+        let mut state = state.write().await;
+        match state.scrape().await {
+            Ok((_, from_cache)) => state
+                .downgrade()
+                .encoded()
+                .map(|encoded| (encoded, from_cache)),
+            Err(e) => Err(e),
+        }
+    };
+
+    match encoded {
+        Ok((encoded, from_cache)) => encoded.response(&headers, from_cache),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Serves the same metrics as `/metrics` as InfluxDB line protocol.
+async fn influx_handler(State(state): State<Arc<RwLock<AppState>>>) -> impl IntoResponse {
+    let mut state = state.write().await;
+
+    let (time, from_cache) = match state.scrape().await {
+        Ok((scrape, from_cache)) => (scrape.time, from_cache),
+        Err(e) => return error_response(e),
+    };
+    let state = state.downgrade();
+
+    let encoder = InfluxLineEncoder::new(&state.power_use, &state.metric_prefix, time);
+    let mut body = String::new();
+    for c in state.clients.iter() {
+        if let Some(inventory) = state.inventory.get(c.address()) {
+            encoder.encode(&mut body, c.address(), inventory);
+        }
+    }
+
+    let mut response = ([(CONTENT_TYPE, INFLUX_CONTENT_TYPE)], body).into_response();
+    if from_cache {
+        response
+            .headers_mut()
+            .insert(SERVED_FROM_CACHE_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+/// A target group in the Prometheus HTTP service discovery format.
+#[derive(Debug, Serialize)]
+struct TargetGroup {
+    targets: Vec<String>,
+    labels: BTreeMap<String, String>,
+}
+
+fn target_group(
+    address: &str,
+    inventory: Option<&Inventory>,
+    device_labels: Option<&BTreeMap<String, String>>,
+) -> TargetGroup {
+    let mut labels = device_labels.cloned().unwrap_or_default();
+    labels.extend([
+        ("__meta_tapo_address".to_string(), address.to_string()),
+        ("__metrics_path__".to_string(), "/probe".to_string()),
+        ("__param_target".to_string(), address.to_string()),
+    ]);
+
+    if let Some(inventory) = inventory {
+        labels.insert(
+            "__meta_tapo_device_id".to_string(),
+            inventory.device_info.power_strip_id.clone(),
+        );
+        labels.insert(
+            "__meta_tapo_model".to_string(),
+            inventory.device_info.model.clone(),
+        );
+        labels.insert(
+            "__meta_tapo_firmware_version".to_string(),
+            inventory.device_info.firmware_version.clone(),
+        );
+        labels.insert(
+            "__meta_tapo_nickname".to_string(),
+            inventory
+                .children
+                .iter()
+                .map(|c| c.nickname.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+    }
+
+    TargetGroup {
+        targets: vec![address.to_string()],
+        labels,
+    }
+}
+
+async fn sd_handler(State(state): State<Arc<RwLock<AppState>>>) -> impl IntoResponse {
+    let mut state = state.write().await;
+
+    if state.inventory.len() < state.clients.len() {
+        // Read as a scrape would be, so unreachable devices are only retried as often as they're
+        // scraped. Failures are already logged and the devices will be listed with what is known
+        // of them
+        let _ = state.scrape().await;
+    }
+    let state = state.downgrade();
+
+    let groups: Vec<TargetGroup> = state
+        .clients
+        .iter()
+        .map(|c| {
+            target_group(
+                c.address(),
+                state.inventory.get(c.address()),
+                state.device_labels.get(c.address()),
+            )
+        })
+        .collect();
+
+    Json(groups)
+}
+
+/// The health of a device, as reported by `/health` when asked for JSON.
+#[derive(Debug, Serialize)]
+struct DeviceHealth {
+    address: String,
+    device_id: Option<String>,
+    model: Option<String>,
+    last_success: Option<String>,
+    consecutive_failures: u64,
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+    devices: Vec<DeviceHealth>,
+}
+
+/// Reports the server as healthy, along with the status of each device from previous scrapes when
+/// asked for JSON. Kept for existing setups, with `/liveness` preferred.
+async fn health(State(state): State<HealthState>, headers: HeaderMap) -> Response {
+    let wants_json = headers
+        .get(ACCEPT)
+        .and_then(|a| a.to_str().ok())
+        .is_some_and(|a| a.contains("application/json"));
+    if !wants_json {
+        return StatusCode::OK.into_response();
+    }
+
+    let statuses = state.statuses.lock().unwrap();
+    let devices: Vec<DeviceHealth> = statuses
+        .addresses
+        .iter()
+        .map(|address| {
+            let status = statuses
+                .by_address
+                .get(address)
+                .cloned()
+                .unwrap_or_default();
+            DeviceHealth {
+                address: address.clone(),
+                device_id: status.device_id,
+                model: status.model,
+                last_success: status
+                    .last_success
+                    .map(|t| humantime::format_rfc3339_seconds(t).to_string()),
+                consecutive_failures: status.consecutive_failures,
+                last_error: status.last_error,
+            }
+        })
+        .collect();
+
+    let status = if devices.iter().all(|d| d.consecutive_failures == 0) {
+        "ok"
+    } else {
+        "degraded"
+    };
+
+    Json(Health { status, devices }).into_response()
+}
+
+/// How many devices must have been read recently for the server to be ready.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadinessPolicy {
+    /// At least one device
+    #[default]
+    Any,
+    /// Every device
+    All,
+}
+
+#[derive(Clone)]
+struct HealthState {
+    statuses: Statuses,
+    policy: ReadinessPolicy,
+    window: Duration,
+}
+
+/// Reports whether devices have been read recently, from what previous scrapes found rather than by
+/// reading from the devices. Without any devices there's nothing to wait for, as with `/readiness`.
+async fn ready(State(state): State<HealthState>) -> impl IntoResponse {
+    let statuses = state.statuses.lock().unwrap();
+    if statuses.addresses.is_empty() {
+        return (StatusCode::OK, String::new());
+    }
+    let recent = statuses
+        .addresses
+        .iter()
+        .filter(|a| {
+            statuses
+                .by_address
+                .get(*a)
+                .and_then(|s| s.last_success)
+                .and_then(|t| t.elapsed().ok())
+                .is_some_and(|elapsed| elapsed < state.window)
+        })
+        .count();
+
+    let ready = match state.policy {
+        ReadinessPolicy::Any => recent > 0,
+        ReadinessPolicy::All => recent == statuses.addresses.len(),
+    };
+
+    if ready {
+        (StatusCode::OK, String::new())
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "{recent} of {} devices read in the last {}",
+                statuses.addresses.len(),
+                humantime::format_duration(state.window)
+            ),
+        )
+    }
+}
+
+/// Reports the process as alive for a Kubernetes liveness probe, without looking at the devices.
+async fn liveness() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Reports whether metrics can be served for a Kubernetes readiness probe, which they can once a
+/// device has been read and while the last read of at least one device succeeded. Without any
+/// devices, such as while waiting for them to be discovered, there's nothing to wait for, so the
+/// empty metrics can be served.
+async fn readiness(State(state): State<HealthState>) -> impl IntoResponse {
+    let statuses = state.statuses.lock().unwrap();
+    if statuses.addresses.is_empty() {
+        return (StatusCode::OK, String::new());
+    }
+    let devices = statuses
+        .addresses
+        .iter()
+        .filter_map(|a| statuses.by_address.get(a));
+
+    let (mut read, mut failing) = (false, 0);
+    for status in devices {
+        read |= status.last_success.is_some();
+        if status.consecutive_failures > 0 {
+            failing += 1;
+        }
+    }
+
+    if !read {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No device has been read yet".to_string(),
+        )
+    } else if failing == statuses.addresses.len() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Reading from all {failing} devices is failing"),
+        )
+    } else {
+        (StatusCode::OK, String::new())
+    }
+}
+
+/// Options for how the server handles requests.
+#[derive(Clone, Debug)]
+pub struct AppConfig {
+    /// Maximum time to spend handling a request that talks to devices.
+    pub http_timeout: Duration,
+    /// Maximum number of requests that talk to devices to handle at once, with any more rejected.
+    pub http_max_concurrent: usize,
+    /// How many devices must have been read recently for `/ready` to report the server as ready.
+    pub readiness_policy: ReadinessPolicy,
+    /// How recently a device must have been read to count towards readiness.
+    pub readiness_window: Duration,
+    /// Minimum time between reading metrics from the devices, with the last metrics served to any
+    /// scrapes in between.
+    pub min_scrape_interval: Duration,
+    /// Minimum time between reading each device's information, with what was last read used in
+    /// between.
+    pub info_refresh_interval: Duration,
+    /// Origins browsers are allowed to fetch the metrics from, with none allowed when empty.
+    pub cors_allowed_origins: Vec<HeaderValue>,
+    /// Labels added to each device's target in service discovery, by address.
+    pub device_labels: HashMap<String, BTreeMap<String, String>>,
+    /// Aliases of the devices that have them, by address.
+    pub aliases: HashMap<String, Alias>,
+    /// Groups of the devices in one, by address.
+    pub groups: HashMap<String, String>,
+    /// MAC addresses of the devices given by one, by the address each was found at. Their plugs'
+    /// power use is labelled with the MAC address instead, so it carries on as one series when
+    /// the device is given another IP address.
+    pub given_macs: HashMap<String, String>,
+    /// Where to send alerts when a plug's power use goes over a threshold, if anywhere.
+    pub alert: Option<AlertConfig>,
+    /// Where to send the metrics as StatsD gauges each time the devices are read, if anywhere.
+    pub statsd: Option<StatsdSender>,
+    /// Counts the requests to the remote write endpoint that failed, if writing to one.
+    pub remote_write_errors: Option<Counter>,
+    /// Counts the writes to InfluxDB that failed, if writing to it.
+    pub influx_write_errors: Option<Counter>,
+    /// Counts the pushes to the OTLP endpoint that failed, if pushing to one.
+    pub otlp_push_errors: Option<Counter>,
+    /// Counts the pushes to the Pushgateway that failed, if pushing to one.
+    pub pushgateway_push_errors: Option<Counter>,
+    /// How many readings of each plug to keep for reporting how its power use is changing.
+    pub history_size: usize,
+    /// The buckets to count each plug's power use readings in, if they're being counted.
+    pub power_histogram_buckets: Option<PowerBuckets>,
+    /// Maximum number of label combinations to record power use with, with plugs that would go
+    /// over it left out.
+    pub max_label_cardinality: usize,
+    /// Maximum time to spend reading from each device before counting it as failed, if limited.
+    pub device_timeout: Option<Duration>,
+    /// Maximum time to spend reading from every device each time they're read, if limited.
+    pub scrape_timeout: Option<Duration>,
+    /// Which plugs to read from.
+    pub plug_filter: PlugFilter,
+    /// The energy used by each plug so far, and where it's saved, if it's being kept.
+    pub energy_totals: Option<EnergyTotals>,
+    /// What the name of every metric starts with, followed by an underscore.
+    pub metric_prefix: String,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            http_timeout: Duration::from_secs(30),
+            http_max_concurrent: 4,
+            readiness_policy: ReadinessPolicy::Any,
+            readiness_window: Duration::from_secs(300),
+            min_scrape_interval: Duration::from_secs(5),
+            info_refresh_interval: Duration::from_secs(300),
+            cors_allowed_origins: Vec::new(),
+            device_labels: HashMap::new(),
+            aliases: HashMap::new(),
+            groups: HashMap::new(),
+            given_macs: HashMap::new(),
+            alert: None,
+            statsd: None,
+            remote_write_errors: None,
+            influx_write_errors: None,
+            otlp_push_errors: None,
+            pushgateway_push_errors: None,
+            history_size: 10,
+            power_histogram_buckets: None,
+            max_label_cardinality: 1000,
+            device_timeout: None,
+            scrape_timeout: Some(Duration::from_secs(30)),
+            plug_filter: PlugFilter::default(),
+            energy_totals: None,
+            metric_prefix: DEFAULT_METRIC_PREFIX.to_string(),
+        }
+    }
+}
+
+impl AppConfig {
+    /// Gives the devices the labels, aliases and groups they're configured with, and their plugs
+    /// the MAC address of any given by one.
+    pub fn with_devices(
+        self,
+        devices: &[DeviceConfig],
+        alias_mode: AliasMode,
+        macs: &ResolvedMacs,
+    ) -> Self {
+        AppConfig {
+            device_labels: device_labels(devices),
+            aliases: device_aliases(devices, alias_mode),
+            groups: device_groups(devices),
+            given_macs: macs.found(),
+            ..self
+        }
+    }
+}
+
+/// Labels added to the devices' targets in service discovery, by address.
+pub(crate) fn device_labels(devices: &[DeviceConfig]) -> HashMap<String, BTreeMap<String, String>> {
+    devices
+        .iter()
+        .filter(|d| !d.labels.is_empty())
+        .map(|d| (d.address.clone(), d.labels.clone()))
+        .collect()
+}
+
+/// Aliases of the devices that have them, by address.
+pub(crate) fn device_aliases(devices: &[DeviceConfig], mode: AliasMode) -> HashMap<String, Alias> {
+    devices
+        .iter()
+        .filter_map(|d| {
+            let name = d.alias.clone()?;
+            Some((d.address.clone(), Alias { name, mode }))
+        })
+        .collect()
+}
+
+/// Groups of the devices in one, by address.
+pub(crate) fn device_groups(devices: &[DeviceConfig]) -> HashMap<String, String> {
+    devices
+        .iter()
+        .filter(|d| !d.group.is_empty())
+        .map(|d| (d.address.clone(), d.group.clone()))
+        .collect()
+}
+
+/// Allows browsers on the given origins to fetch the metrics, with `*` allowing any origin.
+fn cors_layer(allowed_origins: &[HeaderValue]) -> Option<CorsLayer> {
+    if allowed_origins.is_empty() {
+        return None;
+    }
+
+    let origins = if allowed_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(allowed_origins.iter().cloned())
+    };
+
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([Method::GET])
+            .max_age(Duration::from_secs(3600)),
+    )
+}
+
+async fn handle_limit_error(e: BoxError) -> impl IntoResponse {
+    if e.is::<Elapsed>() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Request timed out".to_string(),
+        )
+    } else if e.is::<Overloaded>() {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many concurrent requests".to_string(),
+        )
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+/// Adds devices to be read from once the server is running, such as those found by discovery.
+#[derive(Clone)]
+pub struct Devices {
+    state: Arc<RwLock<AppState>>,
+    statuses: Statuses,
+}
+
+impl Devices {
+    /// Whether metrics are already being read from the device at the address.
+    pub fn contains(&self, address: &str) -> bool {
+        self.statuses
+            .lock()
+            .unwrap()
+            .addresses
+            .iter()
+            .any(|a| a == address)
+    }
+
+    /// The addresses of the devices being read from, in the order they were added, along with
+    /// their models once they've been read.
+    pub fn list(&self) -> Vec<(String, Option<String>)> {
+        let statuses = self.statuses.lock().unwrap();
+        statuses
+            .addresses
+            .iter()
+            .map(|a| {
+                let model = statuses.by_address.get(a).and_then(|s| s.model.clone());
+                (a.clone(), model)
+            })
+            .collect()
+    }
+
+    /// Starts reading metrics from the device, once any scrape in progress has finished.
+    pub async fn add(&self, client: Box<dyn TapoClient + Send + Sync>) {
+        let mut state = self.state.write().await;
+        let mut statuses = self.statuses.lock().unwrap();
+        statuses.addresses.push(client.address().to_string());
+        let status = statuses
+            .by_address
+            .entry(client.address().to_string())
+            .or_default();
+        status.identify(client.identity());
+        drop(statuses);
+        state
+            .reachable
+            .get_or_create(&reachable_labels(&state.inventory, client.address()))
+            .set(0);
+        state.clients.push(client);
+        // Make sure the next scrape includes the new device
+        state.last_scrape = None;
+    }
+
+    /// Stops reading metrics from the device and drops its series, once any scrape in progress
+    /// has finished. Returns whether the device was being read from.
+    pub async fn remove(&self, address: &str) -> bool {
+        let mut state = self.state.write().await;
+        let mut statuses = self.statuses.lock().unwrap();
+        if !statuses.addresses.iter().any(|a| a == address) {
+            return false;
+        }
+
+        statuses.addresses.retain(|a| a != address);
+        statuses.by_address.remove(address);
+        drop(statuses);
+
+        state.clients.retain(|c| c.address() != address);
+        state.remove_series(address);
+        state.last_scrape = None;
+        true
+    }
+
+    /// Replaces the aliases of the devices, dropping the series of any whose alias has changed so
+    /// they're recorded with the new one from the next scrape.
+    pub async fn set_aliases(&self, aliases: HashMap<String, Alias>) {
+        let mut state = self.state.write().await;
+        let changed: Vec<String> = state
+            .inventory
+            .iter()
+            .filter(|(address, inventory)| inventory.alias.as_ref() != aliases.get(*address))
+            .map(|(address, _)| address.clone())
+            .collect();
+
+        for address in changed.iter() {
+            state.remove_power_use(address);
+        }
+        state.aliases = aliases;
+        if !changed.is_empty() {
+            state.last_scrape = None;
+        }
+    }
+
+    /// Replaces the groups of the devices, dropping the series of any whose group has changed so
+    /// they're recorded with the new one from the next scrape.
+    pub async fn set_groups(&self, groups: HashMap<String, String>) {
+        let mut state = self.state.write().await;
+        let changed: Vec<String> = state
+            .inventory
+            .iter()
+            .filter(|(address, inventory)| {
+                inventory.device_info.group != groups.get(*address).map_or("", String::as_str)
+            })
+            .map(|(address, _)| address.clone())
+            .collect();
+
+        for address in changed.iter() {
+            state.remove_power_use(address);
+            if let Some(inventory) = state.inventory.get(address) {
+                let labels = device_info_labels(&inventory.device_info);
+                state.device_info.remove(&labels);
+            }
+        }
+        state.groups = groups;
+        if !changed.is_empty() {
+            state.last_scrape = None;
+        }
+    }
+
+    /// Replaces the MAC addresses of the devices given by one, as they're found at other addresses.
+    /// Devices that move are connected to again at their new address, so their series don't need
+    /// dropping.
+    pub async fn set_given_macs(&self, given_macs: HashMap<String, String>) {
+        self.state.write().await.given_macs = given_macs;
+    }
+
+    /// Whether reading from the device failed the last time it was read from.
+    pub fn is_failing(&self, address: &str) -> bool {
+        self.statuses
+            .lock()
+            .unwrap()
+            .by_address
+            .get(address)
+            .is_some_and(|s| s.consecutive_failures > 0)
+    }
+
+    /// Replaces whether the address of each device given by MAC address has been found, by MAC
+    /// address.
+    pub async fn set_mac_resolutions(&self, resolutions: HashMap<String, bool>) {
+        let state = self.state.read().await;
+        state.mac_resolved.clear();
+        for (mac_address, resolved) in resolutions {
+            state
+                .mac_resolved
+                .get_or_create(&MacAddress { mac_address })
+                .set(resolved as i64);
+        }
+    }
+
+    /// Replaces the labels added to each device's target in service discovery.
+    pub async fn set_labels(&self, device_labels: HashMap<String, BTreeMap<String, String>>) {
+        self.state.write().await.device_labels = device_labels;
+    }
+
+    /// Reads from the devices, as a scrape of `/metrics` would, sending what was read to StatsD if
+    /// configured, then pushes it to the OTLP endpoint, the remote write endpoint, the Pushgateway
+    /// and InfluxDB if given. Each is pushed to even if another fails, with every failure returned.
+    pub async fn push_metrics(
+        &self,
+        otlp: Option<&OtlpExporter>,
+        pushgateway: Option<&Pushgateway>,
+        remote_writer: Option<&RemoteWriter>,
+        influx: Option<&InfluxWriter>,
+    ) -> Result<(), String> {
+        let mut state = self.state.write().await;
+        let (scrape, _) = state.scrape().await.map_err(|e| e.to_string())?;
+        // Pushed as they're served, so both have the same metrics
+        let metrics = match pushgateway.is_some() || remote_writer.is_some() {
+            true => {
+                let encoded = state.encoded().map_err(|e| e.to_string())?;
+                let body = String::from_utf8_lossy(&encoded.body).into_owned();
+                Some((body, SystemTime::now()))
+            }
+            false => None,
+        };
+        let time = scrape.time;
+        let lines = influx.map(|_| {
+            let mut lines = String::new();
+            for (_, inventory) in state.fresh_inventories() {
+                encode_readings(
+                    &mut lines,
+                    &state.metric_prefix,
+                    inventory,
+                    state.energy_totals.as_ref(),
+                    time,
+                );
+            }
+            lines
+        });
+
+        if let Some(otlp) = otlp {
+            for (address, inventory) in state.fresh_inventories() {
+                otlp.record(address, inventory);
+            }
+            for (power_strip_id, device_id, watt_hours) in
+                state.energy_totals.iter().flat_map(EnergyTotals::totals)
+            {
+                otlp.record_energy(power_strip_id, device_id, watt_hours);
+            }
+        }
+        // Don't hold up scrapes while waiting on the endpoints
+        drop(state);
+
+        // Each is pushed to regardless of the others failing, so one being down doesn't leave the
+        // rest without metrics
+        let mut errors = Vec::new();
+        if let Some(otlp) = otlp {
+            if let Err(e) = otlp.push().await {
+                errors.push(format!("Failed to push to the OTLP endpoint: {e}"));
+            }
+        }
+        if let (Some(remote_writer), Some((metrics, at))) = (remote_writer, metrics.as_ref()) {
+            if let Err(e) = remote_writer.write(metrics, *at).await {
+                errors.push(format!("Failed to remote write: {e}"));
+            }
+        }
+        if let (Some(pushgateway), Some((metrics, _))) = (pushgateway, metrics) {
+            if let Err(e) = pushgateway.push(metrics).await {
+                errors.push(format!("Failed to push to the Pushgateway: {e}"));
+            }
+        }
+        if let (Some(influx), Some(lines)) = (influx, lines) {
+            if let Err(e) = influx.write(lines).await {
+                errors.push(format!("Failed to write to InfluxDB: {e}"));
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("; ")),
+        }
+    }
+}
+
+/// Reads from the devices once, returning the metrics encoded as they'd be served. Only fails if
+/// every device failed.
+pub async fn collect(
+    power_strips: Vec<Box<dyn TapoClient + Send + Sync>>,
+    config: AppConfig,
+) -> Result<String, ExporterError> {
+    let mut state = AppState::new(power_strips, &config);
+    state.update_metrics().await?;
+    encode_metrics(&state.registry)
+}
+
+/// Reads the metrics from the devices into a registry, for serving them from an HTTP server other
+/// than the one [`app`] builds.
+pub struct Collector {
+    state: AppState,
+}
+
+impl Collector {
+    pub fn new(power_strips: Vec<Box<dyn TapoClient + Send + Sync>>, config: &AppConfig) -> Self {
+        Collector {
+            state: AppState::new(power_strips, config),
+        }
+    }
+
+    /// Registers the metrics with the registry, which then has what was last read each time it's
+    /// encoded. The metrics can be registered with more than one registry.
+    pub fn register(&self, registry: &mut Registry) {
+        self.state.register(registry);
+    }
+
+    /// Reads from the devices, updating the metrics in the registries they're registered with, and
+    /// sending what was read to StatsD if configured. Only fails if every device failed.
+    pub async fn update(&mut self) -> Result<(), ExporterError> {
+        self.state.read_devices().await?;
+        self.state.send_to_statsd();
+        self.state.save_energy_totals().await;
+        Ok(())
+    }
+}
+
+/// Builds the routes for the metrics along with the health and readiness checks.
+pub fn app(power_strips: Vec<Box<dyn TapoClient + Send + Sync>>, config: AppConfig) -> Router {
+    let (router, health_router, _) = split_app(power_strips, config);
+    router.merge(health_router)
+}
+
+/// Builds the routes for the metrics separately from those for the health and readiness checks,
+/// so they can be served on different ports, along with a handle for adding devices later.
+pub fn split_app(
+    power_strips: Vec<Box<dyn TapoClient + Send + Sync>>,
+    config: AppConfig,
+) -> (Router, Router, Devices) {
+    split_app_with_routes(power_strips, config, Router::new())
+}
+
+/// Builds the routes as [`split_app`] does, serving `routes` alongside the metrics with the same
+/// limits on concurrent requests and how long each can take, such as `/probe` which also reads
+/// from devices.
+pub fn split_app_with_routes(
+    power_strips: Vec<Box<dyn TapoClient + Send + Sync>>,
+    config: AppConfig,
+    routes: Router,
+) -> (Router, Router, Devices) {
+    let state = AppState::new(power_strips, &config);
+    let health_state = HealthState {
+        statuses: state.statuses.clone(),
+        policy: config.readiness_policy,
+        window: config.readiness_window,
+    };
+    let state = Arc::new(RwLock::new(state));
+    let devices = Devices {
+        state: state.clone(),
+        statuses: health_state.statuses.clone(),
+    };
+
+    let router = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/metrics/influx", get(influx_handler))
+        .route("/sd", get(sd_handler))
+        .with_state(state)
+        .merge(routes)
+        .route_layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(handle_limit_error))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::with_semaphore(Arc::new(
+                    Semaphore::new(config.http_max_concurrent),
+                )))
+                .timeout(config.http_timeout),
+        );
+    let router = match cors_layer(&config.cors_allowed_origins) {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+
+    // Health and readiness checks are left out of the limits so they're answered however busy the
+    // devices are
+    let health_router = Router::new()
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/liveness", get(liveness))
+        .route("/readiness", get(readiness))
+        .with_state(health_state);
+
+    (router, health_router, devices)
+}
+
+#[cfg(test)]
+mod test {
+    use super::split_app_with_routes;
+    use super::{AccountLabel, Alias, AliasLabel, AliasMode, ChildDevice, DeviceInfo, TapoClient};
+    use super::{AppConfig, ReadinessPolicy, app, collect, format_mac_address, split_app};
+    use super::{AppState, Collector, ERROR_BODY, metrics_handler, power_strip_info};
+    use super::{device_info_labels, power_use_labels};
+    use crate::fake::FakeClient;
+    use crate::influx::InfluxWriter;
+    use crate::plugs::PlugFilter;
+    use crate::power_histogram::parse_buckets;
+    use crate::pushgateway::Pushgateway;
+    use crate::sensors::SensorReading;
+    use async_trait::async_trait;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::encoding::{EncodeMetric, MetricEncoder};
+    use prometheus_client::metrics::MetricType;
+    use prometheus_client::metrics::family::Family;
+    use prometheus_client::metrics::gauge::Gauge;
+    use prometheus_client::registry::Registry;
+
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::HeaderValue;
+    use axum::http::Request;
+    use axum::http::StatusCode;
+    use http_body_util::BodyExt;
+    use proptest::prelude::*;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::time::{Duration, Instant};
+    use tapo::responses::CurrentPowerResult;
+    use tapo::{Error, TapoResponseError};
+    use tokio::sync::RwLock;
+    use tower::ServiceExt; // for `collect`
+
+    struct UnauthorisedClient {}
+
+    #[async_trait]
+    impl TapoClient for UnauthorisedClient {
+        fn address(&self) -> &str {
+            "10.0.0.6"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Err(Error::Tapo(TapoResponseError::InvalidCredentials(
+                "Invalid credentials".to_string(),
+            )))
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            panic!("device_info shouldn't be called without a session");
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            panic!("child_devices shouldn't be called without a session");
+        }
+
+        async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+            panic!("get_power_for_plug shouldn't be called without a session");
+        }
+    }
+
+    struct LabelClient {
+        nickname: String,
+        model: String,
+        firmware_version: String,
+    }
+
+    #[async_trait]
+    impl TapoClient for LabelClient {
+        fn address(&self) -> &str {
+            "10.0.0.3"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            Ok(DeviceInfo {
+                power_strip_id: "123".to_string(),
+                ip_address: self.address().to_string(),
+                firmware_version: self.firmware_version.clone(),
+                hardware_version: "1.0".to_string(),
+                model: self.model.clone(),
+                mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+                account: AccountLabel::default(),
+                group: String::new(),
+                master_on: None,
+            })
         }
 
         async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
             Ok(vec![ChildDevice {
                 device_id: "456".to_string(),
-                nickname: "".to_string(),
+                nickname: self.nickname.clone(),
+                device_on: true,
+                on_time_seconds: None,
+                overloaded: None,
                 position: 1,
             }])
         }
 
-        async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
-            match device_id.as_ref() {
-                "456" => Ok(CurrentPowerResult { current_power: 45 }),
-                d => {
-                    panic!("unexpected device_id {}", d);
-                }
-            }
-        }
+        async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+            Ok(CurrentPowerResult { current_power: 45 })
+        }
+    }
+
+    /// A power strip with a router on its first plug, which it panics if asked the power use of.
+    struct RouterClient {}
+
+    #[async_trait]
+    impl TapoClient for RouterClient {
+        fn address(&self) -> &str {
+            "10.0.0.7"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            FakeClient::new("10.0.0.1").device_info().await
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            Ok(vec![
+                ChildDevice {
+                    device_id: "789".to_string(),
+                    nickname: "Router".to_string(),
+                    device_on: true,
+                    on_time_seconds: None,
+                    overloaded: None,
+                    position: 1,
+                },
+                ChildDevice {
+                    device_id: "456".to_string(),
+                    nickname: "Kettle".to_string(),
+                    device_on: true,
+                    on_time_seconds: None,
+                    overloaded: None,
+                    position: 2,
+                },
+            ])
+        }
+
+        async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
+            match device_id {
+                "456" => Ok(CurrentPowerResult { current_power: 45 }),
+                d => {
+                    panic!("unexpected device_id {}", d);
+                }
+            }
+        }
+    }
+
+    /// A power strip that can be asked for every plug's power use at once, reporting only some.
+    struct BatchClient {
+        reported: Vec<&'static str>,
+    }
+
+    #[async_trait]
+    impl TapoClient for BatchClient {
+        fn address(&self) -> &str {
+            "10.0.0.9"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            FakeClient::new("10.0.0.1").device_info().await
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            RouterClient {}.child_devices().await
+        }
+
+        async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+            panic!("power use should be asked for from every plug at once");
+        }
+
+        fn reads_all_plug_powers_at_once(&self) -> bool {
+            true
+        }
+
+        async fn get_all_plug_powers(
+            &self,
+            device_ids: &[String],
+        ) -> Result<HashMap<String, CurrentPowerResult>, Error> {
+            assert_eq!(device_ids, ["789", "456"]);
+            Ok(self
+                .reported
+                .iter()
+                .map(|id| (id.to_string(), CurrentPowerResult { current_power: 12 }))
+                .collect())
+        }
+    }
+
+    /// A power strip, like the P300, that doesn't report its plugs' power use.
+    struct NonEnergyMonitoringClient {}
+
+    #[async_trait]
+    impl TapoClient for NonEnergyMonitoringClient {
+        fn address(&self) -> &str {
+            "10.0.0.8"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            Ok(DeviceInfo {
+                power_strip_id: "300".to_string(),
+                ip_address: self.address().to_string(),
+                model: "P300".to_string(),
+                firmware_version: "1.0.7".to_string(),
+                hardware_version: "1.0".to_string(),
+                mac_address: "aa:bb:cc:dd:ee:00".to_string(),
+                account: AccountLabel::default(),
+                group: String::new(),
+                master_on: Some(false),
+            })
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            Ok(vec![
+                ChildDevice {
+                    device_id: "301".to_string(),
+                    nickname: "Lamp".to_string(),
+                    device_on: true,
+                    on_time_seconds: Some(3600),
+                    overloaded: None,
+                    position: 1,
+                },
+                ChildDevice {
+                    device_id: "302".to_string(),
+                    nickname: "Fan".to_string(),
+                    device_on: false,
+                    on_time_seconds: None,
+                    overloaded: None,
+                    position: 2,
+                },
+            ])
+        }
+
+        async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+            panic!("power use shouldn't be asked for from a device that doesn't monitor it");
+        }
+
+        fn monitors_energy(&self) -> bool {
+            false
+        }
+    }
+
+    struct HubClient {}
+
+    #[async_trait]
+    impl TapoClient for HubClient {
+        fn address(&self) -> &str {
+            "10.0.0.10"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            Ok(DeviceInfo {
+                power_strip_id: "400".to_string(),
+                ip_address: self.address().to_string(),
+                model: "H100".to_string(),
+                firmware_version: "1.5.10".to_string(),
+                hardware_version: "1.0".to_string(),
+                mac_address: "aa:bb:cc:dd:ee:10".to_string(),
+                account: AccountLabel::default(),
+                group: String::new(),
+                master_on: None,
+            })
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            Ok(vec![])
+        }
+
+        async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+            panic!("power use shouldn't be asked for from a hub");
+        }
+
+        fn monitors_energy(&self) -> bool {
+            false
+        }
+
+        async fn sensor_readings(&self) -> Result<Vec<SensorReading>, Error> {
+            Ok(vec![SensorReading {
+                device_id: "401".to_string(),
+                nickname: "Bedroom".to_string(),
+                sensor_type: "T310".to_string(),
+                temperature_celsius: Some(21.5),
+                humidity_percent: Some(48.0),
+                battery_low: Some(true),
+            }])
+        }
+    }
+
+    struct SlowClient {}
+
+    #[async_trait]
+    impl TapoClient for SlowClient {
+        fn address(&self) -> &str {
+            "10.0.0.4"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            FakeClient::new("10.0.0.1").device_info().await
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            FakeClient::new("10.0.0.1").child_devices().await
+        }
+
+        async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
+            FakeClient::new("10.0.0.1")
+                .get_power_for_plug(device_id)
+                .await
+        }
+    }
+
+    struct FlakyClient {
+        failing: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl TapoClient for FlakyClient {
+        fn address(&self) -> &str {
+            "10.0.0.5"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            match self.failing.load(Ordering::SeqCst) {
+                true => Err(Error::DeviceNotFound),
+                false => Ok(()),
+            }
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            FakeClient::new("10.0.0.1").device_info().await
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            FakeClient::new("10.0.0.1").child_devices().await
+        }
+
+        async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
+            FakeClient::new("10.0.0.1")
+                .get_power_for_plug(device_id)
+                .await
+        }
+    }
+
+    /// A client that panics reading the device the first time it's read.
+    struct PanickingClient {
+        panicked: AtomicBool,
+    }
+
+    #[async_trait]
+    impl TapoClient for PanickingClient {
+        fn address(&self) -> &str {
+            "10.0.0.1"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            if !self.panicked.swap(true, Ordering::SeqCst) {
+                panic!("unexpected response from the device");
+            }
+            FakeClient::new("10.0.0.1").device_info().await
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            FakeClient::new("10.0.0.1").child_devices().await
+        }
+
+        async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
+            FakeClient::new("10.0.0.1")
+                .get_power_for_plug(device_id)
+                .await
+        }
+    }
+
+    struct RisingClient {
+        watts: Arc<AtomicU64>,
+    }
+
+    #[async_trait]
+    impl TapoClient for RisingClient {
+        fn address(&self) -> &str {
+            "10.0.0.1"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            FakeClient::new("10.0.0.1").device_info().await
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            FakeClient::new("10.0.0.1").child_devices().await
+        }
+
+        async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+            Ok(CurrentPowerResult {
+                current_power: self.watts.fetch_add(1000, Ordering::SeqCst),
+            })
+        }
+    }
+
+    /// Counts how many times its device information is read.
+    struct InfoCountingClient {
+        info_reads: Arc<AtomicU64>,
+    }
+
+    #[async_trait]
+    impl TapoClient for InfoCountingClient {
+        fn address(&self) -> &str {
+            "10.0.0.1"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            self.info_reads.fetch_add(1, Ordering::SeqCst);
+            FakeClient::new("10.0.0.1").device_info().await
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            FakeClient::new("10.0.0.1").child_devices().await
+        }
+
+        async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
+            FakeClient::new("10.0.0.1")
+                .get_power_for_plug(device_id)
+                .await
+        }
+    }
+
+    /// A power strip whose master switch can be turned on and off between reads.
+    struct MasterSwitchClient {
+        master_on: Arc<AtomicBool>,
+        info_reads: Arc<AtomicU64>,
+    }
+
+    #[async_trait]
+    impl TapoClient for MasterSwitchClient {
+        fn address(&self) -> &str {
+            "10.0.0.1"
+        }
+
+        async fn refresh_session(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn device_info(&self) -> Result<DeviceInfo, Error> {
+            self.info_reads.fetch_add(1, Ordering::SeqCst);
+            Ok(DeviceInfo {
+                master_on: Some(self.master_on.load(Ordering::SeqCst)),
+                ..FakeClient::new("10.0.0.1").device_info().await?
+            })
+        }
+
+        async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+            FakeClient::new("10.0.0.1").child_devices().await
+        }
+
+        async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
+            FakeClient::new("10.0.0.1")
+                .get_power_for_plug(device_id)
+                .await
+        }
+    }
+
+    async fn get(app: &Router, uri: &str) -> StatusCode {
+        app.clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    async fn get_response(app: Router, uri: &'static str) -> (StatusCode, String) {
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body_bytes.to_vec()).unwrap())
+    }
+
+    async fn get_body(app: &Router, uri: &str) -> String {
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body_bytes.to_vec()).unwrap()
+    }
+
+    /// Parses the labels of a sample line as a Prometheus parser would, returning the unescaped
+    /// values or `None` if the labels aren't valid.
+    fn parse_labels(line: &str) -> Option<Vec<(String, String)>> {
+        let mut chars = line[line.find('{')? + 1..].chars();
+        let mut labels = Vec::new();
+
+        loop {
+            let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+            if chars.next()? != '"' {
+                return None;
+            }
+
+            let mut value = String::new();
+            loop {
+                match chars.next()? {
+                    '\\' => match chars.next()? {
+                        '\\' => value.push('\\'),
+                        '"' => value.push('"'),
+                        'n' => value.push('\n'),
+                        _ => return None,
+                    },
+                    '"' => break,
+                    '\n' => return None,
+                    c => value.push(c),
+                }
+            }
+            labels.push((name, value));
+
+            match chars.next()? {
+                ',' => {}
+                '}' => return Some(labels),
+                _ => return None,
+            }
+        }
+    }
+
+    fn label_value() -> impl Strategy<Value = String> {
+        prop_oneof![
+            any::<String>(),
+            "[a-zA-Z0-9 \"\\\\\n{}=,]*",
+            Just("my\"device\n".to_string()),
+            Just("rack{1}".to_string()),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn label_values_are_encoded_safely(
+            nickname in label_value(),
+            model in label_value(),
+            firmware_version in label_value(),
+            group in label_value(),
+        ) {
+            let app = app(
+                vec![Box::new(LabelClient {
+                    nickname: nickname.clone(),
+                    model: model.clone(),
+                    firmware_version: firmware_version.clone(),
+                })],
+                AppConfig {
+                    groups: HashMap::from([("10.0.0.3".to_string(), group.clone())]),
+                    ..AppConfig::default()
+                },
+            );
+
+            let body = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    let response = app
+                        .oneshot(
+                            Request::builder()
+                                .uri("/metrics")
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                        .await
+                        .unwrap();
+                    let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+                    String::from_utf8(body_bytes.to_vec()).unwrap()
+                });
+
+            let power_use = body
+                .lines()
+                .find(|l| l.starts_with("tapo_power_use_watts{"))
+                .unwrap();
+            prop_assert_eq!(
+                parse_labels(power_use),
+                Some(vec![
+                    ("power_strip_id".to_string(), "123".to_string()),
+                    ("ip_address".to_string(), "10.0.0.3".to_string()),
+                    ("device_id".to_string(), "456".to_string()),
+                    ("nickname".to_string(), nickname),
+                    ("group".to_string(), group.clone()),
+                    ("position".to_string(), "1".to_string()),
+                ])
+            );
+
+            let device_info = body
+                .lines()
+                .find(|l| l.starts_with("tapo_device_info{"))
+                .unwrap();
+            prop_assert_eq!(
+                parse_labels(device_info),
+                Some(vec![
+                    ("power_strip_id".to_string(), "123".to_string()),
+                    ("ip_address".to_string(), "10.0.0.3".to_string()),
+                    ("model".to_string(), model),
+                    ("firmware_version".to_string(), firmware_version),
+                    ("hardware_version".to_string(), "1.0".to_string()),
+                    ("mac_address".to_string(), "aa:bb:cc:dd:ee:ff".to_string()),
+                    ("group".to_string(), group),
+                ])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn get_metrics() {
+        let client = Box::new(FakeClient::new("10.0.0.1"));
+        let app = app(vec![client], AppConfig::default());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        // The calls made to the device are served in no particular order
+        let (mut api_calls, lines): (Vec<&str>, Vec<&str>) = str::from_utf8(body_bytes.as_ref())
+            .unwrap()
+            .split_inclusive('\n')
+            .partition(|line| line.starts_with("tapo_api_calls_total{"));
+        api_calls.sort();
+        assert_eq!(
+            api_calls,
+            [
+                "tapo_api_calls_total{power_strip_id=\"\",ip_address=\"10.0.0.1\",method=\"get_device_info\"} 1\n",
+                "tapo_api_calls_total{power_strip_id=\"\",ip_address=\"10.0.0.1\",method=\"refresh_session\"} 1\n",
+                "tapo_api_calls_total{power_strip_id=\"123\",ip_address=\"10.0.0.1\",method=\"get_child_device_list\"} 1\n",
+                "tapo_api_calls_total{power_strip_id=\"123\",ip_address=\"10.0.0.1\",method=\"get_current_power\"} 1\n",
+            ]
+        );
+        let body = lines.concat();
+
+        let expected = "# HELP tapo_power_use_watts Current power use in watts.\n\
+        # TYPE tapo_power_use_watts gauge\n\
+        tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"} 45\n\
+        # HELP tapo_plug_on Whether the plug is switched on.\n\
+        # TYPE tapo_plug_on gauge\n\
+        tapo_plug_on{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"} 1\n\
+        # HELP tapo_plug_on_since_seconds How long the plug has been switched on for in seconds, while it's on.\n\
+        # TYPE tapo_plug_on_since_seconds gauge\n\
+        # HELP tapo_device_info Device information.\n\
+        # TYPE tapo_device_info gauge\n\
+        tapo_device_info{power_strip_id=\"123\",ip_address=\"10.0.0.1\",model=\"catwalk\",firmware_version=\"\",hardware_version=\"1.0\",mac_address=\"aa:bb:cc:dd:ee:ff\",group=\"\"} 1\n\
+        # HELP tapo_scrape_errors Number of failed attempts to read metrics from a device.\n\
+        # TYPE tapo_scrape_errors counter\n\
+        # HELP tapo_session_refresh_errors Number of failed attempts to refresh the session with a device, by kind of error.\n\
+        # TYPE tapo_session_refresh_errors counter\n\
+        # HELP tapo_api_calls Number of calls made to a device, by method of its API.\n\
+        # TYPE tapo_api_calls counter\n\
+        # HELP tapo_device_reachable Whether the device responded when last read from.\n\
+        # TYPE tapo_device_reachable gauge\n\
+        tapo_device_reachable{power_strip_id=\"123\",ip_address=\"10.0.0.1\"} 1\n\
+        # HELP tapo_power_strip_master_on Whether the power strip's master switch is on, for power strips that have one.\n\
+        # TYPE tapo_power_strip_master_on gauge\n\
+        # HELP tapo_device_mac_resolved Whether the address of the device given by its MAC address has been found.\n\
+        # TYPE tapo_device_mac_resolved gauge\n\
+        # HELP tapo_exporter_internal_errors Number of times reading the devices panicked, which was recovered from.\n\
+        # TYPE tapo_exporter_internal_errors counter\n\
+        tapo_exporter_internal_errors_total 0\n\
+        # HELP tapo_power_change_watts Change in power use in watts since the previous reading.\n\
+        # TYPE tapo_power_change_watts gauge\n\
+        # HELP tapo_power_use_delta_watts Change in power use in watts since the previous reading.\n\
+        # TYPE tapo_power_use_delta_watts gauge\n\
+        # HELP tapo_power_rate_watts_per_second Rate of change in power use in watts per second across the readings kept.\n\
+        # TYPE tapo_power_rate_watts_per_second gauge\n\
+        # HELP tapo_overload_events Number of times the plug has been read as overloaded having not been before.\n\
+        # TYPE tapo_overload_events counter\n\
+        tapo_overload_events_total{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"} 0\n\
+        # HELP tapo_firmware_version_major Major version of the device's firmware.\n\
+        # TYPE tapo_firmware_version_major gauge\n\
+        tapo_firmware_version_major{power_strip_id=\"123\",ip_address=\"10.0.0.1\"} 0\n\
+        # HELP tapo_firmware_version_minor Minor version of the device's firmware.\n\
+        # TYPE tapo_firmware_version_minor gauge\n\
+        tapo_firmware_version_minor{power_strip_id=\"123\",ip_address=\"10.0.0.1\"} 0\n\
+        # HELP tapo_firmware_version_patch Patch version of the device's firmware.\n\
+        # TYPE tapo_firmware_version_patch gauge\n\
+        tapo_firmware_version_patch{power_strip_id=\"123\",ip_address=\"10.0.0.1\"} 0\n\
+        # HELP tapo_firmware_version_parse_error Whether the device's firmware version couldn't be parsed, leaving it reported as 0.0.0.\n\
+        # TYPE tapo_firmware_version_parse_error gauge\n\
+        tapo_firmware_version_parse_error{power_strip_id=\"123\",ip_address=\"10.0.0.1\"} 1\n\
+        # HELP tapo_temperature_celsius Temperature reported by the sensor in degrees Celsius.\n\
+        # TYPE tapo_temperature_celsius gauge\n\
+        # HELP tapo_humidity_percent Relative humidity reported by the sensor as a percentage.\n\
+        # TYPE tapo_humidity_percent gauge\n\
+        # HELP tapo_battery_low Whether the sensor's battery is low.\n\
+        # TYPE tapo_battery_low gauge\n\
+        # HELP tapo_label_cardinality_current Number of label combinations power use is recorded with.\n\
+        # TYPE tapo_label_cardinality_current gauge\n\
+        tapo_label_cardinality_current 1\n\
+        # HELP tapo_label_cardinality_limit Maximum number of label combinations power use is recorded with.\n\
+        # TYPE tapo_label_cardinality_limit gauge\n\
+        tapo_label_cardinality_limit 1000\n\
+        # EOF\n\
+        ";
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn power_strip_master_switch() {
+        let mut result = serde_json::json!({
+            "avatar": "", "device_id": "123", "fw_id": "", "fw_ver": "1.0.3", "has_set_location_info": false,
+            "hw_id": "", "hw_ver": "1.0", "ip": "10.0.0.1", "lang": "en_GB", "mac": "AA-BB-CC-DD-EE-FF",
+            "model": "P304M", "oem_id": "", "rssi": -50, "signal_level": 3, "specs": "", "ssid": "",
+            "time_diff": 0, "type": "SMART.TAPOPLUG",
+        });
+        let info = power_strip_info(result.clone(), "10.0.0.1", &None).unwrap();
+        assert_eq!(info.master_on, None);
+        assert_eq!(info.mac_address, "aa:bb:cc:dd:ee:ff");
+
+        result["device_on"] = serde_json::json!(false);
+        let info = power_strip_info(result, "10.0.0.1", &None).unwrap();
+        assert_eq!(info.master_on, Some(false));
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_batched_power_use() {
+        let all_reported = app(
+            vec![Box::new(BatchClient {
+                reported: vec!["789", "456"],
+            })],
+            AppConfig::default(),
+        );
+        let body = get_body(&all_reported, "/metrics").await;
+        assert_eq!(
+            body.lines()
+                .filter(|line| line.starts_with("tapo_power_use_watts{") && line.ends_with(" 12"))
+                .count(),
+            2,
+            "{body}"
+        );
+        // Both plugs are asked with the one call
+        assert!(
+            body.contains("tapo_api_calls_total{power_strip_id=\"123\",ip_address=\"10.0.0.9\",method=\"get_current_power\"} 1\n"),
+            "{body}"
+        );
+
+        // Nothing is recorded if a plug's power use is missing
+        let missing = app(
+            vec![Box::new(BatchClient {
+                reported: vec!["789"],
+            })],
+            AppConfig::default(),
+        );
+        let response = missing
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body = str::from_utf8(body_bytes.as_ref()).unwrap();
+        assert_eq!(body, ERROR_BODY);
+        assert!(!body.contains("10.0.0.9"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn get_metrics_without_energy_monitoring() {
+        let app = app(
+            vec![Box::new(NonEnergyMonitoringClient {})],
+            AppConfig::default(),
+        );
+
+        let body = get_body(&app, "/metrics").await;
+        assert!(
+            body.contains(
+                "tapo_device_info{power_strip_id=\"300\",ip_address=\"10.0.0.8\",model=\"P300\""
+            ),
+            "{body}"
+        );
+        assert!(!body.contains("tapo_power_use_watts{"), "{body}");
+        // Only the lamp is on
+        let on_since: Vec<&str> = body
+            .lines()
+            .filter(|line| line.starts_with("tapo_plug_on_since_seconds{"))
+            .collect();
+        assert_eq!(
+            on_since,
+            [
+                "tapo_plug_on_since_seconds{power_strip_id=\"300\",ip_address=\"10.0.0.8\",device_id=\"301\",nickname=\"Lamp\",group=\"\",position=\"1\"} 3600"
+            ]
+        );
+        assert!(
+            body.contains(
+                "tapo_power_strip_master_on{power_strip_id=\"300\",ip_address=\"10.0.0.8\"} 0\n"
+            ),
+            "{body}"
+        );
+        let mut plug_on: Vec<&str> = body
+            .lines()
+            .filter(|line| line.starts_with("tapo_plug_on{"))
+            .collect();
+        plug_on.sort();
+        assert_eq!(
+            plug_on,
+            [
+                "tapo_plug_on{power_strip_id=\"300\",ip_address=\"10.0.0.8\",device_id=\"301\",nickname=\"Lamp\",group=\"\",position=\"1\"} 1",
+                "tapo_plug_on{power_strip_id=\"300\",ip_address=\"10.0.0.8\",device_id=\"302\",nickname=\"Fan\",group=\"\",position=\"2\"} 0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_sensors() {
+        let hub = app(vec![Box::new(HubClient {})], AppConfig::default());
+
+        let body = get_body(&hub, "/metrics").await;
+        let labels = "hub_id=\"400\",device_id=\"401\",nickname=\"Bedroom\",sensor_type=\"T310\"";
+        for line in [
+            format!("tapo_temperature_celsius{{{labels}}} 21.5\n"),
+            format!("tapo_humidity_percent{{{labels}}} 48.0\n"),
+            format!("tapo_battery_low{{{labels}}} 1\n"),
+        ] {
+            assert!(body.contains(&line), "{body}");
+        }
+        assert!(!body.contains("tapo_plug_on{"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_firmware_version() {
+        let app = app(
+            vec![Box::new(LabelClient {
+                nickname: "Kettle".to_string(),
+                model: "P304M".to_string(),
+                firmware_version: "1.0.5 Build 230905 Rel.134850".to_string(),
+            })],
+            AppConfig::default(),
+        );
+
+        let body = get_body(&app, "/metrics").await;
+        let firmware: Vec<&str> = body
+            .lines()
+            .filter(|line| line.starts_with("tapo_firmware_version_"))
+            .collect();
+        assert_eq!(
+            firmware,
+            [
+                "tapo_firmware_version_major{power_strip_id=\"123\",ip_address=\"10.0.0.3\"} 1",
+                "tapo_firmware_version_minor{power_strip_id=\"123\",ip_address=\"10.0.0.3\"} 0",
+                "tapo_firmware_version_patch{power_strip_id=\"123\",ip_address=\"10.0.0.3\"} 5",
+                "tapo_firmware_version_parse_error{power_strip_id=\"123\",ip_address=\"10.0.0.3\"} 0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn get_metrics_over_label_cardinality_limit() {
+        let app = app(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(LabelClient {
+                    nickname: "Kettle".to_string(),
+                    model: "P304M".to_string(),
+                    firmware_version: "1.0.5".to_string(),
+                }),
+            ],
+            AppConfig {
+                max_label_cardinality: 1,
+                ..AppConfig::default()
+            },
+        );
+
+        let body = get_body(&app, "/metrics").await;
+        assert!(
+            body.contains("tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.1\""),
+            "{body}"
+        );
+        assert!(!body.contains("nickname=\"Kettle\""), "{body}");
+        // The device is still reported, just not the power use of its plug
+        assert!(
+            body.contains("tapo_device_info{power_strip_id=\"123\",ip_address=\"10.0.0.3\""),
+            "{body}"
+        );
+        assert!(
+            body.contains("tapo_label_cardinality_current 1\n"),
+            "{body}"
+        );
+        assert!(body.contains("tapo_label_cardinality_limit 1\n"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn renamed_plugs_free_their_labels() {
+        let label_client = |nickname: &str| {
+            Box::new(LabelClient {
+                nickname: nickname.to_string(),
+                model: "P304M".to_string(),
+                firmware_version: "1.0.5".to_string(),
+            })
+        };
+        let mut state = AppState::new(
+            vec![label_client("Kettle")],
+            &AppConfig {
+                max_label_cardinality: 1,
+                ..AppConfig::default()
+            },
+        );
+        let mut registry = Registry::default();
+        state.register(&mut registry);
+        state.update_metrics().await.unwrap();
+
+        // The old series is dropped and the new one admitted, rather than the limit being used up
+        state.clients[0] = label_client("Toaster");
+        state.update_metrics().await.unwrap();
+        state.update_metrics().await.unwrap();
+        let mut body = String::new();
+        encode(&mut body, &registry).unwrap();
+        assert!(!body.contains("nickname=\"Kettle\""), "{body}");
+        assert!(
+            body.contains("tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.3\",device_id=\"456\",nickname=\"Toaster\""),
+            "{body}"
+        );
+        assert!(
+            body.contains("tapo_label_cardinality_current 1\n"),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_power_histogram() {
+        let router = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig {
+                power_histogram_buckets: Some(parse_buckets("0,50,100").unwrap()),
+                min_scrape_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+
+        get_body(&router, "/metrics").await;
+        let body = get_body(&router, "/metrics").await;
+        let labels = "power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"";
+        for line in [
+            "# TYPE tapo_power_readings_watts histogram".to_string(),
+            format!("tapo_power_readings_watts_sum{{{labels}}} 90.0"),
+            format!("tapo_power_readings_watts_count{{{labels}}} 2"),
+            format!("tapo_power_readings_watts_bucket{{le=\"0.0\",{labels}}} 0"),
+            format!("tapo_power_readings_watts_bucket{{le=\"+Inf\",{labels}}} 2"),
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "{line} missing from {body}"
+            );
+        }
+        // The bucket the last reading was counted in has it as its exemplar
+        let bucket = body
+            .lines()
+            .find(|l| {
+                l.starts_with(&format!(
+                    "tapo_power_readings_watts_bucket{{le=\"50.0\",{labels}}} 2 # "
+                ))
+            })
+            .unwrap_or_else(|| panic!("bucket missing from {body}"));
+        let (scrape_timestamp, timestamp) = bucket
+            .split_once("# {scrape_timestamp=\"")
+            .and_then(|(_, exemplar)| exemplar.split_once("\"} 45.0 "))
+            .unwrap_or_else(|| panic!("exemplar missing from {bucket}"));
+        assert_eq!(
+            timestamp.split_once('.').map(|(seconds, _)| seconds),
+            Some(scrape_timestamp)
+        );
+        // The gauge is still served
+        assert!(body.contains(&format!("tapo_power_use_watts{{{labels}}} 45\n")));
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_alias() {
+        let aliases = |mode| {
+            HashMap::from([(
+                "10.0.0.1".to_string(),
+                Alias {
+                    name: "desk".to_string(),
+                    mode,
+                },
+            )])
+        };
+
+        let label_app = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig {
+                aliases: aliases(AliasMode::Label),
+                ..AppConfig::default()
+            },
+        );
+        let body = get_body(&label_app, "/metrics").await;
+        assert!(
+            body.contains("tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\",strip_alias=\"desk\"} 45\n"),
+            "{body}"
+        );
+
+        let nickname_app = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig {
+                aliases: aliases(AliasMode::Nickname),
+                ..AppConfig::default()
+            },
+        );
+        let body = get_body(&nickname_app, "/metrics").await;
+        // The power strip's plug keeps its nickname, as the alias names the whole strip
+        assert!(
+            body.contains("tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\",strip_alias=\"desk\"} 45\n"),
+            "{body}"
+        );
+    }
+
+    #[test]
+    fn alias_replaces_nickname_of_plug_only() {
+        let info = device_info_labels(&DeviceInfo {
+            power_strip_id: "123".to_string(),
+            ip_address: "10.0.0.1".to_string(),
+            model: "P110".to_string(),
+            firmware_version: "".to_string(),
+            hardware_version: "1.0".to_string(),
+            mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+            account: AccountLabel::default(),
+            group: String::new(),
+            master_on: None,
+        });
+        let alias = Alias {
+            name: "desk".to_string(),
+            mode: AliasMode::Nickname,
+        };
+        let child = |position| ChildDevice {
+            device_id: "456".to_string(),
+            nickname: "Kettle".to_string(),
+            device_on: true,
+            on_time_seconds: None,
+            overloaded: None,
+            position,
+        };
+
+        let plug = power_use_labels(&info, "10.0.0.1", &child(0), Some(&alias));
+        assert_eq!(plug.nickname, "desk");
+        assert_eq!(plug.strip_alias, AliasLabel(None));
+
+        let strip_plug = power_use_labels(&info, "10.0.0.1", &child(1), Some(&alias));
+        assert_eq!(strip_plug.nickname, "Kettle");
+        assert_eq!(strip_plug.strip_alias, AliasLabel(Some("desk".to_string())));
+    }
+
+    #[tokio::test]
+    async fn change_alias_while_running() {
+        let (app, _, devices) = split_app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig {
+                aliases: HashMap::from([(
+                    "10.0.0.1".to_string(),
+                    Alias {
+                        name: "desk".to_string(),
+                        mode: AliasMode::Label,
+                    },
+                )]),
+                ..AppConfig::default()
+            },
+        );
+        let body = get_body(&app, "/metrics").await;
+        assert!(body.contains("strip_alias=\"desk\""), "{body}");
+
+        devices.set_aliases(HashMap::new()).await;
+
+        // The series with the old alias is dropped rather than left alongside the new one
+        let body = get_body(&app, "/metrics").await;
+        assert!(!body.contains("strip_alias"), "{body}");
+        assert_eq!(body.matches("tapo_power_use_watts{").count(), 1, "{body}");
+    }
+
+    #[tokio::test]
+    async fn change_group_while_running() {
+        let (router, _, devices) = split_app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig {
+                groups: HashMap::from([("10.0.0.1".to_string(), "server-room".to_string())]),
+                ..AppConfig::default()
+            },
+        );
+        let body = get_body(&router, "/metrics").await;
+        assert!(
+            body.contains("nickname=\"\",group=\"server-room\",position=\"1\"} 45\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("mac_address=\"aa:bb:cc:dd:ee:ff\",group=\"server-room\"} 1\n"),
+            "{body}"
+        );
+
+        devices
+            .set_groups(HashMap::from([(
+                "10.0.0.1".to_string(),
+                "3d-printers".to_string(),
+            )]))
+            .await;
+
+        // The series in the old group are dropped rather than left alongside the new ones
+        let body = get_body(&router, "/metrics").await;
+        assert!(!body.contains("server-room"), "{body}");
+        assert_eq!(body.matches("tapo_power_use_watts{").count(), 1, "{body}");
+        assert_eq!(body.matches("tapo_device_info{").count(), 1, "{body}");
+        assert!(body.contains("group=\"3d-printers\""), "{body}");
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_excluded_plug() {
+        let app = app(
+            vec![Box::new(RouterClient {})],
+            AppConfig {
+                plug_filter: PlugFilter::new(vec![], vec!["Rout*".parse().unwrap()]),
+                ..AppConfig::default()
+            },
+        );
+
+        let body = get_body(&app, "/metrics").await;
+        assert!(!body.contains("Router"), "{body}");
+        assert!(
+            body.contains("tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.7\",device_id=\"456\",nickname=\"Kettle\",group=\"\",position=\"2\"} 45\n"),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_failing_device() {
+        let app = app(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(FakeClient::unreachable("10.0.0.2")),
+            ],
+            AppConfig::default(),
+        );
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body = str::from_utf8(body_bytes.as_ref()).unwrap();
+
+        assert!(body.contains(
+            "tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"} 45\n"
+        ));
+        assert!(body.contains(
+            "tapo_device_info{power_strip_id=\"123\",ip_address=\"10.0.0.1\",model=\"catwalk\",firmware_version=\"\",hardware_version=\"1.0\",mac_address=\"aa:bb:cc:dd:ee:ff\",group=\"\"} 1\n"
+        ));
+        assert_eq!(body.matches("tapo_power_use_watts{").count(), 1);
+        assert_eq!(body.matches("tapo_device_info{").count(), 1);
+        assert!(body.contains("tapo_scrape_errors_total{ip_address=\"10.0.0.2\"} 1\n"));
+        assert!(
+            body.contains("tapo_device_reachable{power_strip_id=\"\",ip_address=\"10.0.0.2\"} 0\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn get_session_refresh_errors() {
+        let app = app(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(FakeClient::unreachable("10.0.0.2")),
+                Box::new(UnauthorisedClient {}),
+            ],
+            AppConfig::default(),
+        );
+
+        let body = get_body(&app, "/metrics").await;
+        assert!(
+            body.contains("tapo_session_refresh_errors_total{power_strip_id=\"\",ip_address=\"10.0.0.6\",error_kind=\"invalid_credentials\"} 1\n"),
+            "{body}"
+        );
+        // Failing to read from a device after refreshing the session isn't a session error
+        assert_eq!(
+            body.matches("tapo_session_refresh_errors_total{").count(),
+            1,
+            "{body}"
+        );
+        assert!(body.contains("tapo_scrape_errors_total{ip_address=\"10.0.0.2\"} 1\n"));
+        assert!(body.contains("tapo_scrape_errors_total{ip_address=\"10.0.0.6\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn get_device_reachable() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let app = app(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(FlakyClient {
+                    failing: failing.clone(),
+                }),
+            ],
+            AppConfig {
+                min_scrape_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+        let reachable = |body: &str| {
+            body.lines()
+                .filter(|line| line.starts_with("tapo_device_reachable{"))
+                .filter(|line| line.contains("10.0.0.5"))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        // Not yet read, so its ID isn't known
+        let body = get_body(&app, "/metrics").await;
+        assert_eq!(
+            reachable(&body),
+            ["tapo_device_reachable{power_strip_id=\"\",ip_address=\"10.0.0.5\"} 0"]
+        );
+
+        failing.store(false, Ordering::SeqCst);
+        let body = get_body(&app, "/metrics").await;
+        assert_eq!(
+            reachable(&body),
+            ["tapo_device_reachable{power_strip_id=\"123\",ip_address=\"10.0.0.5\"} 1"]
+        );
+
+        failing.store(true, Ordering::SeqCst);
+        let body = get_body(&app, "/metrics").await;
+        assert_eq!(
+            reachable(&body),
+            ["tapo_device_reachable{power_strip_id=\"123\",ip_address=\"10.0.0.5\"} 0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn device_given_by_mac_address() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let (router, _, devices) = split_app(
+            vec![Box::new(FlakyClient {
+                failing: failing.clone(),
+            })],
+            AppConfig {
+                min_scrape_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+
+        // Not failing until it's been read from
+        assert!(!devices.is_failing("10.0.0.5"));
+        get_body(&router, "/metrics").await;
+        assert!(devices.is_failing("10.0.0.5"));
+        failing.store(false, Ordering::SeqCst);
+        get_body(&router, "/metrics").await;
+        assert!(!devices.is_failing("10.0.0.5"));
+
+        devices
+            .set_mac_resolutions(HashMap::from([
+                ("aa:bb:cc:dd:ee:ff".to_string(), true),
+                ("aa:bb:cc:dd:ee:00".to_string(), false),
+            ]))
+            .await;
+        devices
+            .set_mac_resolutions(HashMap::from([("aa:bb:cc:dd:ee:00".to_string(), false)]))
+            .await;
+        let body = get_body(&router, "/metrics").await;
+        let resolved: Vec<&str> = body
+            .lines()
+            .filter(|line| line.starts_with("tapo_device_mac_resolved{"))
+            .collect();
+        assert_eq!(
+            resolved,
+            ["tapo_device_mac_resolved{mac_address=\"aa:bb:cc:dd:ee:00\"} 0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_all_devices_failing() {
+        let app = app(
+            vec![Box::new(FakeClient::unreachable("10.0.0.2"))],
+            AppConfig::default(),
+        );
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        // Why reading the device failed is only logged
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body = str::from_utf8(body_bytes.as_ref()).unwrap();
+        assert_eq!(body, ERROR_BODY);
+        assert!(!body.contains("10.0.0.2"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn get_metrics_after_panicking() {
+        let app = app(
+            vec![Box::new(PanickingClient {
+                panicked: AtomicBool::new(false),
+            })],
+            AppConfig::default(),
+        );
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(str::from_utf8(body_bytes.as_ref()).unwrap(), ERROR_BODY);
+
+        // The server carries on serving, having counted the panic
+        let body = get_body(&app, "/metrics").await;
+        assert!(
+            body.contains("tapo_exporter_internal_errors_total 1\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("tapo_power_use_watts{power_strip_id=\"123\""),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn panicking_device_fails_alone() {
+        let app = app(
+            vec![
+                Box::new(PanickingClient {
+                    panicked: AtomicBool::new(false),
+                }),
+                Box::new(NonEnergyMonitoringClient {}),
+            ],
+            AppConfig::default(),
+        );
+
+        // The other device is still reported, with the panic counted against the one that panicked
+        let body = get_body(&app, "/metrics").await;
+        assert!(
+            body.contains(
+                "tapo_device_reachable{power_strip_id=\"300\",ip_address=\"10.0.0.8\"} 1\n"
+            ),
+            "{body}"
+        );
+        assert!(
+            body.contains("tapo_scrape_errors_total{ip_address=\"10.0.0.1\"} 1\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("tapo_exporter_internal_errors_total 1\n"),
+            "{body}"
+        );
+    }
+
+    /// A metric that can never be encoded, which waits at the barrier, if given, the first time
+    /// it's encoded, both on starting and before failing.
+    #[derive(Debug)]
+    struct UnencodableMetric {
+        barrier: Option<Arc<std::sync::Barrier>>,
+        waited: AtomicBool,
+    }
+
+    impl EncodeMetric for UnencodableMetric {
+        fn encode(&self, _encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+            if let Some(barrier) = self.barrier.as_ref() {
+                if !self.waited.swap(true, Ordering::SeqCst) {
+                    barrier.wait();
+                    barrier.wait();
+                }
+            }
+            Err(std::fmt::Error)
+        }
+
+        fn metric_type(&self) -> MetricType {
+            MetricType::Gauge
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn get_metrics_failing_to_encode() {
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let mut state = AppState::new(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            &AppConfig::default(),
+        );
+        state.registry.register(
+            "unencodable",
+            "Fails to encode",
+            UnencodableMetric {
+                barrier: Some(barrier.clone()),
+                waited: AtomicBool::new(false),
+            },
+        );
+        let state = Arc::new(RwLock::new(state));
+        let app = Router::new()
+            .route("/metrics", axum::routing::get(metrics_handler))
+            .with_state(state.clone());
+
+        let first = tokio::spawn(get_response(app.clone(), "/metrics"));
+        let waiting = barrier.clone();
+        tokio::task::spawn_blocking(move || waiting.wait())
+            .await
+            .unwrap();
+        // The state can still be read while encoding, with another scrape waiting its turn
+        assert!(state.try_read().is_ok());
+        let second = tokio::spawn(get_response(app, "/metrics"));
+        tokio::task::spawn_blocking(move || barrier.wait())
+            .await
+            .unwrap();
+
+        for response in [first, second] {
+            let (status, body) = tokio::time::timeout(Duration::from_secs(5), response)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(body, ERROR_BODY);
+            assert!(!body.contains("formatting an argument"), "{body}");
+        }
+        // Failing doesn't leave the state locked, holding up every request after
+        assert!(state.try_write().is_ok());
+    }
+
+    #[tokio::test]
+    async fn stale_inventories_are_left_out() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let mut state = AppState::new(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(FlakyClient {
+                    failing: failing.clone(),
+                }),
+            ],
+            &AppConfig::default(),
+        );
+        let fresh = |state: &AppState| -> Vec<String> {
+            state
+                .fresh_inventories()
+                .map(|(address, _)| address.to_string())
+                .collect()
+        };
+
+        state.update_metrics().await.unwrap();
+        assert_eq!(fresh(&state), ["10.0.0.1", "10.0.0.5"]);
+
+        // What was last read from the failing device is kept, but not passed on
+        failing.store(true, Ordering::SeqCst);
+        state.update_metrics().await.unwrap();
+        assert!(state.inventory.contains_key("10.0.0.5"));
+        assert_eq!(fresh(&state), ["10.0.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn push_to_each_backend_independently() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let influx = Router::new()
+            .route(
+                "/api/v2/write",
+                axum::routing::post(
+                    |axum::extract::State(tx): axum::extract::State<
+                        tokio::sync::mpsc::Sender<String>,
+                    >,
+                     body: String| async move {
+                        tx.send(body).await.unwrap();
+                        StatusCode::NO_CONTENT
+                    },
+                ),
+            )
+            .with_state(tx);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, influx).await.unwrap() });
+        // Nothing listens on a port that was just freed
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let (_, _, devices) = split_app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig::default(),
+        );
+        let pushgateway = Pushgateway::new(
+            &format!("http://{closed}").parse().unwrap(),
+            "tapo",
+            &BTreeMap::new(),
+            None,
+        );
+        let influx = InfluxWriter::new(
+            &format!("http://{address}").parse().unwrap(),
+            "home",
+            None,
+            None,
+        );
+
+        let error = devices
+            .push_metrics(None, Some(&pushgateway), None, Some(&influx))
+            .await
+            .unwrap_err();
+        assert!(error.contains("Pushgateway"), "{error}");
+        assert_eq!(pushgateway.errors().get(), 1);
+
+        // InfluxDB is still written to after the Pushgateway failed
+        let lines = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(lines.contains("device_id=456"), "{lines}");
+        assert_eq!(influx.errors().get(), 0);
+    }
+
+    #[tokio::test]
+    async fn collect_once() {
+        let config = AppConfig {
+            device_timeout: Some(Duration::from_millis(100)),
+            ..AppConfig::default()
+        };
+
+        let metrics = collect(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(SlowClient {}),
+            ],
+            config.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(
+            metrics.contains("tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.1\""),
+            "{metrics}"
+        );
+        assert!(
+            metrics.contains("tapo_scrape_errors_total{ip_address=\"10.0.0.4\"} 1\n"),
+            "{metrics}"
+        );
+        assert!(metrics.ends_with("# EOF\n"), "{metrics}");
+
+        let e = collect(vec![Box::new(SlowClient {})], config)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "10.0.0.4: timed out after 100ms");
+    }
+
+    #[tokio::test]
+    async fn collect_into_own_registry() {
+        let mut collector = Collector::new(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            &AppConfig::default(),
+        );
+        let mut registry = Registry::default();
+        collector.register(&mut registry);
+
+        collector.update().await.unwrap();
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        assert!(
+            metrics.contains("tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"} 45\n"),
+            "{metrics}"
+        );
+
+        let e = Collector::new(
+            vec![Box::new(FakeClient::unreachable("10.0.0.2"))],
+            &AppConfig::default(),
+        )
+        .update()
+        .await;
+        assert!(e.is_err());
+
+        // Panics are recovered from as they are when serving the metrics
+        let e = Collector::new(
+            vec![Box::new(PanickingClient {
+                panicked: AtomicBool::new(false),
+            })],
+            &AppConfig::default(),
+        )
+        .update()
+        .await
+        .unwrap_err();
+        assert!(e.to_string().contains("panicked"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_prefix() {
+        let app = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig {
+                metric_prefix: "home_tapo".to_string(),
+                ..AppConfig::default()
+            },
+        );
+
+        let body = get_body(&app, "/metrics").await;
+        assert!(
+            body.contains("home_tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"} 45\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("# TYPE home_tapo_device_reachable gauge"),
+            "{body}"
+        );
+        assert!(
+            !body.lines().any(|line| line.starts_with("tapo_")),
+            "{body}"
+        );
+
+        let body = get_body(&app, "/metrics/influx").await;
+        assert!(
+            body.starts_with("home_tapo_power_use_watts,power_strip_id=123,"),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_metrics_given_by_mac() {
+        let app = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig {
+                given_macs: HashMap::from([(
+                    "10.0.0.1".to_string(),
+                    "aa:bb:cc:dd:ee:ff".to_string(),
+                )]),
+                ..AppConfig::default()
+            },
+        );
+
+        // Power use carries on as the same series when the device moves, while its information
+        // says where it was found
+        let body = get_body(&app, "/metrics").await;
+        assert!(
+            body.contains("tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"aa:bb:cc:dd:ee:ff\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"} 45\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("tapo_device_info{power_strip_id=\"123\",ip_address=\"10.0.0.1\","),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_power_change() {
+        let app = app(
+            vec![Box::new(RisingClient {
+                watts: Arc::new(AtomicU64::new(5)),
+            })],
+            AppConfig {
+                min_scrape_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+        let labels = "{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"}";
+
+        let body = get_body(&app, "/metrics").await;
+        assert!(!body.contains("tapo_power_change_watts{"), "{body}");
+        assert!(!body.contains("tapo_power_use_delta_watts{"), "{body}");
+
+        // A kettle being turned on
+        let body = get_body(&app, "/metrics").await;
+        assert!(
+            body.contains(&format!("tapo_power_change_watts{labels} 1000\n")),
+            "{body}"
+        );
+        assert!(
+            body.contains(&format!("tapo_power_use_delta_watts{labels} 1000\n")),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn device_info_read_once_per_interval() {
+        for (info_refresh_interval, expected_reads) in
+            [(Duration::from_secs(300), 1), (Duration::ZERO, 3)]
+        {
+            let info_reads = Arc::new(AtomicU64::new(0));
+            let router = app(
+                vec![Box::new(InfoCountingClient {
+                    info_reads: info_reads.clone(),
+                })],
+                AppConfig {
+                    min_scrape_interval: Duration::ZERO,
+                    info_refresh_interval,
+                    ..AppConfig::default()
+                },
+            );
+
+            let mut body = String::new();
+            for _ in 0..3 {
+                body = get_body(&router, "/metrics").await;
+                // The device information last read is still served along with the power use
+                assert!(body.contains("tapo_device_info{"), "{body}");
+                assert!(body.contains("tapo_power_use_watts{"), "{body}");
+            }
+            assert_eq!(info_reads.load(Ordering::SeqCst), expected_reads);
+            // The client doesn't keep the device's ID, so it's only known once it's been read
+            assert!(
+                body.contains(&format!(
+                    "tapo_api_calls_total{{power_strip_id=\"\",ip_address=\"10.0.0.1\",method=\"get_device_info\"}} {expected_reads}\n"
+                )),
+                "{body}"
+            );
+            assert!(
+                body.contains(
+                    "tapo_api_calls_total{power_strip_id=\"123\",ip_address=\"10.0.0.1\",method=\"get_child_device_list\"} 3\n"
+                ),
+                "{body}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn master_switch_read_with_device_info() {
+        for (info_refresh_interval, expected_reads, served) in
+            [(Duration::from_secs(300), 1, 1), (Duration::ZERO, 2, 0)]
+        {
+            let master_on = Arc::new(AtomicBool::new(true));
+            let info_reads = Arc::new(AtomicU64::new(0));
+            let router = app(
+                vec![Box::new(MasterSwitchClient {
+                    master_on: master_on.clone(),
+                    info_reads: info_reads.clone(),
+                })],
+                AppConfig {
+                    min_scrape_interval: Duration::ZERO,
+                    info_refresh_interval,
+                    ..AppConfig::default()
+                },
+            );
+            let series =
+                "tapo_power_strip_master_on{power_strip_id=\"123\",ip_address=\"10.0.0.1\"}";
+
+            let body = get_body(&router, "/metrics").await;
+            assert!(body.contains(&format!("{series} 1\n")), "{body}");
+
+            // Turning the switch off is only seen once the device information is read again
+            master_on.store(false, Ordering::SeqCst);
+            let body = get_body(&router, "/metrics").await;
+            assert!(body.contains(&format!("{series} {served}\n")), "{body}");
+            assert_eq!(info_reads.load(Ordering::SeqCst), expected_reads);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_service_discovery() {
+        let app = app(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(FakeClient::unreachable("10.0.0.2")),
+            ],
+            AppConfig {
+                device_labels: HashMap::from([(
+                    "10.0.0.2".to_string(),
+                    BTreeMap::from([("room".to_string(), "office".to_string())]),
+                )]),
+                ..AppConfig::default()
+            },
+        );
+
+        let response = app
+            .oneshot(Request::builder().uri("/sd").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+
+        // Each target group must only contain a list of targets and a map of string labels
+        let groups = body.as_array().unwrap();
+        for group in groups {
+            let group = group.as_object().unwrap();
+            assert_eq!(group.keys().collect::<Vec<_>>(), vec!["labels", "targets"]);
+            assert!(
+                group["targets"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .all(|t| t.is_string())
+            );
+            for (name, value) in group["labels"].as_object().unwrap() {
+                assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+                assert!(!name.starts_with(|c: char| c.is_ascii_digit()));
+                assert!(value.is_string());
+            }
+        }
+
+        assert_eq!(
+            body,
+            serde_json::json!([
+                {
+                    "targets": ["10.0.0.1"],
+                    "labels": {
+                        "__meta_tapo_address": "10.0.0.1",
+                        "__meta_tapo_device_id": "123",
+                        "__meta_tapo_firmware_version": "",
+                        "__meta_tapo_model": "catwalk",
+                        "__meta_tapo_nickname": "",
+                        "__metrics_path__": "/probe",
+                        "__param_target": "10.0.0.1",
+                    },
+                },
+                {
+                    "targets": ["10.0.0.2"],
+                    "labels": {
+                        "__meta_tapo_address": "10.0.0.2",
+                        "__metrics_path__": "/probe",
+                        "__param_target": "10.0.0.2",
+                        "room": "office",
+                    },
+                },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn service_discovery_reads_as_often_as_scrapes() {
+        let info_reads = Arc::new(AtomicU64::new(0));
+        let app = app(
+            vec![
+                Box::new(InfoCountingClient {
+                    info_reads: info_reads.clone(),
+                }),
+                Box::new(FakeClient::unreachable("10.0.0.2")),
+            ],
+            AppConfig {
+                info_refresh_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+
+        // The unreachable device is missing each time, but the devices were read too recently to
+        // be read again
+        for _ in 0..3 {
+            let body = get_body(&app, "/sd").await;
+            assert!(body.contains("\"__meta_tapo_model\":\"catwalk\""), "{body}");
+        }
+        get_body(&app, "/metrics").await;
+        assert_eq!(info_reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn mac_address_is_formatted() {
+        assert_eq!(format_mac_address("AA-BB-CC-0D-1E-2F"), "aa:bb:cc:0d:1e:2f");
+        assert_eq!(format_mac_address("aa:bb:cc:0d:1e:2f"), "aa:bb:cc:0d:1e:2f");
+    }
+
+    #[test]
+    fn account_is_labelled_when_known() {
+        let info = |account: Option<&str>| DeviceInfo {
+            power_strip_id: "123".to_string(),
+            ip_address: "10.0.0.1".to_string(),
+            model: "P304M".to_string(),
+            firmware_version: "1.0".to_string(),
+            hardware_version: "1.0".to_string(),
+            mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+            account: AccountLabel(account.map(str::to_string)),
+            group: String::new(),
+            master_on: None,
+        };
+        let family = Family::<DeviceInfo, Gauge>::default();
+        family.get_or_create(&info(None)).set(1);
+        family.get_or_create(&info(Some("parents"))).set(1);
+        let mut registry = Registry::default();
+        registry.register("tapo_device_info", "Device information", family);
+
+        let mut body = String::new();
+        encode(&mut body, &registry).unwrap();
+
+        assert!(
+            body.contains("mac_address=\"aa:bb:cc:dd:ee:ff\",group=\"\"} 1\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("mac_address=\"aa:bb:cc:dd:ee:ff\",group=\"\",account=\"parents\"} 1\n"),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_influx_metrics() {
+        let app = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig::default(),
+        );
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics/influx")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body_bytes.to_vec()).unwrap();
+        let (line, timestamp) = body.trim_end().rsplit_once(' ').unwrap();
+        assert_eq!(
+            line,
+            "tapo_power_use_watts,power_strip_id=123,device_id=456,position=1 value=45i"
+        );
+        assert!(timestamp.parse::<u128>().is_ok());
+
+        // Shares the cache with the Prometheus format
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-served-from-cache"], "true");
     }
 
     #[tokio::test]
-    async fn get_metrics() {
-        let client = Box::new(TestClient {});
-        let app = app(vec![client]);
+    async fn get_metrics_from_cache() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let app = app(
+            vec![Box::new(FlakyClient {
+                failing: failing.clone(),
+            })],
+            AppConfig {
+                min_scrape_interval: Duration::from_millis(100),
+                ..AppConfig::default()
+            },
+        );
+
+        let first = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(!first.headers().contains_key("x-served-from-cache"));
+        let first = first.into_body().collect().await.unwrap().to_bytes();
+
+        // The device isn't read again until the interval has passed
+        failing.store(true, Ordering::SeqCst);
+        let second = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.headers()["x-served-from-cache"], "true");
+        assert_eq!(
+            second.into_body().collect().await.unwrap().to_bytes(),
+            first
+        );
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(
+            get(&app, "/metrics").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn get_metrics_when_unmodified() {
+        let app = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig {
+                min_scrape_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+        // The change in power use is only served once there's a previous reading to compare to
+        get(&app, "/metrics").await;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()["etag"].clone();
 
         let response = app
+            .clone()
             .oneshot(
                 Request::builder()
                     .uri("/metrics")
+                    .header("if-none-match", etag.clone())
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["etag"], etag);
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body_bytes.is_empty());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .header("if-none-match", "\"0123456789abcdef\"")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_metrics_with_cors() {
+        let app = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig {
+                cors_allowed_origins: vec![HeaderValue::from_static("https://grafana.example")],
+                ..AppConfig::default()
+            },
+        );
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .header("origin", "https://grafana.example")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://grafana.example"
+        );
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("OPTIONS")
+                    .uri("/metrics")
+                    .header("origin", "https://grafana.example")
+                    .header("access-control-request-method", "GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["access-control-allow-methods"], "GET");
+        assert_eq!(response.headers()["access-control-max-age"], "3600");
+    }
+
+    #[tokio::test]
+    async fn get_metrics_without_cors() {
+        let app = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig::default(),
+        );
 
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .header("origin", "https://grafana.example")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            !response
+                .headers()
+                .contains_key("access-control-allow-origin")
+        );
+    }
+
+    #[tokio::test]
+    async fn get_metrics_timeout() {
+        let app = app(
+            vec![Box::new(SlowClient {})],
+            AppConfig {
+                http_timeout: Duration::from_millis(100),
+                ..AppConfig::default()
+            },
+        );
+
+        assert_eq!(get(&app, "/metrics").await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn get_metrics_scrape_timeout() {
+        // Each device is read within its own timeout, but not every device within the scrape's
+        let app = app(
+            vec![Box::new(SlowClient {}), Box::new(SlowClient {})],
+            AppConfig {
+                device_timeout: Some(Duration::from_millis(150)),
+                scrape_timeout: Some(Duration::from_millis(200)),
+                ..AppConfig::default()
+            },
+        );
 
+        let start = Instant::now();
+        let response = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(start.elapsed() < Duration::from_millis(300));
         let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
-        let body = str::from_utf8(body_bytes.as_ref()).unwrap();
+        assert_eq!(str::from_utf8(body_bytes.as_ref()).unwrap(), ERROR_BODY);
+    }
 
-        let expected = "# HELP tapo_power_use_watts Current power use in watts.\n\
-        # TYPE tapo_power_use_watts gauge\n\
-        tapo_power_use_watts{power_strip_id=\"123\",device_id=\"456\",nickname=\"\",position=\"1\"} 45\n\
-        # HELP tapo_device_info Device information.\n\
-        # TYPE tapo_device_info gauge\n\
-        tapo_device_info{power_strip_id=\"123\",model=\"catwalk\",firmware_version=\"\"} 1\n\
-        # EOF\n\
-        ";
-        assert_eq!(body, expected);
+    #[tokio::test]
+    async fn get_metrics_when_saturated() {
+        let app = app(
+            vec![Box::new(SlowClient {})],
+            AppConfig {
+                http_max_concurrent: 1,
+                ..AppConfig::default()
+            },
+        );
+
+        let in_flight = tokio::spawn({
+            let app = app.clone();
+            async move { get(&app, "/metrics").await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(get(&app, "/metrics").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(get(&app, "/health").await, StatusCode::OK);
+        assert_eq!(in_flight.await.unwrap(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_other_routes_when_saturated() {
+        let (app, _, _) = split_app_with_routes(
+            vec![Box::new(SlowClient {})],
+            AppConfig {
+                http_max_concurrent: 1,
+                ..AppConfig::default()
+            },
+            Router::new().route("/probe", axum::routing::get(|| async { "probed" })),
+        );
+
+        let in_flight = tokio::spawn({
+            let app = app.clone();
+            async move { get(&app, "/metrics").await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Shares the limit with the metrics rather than having one of its own
+        assert_eq!(get(&app, "/probe").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(in_flight.await.unwrap(), StatusCode::OK);
+        assert_eq!(get(&app, "/probe").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_health_when_served_separately() {
+        let (app, health_app, _) = split_app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig::default(),
+        );
+
+        assert_eq!(get(&app, "/health").await, StatusCode::NOT_FOUND);
+        assert_eq!(get(&app, "/metrics").await, StatusCode::OK);
+        assert_eq!(get(&health_app, "/health").await, StatusCode::OK);
+        assert_eq!(get(&health_app, "/ready").await, StatusCode::OK);
+        assert_eq!(get(&health_app, "/metrics").await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn add_device_while_running() {
+        let (app, health_app, devices) = split_app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig::default(),
+        );
+        assert_eq!(get(&app, "/metrics").await, StatusCode::OK);
+
+        assert!(!devices.contains("10.0.0.5"));
+        devices
+            .add(Box::new(FlakyClient {
+                failing: Arc::new(AtomicBool::new(false)),
+            }))
+            .await;
+        assert!(devices.contains("10.0.0.5"));
+
+        // The metrics cached before the device was added aren't served
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("x-served-from-cache"));
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body_bytes.to_vec()).unwrap();
+        assert!(body.contains(r#"ip_address="10.0.0.5""#), "{body}");
+
+        let response = health_app
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .header("accept", "application/json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body["devices"][1]["address"], "10.0.0.5");
+    }
+
+    #[tokio::test]
+    async fn remove_device_while_running() {
+        let (app, health_app, devices) = split_app(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(FlakyClient {
+                    failing: Arc::new(AtomicBool::new(false)),
+                }),
+            ],
+            AppConfig::default(),
+        );
+        assert_eq!(get(&app, "/metrics").await, StatusCode::OK);
+
+        assert!(devices.remove("10.0.0.5").await);
+        assert!(!devices.remove("10.0.0.5").await);
+        assert!(!devices.contains("10.0.0.5"));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body_bytes.to_vec()).unwrap();
+        assert!(!body.contains("10.0.0.5"), "{body}");
+        assert!(body.contains(r#"ip_address="10.0.0.1""#), "{body}");
+
+        let response = health_app
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .header("accept", "application/json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body["devices"].as_array().unwrap().len(), 1);
     }
 
     #[tokio::test]
     async fn get_health() {
-        let client = Box::new(TestClient {});
-        let app = app(vec![client]);
+        let client = Box::new(FakeClient::new("10.0.0.1"));
+        let app = app(vec![client], AppConfig::default());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_ready() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let app = app(
+            vec![Box::new(FlakyClient {
+                failing: failing.clone(),
+            })],
+            AppConfig {
+                readiness_window: Duration::from_millis(100),
+                min_scrape_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+
+        assert_eq!(get(&app, "/ready").await, StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(get(&app, "/metrics").await, StatusCode::OK);
+        assert_eq!(get(&app, "/ready").await, StatusCode::OK);
+
+        failing.store(true, Ordering::SeqCst);
+        assert_eq!(
+            get(&app, "/metrics").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(get(&app, "/ready").await, StatusCode::OK);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(get(&app, "/ready").await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn get_liveness_and_readiness() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let app = app(
+            vec![
+                Box::new(FlakyClient {
+                    failing: failing.clone(),
+                }),
+                Box::new(FakeClient::unreachable("10.0.0.2")),
+            ],
+            AppConfig {
+                min_scrape_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+
+        assert_eq!(get(&app, "/liveness").await, StatusCode::OK);
+        assert_eq!(
+            get(&app, "/readiness").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        assert_eq!(get(&app, "/metrics").await, StatusCode::OK);
+        assert_eq!(get(&app, "/readiness").await, StatusCode::OK);
+
+        // Every device now failing, despite one having been read before
+        failing.store(true, Ordering::SeqCst);
+        assert_eq!(
+            get(&app, "/metrics").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            get(&app, "/readiness").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(get(&app, "/liveness").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_readiness_without_devices() {
+        let (_, app, devices) = split_app(vec![], AppConfig::default());
+        assert_eq!(get(&app, "/readiness").await, StatusCode::OK);
+        assert_eq!(get(&app, "/ready").await, StatusCode::OK);
+
+        // Once there's a device, it has to be read first
+        devices
+            .add(Box::new(FakeClient::unreachable("10.0.0.2")))
+            .await;
+        assert_eq!(
+            get(&app, "/readiness").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(get(&app, "/ready").await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn get_ready_with_all_policy() {
+        let app = app(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(FakeClient::unreachable("10.0.0.2")),
+            ],
+            AppConfig {
+                readiness_policy: ReadinessPolicy::All,
+                ..AppConfig::default()
+            },
+        );
+
+        assert_eq!(get(&app, "/metrics").await, StatusCode::OK);
+        assert_eq!(get(&app, "/ready").await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn get_health_details() {
+        let app = app(
+            vec![
+                Box::new(FakeClient::new("10.0.0.1")),
+                Box::new(FakeClient::unreachable("10.0.0.2")),
+            ],
+            AppConfig {
+                min_scrape_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+        get(&app, "/metrics").await;
+        get(&app, "/metrics").await;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .header("accept", "application/json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let mut body: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+
+        let last_success = body["devices"][0]["last_success"].take();
+        assert!(humantime::parse_rfc3339(last_success.as_str().unwrap()).is_ok());
+
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "status": "degraded",
+                "devices": [
+                    {
+                        "address": "10.0.0.1",
+                        "device_id": "123",
+                        "model": "catwalk",
+                        "last_success": null,
+                        "consecutive_failures": 0,
+                        "last_error": null,
+                    },
+                    {
+                        "address": "10.0.0.2",
+                        "device_id": null,
+                        "model": null,
+                        "last_success": null,
+                        "consecutive_failures": 2,
+                        "last_error": "10.0.0.2: device_info failed: Device not found",
+                    },
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn get_health_after_recovering() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let app = app(
+            vec![Box::new(FlakyClient {
+                failing: failing.clone(),
+            })],
+            AppConfig {
+                min_scrape_interval: Duration::ZERO,
+                ..AppConfig::default()
+            },
+        );
+        let last_error = || async {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/health")
+                        .header("accept", "application/json")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+            let body: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+            body["devices"][0]["last_error"].clone()
+        };
+
+        get(&app, "/metrics").await;
+        assert_eq!(
+            last_error().await,
+            "10.0.0.5: refresh failed: Device not found"
+        );
+
+        // The error is no longer the device's once it's been read again
+        failing.store(false, Ordering::SeqCst);
+        get(&app, "/metrics").await;
+        assert_eq!(last_error().await, serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn get_health_without_asking_for_json() {
+        let app = app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig::default(),
+        );
 
         let response = app
             .oneshot(
@@ -319,5 +5092,7 @@ mod test {
             .unwrap();
 
         assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body_bytes.is_empty());
     }
 }
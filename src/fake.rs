@@ -0,0 +1,108 @@
+use crate::exporter::{AccountLabel, ChildDevice, DeviceInfo, TapoClient};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use tapo::Error;
+use tapo::responses::CurrentPowerResult;
+
+/// A device for tests, answering with what it's given rather than being connected to.
+#[derive(Clone, Debug)]
+pub struct FakeClient {
+    pub address: String,
+    pub device_info: DeviceInfo,
+    pub children: Vec<ChildDevice>,
+    /// Power use in watts, by plug ID. Plugs without any fail to be read.
+    pub power_watts: HashMap<String, u64>,
+    /// Whether reading from the device fails, as it would if it couldn't be reached.
+    pub unreachable: bool,
+}
+
+impl FakeClient {
+    /// A power strip at the address with one plug, `456`, using 45 W.
+    pub fn new(address: &str) -> Self {
+        FakeClient {
+            address: address.to_string(),
+            device_info: DeviceInfo {
+                power_strip_id: "123".to_string(),
+                ip_address: address.to_string(),
+                model: "catwalk".to_string(),
+                firmware_version: "".to_string(),
+                hardware_version: "1.0".to_string(),
+                mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+                account: AccountLabel::default(),
+                group: String::new(),
+                master_on: None,
+            },
+            children: vec![plug("456", "", 1)],
+            power_watts: HashMap::from([("456".to_string(), 45)]),
+            unreachable: false,
+        }
+    }
+
+    /// A device at the address that can't be read from.
+    pub fn unreachable(address: &str) -> Self {
+        FakeClient {
+            unreachable: true,
+            ..FakeClient::new(address)
+        }
+    }
+
+    /// Gives the device the model and firmware version, which are otherwise `catwalk` and empty.
+    pub fn with_model(mut self, model: &str, firmware_version: &str) -> Self {
+        self.device_info.model = model.to_string();
+        self.device_info.firmware_version = firmware_version.to_string();
+        self
+    }
+
+    /// Replaces the device's plugs, each of which uses 45 W.
+    pub fn with_plugs(mut self, children: Vec<ChildDevice>) -> Self {
+        self.power_watts = children.iter().map(|c| (c.device_id.clone(), 45)).collect();
+        self.children = children;
+        self
+    }
+}
+
+/// A plug that's switched on and hasn't been overloaded.
+pub fn plug(device_id: &str, nickname: &str, position: u8) -> ChildDevice {
+    ChildDevice {
+        device_id: device_id.to_string(),
+        nickname: nickname.to_string(),
+        device_on: true,
+        on_time_seconds: None,
+        overloaded: Some(false),
+        position,
+    }
+}
+
+#[async_trait]
+impl TapoClient for FakeClient {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    async fn refresh_session(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo, Error> {
+        match self.unreachable {
+            true => Err(Error::DeviceNotFound),
+            false => Ok(self.device_info.clone()),
+        }
+    }
+
+    async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+        match self.unreachable {
+            true => Err(Error::DeviceNotFound),
+            false => Ok(self.children.clone()),
+        }
+    }
+
+    async fn get_power_for_plug(&self, device_id: &str) -> Result<CurrentPowerResult, Error> {
+        match self.power_watts.get(device_id) {
+            Some(watts) if !self.unreachable => Ok(CurrentPowerResult {
+                current_power: *watts,
+            }),
+            _ => Err(Error::DeviceNotFound),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::exporter::Reachable;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// The version at the start of firmware versions such as `1.0.13 Build 230905 Rel.134850`.
+static VERSION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*v?(\d+)\.(\d+)\.(\d+)\b").unwrap());
+
+/// The major, minor and patch numbers of a device's firmware version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    pub major: i64,
+    pub minor: i64,
+    pub patch: i64,
+}
+
+impl FirmwareVersion {
+    pub fn parse(firmware: &str) -> Option<Self> {
+        let captures = VERSION.captures(firmware)?;
+        let number = |i: usize| captures[i].parse().ok();
+        Some(FirmwareVersion {
+            major: number(1)?,
+            minor: number(2)?,
+            patch: number(3)?,
+        })
+    }
+}
+
+/// Reports each device's firmware version as numbers, so alerts can compare versions, which they
+/// can't do with the `firmware_version` label of `tapo_device_info`.
+#[derive(Default)]
+pub struct FirmwareMetrics {
+    major: Family<Reachable, Gauge>,
+    minor: Family<Reachable, Gauge>,
+    patch: Family<Reachable, Gauge>,
+    parse_error: Family<Reachable, Gauge>,
+}
+
+impl FirmwareMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "firmware_version_major",
+            "Major version of the device's firmware",
+            self.major.clone(),
+        );
+        registry.register(
+            "firmware_version_minor",
+            "Minor version of the device's firmware",
+            self.minor.clone(),
+        );
+        registry.register(
+            "firmware_version_patch",
+            "Patch version of the device's firmware",
+            self.patch.clone(),
+        );
+        registry.register(
+            "firmware_version_parse_error",
+            "Whether the device's firmware version couldn't be parsed, leaving it reported as 0.0.0",
+            self.parse_error.clone(),
+        );
+    }
+
+    /// Records the device's firmware version, or 0.0.0 along with a parse error if the version
+    /// isn't understood.
+    pub fn record(&self, labels: &Reachable, firmware: &str) {
+        let version = FirmwareVersion::parse(firmware);
+        let FirmwareVersion {
+            major,
+            minor,
+            patch,
+        } = version.unwrap_or(FirmwareVersion {
+            major: 0,
+            minor: 0,
+            patch: 0,
+        });
+
+        self.major.get_or_create(labels).set(major);
+        self.minor.get_or_create(labels).set(minor);
+        self.patch.get_or_create(labels).set(patch);
+        self.parse_error
+            .get_or_create(labels)
+            .set(version.is_none() as i64);
+    }
+
+    pub fn remove(&self, labels: &Reachable) {
+        self.major.remove(labels);
+        self.minor.remove(labels);
+        self.patch.remove(labels);
+        self.parse_error.remove(labels);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FirmwareVersion;
+
+    #[test]
+    fn parse_firmware_versions() {
+        let version = |major, minor, patch| {
+            Some(FirmwareVersion {
+                major,
+                minor,
+                patch,
+            })
+        };
+
+        assert_eq!(
+            FirmwareVersion::parse("1.0.13 Build 230905 Rel.134850"),
+            version(1, 0, 13)
+        );
+        assert_eq!(FirmwareVersion::parse("1.2.3"), version(1, 2, 3));
+        assert_eq!(FirmwareVersion::parse("v2.10.0"), version(2, 10, 0));
+        assert_eq!(FirmwareVersion::parse(""), None);
+        assert_eq!(FirmwareVersion::parse("1.0"), None);
+        assert_eq!(FirmwareVersion::parse("Build 1.2.3"), None);
+        assert_eq!(FirmwareVersion::parse("1.2.3a"), None);
+        // Too large to be a gauge's value
+        assert_eq!(FirmwareVersion::parse("99999999999999999999.0.0"), None);
+    }
+}
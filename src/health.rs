@@ -1,9 +1,561 @@
-use reqwest::Error;
+use crate::exporter::DEFAULT_METRIC_PREFIX;
+use reqwest::{Client, Error, StatusCode};
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
 
-pub async fn health(port: u16) -> Result<(), Error> {
-    let response = reqwest::get(&format!("http://localhost:{}/health", port)).await?;
+/// How to reach the server's health endpoint.
+#[derive(Clone, Debug)]
+pub struct HealthOptions {
+    /// Host to connect to, instead of the address the server is listening on.
+    pub host: Option<String>,
+    pub path: String,
+    pub timeout: Duration,
+    pub use_tls: bool,
+    /// Whether to accept any certificate when using TLS, such as a self-signed one.
+    pub insecure_skip_verify: bool,
+    /// Whether to check the metrics can be scraped, rather than just that the server is running.
+    pub deep: bool,
+    /// Whether a deep check requires the metrics to include power use.
+    pub require_samples: bool,
+    /// What the name of every metric the server serves starts with.
+    pub metric_prefix: String,
+}
+
+impl Default for HealthOptions {
+    fn default() -> Self {
+        HealthOptions {
+            host: None,
+            path: "/health".to_string(),
+            timeout: Duration::from_secs(5),
+            use_tls: false,
+            insecure_skip_verify: false,
+            deep: false,
+            require_samples: false,
+            metric_prefix: DEFAULT_METRIC_PREFIX.to_string(),
+        }
+    }
+}
+
+/// How the outcome of a health check is reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Nothing when healthy, otherwise the reason on stderr
+    #[default]
+    Text,
+    /// A JSON object describing the outcome on stdout
+    Json,
+}
+
+/// Why a health check failed.
+#[derive(Debug)]
+pub enum HealthError {
+    /// The server couldn't be connected to, such as when it isn't running.
+    Connect(Error),
+    Timeout(Error),
+    /// The server responded with a status other than success.
+    Status(Error),
+    Tls(Error),
+    /// Any other failure to make the request or read the response.
+    Request(Error),
+    /// The metrics were served without any power use, such as when no device could be read.
+    NoData {
+        url: String,
+        status: StatusCode,
+    },
+}
+
+impl HealthError {
+    /// The code to exit with, so scripts can tell why the check failed.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            HealthError::Request(_) => 1,
+            HealthError::Connect(_) => 2,
+            HealthError::Timeout(_) => 3,
+            HealthError::Status(_) => 4,
+            HealthError::Tls(_) => 5,
+            HealthError::NoData { .. } => 6,
+        }
+    }
+
+    fn outcome(&self) -> &'static str {
+        match self {
+            HealthError::Request(_) => "error",
+            HealthError::Connect(_) => "connection_failed",
+            HealthError::Timeout(_) => "timeout",
+            HealthError::Status(_) => "unsuccessful_status",
+            HealthError::Tls(_) => "tls_error",
+            HealthError::NoData { .. } => "no_data",
+        }
+    }
+
+    fn status(&self) -> Option<StatusCode> {
+        match self {
+            HealthError::Status(e) => e.status(),
+            HealthError::NoData { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HealthError {
+    /// Describes why the health check failed in a single line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let url = |e: &Error| {
+            e.url()
+                .map(|u| u.to_string())
+                .unwrap_or("the server".to_string())
+        };
+
+        match self {
+            HealthError::Connect(e) => write!(f, "couldn't connect to {}", url(e)),
+            HealthError::Timeout(e) => write!(f, "timed out waiting for {}", url(e)),
+            HealthError::Status(e) => match e.status() {
+                Some(status) => write!(f, "{} returned {status}", url(e)),
+                None => write!(f, "{e}"),
+            },
+            HealthError::Tls(e) => match tls_error(e) {
+                Some(tls) => write!(f, "TLS connection to {} failed: {tls}", url(e)),
+                None => write!(f, "TLS connection to {} failed", url(e)),
+            },
+            HealthError::Request(e) => write!(f, "{e}"),
+            HealthError::NoData { url, .. } => write!(f, "{url} returned no power use"),
+        }
+    }
+}
+
+impl From<Error> for HealthError {
+    fn from(e: Error) -> Self {
+        if e.is_timeout() {
+            HealthError::Timeout(e)
+        } else if tls_error(&e).is_some() {
+            HealthError::Tls(e)
+        } else if e.is_connect() {
+            HealthError::Connect(e)
+        } else if e.is_status() {
+            HealthError::Status(e)
+        } else {
+            HealthError::Request(e)
+        }
+    }
+}
+
+/// Finds the TLS error that caused a request to fail, if any. These are wrapped in I/O errors,
+/// which don't report what they wrap as their source.
+fn tls_error(e: &Error) -> Option<&rustls::Error> {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(e) = source {
+        if let Some(tls) = e.downcast_ref::<rustls::Error>() {
+            return Some(tls);
+        }
+        source = match e.downcast_ref::<io::Error>().and_then(|io| io.get_ref()) {
+            Some(inner) => Some(inner),
+            None => e.source(),
+        };
+    }
+    None
+}
+
+/// The outcome of a health check, as output in JSON.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    outcome: &'static str,
+    latency_seconds: f64,
+    status_code: Option<u16>,
+    error: Option<String>,
+}
+
+impl Report {
+    pub fn new(result: &Result<StatusCode, HealthError>, latency: Duration) -> Self {
+        match result {
+            Ok(status) => Report {
+                outcome: "healthy",
+                latency_seconds: latency.as_secs_f64(),
+                status_code: Some(status.as_u16()),
+                error: None,
+            },
+            Err(e) => Report {
+                outcome: e.outcome(),
+                latency_seconds: latency.as_secs_f64(),
+                status_code: e.status().map(|s| s.as_u16()),
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+pub async fn health(
+    address: IpAddr,
+    port: u16,
+    options: &HealthOptions,
+) -> Result<StatusCode, HealthError> {
+    let client = Client::builder()
+        .timeout(options.timeout)
+        .danger_accept_invalid_certs(options.insecure_skip_verify)
+        .build()?;
+
+    check(client, &health_url(address, port, options), options).await
+}
+
+/// Checks the health of a server listening on a Unix domain socket.
+pub async fn health_unix(path: &Path, options: &HealthOptions) -> Result<StatusCode, HealthError> {
+    let client = Client::builder()
+        .unix_socket(path)
+        .timeout(options.timeout)
+        .build()?;
+
+    // The host is ignored as requests are sent over the socket
+    check(
+        client,
+        &format!("http://localhost{}", check_path(options)),
+        options,
+    )
+    .await
+}
+
+async fn check(
+    client: Client,
+    url: &str,
+    options: &HealthOptions,
+) -> Result<StatusCode, HealthError> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    let status = response.status();
+
+    if options.deep && options.require_samples {
+        let body = response.text().await?;
+        let power_use = format!("{}_power_use_watts", options.metric_prefix);
+        let has_samples = body.lines().any(|l| {
+            l.strip_prefix(power_use.as_str())
+                .is_some_and(|rest| rest.starts_with(['{', ' ']))
+        });
+        if !has_samples {
+            return Err(HealthError::NoData {
+                url: url.to_string(),
+                status,
+            });
+        }
+    }
+
+    Ok(status)
+}
+
+/// Builds the URL of the health endpoint for a server listening on the address, using loopback when
+/// the server is listening on all addresses and no host has been given.
+fn health_url(address: IpAddr, port: u16, options: &HealthOptions) -> String {
+    let scheme = if options.use_tls { "https" } else { "http" };
+
+    let authority = match &options.host {
+        Some(host) if host.parse::<Ipv6Addr>().is_ok() => format!("[{host}]:{port}"),
+        Some(host) => format!("{host}:{port}"),
+        None => {
+            let address = match address {
+                IpAddr::V4(a) if a.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(a) if a.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                a => a,
+            };
+            SocketAddr::new(address, port).to_string()
+        }
+    };
+
+    format!("{scheme}://{authority}{}", check_path(options))
+}
+
+/// The path to check, which is the metrics for a deep check.
+fn check_path(options: &HealthOptions) -> String {
+    let path = if options.deep {
+        "/metrics"
+    } else {
+        &options.path
+    };
+
+    match path.starts_with('/') {
+        true => path.to_string(),
+        false => format!("/{path}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HealthError, HealthOptions, Report, health, health_unix, health_url};
+    use crate::error::AppError;
+    use crate::exporter::{AppConfig, app};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::time::Duration;
+
+    #[test]
+    fn url_for_address() {
+        let options = HealthOptions::default();
+
+        assert_eq!(
+            health_url("0.0.0.0".parse().unwrap(), 8080, &options),
+            "http://127.0.0.1:8080/health"
+        );
+        assert_eq!(
+            health_url("10.0.0.1".parse().unwrap(), 8080, &options),
+            "http://10.0.0.1:8080/health"
+        );
+        assert_eq!(
+            health_url("::".parse().unwrap(), 8080, &options),
+            "http://[::1]:8080/health"
+        );
+        assert_eq!(
+            health_url("fd00::10".parse().unwrap(), 8080, &options),
+            "http://[fd00::10]:8080/health"
+        );
+    }
+
+    #[test]
+    fn url_with_options() {
+        let address = "0.0.0.0".parse().unwrap();
+
+        assert_eq!(
+            health_url(
+                address,
+                8443,
+                &HealthOptions {
+                    host: Some("exporter.local".to_string()),
+                    path: "ready".to_string(),
+                    use_tls: true,
+                    ..HealthOptions::default()
+                }
+            ),
+            "https://exporter.local:8443/ready"
+        );
+        assert_eq!(
+            health_url(
+                address,
+                8080,
+                &HealthOptions {
+                    host: Some("fd00::10".to_string()),
+                    ..HealthOptions::default()
+                }
+            ),
+            "http://[fd00::10]:8080/health"
+        );
+    }
+
+    #[tokio::test]
+    async fn health_over_ipv6() {
+        let address = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let listener = tokio::net::TcpListener::bind(SocketAddr::new(address, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app(vec![], AppConfig::default())).await });
+
+        health(address, port, &HealthOptions::default())
+            .await
+            .unwrap();
+
+        // The metrics are scraped over IPv6 too
+        let options = HealthOptions {
+            deep: true,
+            ..HealthOptions::default()
+        };
+        health(address, port, &options).await.unwrap();
+        let response = reqwest::get(format!("http://[::1]:{port}/metrics"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn health_over_tcp() {
+        let address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let listener = tokio::net::TcpListener::bind(SocketAddr::new(address, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app(vec![], AppConfig::default())).await });
+
+        health(address, port, &HealthOptions::default())
+            .await
+            .unwrap();
+
+        let e = health(
+            address,
+            port,
+            &HealthOptions {
+                path: "/missing".to_string(),
+                ..HealthOptions::default()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("http://127.0.0.1:{port}/missing returned 404 Not Found")
+        );
+        assert_eq!(e.exit_code(), 4);
+    }
+
+    #[tokio::test]
+    async fn deep_health() {
+        let address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let listener = tokio::net::TcpListener::bind(SocketAddr::new(address, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Without any devices the metrics are served without any power use
+        tokio::spawn(async move { axum::serve(listener, app(vec![], AppConfig::default())).await });
+
+        let options = HealthOptions {
+            deep: true,
+            ..HealthOptions::default()
+        };
+        health(address, port, &options).await.unwrap();
+
+        let e = health(
+            address,
+            port,
+            &HealthOptions {
+                require_samples: true,
+                ..options
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("http://127.0.0.1:{port}/metrics returned no power use")
+        );
+        assert_eq!(e.exit_code(), 6);
+    }
+
+    #[tokio::test]
+    async fn health_timeout() {
+        let address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        // Accepts connections without ever responding
+        let listener = tokio::net::TcpListener::bind(SocketAddr::new(address, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let e = health(
+            address,
+            port,
+            &HealthOptions {
+                timeout: Duration::from_millis(100),
+                ..HealthOptions::default()
+            },
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(e, HealthError::Timeout(_)));
+        assert_eq!(
+            e.to_string(),
+            format!("timed out waiting for http://127.0.0.1:{port}/health")
+        );
+        assert_eq!(e.exit_code(), 3);
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn health_connection_refused() {
+        let address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        // Find a port nothing is listening on
+        let port = std::net::TcpListener::bind(SocketAddr::new(address, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let e = health(address, port, &HealthOptions::default())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(e, HealthError::Connect(_)));
+        assert_eq!(
+            e.to_string(),
+            format!("couldn't connect to http://127.0.0.1:{port}/health")
+        );
+        assert_eq!(e.exit_code(), 2);
+
+        // Returned from the subcommand with its own exit code, only printed if it hasn't been
+        let e = AppError::Health {
+            error: e,
+            reported: false,
+        };
+        assert_eq!(e.exit_code(), 2);
+        assert!(!e.reported());
+        assert_eq!(
+            e.to_string(),
+            format!("Health check failed: couldn't connect to http://127.0.0.1:{port}/health")
+        );
+    }
+
+    #[tokio::test]
+    async fn health_tls_error() {
+        let address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let listener = tokio::net::TcpListener::bind(SocketAddr::new(address, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app(vec![], AppConfig::default())).await });
+
+        // The server doesn't use TLS, so the handshake fails
+        let e = health(
+            address,
+            port,
+            &HealthOptions {
+                use_tls: true,
+                ..HealthOptions::default()
+            },
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(e, HealthError::Tls(_)), "{e:?}");
+        assert!(e.to_string().starts_with(&format!(
+            "TLS connection to https://127.0.0.1:{port}/health failed"
+        )));
+        assert_eq!(e.exit_code(), 5);
+    }
+
+    #[test]
+    fn report_outcome() {
+        let healthy = Report::new(&Ok(reqwest::StatusCode::OK), Duration::from_millis(1500));
+        assert_eq!(
+            serde_json::to_value(&healthy).unwrap(),
+            serde_json::json!({
+                "outcome": "healthy",
+                "latency_seconds": 1.5,
+                "status_code": 200,
+                "error": null,
+            })
+        );
+
+        let no_data = Report::new(
+            &Err(HealthError::NoData {
+                url: "http://127.0.0.1:8080/metrics".to_string(),
+                status: reqwest::StatusCode::OK,
+            }),
+            Duration::from_millis(1500),
+        );
+        assert_eq!(
+            serde_json::to_value(&no_data).unwrap(),
+            serde_json::json!({
+                "outcome": "no_data",
+                "latency_seconds": 1.5,
+                "status_code": 200,
+                "error": "http://127.0.0.1:8080/metrics returned no power use",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn health_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("health-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app(vec![], AppConfig::default())).await });
 
-    response.error_for_status()?;
+        health_unix(&path, &HealthOptions::default()).await.unwrap();
 
-    Ok(())
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,141 @@
+use crate::exporter::PowerUse;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::AtomicU64;
+use std::time::Instant;
+
+/// Keeps the last few power readings of each plug, to report how its power use is changing.
+pub struct PowerHistory {
+    size: usize,
+    readings: HashMap<PowerUse, VecDeque<(Instant, i64)>>,
+    delta: Family<PowerUse, Gauge>,
+    rate: Family<PowerUse, Gauge<f64, AtomicU64>>,
+}
+
+impl PowerHistory {
+    /// Creates a history keeping `size` readings of each plug, with at least two kept so there's
+    /// always a change to report.
+    pub fn new(size: usize) -> Self {
+        PowerHistory {
+            size: size.max(2),
+            readings: HashMap::new(),
+            delta: Family::default(),
+            rate: Family::default(),
+        }
+    }
+
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "power_change_watts",
+            "Change in power use in watts since the previous reading",
+            self.delta.clone(),
+        );
+        // Also served under the name it was asked for as, for alerts written against either
+        registry.register(
+            "power_use_delta_watts",
+            "Change in power use in watts since the previous reading",
+            self.delta.clone(),
+        );
+        registry.register(
+            "power_rate_watts_per_second",
+            "Rate of change in power use in watts per second across the readings kept",
+            self.rate.clone(),
+        );
+    }
+
+    /// Adds a plug's reading, updating how its power use is changing once there's a previous
+    /// reading to compare it to.
+    pub fn record(&mut self, labels: &PowerUse, watts: i64, at: Instant) {
+        let readings = self.readings.entry(labels.clone()).or_default();
+        readings.push_back((at, watts));
+        while readings.len() > self.size {
+            readings.pop_front();
+        }
+
+        let (Some(&(first_at, first)), Some(&(_, previous))) =
+            (readings.front(), readings.iter().rev().nth(1))
+        else {
+            return;
+        };
+
+        self.delta.get_or_create(labels).set(watts - previous);
+
+        let elapsed = at.duration_since(first_at).as_secs_f64();
+        if elapsed > 0.0 {
+            self.rate
+                .get_or_create(labels)
+                .set((watts - first) as f64 / elapsed);
+        }
+    }
+
+    /// Forgets a plug's readings and stops serving how its power use is changing.
+    pub fn remove(&mut self, labels: &PowerUse) {
+        self.readings.remove(labels);
+        self.delta.remove(labels);
+        self.rate.remove(labels);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PowerHistory;
+    use crate::exporter::{AliasLabel, PowerUse};
+    use std::time::{Duration, Instant};
+
+    fn labels() -> PowerUse {
+        PowerUse {
+            power_strip_id: "123".to_string(),
+            ip_address: "10.0.0.1".to_string(),
+            device_id: "456".to_string(),
+            nickname: "Kettle".to_string(),
+            group: String::new(),
+            position: 1,
+            strip_alias: AliasLabel::default(),
+        }
+    }
+
+    #[test]
+    fn delta_and_rate() {
+        let mut history = PowerHistory::new(3);
+        let labels = labels();
+        let start = Instant::now();
+
+        // Nothing to compare the first reading to
+        history.record(&labels, 10, start);
+        assert!(history.delta.get(&labels).is_none());
+        assert!(history.rate.get(&labels).is_none());
+
+        history.record(&labels, 30, start + Duration::from_secs(10));
+        assert_eq!(history.delta.get(&labels).unwrap().get(), 20);
+        assert_eq!(history.rate.get(&labels).unwrap().get(), 2.0);
+
+        history.record(&labels, 20, start + Duration::from_secs(20));
+        assert_eq!(history.delta.get(&labels).unwrap().get(), -10);
+        assert_eq!(history.rate.get(&labels).unwrap().get(), 0.5);
+
+        // The first reading has dropped out of the window
+        history.record(&labels, 50, start + Duration::from_secs(30));
+        assert_eq!(history.delta.get(&labels).unwrap().get(), 30);
+        assert_eq!(history.rate.get(&labels).unwrap().get(), 1.0);
+        assert_eq!(history.readings[&labels].len(), 3);
+
+        history.remove(&labels);
+        assert!(history.delta.get(&labels).is_none());
+        assert!(history.readings.is_empty());
+    }
+
+    #[test]
+    fn keeps_at_least_two_readings() {
+        let mut history = PowerHistory::new(0);
+        let labels = labels();
+        let start = Instant::now();
+
+        history.record(&labels, 10, start);
+        history.record(&labels, 15, start + Duration::from_secs(5));
+
+        assert_eq!(history.delta.get(&labels).unwrap().get(), 5);
+        assert_eq!(history.rate.get(&labels).unwrap().get(), 1.0);
+    }
+}
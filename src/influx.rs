@@ -0,0 +1,142 @@
+use prometheus_client::metrics::counter::Counter;
+use reqwest::Url;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use std::time::Duration;
+
+/// Writes the plugs' readings as line protocol to an InfluxDB bucket, for keeping their power and
+/// energy use over the long term.
+pub struct InfluxWriter {
+    client: reqwest::Client,
+    url: Url,
+    token: Option<String>,
+    errors: Counter,
+}
+
+impl InfluxWriter {
+    /// Creates a writer for the bucket on the InfluxDB at the base URL, e.g.
+    /// `http://localhost:8086`, in the organization if the server needs one.
+    pub fn new(base_url: &Url, bucket: &str, org: Option<&str>, token: Option<String>) -> Self {
+        let mut url = base_url.clone();
+        url.path_segments_mut()
+            .expect("InfluxDB URLs are HTTP URLs, which have paths")
+            .pop_if_empty()
+            .extend(["api", "v2", "write"]);
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("bucket", bucket);
+            if let Some(org) = org {
+                query.append_pair("org", org);
+            }
+            query.append_pair("precision", "ns");
+        }
+
+        InfluxWriter {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            url,
+            token,
+            errors: Counter::default(),
+        }
+    }
+
+    /// Counts the writes that failed.
+    pub fn errors(&self) -> Counter {
+        self.errors.clone()
+    }
+
+    /// Writes the lines, which are in line protocol with nanosecond timestamps.
+    pub async fn write(&self, lines: String) -> Result<(), reqwest::Error> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(lines);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Token {token}"));
+        }
+
+        let result = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if result.is_err() {
+            self.errors.inc();
+        }
+        result.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InfluxWriter;
+    use axum::Router;
+    use axum::extract::{RawQuery, State};
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn write_lines() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let influx = Router::new()
+            .route(
+                "/influx/api/v2/write",
+                post(
+                    |State(tx): State<mpsc::Sender<(Option<String>, HeaderMap, String)>>,
+                     RawQuery(query): RawQuery,
+                     headers: HeaderMap,
+                     body: String| async move {
+                        tx.send((query, headers, body)).await.unwrap();
+                        StatusCode::NO_CONTENT
+                    },
+                ),
+            )
+            .route(
+                "/unauthorized/api/v2/write",
+                post(|| async { StatusCode::UNAUTHORIZED }),
+            )
+            .with_state(tx);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, influx).await.unwrap() });
+
+        let writer = InfluxWriter::new(
+            &format!("http://{address}/influx/").parse().unwrap(),
+            "home energy",
+            Some("home"),
+            Some("secret".to_string()),
+        );
+        writer
+            .write("tapo_power,device_id=456 watts=45i 1700000000000000000\n".to_string())
+            .await
+            .unwrap();
+
+        let (query, headers, body) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            query.as_deref(),
+            Some("bucket=home+energy&org=home&precision=ns")
+        );
+        assert_eq!(headers[AUTHORIZATION], "Token secret");
+        assert_eq!(
+            body,
+            "tapo_power,device_id=456 watts=45i 1700000000000000000\n"
+        );
+        assert_eq!(writer.errors().get(), 0);
+
+        let writer = InfluxWriter::new(
+            &format!("http://{address}/unauthorized").parse().unwrap(),
+            "home",
+            None,
+            None,
+        );
+        assert!(writer.write(String::new()).await.is_err());
+        assert_eq!(writer.errors().get(), 1);
+    }
+}
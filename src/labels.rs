@@ -0,0 +1,87 @@
+/// Makes a user supplied value, such as a device nickname, safe to use as a label value by
+/// replacing anything other than printable ASCII, along with characters that are significant in
+/// the exposition format, with `_`.
+pub fn sanitize_label_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | '{' | '}' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Whether the name can be used as a label's, which excludes those starting with `__` as they're
+/// reserved for Prometheus.
+pub fn is_valid_label_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && !name.starts_with("__")
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Escapes a label value as required by OpenMetrics, as the encoder writes values out verbatim.
+pub fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape_label_value, sanitize_label_value};
+    use proptest::prelude::*;
+
+    fn is_safe(value: &str) -> bool {
+        value
+            .chars()
+            .all(|c| (c.is_ascii_graphic() || c == ' ') && !matches!(c, '"' | '\\' | '{' | '}'))
+    }
+
+    #[test]
+    fn sanitize_adversarial_values() {
+        assert_eq!(sanitize_label_value("my\"device\n"), "my_device_");
+        assert_eq!(sanitize_label_value("rack{1}"), "rack_1_");
+        assert_eq!(sanitize_label_value("back\\slash"), "back_slash");
+        assert_eq!(sanitize_label_value("Küche"), "K_che");
+    }
+
+    #[test]
+    fn sanitize_typical_values() {
+        assert_eq!(sanitize_label_value("Living Room TV"), "Living Room TV");
+        assert_eq!(
+            sanitize_label_value("desk-lamp_2 (left)"),
+            "desk-lamp_2 (left)"
+        );
+        assert_eq!(sanitize_label_value(""), "");
+    }
+
+    #[test]
+    fn escape_values() {
+        assert_eq!(escape_label_value("my\"device\n"), "my\\\"device\\n");
+        assert_eq!(escape_label_value("back\\slash"), "back\\\\slash");
+        assert_eq!(escape_label_value("rack{1}"), "rack{1}");
+    }
+
+    proptest! {
+        #[test]
+        fn sanitized_values_are_safe(value in any::<String>()) {
+            let sanitized = sanitize_label_value(&value);
+
+            prop_assert!(is_safe(&sanitized));
+            prop_assert_eq!(sanitized.chars().count(), value.chars().count());
+        }
+
+        #[test]
+        fn safe_values_are_unchanged(value in "[a-zA-Z0-9 ()_.-]*") {
+            prop_assert_eq!(sanitize_label_value(&value), value);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Reads the power use of Tapo plugs and power strips, such as the P304M, and serves it as
+//! Prometheus metrics.
+//!
+//! The `p304m-prometheus-exporter` binary is a command line interface over this library, which can
+//! also be embedded in another program:
+//!
+//! - [`build_clients`] connects to the devices with a [`ClientFactory`], giving a [`TapoClient`]
+//!   for each.
+//! - [`app`] builds an axum [`Router`](axum::Router) serving their metrics, along with the health
+//!   and readiness checks.
+//! - [`Collector`] reads their metrics into a [`Registry`](prometheus_client::registry::Registry)
+//!   without axum, for serving from any HTTP server.
+//! - [`register_plugin`] adds a [`DevicePlugin`] connecting to models that aren't supported yet.
+//! - [`server::ServerOptions`] runs the whole server from its command line options, as the binary
+//!   does.
+
+mod address;
+mod alert;
+mod cardinality;
+/// Checking each device can be connected to and read from, without serving its metrics.
+pub mod check;
+/// Listing the devices on a Tapo account from the TP-Link cloud.
+pub mod cloud;
+/// The configuration file, and how it's combined with the command line.
+pub mod config;
+#[cfg(feature = "watch-config")]
+mod config_watch;
+mod connect;
+/// Finding devices as they announce themselves over mDNS.
+pub mod discovery;
+mod encoders;
+mod energy;
+mod error;
+mod exporter;
+#[cfg(test)]
+mod fake;
+mod firmware;
+/// Checking that a running server is healthy.
+pub mod health;
+mod history;
+mod influx;
+mod labels;
+/// Listing what each device reports about itself and its plugs.
+pub mod list;
+/// Finding the IP addresses of devices given by MAC address.
+pub mod mac;
+mod management;
+/// Command line options shared by the server and the other subcommands.
+pub mod options;
+mod otlp;
+mod overload;
+mod pid_file;
+mod plugins;
+mod plugs;
+mod power_histogram;
+mod probe;
+mod pushgateway;
+mod reload;
+mod remote_write;
+mod retry;
+/// Finding devices on the local network with the Tapo discovery packet.
+pub mod scan;
+mod sensors;
+/// Running the exporter's server from its command line options.
+pub mod server;
+mod statsd;
+mod systemd;
+/// Writing files for the node_exporter textfile collector.
+pub mod textfile;
+/// The version of the exporter and what it was built from.
+pub mod version;
+
+pub use crate::config::{Credentials, DeviceConfig};
+pub use crate::connect::{
+    ClientFactory, TapoClientFactory, build_clients, client_for_device, is_unsupported_model,
+};
+pub use crate::error::{AppError, ExporterError};
+pub use crate::exporter::{
+    AccountLabel, Alias, AliasMode, AppConfig, ChildDevice, Collector, DEFAULT_METRIC_PREFIX,
+    DeviceIdentity, DeviceInfo, ReadinessPolicy, TapoClient, app, collect,
+};
+pub use crate::exporter::{
+    GenericClient, H100Client, PlugClient, PlugNonEmClient, PowerStripClient, PowerStripNonEmClient,
+};
+pub use crate::plugins::{DevicePlugin, Handler, register as register_plugin};
+pub use crate::sensors::SensorReading;
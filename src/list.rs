@@ -0,0 +1,231 @@
+use crate::exporter::TapoClient;
+use serde::Serialize;
+use tapo::Error;
+
+/// How the devices are listed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ListFormat {
+    /// A table with a row for each plug, or each device when discovering them
+    #[default]
+    Table,
+    /// A JSON array with an object for each device
+    Json,
+}
+
+/// What could be read from a device, or why it couldn't be.
+#[derive(Debug, Serialize)]
+pub struct DeviceListing {
+    address: String,
+    model: Option<String>,
+    firmware_version: Option<String>,
+    device_id: Option<String>,
+    plugs: Vec<PlugListing>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PlugListing {
+    position: u8,
+    nickname: String,
+    device_id: String,
+    on: bool,
+}
+
+impl DeviceListing {
+    /// Reads the device's information and plugs, listing the error instead if either can't be
+    /// read.
+    pub async fn read(client: &(dyn TapoClient + Send + Sync)) -> Self {
+        let read = async {
+            let info = client.device_info().await?;
+            let children = client.child_devices().await?;
+            Ok::<_, Error>((info, children))
+        };
+
+        match read.await {
+            Ok((info, children)) => DeviceListing {
+                address: client.address().to_string(),
+                model: Some(info.model),
+                firmware_version: Some(info.firmware_version),
+                device_id: Some(info.power_strip_id),
+                plugs: children
+                    .into_iter()
+                    .map(|child| PlugListing {
+                        position: child.position,
+                        nickname: child.nickname,
+                        device_id: child.device_id,
+                        on: child.device_on,
+                    })
+                    .collect(),
+                error: None,
+            },
+            Err(e) => DeviceListing::failed(client.address(), e),
+        }
+    }
+
+    /// Lists a device that couldn't be connected to.
+    pub fn failed(address: &str, error: impl ToString) -> Self {
+        DeviceListing {
+            address: address.to_string(),
+            model: None,
+            firmware_version: None,
+            device_id: None,
+            plugs: Vec::new(),
+            error: Some(error.to_string()),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Formats the devices as a table with a row for each plug, or the error for devices that couldn't
+/// be read.
+pub fn table(listings: &[DeviceListing]) -> String {
+    let mut rows: Vec<Vec<String>> = vec![
+        [
+            "ADDRESS",
+            "MODEL",
+            "FIRMWARE",
+            "DEVICE ID",
+            "POSITION",
+            "NICKNAME",
+            "PLUG ID",
+            "STATE",
+        ]
+        .map(str::to_string)
+        .to_vec(),
+    ];
+
+    for listing in listings {
+        if let Some(error) = &listing.error {
+            rows.push(vec![listing.address.clone(), format!("error: {error}")]);
+            continue;
+        }
+
+        let device = [
+            listing.address.clone(),
+            listing.model.clone().unwrap_or_default(),
+            listing.firmware_version.clone().unwrap_or_default(),
+            listing.device_id.clone().unwrap_or_default(),
+        ];
+        if listing.plugs.is_empty() {
+            rows.push(device.to_vec());
+        }
+        for plug in listing.plugs.iter() {
+            let mut row = device.to_vec();
+            row.extend([
+                plug.position.to_string(),
+                plug.nickname.clone(),
+                plug.device_id.clone(),
+                if plug.on { "on" } else { "off" }.to_string(),
+            ]);
+            rows.push(row);
+        }
+    }
+
+    columns(&rows)
+}
+
+/// Lines up the cells of each row in columns. The last cell of a row isn't padded, so a cell
+/// running across the remaining columns, such as an error, doesn't widen them.
+pub fn columns(rows: &[Vec<String>]) -> String {
+    let mut widths = Vec::new();
+    for row in rows.iter() {
+        for (i, cell) in row.iter().take(row.len() - 1).enumerate() {
+            if widths.len() <= i {
+                widths.push(0);
+            }
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    for row in rows.iter() {
+        for (i, cell) in row.iter().enumerate() {
+            if i + 1 < row.len() {
+                out.push_str(&format!("{cell:<width$}  ", width = widths[i]));
+            } else {
+                out.push_str(cell);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DeviceListing, table};
+    use crate::exporter::ChildDevice;
+    use crate::fake::{FakeClient, plug};
+    use tapo::Error;
+
+    fn power_strip() -> FakeClient {
+        FakeClient::new("10.0.0.1")
+            .with_model("P304M", "1.0.5")
+            .with_plugs(vec![
+                plug("456", "Kettle", 1),
+                ChildDevice {
+                    device_on: false,
+                    ..plug("789", "Living room", 2)
+                },
+            ])
+    }
+
+    #[tokio::test]
+    async fn list_as_table() {
+        let listings = vec![
+            DeviceListing::read(&power_strip()).await,
+            DeviceListing::read(&FakeClient::unreachable("10.0.0.2")).await,
+            DeviceListing::failed("power-strip.local", "Failed to connect"),
+        ];
+
+        assert!(listings[0].is_ok());
+        assert!(!listings[1].is_ok());
+        assert_eq!(
+            table(&listings),
+            format!(
+                "ADDRESS            MODEL  FIRMWARE  DEVICE ID  POSITION  NICKNAME     PLUG ID  STATE\n\
+                10.0.0.1           P304M  1.0.5     123        1         Kettle       456      on\n\
+                10.0.0.1           P304M  1.0.5     123        2         Living room  789      off\n\
+                10.0.0.2           error: {}\n\
+                power-strip.local  error: Failed to connect\n",
+                Error::DeviceNotFound
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn list_as_json() {
+        let listings = vec![
+            DeviceListing::read(&power_strip()).await,
+            DeviceListing::read(&FakeClient::unreachable("10.0.0.2")).await,
+        ];
+
+        assert_eq!(
+            serde_json::to_value(&listings).unwrap(),
+            serde_json::json!([
+                {
+                    "address": "10.0.0.1",
+                    "model": "P304M",
+                    "firmware_version": "1.0.5",
+                    "device_id": "123",
+                    "plugs": [
+                        {"position": 1, "nickname": "Kettle", "device_id": "456", "on": true},
+                        {"position": 2, "nickname": "Living room", "device_id": "789", "on": false},
+                    ],
+                    "error": null,
+                },
+                {
+                    "address": "10.0.0.2",
+                    "model": null,
+                    "firmware_version": null,
+                    "device_id": null,
+                    "plugs": [],
+                    "error": Error::DeviceNotFound.to_string(),
+                },
+            ])
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::address::{device_mac, parse_mac_address};
+use crate::config::DeviceConfig;
+use crate::exporter::Devices;
+use crate::scan::{self, FoundDevice};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Where Linux lists the MAC addresses of the hosts it's recently talked to on the local network.
+const NEIGHBOUR_TABLE: &str = "/proc/net/arp";
+
+/// How long to wait for devices missing from the neighbour table to respond to being discovered.
+const DISCOVERY_DURATION: Duration = Duration::from_secs(3);
+
+/// The addresses found for the devices given by MAC address, shared between finding them again and
+/// reloading the devices.
+#[derive(Clone, Default)]
+pub struct ResolvedMacs(Arc<Mutex<BTreeMap<String, Option<String>>>>);
+
+impl ResolvedMacs {
+    /// Gives the devices given by MAC address the address found for them, leaving out those that
+    /// haven't been found, and starts finding the addresses of any given that weren't before.
+    pub fn substitute(&self, devices: &[DeviceConfig]) -> Vec<DeviceConfig> {
+        let mut resolved = self.0.lock().unwrap();
+        resolved.retain(|mac, _| {
+            devices
+                .iter()
+                .any(|d| device_mac(&d.address) == Some(mac.as_str()))
+        });
+
+        devices
+            .iter()
+            .filter_map(|device| match device_mac(&device.address) {
+                Some(mac) => {
+                    let address = resolved.entry(mac.to_string()).or_default().clone()?;
+                    Some(DeviceConfig {
+                        address,
+                        ..device.clone()
+                    })
+                }
+                None => Some(device.clone()),
+            })
+            .collect()
+    }
+
+    /// The MAC addresses of the devices given by one, with the address found for each so far.
+    pub fn addresses(&self) -> BTreeMap<String, Option<String>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// The MAC addresses of the devices given by one that have been found, by the address each was
+    /// found at.
+    pub fn found(&self) -> HashMap<String, String> {
+        self.addresses()
+            .into_iter()
+            .filter_map(|(mac, address)| Some((address?, mac)))
+            .collect()
+    }
+
+    /// Whether the IP address of each device given by MAC address has been found, by MAC address.
+    pub fn resolutions(&self) -> HashMap<String, bool> {
+        self.addresses()
+            .into_iter()
+            .map(|(mac, address)| (mac, address.is_some()))
+            .collect()
+    }
+
+    /// Records the addresses found for the devices, returning whether any changed.
+    pub fn update(&self, found: HashMap<String, String>) -> bool {
+        let mut resolved = self.0.lock().unwrap();
+        let mut changed = false;
+        for (mac, address) in found {
+            if let Some(current) = resolved.get_mut(&mac) {
+                if current.as_ref() != Some(&address) {
+                    *current = Some(address);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// Finds the addresses of the devices with the MAC addresses in the neighbour table, or failing
+/// that by discovering them, ignoring `failing` addresses in the table as it may not have caught up
+/// with the device moving.
+pub async fn resolve(macs: &[String], failing: &[String]) -> HashMap<String, String> {
+    let table = std::fs::read_to_string(NEIGHBOUR_TABLE).unwrap_or_else(|e| {
+        debug!("Failed to read {NEIGHBOUR_TABLE}: {e}");
+        String::new()
+    });
+    let mut resolved = from_neighbours(&table, macs, failing);
+
+    let missing: Vec<String> = macs
+        .iter()
+        .filter(|mac| !resolved.contains_key(*mac))
+        .cloned()
+        .collect();
+    if missing.is_empty() {
+        return resolved;
+    }
+    let found = match scan::bind().await {
+        Ok(socket) => {
+            scan::discover(&socket, &scan::broadcast_addresses(), DISCOVERY_DURATION).await
+        }
+        Err(e) => Err(e),
+    };
+    match found {
+        Ok(found) => resolved.extend(from_found(&found, &missing)),
+        Err(e) => warn!("Failed to discover devices given by MAC address: {e}"),
+    }
+    resolved
+}
+
+/// Finds the IP addresses of the devices given by MAC address that haven't been found yet,
+/// returning the devices with them, leaving out and warning about any that couldn't be found.
+pub async fn find_devices(devices: &[DeviceConfig], macs: &ResolvedMacs) -> Vec<DeviceConfig> {
+    macs.substitute(devices);
+    let unresolved: Vec<String> = macs
+        .addresses()
+        .into_iter()
+        .filter(|(_, address)| address.is_none())
+        .map(|(mac, _)| mac)
+        .collect();
+    if unresolved.is_empty() {
+        return macs.substitute(devices);
+    }
+
+    macs.update(resolve(&unresolved, &[]).await);
+    for mac in unresolved {
+        match macs.addresses().get(&mac).cloned().flatten() {
+            Some(address) => info!("Found the device with MAC address {mac} at {address}"),
+            None => warn!("Couldn't find the IP address of the device with MAC address {mac}"),
+        }
+    }
+    macs.substitute(devices)
+}
+
+/// Looks for the IP addresses of the devices given by MAC address on every interval, for those
+/// that haven't been found and those that can't be read from, in case they've been given another
+/// IP address, asking for the devices to be reloaded when any have `moved` or need connecting to
+/// again.
+pub(crate) async fn resolve_devices(
+    devices: Devices,
+    macs: ResolvedMacs,
+    interval: Duration,
+    moved: mpsc::Sender<()>,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    // They were looked for when starting
+    ticks.tick().await;
+
+    loop {
+        devices.set_mac_resolutions(macs.resolutions()).await;
+        ticks.tick().await;
+
+        let addresses = macs.addresses();
+        let failing: Vec<String> = addresses
+            .values()
+            .flatten()
+            .filter(|address| !devices.contains(address) || devices.is_failing(address))
+            .cloned()
+            .collect();
+        let lost: Vec<String> = addresses
+            .iter()
+            .filter(|(_, address)| address.as_ref().is_none_or(|a| failing.contains(a)))
+            .map(|(mac, _)| mac.clone())
+            .collect();
+        if lost.is_empty() {
+            continue;
+        }
+
+        let found = resolve(&lost, &failing).await;
+        for (mac, address) in found.iter() {
+            if addresses.get(mac).cloned().flatten().as_ref() != Some(address) {
+                info!("Found the device with MAC address {mac} at {address}");
+            }
+        }
+        let changed = macs.update(found);
+        let disconnected = macs
+            .addresses()
+            .values()
+            .flatten()
+            .any(|address| !devices.contains(address));
+        if (changed || disconnected) && moved.send(()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Looks up the addresses of the MAC addresses in the contents of `/proc/net/arp`, skipping
+/// entries that are incomplete or `failing`.
+fn from_neighbours(table: &str, macs: &[String], failing: &[String]) -> HashMap<String, String> {
+    let mut resolved = HashMap::new();
+    // After the header, each line is the address, hardware type, flags, MAC address, mask and
+    // interface
+    for line in table.lines().skip(1) {
+        let [address, _, flags, mac, ..] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+            continue;
+        };
+        let Ok(mac) = parse_mac_address(mac) else {
+            continue;
+        };
+        if flags == "0x0" || failing.iter().any(|f| f == address) || !macs.contains(&mac) {
+            continue;
+        }
+        resolved.insert(mac, address.to_string());
+    }
+    resolved
+}
+
+/// Looks up the addresses of the MAC addresses among the devices that responded to discovery.
+fn from_found(found: &[FoundDevice], macs: &[String]) -> HashMap<String, String> {
+    found
+        .iter()
+        .filter_map(|device| {
+            let mac = parse_mac_address(&device.mac).ok()?;
+            macs.contains(&mac).then(|| (mac, device.address.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ResolvedMacs, from_found, from_neighbours};
+    use crate::config::DeviceConfig;
+    use crate::scan::FoundDevice;
+    use std::collections::HashMap;
+
+    const TABLE: &str = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.10     0x1         0x2         aa:bb:cc:dd:ee:ff     *        eth0
+192.168.1.11     0x1         0x0         00:00:00:00:00:00     *        eth0
+192.168.1.12     0x1         0x2         aa:bb:cc:dd:ee:01     *        eth0
+192.168.1.13     0x1         0x2         aa:bb:cc:dd:ee:01     *        eth0
+";
+
+    #[test]
+    fn addresses_from_neighbour_table() {
+        let macs = [
+            "aa:bb:cc:dd:ee:ff".to_string(),
+            "aa:bb:cc:dd:ee:01".to_string(),
+        ];
+
+        assert_eq!(
+            from_neighbours(TABLE, &macs, &["192.168.1.13".to_string()]),
+            HashMap::from([
+                ("aa:bb:cc:dd:ee:ff".to_string(), "192.168.1.10".to_string()),
+                ("aa:bb:cc:dd:ee:01".to_string(), "192.168.1.12".to_string()),
+            ])
+        );
+        assert!(from_neighbours("", &macs, &[]).is_empty());
+
+        let found = [FoundDevice {
+            address: "192.168.1.20".to_string(),
+            mac: "AA:BB:CC:DD:EE:01".to_string(),
+            model: "P304M(UK)".to_string(),
+        }];
+        assert_eq!(
+            from_found(&found, &macs),
+            HashMap::from([("aa:bb:cc:dd:ee:01".to_string(), "192.168.1.20".to_string())])
+        );
+    }
+
+    #[test]
+    fn devices_given_the_addresses_found() {
+        let device = |address: &str| DeviceConfig {
+            address: address.to_string(),
+            ..Default::default()
+        };
+        let devices = [device("10.0.0.1"), device("mac:aa:bb:cc:dd:ee:ff")];
+        let resolved = ResolvedMacs::default();
+        let addresses = |devices: Vec<DeviceConfig>| -> Vec<String> {
+            devices.into_iter().map(|d| d.address).collect()
+        };
+
+        // Left out until found
+        assert_eq!(addresses(resolved.substitute(&devices)), ["10.0.0.1"]);
+        assert!(resolved.update(HashMap::from([(
+            "aa:bb:cc:dd:ee:ff".to_string(),
+            "10.0.0.2".to_string()
+        )])));
+        assert_eq!(
+            addresses(resolved.substitute(&devices)),
+            ["10.0.0.1", "10.0.0.2"]
+        );
+        assert!(!resolved.update(HashMap::from([(
+            "aa:bb:cc:dd:ee:ff".to_string(),
+            "10.0.0.2".to_string()
+        )])));
+
+        // Forgotten once no longer given
+        resolved.substitute(&devices[..1]);
+        assert!(resolved.addresses().is_empty());
+    }
+}
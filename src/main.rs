@@ -1,130 +1,644 @@
-mod exporter;
-mod health;
-
-use crate::exporter::TapoClient;
-use clap::{Command, CommandFactory, Parser, Subcommand};
-use clap_complete::aot::{Generator, Shell, generate};
+use clap::{ArgMatches, Command, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::aot::{Generator, Shell, generate, generate_to};
+use clap_mangen::Man;
+use ipnet::Ipv4Net;
+use p304m_prometheus_exporter::cloud::{self, CloudDevice, DEFAULT_CLOUD_URL};
+use p304m_prometheus_exporter::config::{device_credentials, merge, parse_metric_prefix};
+use p304m_prometheus_exporter::discovery::Discovery;
+use p304m_prometheus_exporter::health::{self, HealthOptions, OutputFormat};
+use p304m_prometheus_exporter::list::{self, DeviceListing, ListFormat};
+use p304m_prometheus_exporter::mac::{self, ResolvedMacs};
+use p304m_prometheus_exporter::options::{
+    DeviceOptions, ListenOptions, PushGatewayOptions, ResolvedDevices,
+};
+use p304m_prometheus_exporter::server::ServerOptions;
+use p304m_prometheus_exporter::version::{BuildInfo, VERSION, VersionFormat};
+use p304m_prometheus_exporter::{
+    AppConfig, AppError, Credentials, DEFAULT_METRIC_PREFIX, TapoClient, check, client_for_device,
+    scan, textfile,
+};
+use serde::Serialize;
+use std::backtrace::Backtrace;
 use std::io;
-use tapo::{ApiClient, Error};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
 
 #[derive(Parser)]
-#[command(arg_required_else_help = true, version = option_env!("VERSION").unwrap_or("dev-build"))]
+#[command(arg_required_else_help = true, version = VERSION)]
 struct Cli {
-    /// Port number the server is or should be running on
-    #[arg(short, long, env, default_value_t = 8080)]
-    port: u16,
+    #[command(flatten)]
+    listen: ListenOptions,
 
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+/// A shell to generate completions for, or every one of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum CompletionShell {
+    #[default]
+    Bash,
+    Elvish,
+    Fish,
+    #[value(name = "powershell")]
+    PowerShell,
+    Zsh,
+    All,
+}
+
+impl CompletionShell {
+    fn shells(self) -> Vec<Shell> {
+        match self {
+            CompletionShell::Bash => vec![Shell::Bash],
+            CompletionShell::Elvish => vec![Shell::Elvish],
+            CompletionShell::Fish => vec![Shell::Fish],
+            CompletionShell::PowerShell => vec![Shell::PowerShell],
+            CompletionShell::Zsh => vec![Shell::Zsh],
+            CompletionShell::All => Shell::value_variants().to_vec(),
+        }
+    }
+}
+
+// Only one is ever created, so the size of the server's options doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Perform health check against server as Docker health check doesn't support simple HTTP endpoints
-    Health {},
+    Health {
+        /// Host to connect to, defaulting to the address the server is listening on
+        #[arg(long, env = "HEALTH_HOST")]
+        host: Option<String>,
+
+        /// Path of the health endpoint
+        #[arg(long, env = "HEALTH_PATH", default_value = "/health")]
+        path: String,
+
+        /// Maximum time to wait for the server to respond
+        #[arg(long, visible_alias = "health-timeout", env = "HEALTH_TIMEOUT", default_value = "5s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+
+        /// Connect to the server using TLS
+        #[arg(long, env = "HEALTH_USE_TLS")]
+        use_tls: bool,
+
+        /// Accept any certificate from the server when using TLS
+        #[arg(long, env = "HEALTH_INSECURE_SKIP_VERIFY", requires = "use_tls")]
+        insecure_skip_verify: bool,
+
+        /// Check the metrics can be scraped rather than just that the server is running
+        #[arg(long, env = "HEALTH_DEEP", conflicts_with = "path")]
+        deep: bool,
+
+        /// Fail a deep check, with exit code 6, if the metrics don't include any power use
+        #[arg(long, env = "HEALTH_REQUIRE_SAMPLES", requires = "deep")]
+        require_samples: bool,
+
+        /// What the name of every metric the server serves starts with, for a deep check
+        #[arg(long, env = "METRIC_PREFIX", default_value = DEFAULT_METRIC_PREFIX, value_parser = parse_metric_prefix)]
+        metric_prefix: String,
+
+        /// How to report the outcome of the check
+        #[arg(long, env = "HEALTH_OUTPUT", value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
     /// Run server
-    Server {
-        /// Username for the Tapo service
-        #[arg(short, long, env = "TAPO_USERNAME", hide_env_values = true)]
-        username: String,
+    Server(ServerOptions),
+    /// Connect to each device and list what it reports about itself and its plugs
+    ListDevices {
+        #[command(flatten)]
+        devices: DeviceOptions,
 
-        /// Password for the Tapo service
-        #[arg(short, long, env = "TAPO_PASSWORD", hide_env_values = true)]
-        password: String,
+        /// How to list the devices
+        #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+        output: ListFormat,
+    },
+    /// Check the configuration can be read and every device can be read from, without starting the
+    /// server
+    Check {
+        #[command(flatten)]
+        devices: DeviceOptions,
 
-        /// IP address or DNS name for the devices
-        #[arg(
-            short,
-            long,
-            env = "IP_ADDRESS",
-            hide_env_values = true,
-            value_delimiter = ' '
-        )]
-        device_addresses: Vec<String>,
+        /// Maximum time to spend connecting to and reading from each device
+        #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+
+        /// Stop at the first device that fails its check
+        #[arg(long)]
+        fail_fast: bool,
+    },
+    /// Read from each device once and print the metrics, for the node_exporter textfile collector
+    Collect {
+        #[command(flatten)]
+        devices: DeviceOptions,
+
+        /// Maximum time to spend connecting to and reading from each device
+        #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+
+        /// Path to write the metrics to instead of stdout, replacing the file in one go so it's
+        /// never read part written
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// What the name of every metric starts with, followed by an underscore
+        #[arg(long, env = "METRIC_PREFIX", default_value = DEFAULT_METRIC_PREFIX, value_parser = parse_metric_prefix)]
+        metric_prefix: String,
+
+        #[command(flatten)]
+        push_gateway: PushGatewayOptions,
+    },
+    /// Find devices on the local network by broadcasting the Tapo discovery packet, and list their
+    /// address, MAC address and model
+    Discover {
+        /// How long to wait for devices to respond [default: 5s, or 10s with `--mdns`]
+        #[arg(long, value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+
+        /// List devices announcing themselves over mDNS instead, printing each one's address and
+        /// name as it's found
+        #[arg(long, conflicts_with_all = ["subnets", "output", "register"])]
+        mdns: bool,
+
+        /// Send the discovery packet to every address in these subnets, e.g. `192.168.1.0/24`,
+        /// rather than broadcasting it on each network interface
+        #[arg(long = "subnet", value_delimiter = ',')]
+        subnets: Vec<Ipv4Net>,
+
+        /// How to list the devices found
+        #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+        output: ListFormat,
+
+        /// Path to write a devices file listing the devices found to, for `--devices-file`
+        #[arg(long)]
+        register: Option<PathBuf>,
+    },
+    /// List the supported devices on the Tapo account, along with their addresses on the local
+    /// network, found by their MAC addresses
+    CloudDevices {
+        #[command(flatten)]
+        devices: DeviceOptions,
+
+        /// How long to wait for devices on the local network to respond
+        #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+        duration: Duration,
+
+        /// URL of the TP-Link cloud's API to sign in to, which differs for accounts in some regions
+        #[arg(long, env = "CLOUD_URL", default_value = DEFAULT_CLOUD_URL)]
+        cloud_url: reqwest::Url,
+
+        /// How to list the devices
+        #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+        output: ListFormat,
     },
     /// Generate shell auto-completions
     Completion {
-        #[arg(value_enum)]
-        shell: Shell,
+        /// Shell to generate completions for
+        #[arg(value_enum, required_unless_present = "shell_option")]
+        shell: Option<CompletionShell>,
+
+        /// Shell to generate completions for, or `all` for every shell along with `--out-dir`
+        #[arg(
+            long = "shell",
+            value_name = "SHELL",
+            value_enum,
+            conflicts_with = "shell"
+        )]
+        shell_option: Option<CompletionShell>,
+
+        /// Directory to write the completions to, named as each shell expects, rather than stdout
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+    },
+    /// Generate manual pages for the command and each of its subcommands
+    Man {
+        /// Directory to write a page for each subcommand to, rather than writing them all to stdout
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+    },
+    /// Print the version along with what the binary was built from
+    Version {
+        /// How to print the build details
+        #[arg(long, value_enum, default_value_t = VersionFormat::Text)]
+        output: VersionFormat,
     },
 }
 
+/// Logs panics with a backtrace like any other error, rather than only writing them to stderr.
+fn log_panics() {
+    std::panic::set_hook(Box::new(|info| {
+        error!("{info}\n{}", Backtrace::force_capture());
+    }));
+}
+
 #[tokio::main]
-async fn main() {
-    let cli = Cli::parse();
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt::init();
+    log_panics();
+
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    match run(matches, cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if !e.reported() {
+                eprintln!("{e}");
+            }
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
 
-    let port = cli.port;
+/// Runs the subcommand, returning why it failed for `main` to report.
+async fn run(matches: ArgMatches, cli: Cli) -> Result<(), AppError> {
+    let listen = &cli.listen;
 
     match &cli.command {
-        Some(Commands::Health {}) => {
-            health::health(port).await.unwrap();
+        Some(Commands::Health {
+            host,
+            path,
+            timeout,
+            use_tls,
+            insecure_skip_verify,
+            deep,
+            require_samples,
+            metric_prefix,
+            output,
+        }) => {
+            let options = HealthOptions {
+                host: host.clone(),
+                path: path.clone(),
+                timeout: *timeout,
+                use_tls: *use_tls,
+                insecure_skip_verify: *insecure_skip_verify,
+                deep: *deep,
+                require_samples: *require_samples,
+                metric_prefix: metric_prefix.clone(),
+            };
+
+            let start = Instant::now();
+            let result = match listen.health_port {
+                Some(health_port) => {
+                    health::health(
+                        listen.health_bind_address.unwrap_or(listen.bind_address),
+                        health_port,
+                        &options,
+                    )
+                    .await
+                }
+                None => match &listen.unix_socket {
+                    Some(path) => health::health_unix(path, &options).await,
+                    None => health::health(listen.bind_address, listen.port, &options).await,
+                },
+            };
+
+            if *output == OutputFormat::Json {
+                let report = health::Report::new(&result, start.elapsed());
+                println!("{}", to_json(&report)?);
+            }
+
+            result.map_err(|error| AppError::Health {
+                error,
+                reported: *output == OutputFormat::Json,
+            })?;
+        }
+        Some(Commands::Server(server)) => {
+            let server_matches = matches.subcommand_matches("server").unwrap();
+            server.run(server_matches, listen, &matches).await?;
+        }
+        Some(Commands::ListDevices {
+            devices: device_options,
+            output,
+        }) => {
+            let file = device_options.config_file()?;
+            let list_devices = matches.subcommand_matches("list-devices").unwrap();
+            let ResolvedDevices {
+                devices,
+                username,
+                password,
+                ..
+            } = device_options.resolve(list_devices, &file.server, file.devices)?;
+            let devices = mac::find_devices(&devices, &ResolvedMacs::default()).await;
+            let credentials =
+                device_credentials(&devices, username.as_deref(), password.as_deref())
+                    .map_err(AppError::Config)?;
+
+            // Devices that can't be connected to are listed with the error rather than stopping
+            let mut listings = Vec::with_capacity(devices.len());
+            for (device, credentials) in devices.iter().zip(credentials) {
+                let listing =
+                    match client_for_device(&credentials, &device.address, device.model.as_deref())
+                        .await
+                    {
+                        Ok(client) => DeviceListing::read(client.as_ref()).await,
+                        Err(e) => DeviceListing::failed(&device.address, e),
+                    };
+                listings.push(listing);
+            }
+
+            match output {
+                ListFormat::Table => print!("{}", list::table(&listings)),
+                ListFormat::Json => println!("{}", to_json(&listings)?),
+            }
+
+            let failed = listings.iter().filter(|l| !l.is_ok()).count();
+            if failed > 0 {
+                return Err(AppError::Connection(format!(
+                    "{failed} of {} devices couldn't be read",
+                    listings.len()
+                )));
+            }
         }
-        Some(Commands::Server {
-            username,
-            password,
-            device_addresses,
+        Some(Commands::Check {
+            devices: device_options,
+            timeout,
+            fail_fast,
         }) => {
+            let file = device_options.config_file()?;
+            let check = matches.subcommand_matches("check").unwrap();
+            let ResolvedDevices {
+                devices,
+                username,
+                password,
+                ..
+            } = device_options.resolve(check, &file.server, file.devices)?;
+            let devices = mac::find_devices(&devices, &ResolvedMacs::default()).await;
+            let credentials =
+                device_credentials(&devices, username.as_deref(), password.as_deref())
+                    .map_err(AppError::Config)?;
+
+            let mut passed = 0;
+            for (device, credentials) in devices.iter().zip(credentials) {
+                let result = check::check_device(device, &credentials, *timeout).await;
+                println!("{}", check::summary(&device.address, &result));
+
+                match result {
+                    Ok(_) => passed += 1,
+                    Err(_) if *fail_fast => break,
+                    Err(_) => {}
+                }
+            }
+
+            println!("{passed} of {} devices passed", devices.len());
+            if passed < devices.len() {
+                return Err(AppError::Connection(format!(
+                    "{} devices failed the check",
+                    devices.len() - passed
+                )));
+            }
+        }
+        Some(Commands::Collect {
+            devices: device_options,
+            timeout,
+            output,
+            metric_prefix,
+            push_gateway,
+        }) => {
+            let file = device_options.config_file()?;
+            let collect = matches.subcommand_matches("collect").unwrap();
+            let pushgateway = push_gateway.resolve(collect, &file.server)?;
+            let metric_prefix = merge(
+                collect,
+                "metric_prefix",
+                metric_prefix.clone(),
+                file.server.metric_prefix.clone(),
+            );
+            let ResolvedDevices {
+                devices,
+                alias_mode,
+                plug_filter,
+                username,
+                password,
+                ..
+            } = device_options.resolve(collect, &file.server, file.devices)?;
+            let macs = ResolvedMacs::default();
+            let devices = mac::find_devices(&devices, &macs).await;
+            let credentials =
+                device_credentials(&devices, username.as_deref(), password.as_deref())
+                    .map_err(AppError::Config)?;
+
+            // Devices that can't be connected to are left out, as they would be from a scrape
             let mut clients: Vec<Box<dyn TapoClient + Send + Sync>> = Vec::new();
+            for (device, credentials) in devices.iter().zip(credentials) {
+                let connect =
+                    client_for_device(&credentials, &device.address, device.model.as_deref());
+                match tokio::time::timeout(*timeout, connect).await {
+                    Ok(Ok(client)) => clients.push(client),
+                    Ok(Err(e)) => warn!("Failed to connect to {}: {e}", device.address),
+                    Err(_) => warn!("Timed out connecting to {}", device.address),
+                }
+            }
+            if clients.is_empty() && !devices.is_empty() {
+                return Err(AppError::Connection(
+                    "Failed to connect to any device".to_string(),
+                ));
+            }
 
-            for device_address in device_addresses {
-                let client = client_for_device(username, password, device_address)
-                    .await
-                    .unwrap();
+            let config = AppConfig {
+                device_timeout: Some(*timeout),
+                plug_filter,
+                metric_prefix,
+                ..AppConfig::default()
+            }
+            .with_devices(&devices, alias_mode, &macs);
+            let metrics = p304m_prometheus_exporter::collect(clients, config)
+                .await
+                .map_err(|e| {
+                    AppError::Connection(format!("Failed to read from any device: {e}"))
+                })?;
 
-                clients.push(client);
+            if let Some(pushgateway) = pushgateway.as_ref() {
+                pushgateway.push(metrics.clone()).await.map_err(|e| {
+                    AppError::Connection(format!("Failed to push to the Pushgateway: {e}"))
+                })?;
+            }
+            // Only printed when they aren't going anywhere else
+            match output {
+                Some(path) => textfile::write_atomically(path, &metrics).map_err(|e| {
+                    AppError::Runtime(format!("Failed to write {}: {e}", path.display()))
+                })?,
+                None if pushgateway.is_none() => print!("{metrics}"),
+                None => {}
             }
+        }
+        Some(Commands::Discover {
+            duration,
+            mdns: true,
+            ..
+        }) => {
+            let mut discovery = Discovery::start()
+                .map_err(|e| AppError::Runtime(format!("Failed to start discovery: {e}")))?;
 
-            let router = exporter::app(clients);
+            let duration = duration.unwrap_or(Duration::from_secs(10));
+            let _ = tokio::time::timeout(duration, async {
+                while let Some(device) = discovery.next().await {
+                    println!("{}\t{}", device.address, device.name);
+                }
+            })
+            .await;
+        }
+        Some(Commands::Discover {
+            duration,
+            mdns: false,
+            subnets,
+            output,
+            register,
+        }) => {
+            let socket = scan::bind().await.map_err(|e| {
+                AppError::Runtime(format!("Failed to open socket for discovery: {e}"))
+            })?;
+            let targets = if subnets.is_empty() {
+                scan::broadcast_addresses()
+            } else {
+                scan::subnet_hosts(subnets)
+            };
 
-            let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
+            let duration = duration.unwrap_or(Duration::from_secs(5));
+            let found = scan::discover(&socket, &targets, duration)
                 .await
-                .unwrap();
+                .map_err(|e| AppError::Runtime(format!("Failed to send discovery packet: {e}")))?;
+
+            match output {
+                ListFormat::Table if found.is_empty() => eprintln!("No devices found"),
+                ListFormat::Table => {
+                    let mut rows = vec![["ADDRESS", "MAC", "MODEL"].map(str::to_string).to_vec()];
+                    rows.extend(
+                        found
+                            .iter()
+                            .map(|d| vec![d.address.clone(), d.mac.clone(), d.model.clone()]),
+                    );
+                    print!("{}", list::columns(&rows));
+                }
+                ListFormat::Json => println!("{}", to_json(&found)?),
+            }
 
-            println!("Server is listening on {port}");
-            axum::serve(listener, router).await.unwrap();
+            if let Some(path) = register {
+                std::fs::write(path, scan::devices_file(&found)).map_err(|e| {
+                    AppError::Runtime(format!("Failed to write {}: {e}", path.display()))
+                })?;
+            }
         }
-        Some(Commands::Completion { shell }) => {
+        Some(Commands::CloudDevices {
+            devices: device_options,
+            duration,
+            cloud_url,
+            output,
+        }) => {
+            let file = device_options.config_file()?;
+            let cloud_devices = matches.subcommand_matches("cloud-devices").unwrap();
+            let ResolvedDevices {
+                username, password, ..
+            } = device_options.resolve(cloud_devices, &file.server, file.devices)?;
+            let credentials = match (username, password) {
+                (Some(username), Some(password)) => Credentials {
+                    username,
+                    password,
+                    account: None,
+                },
+                (None, _) => return Err(AppError::missing_option("username")),
+                (Some(_), None) => return Err(AppError::missing_option("password")),
+            };
+
+            let cloud_url = merge(
+                cloud_devices,
+                "cloud_url",
+                cloud_url.clone(),
+                file.server.cloud_url.clone(),
+            );
+            let (found, unreachable) = cloud::find_devices(&cloud_url, &credentials, *duration)
+                .await
+                .map_err(AppError::Connection)?;
+            let listings: Vec<CloudListing> = found
+                .into_iter()
+                .map(|(device, address)| CloudListing {
+                    device,
+                    address: Some(address),
+                })
+                .chain(unreachable.into_iter().map(|device| CloudListing {
+                    device,
+                    address: None,
+                }))
+                .collect();
+
+            match output {
+                ListFormat::Table if listings.is_empty() => {
+                    eprintln!("No supported devices on the Tapo account")
+                }
+                ListFormat::Table => {
+                    let mut rows = vec![
+                        ["DEVICE ID", "MODEL", "MAC", "ADDRESS"]
+                            .map(str::to_string)
+                            .to_vec(),
+                    ];
+                    rows.extend(listings.iter().map(|l| {
+                        vec![
+                            l.device.device_id.clone(),
+                            l.device.model.clone(),
+                            l.device.mac.clone(),
+                            l.address.clone().unwrap_or_else(|| "not found".to_string()),
+                        ]
+                    }));
+                    print!("{}", list::columns(&rows));
+                }
+                ListFormat::Json => println!("{}", to_json(&listings)?),
+            }
+        }
+        Some(Commands::Completion {
+            shell,
+            shell_option,
+            out_dir,
+        }) => {
+            let shells = shell.or(*shell_option).unwrap_or_default().shells();
             let mut cmd = Cli::command();
-            print_completions(*shell, &mut cmd);
+            match out_dir {
+                Some(out_dir) => write_completions(&shells, &mut cmd, out_dir)
+                    .map_err(|e| AppError::Runtime(format!("Failed to write completions: {e}")))?
+                    .iter()
+                    .for_each(|p| println!("{}", p.display())),
+                None if shells.len() > 1 => {
+                    return Err(AppError::Config(
+                        "Completions for every shell can only be written with --out-dir"
+                            .to_string(),
+                    ));
+                }
+                None => shells
+                    .into_iter()
+                    .for_each(|shell| print_completions(shell, &mut cmd)),
+            }
+        }
+        Some(Commands::Man { out_dir }) => {
+            let result = match out_dir {
+                Some(out_dir) => write_man_pages(out_dir),
+                None => man_pages()
+                    .iter()
+                    .try_for_each(|page| page.render(&mut io::stdout())),
+            };
+            result.map_err(|e| AppError::Runtime(format!("Failed to write manual pages: {e}")))?;
+        }
+        Some(Commands::Version { output }) => {
+            print!("{}", BuildInfo::current().format(*output));
         }
         None => {
-            panic!("No command provided");
+            return Err(AppError::Usage(Cli::command().render_help().to_string()));
         }
     }
+    Ok(())
 }
 
-async fn client_for_device(
-    username: &str,
-    password: &str,
-    device_address: &str,
-) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
-    let client = ApiClient::new(username, password);
-    let device = client
-        .generic_device(device_address)
-        .await?
-        .get_device_info()
-        .await?;
-    match device.model.as_ref() {
-        "P304M" => {
-            let power_strip = ApiClient::new(username, password)
-                .p304(device_address)
-                .await?;
-
-            Ok(Box::new(exporter::PowerStripClient {
-                client: power_strip,
-            }))
-        }
-        "P110M" => {
-            let plug = ApiClient::new(username, password)
-                .p110(device_address)
-                .await?;
-
-            Ok(Box::new(exporter::PlugClient { client: plug }))
-        }
-        _ => {
-            panic!("Unknown model: {}", device.model);
-        }
-    }
+/// A device on the Tapo account, with its address if it was found on the local network.
+#[derive(Serialize)]
+struct CloudListing {
+    #[serde(flatten)]
+    device: CloudDevice,
+    address: Option<String>,
+}
+
+/// Formats what's printed with `--output json`.
+fn to_json(value: &impl serde::Serialize) -> Result<String, AppError> {
+    serde_json::to_string(value)
+        .map_err(|e| AppError::Runtime(format!("Failed to format the output as JSON: {e}")))
 }
 
 fn print_completions<G: Generator>(generator: G, cmd: &mut Command) {
@@ -135,3 +649,137 @@ fn print_completions<G: Generator>(generator: G, cmd: &mut Command) {
         &mut io::stdout(),
     );
 }
+
+/// Writes the completions for each shell to the directory, named as the shell expects, such as
+/// `_p304m-prometheus-exporter` for zsh, returning the paths written.
+fn write_completions(
+    shells: &[Shell],
+    cmd: &mut Command,
+    out_dir: &Path,
+) -> io::Result<Vec<PathBuf>> {
+    let name = cmd.get_name().to_string();
+    shells
+        .iter()
+        .map(|shell| generate_to(*shell, cmd, &name, out_dir))
+        .collect()
+}
+
+/// A manual page for the command and each of its subcommands, such as
+/// `p304m-prometheus-exporter-server`, all showing the command's version.
+fn man_pages() -> Vec<Man> {
+    let mut cmd = Cli::command().disable_help_subcommand(true);
+    cmd.build();
+    let source = format!(
+        "{} {}",
+        cmd.get_name(),
+        cmd.get_version().unwrap_or_default()
+    );
+
+    let mut pages = vec![Man::new(cmd.clone())];
+    pages.extend(
+        cmd.get_subcommands()
+            .filter(|s| !s.is_hide_set())
+            .map(|s| Man::new(s.clone()).source(source.clone())),
+    );
+    pages
+}
+
+fn write_man_pages(out_dir: &Path) -> io::Result<()> {
+    for page in man_pages() {
+        page.generate_to(out_dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cli, Commands, CompletionShell, write_completions, write_man_pages};
+    use clap::CommandFactory;
+    use clap::Parser;
+    use p304m_prometheus_exporter::version::VERSION;
+    use std::time::Duration;
+
+    #[test]
+    fn health_timeout() {
+        let timeout =
+            |args: &[&str]| match Cli::try_parse_from(["exporter", "health"].iter().chain(args))
+                .unwrap()
+                .command
+            {
+                Some(Commands::Health { timeout, .. }) => timeout,
+                _ => panic!("expected the health subcommand"),
+            };
+
+        assert_eq!(timeout(&[]), Duration::from_secs(5));
+        assert_eq!(timeout(&["--timeout", "2s"]), Duration::from_secs(2));
+        assert_eq!(timeout(&["--health-timeout", "1s"]), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn completions_for_each_shell() {
+        let dir = std::env::temp_dir().join(format!("completions-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+
+        let paths = write_completions(&CompletionShell::All.shells(), &mut Cli::command(), &dir);
+        let names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(paths.unwrap().len(), 5);
+        for name in [
+            "p304m-prometheus-exporter.bash",
+            "p304m-prometheus-exporter.elv",
+            "p304m-prometheus-exporter.fish",
+            "_p304m-prometheus-exporter.ps1",
+            "_p304m-prometheus-exporter",
+        ] {
+            assert!(names.contains(&name.to_string()), "{name} in {names:?}");
+        }
+
+        // Given as an option or positionally, but not both
+        for args in [["completion", "zsh"], ["completion", "--shell=zsh"]] {
+            let cli = Cli::try_parse_from(["exporter"].iter().chain(args.iter())).unwrap();
+            match cli.command {
+                Some(Commands::Completion {
+                    shell,
+                    shell_option,
+                    ..
+                }) => assert_eq!(shell.or(shell_option), Some(CompletionShell::Zsh)),
+                _ => panic!("expected the completion subcommand"),
+            }
+        }
+        assert!(Cli::try_parse_from(["exporter", "completion", "zsh", "--shell", "fish"]).is_err());
+        assert!(Cli::try_parse_from(["exporter", "completion"]).is_err());
+    }
+
+    #[test]
+    fn man_pages_for_each_subcommand() {
+        let dir = std::env::temp_dir().join(format!("man-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+
+        write_man_pages(&dir).unwrap();
+        let page = |name: &str| std::fs::read_to_string(dir.join(name));
+        let main = page("p304m-prometheus-exporter.1");
+        let server = page("p304m-prometheus-exporter-server.1");
+        let health = page("p304m-prometheus-exporter-health.1");
+        let completion = page("p304m-prometheus-exporter-completion.1");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(
+            main.unwrap()
+                .contains("p304m\\-prometheus\\-exporter\\-server(1)")
+        );
+        let server = server.unwrap();
+        assert!(server.contains("\\-\\-device\\-addresses"), "{server}");
+        assert!(server.contains("\\-\\-min\\-scrape\\-interval"), "{server}");
+        // In the title, along with the source
+        assert!(
+            server.contains(&format!("p304m-prometheus-exporter {VERSION}")),
+            "{server}"
+        );
+        assert!(health.unwrap().contains("\\-\\-timeout"));
+        assert!(completion.is_ok());
+    }
+}
@@ -0,0 +1,467 @@
+use crate::address::{parse_device_address, resolves_within};
+use crate::config::Credentials;
+use crate::connect::client_for_device;
+use crate::exporter::{Devices, TapoClient};
+use async_trait::async_trait;
+use axum::extract::{Path, Request, State};
+use axum::http::StatusCode;
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tapo::Error;
+use tracing::info;
+
+/// Creates clients for devices added through the management API.
+#[async_trait]
+pub trait DeviceConnector {
+    async fn connect(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+        model: Option<&str>,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error>;
+}
+
+/// Connects to devices as they're connected to when starting.
+pub(crate) struct ClientConnector {}
+
+#[async_trait]
+impl DeviceConnector for ClientConnector {
+    async fn connect(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+        model: Option<&str>,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+        client_for_device(credentials, address, model).await
+    }
+}
+
+/// Adds and removes the devices being read from while the server is running.
+pub struct Management {
+    devices: Devices,
+    connector: Box<dyn DeviceConnector + Send + Sync>,
+    /// Used for devices added without their own credentials.
+    credentials: Option<Credentials>,
+    /// Networks that devices must be within to be connected to with the server's credentials, so
+    /// they aren't given away to any host. They're never used when there are none.
+    credential_networks: Vec<IpNet>,
+    /// The bearer token requests must be made with, if any.
+    token: Option<String>,
+}
+
+impl Management {
+    pub fn new(
+        devices: Devices,
+        connector: Box<dyn DeviceConnector + Send + Sync>,
+        credentials: Option<Credentials>,
+        credential_networks: Vec<IpNet>,
+        token: Option<String>,
+    ) -> Self {
+        Management {
+            devices,
+            connector,
+            credentials,
+            credential_networks,
+            token,
+        }
+    }
+}
+
+/// A device to start reading from, falling back to the credentials given to the server if it's
+/// given none of its own.
+#[derive(Deserialize)]
+struct AddDevice {
+    address: String,
+    username: Option<String>,
+    password: Option<String>,
+    model: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceListing {
+    address: String,
+    model: Option<String>,
+}
+
+async fn list_devices(State(management): State<Arc<Management>>) -> Json<Vec<DeviceListing>> {
+    Json(
+        management
+            .devices
+            .list()
+            .into_iter()
+            .map(|(address, model)| DeviceListing { address, model })
+            .collect(),
+    )
+}
+
+async fn add_device(
+    State(management): State<Arc<Management>>,
+    Json(device): Json<AddDevice>,
+) -> Response {
+    let address = match parse_device_address(&device.address) {
+        Ok(address) => address,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    if management.devices.contains(&address) {
+        return (
+            StatusCode::CONFLICT,
+            format!("{address} is already being read from"),
+        )
+            .into_response();
+    }
+
+    let credentials = match (device.username, device.password) {
+        (Some(username), Some(password)) => Credentials {
+            username,
+            password,
+            account: None,
+        },
+        (None, None) => match &management.credentials {
+            Some(credentials)
+                if !management.credential_networks.is_empty()
+                    && resolves_within(&address, &management.credential_networks).await =>
+            {
+                credentials.clone()
+            }
+            Some(_) => {
+                return (
+                    StatusCode::FORBIDDEN,
+                    format!(
+                        "{address} isn't within --probe-allow-cidr, so needs a username and password of its own"
+                    ),
+                )
+                    .into_response();
+            }
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "No username and password given for the device or the server".to_string(),
+                )
+                    .into_response();
+            }
+        },
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Both a username and password are needed".to_string(),
+            )
+                .into_response();
+        }
+    };
+
+    let client = match management
+        .connector
+        .connect(&credentials, &address, device.model.as_deref())
+        .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            return (
+                StatusCode::BAD_GATEWAY,
+                format!("Failed to connect to {address}: {e}"),
+            )
+                .into_response();
+        }
+    };
+
+    // Another request may have added the device while this one was connecting
+    if management.devices.contains(&address) {
+        return (
+            StatusCode::CONFLICT,
+            format!("{address} is already being read from"),
+        )
+            .into_response();
+    }
+    management.devices.add(client).await;
+    info!("Added {address} through the management API");
+
+    (
+        StatusCode::CREATED,
+        Json(DeviceListing {
+            address,
+            model: None,
+        }),
+    )
+        .into_response()
+}
+
+async fn remove_device(
+    State(management): State<Arc<Management>>,
+    Path(address): Path<String>,
+) -> Response {
+    let address = match parse_device_address(&address) {
+        Ok(address) => address,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    if management.devices.remove(&address).await {
+        info!("Removed {address} through the management API");
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("{address} isn't being read from"),
+        )
+            .into_response()
+    }
+}
+
+/// Rejects requests without the bearer token, if one is needed.
+async fn authorize(
+    State(management): State<Arc<Management>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = management.token.as_deref() else {
+        return next.run(request).await;
+    };
+
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given.is_some_and(|given| tokens_match(given, token)) {
+        true => next.run(request).await,
+        false => (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Bearer")],
+            "A valid bearer token is needed",
+        )
+            .into_response(),
+    }
+}
+
+/// Compares the tokens in the same time however much of them matches, so the token can't be
+/// guessed a character at a time.
+fn tokens_match(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |differences, (a, b)| differences | (a ^ b))
+            == 0
+}
+
+pub fn router(management: Management) -> Router {
+    let management = Arc::new(management);
+    Router::new()
+        .route("/api/v1/devices", get(list_devices).post(add_device))
+        .route("/api/v1/devices/{address}", delete(remove_device))
+        .route_layer(middleware::from_fn_with_state(
+            management.clone(),
+            authorize,
+        ))
+        .with_state(management)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DeviceConnector, Management, router};
+    use crate::config::Credentials;
+    use crate::exporter::{AppConfig, TapoClient, split_app};
+    use crate::fake::FakeClient;
+    use async_trait::async_trait;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+    use axum::http::{Method, Request, StatusCode};
+    use http_body_util::BodyExt;
+    use tapo::Error;
+    use tower::ServiceExt;
+
+    /// Connects to any device whose password is `secret`.
+    struct TestConnector {}
+
+    #[async_trait]
+    impl DeviceConnector for TestConnector {
+        async fn connect(
+            &self,
+            credentials: &Credentials,
+            address: &str,
+            _: Option<&str>,
+        ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+            match credentials.password.as_str() {
+                "secret" => Ok(Box::new(FakeClient::new(address))),
+                _ => Err(Error::Validation {
+                    field: "password".to_string(),
+                    message: "wrong password".to_string(),
+                }),
+            }
+        }
+    }
+
+    async fn request(app: &Router, method: Method, uri: &str, body: &str) -> (StatusCode, String) {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body_bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn add_and_remove_devices() {
+        let (_, _, devices) = split_app(
+            vec![Box::new(FakeClient::new("10.0.0.1"))],
+            AppConfig::default(),
+        );
+        let app = router(Management::new(
+            devices.clone(),
+            Box::new(TestConnector {}),
+            None,
+            vec![],
+            None,
+        ));
+
+        let (status, body) = request(&app, Method::GET, "/api/v1/devices", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"[{"address":"10.0.0.1","model":null}]"#);
+
+        let (status, _) = request(
+            &app,
+            Method::POST,
+            "/api/v1/devices",
+            r#"{"address": "[FD00::10]", "username": "me", "password": "secret"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(devices.contains("fd00::10"));
+
+        let (status, _) = request(
+            &app,
+            Method::POST,
+            "/api/v1/devices",
+            r#"{"address": "fd00::10", "username": "me", "password": "secret"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, body) = request(
+            &app,
+            Method::POST,
+            "/api/v1/devices",
+            r#"{"address": "10.0.0.2", "username": "me", "password": "wrong"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(body.starts_with("Failed to connect to 10.0.0.2"), "{body}");
+
+        // Without credentials of its own or the server's
+        let (status, _) = request(
+            &app,
+            Method::POST,
+            "/api/v1/devices",
+            r#"{"address": "10.0.0.2"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = request(&app, Method::DELETE, "/api/v1/devices/10.0.0.1", "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = request(&app, Method::DELETE, "/api/v1/devices/10.0.0.1", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, body) = request(&app, Method::GET, "/api/v1/devices", "").await;
+        assert_eq!(body, r#"[{"address":"fd00::10","model":null}]"#);
+    }
+
+    #[tokio::test]
+    async fn add_device_with_server_credentials() {
+        let (_, _, devices) = split_app(vec![], AppConfig::default());
+        let management = |networks: &[&str]| {
+            router(Management::new(
+                devices.clone(),
+                Box::new(TestConnector {}),
+                Some(Credentials {
+                    username: "me".to_string(),
+                    password: "secret".to_string(),
+                    account: None,
+                }),
+                networks.iter().map(|n| n.parse().unwrap()).collect(),
+                None,
+            ))
+        };
+        let app = management(&["10.0.0.0/24"]);
+
+        let (status, _) = request(
+            &app,
+            Method::POST,
+            "/api/v1/devices",
+            r#"{"address": "10.0.0.2"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(devices.contains("10.0.0.2"));
+
+        // The server's credentials aren't sent anywhere else
+        let (status, _) = request(
+            &app,
+            Method::POST,
+            "/api/v1/devices",
+            r#"{"address": "192.168.0.2"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(!devices.contains("192.168.0.2"));
+
+        // Nor anywhere at all without an allowlist
+        let (status, _) = request(
+            &management(&[]),
+            Method::POST,
+            "/api/v1/devices",
+            r#"{"address": "10.0.0.3"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(!devices.contains("10.0.0.3"));
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_token() {
+        let (_, _, devices) = split_app(vec![], AppConfig::default());
+        let app = router(Management::new(
+            devices,
+            Box::new(TestConnector {}),
+            None,
+            vec![],
+            Some("let-me-in".to_string()),
+        ));
+        let list = |authorization: Option<&str>| {
+            let mut builder = Request::builder().uri("/api/v1/devices");
+            if let Some(authorization) = authorization {
+                builder = builder.header(AUTHORIZATION, authorization);
+            }
+            app.clone().oneshot(builder.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(list(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            list(Some("Bearer let-me-out")).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            list(Some("Basic let-me-in")).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            list(Some("Bearer let-me-in")).await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use crate::address::{
+    DeviceAddresses, merge_device_addresses, parse_bind_address, parse_device_addresses,
+    parse_device_alias,
+};
+use crate::config::{
+    self, ConfigFile, DeviceConfig, DeviceSources, ServerConfig, merge, merge_secret,
+};
+use crate::error::AppError;
+use crate::exporter::AliasMode;
+use crate::plugs::{PlugFilter, PlugMatcher};
+use crate::pushgateway::{DEFAULT_JOB, Pushgateway, parse_grouping_label};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Args};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+/// Where the server listens, which the `health` subcommand connects to as well.
+#[derive(Args, Clone)]
+// The command these are flattened into describes itself
+#[command(about = None, long_about = None)]
+pub struct ListenOptions {
+    /// Port number the server is or should be running on
+    #[arg(short, long, env, default_value_t = 8080)]
+    pub port: u16,
+
+    /// IP address the server is or should be listening on, such as `0.0.0.0`, `::` or `[fd00::10]`
+    #[arg(short, long, env, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED), value_parser = parse_bind_address)]
+    pub bind_address: IpAddr,
+
+    /// Path of the Unix domain socket the server is or should be listening on instead of a TCP port
+    #[arg(long, env)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Port number health checks are or should be served on, if separate from the metrics
+    #[arg(long, env)]
+    pub health_port: Option<u16>,
+
+    /// IP address health checks are or should be served on, if separate from the metrics, defaulting to the bind address
+    #[arg(long, env, requires = "health_port", value_parser = parse_bind_address)]
+    pub health_bind_address: Option<IpAddr>,
+}
+
+/// Options for pushing the metrics to a Prometheus Pushgateway, shared by the server and `collect`.
+#[derive(Args)]
+pub struct PushGatewayOptions {
+    /// URL of a Prometheus Pushgateway, e.g. `http://localhost:9091`, to push the metrics to
+    #[arg(long, env = "PUSH_GATEWAY_URL", visible_alias = "pushgateway-url")]
+    push_gateway_url: Option<reqwest::Url>,
+
+    /// Username to push to the Pushgateway with
+    #[arg(long, env = "PUSH_GATEWAY_USERNAME")]
+    push_gateway_username: Option<String>,
+
+    /// Password to push to the Pushgateway with
+    #[arg(long, env = "PUSH_GATEWAY_PASSWORD", hide_env_values = true)]
+    push_gateway_password: Option<String>,
+
+    /// Job to group the metrics under on the Pushgateway
+    #[arg(long, env = "PUSH_JOB", default_value = DEFAULT_JOB)]
+    push_job: String,
+
+    /// Other labels to group the metrics under on the Pushgateway, as `name=value`, e.g.
+    /// `instance=kitchen`
+    #[arg(long = "push-grouping-label", env = "PUSH_GROUPING_LABELS", value_delimiter = ',', value_parser = parse_grouping_label)]
+    push_grouping_labels: Vec<(String, String)>,
+}
+
+/// Options for finding and connecting to the devices, shared by the subcommands that read from
+/// them.
+#[derive(Args)]
+pub struct DeviceOptions {
+    /// Path of a TOML file to read settings from, which are overridden by any given as options
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+
+    /// Username for the Tapo service
+    #[arg(short, long, env = "TAPO_USERNAME", hide_env_values = true)]
+    username: Option<String>,
+
+    /// Path of a file holding the username for the Tapo service, such as a Docker secret
+    #[arg(long, env = "TAPO_USERNAME_FILE", conflicts_with = "username")]
+    username_file: Option<PathBuf>,
+
+    /// Password for the Tapo service
+    #[arg(short, long, env = "TAPO_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// Path of a file holding the password for the Tapo service, such as a Docker secret
+    #[arg(long, env = "TAPO_PASSWORD_FILE", conflicts_with = "password")]
+    password_file: Option<PathBuf>,
+
+    /// IP addresses or DNS names for the devices, separated by commas or spaces, which can be
+    /// given more than once. Each can be followed by its model, such as `10.0.0.5:P304M`, to
+    /// connect without asking the device for it. Devices can also be given by MAC address, such
+    /// as `mac:AA-BB-CC-DD-EE-FF`, to find their IP addresses on the local network
+    #[arg(
+        short,
+        long,
+        visible_alias = "device",
+        env = "IP_ADDRESS",
+        hide_env_values = true,
+        action = ArgAction::Append,
+        value_parser = parse_device_addresses
+    )]
+    device_addresses: Vec<DeviceAddresses>,
+
+    /// Path of a file listing a device's address on each line, optionally followed by its model
+    #[arg(long, env = "DEVICES_FILE")]
+    devices_file: Option<PathBuf>,
+
+    /// Alias for a device, given as its address and alias separated by `=`, such as
+    /// `10.0.0.5=garage-strip`, which can be given more than once
+    #[arg(long = "device-alias", env = "DEVICE_ALIASES", value_delimiter = ',', value_parser = parse_device_alias)]
+    device_aliases: Vec<(String, String)>,
+
+    /// How devices' aliases are added to the labels of their plugs' power use. Power strips' plugs
+    /// always keep their nicknames and get a `strip_alias` label
+    #[arg(long, env = "ALIAS_MODE", value_enum, default_value_t = AliasMode::Label)]
+    alias_mode: AliasMode,
+
+    /// Plugs to read from despite matching `--exclude-plug`, or only these if that isn't given,
+    /// by nickname glob, `position:N` or `id:ID`, which can be given more than once
+    #[arg(long = "include-plug", env = "INCLUDE_PLUGS", value_delimiter = ',')]
+    include_plugs: Vec<PlugMatcher>,
+
+    /// Plugs not to read from, by nickname glob, `position:N` or `id:ID`, which can be given more
+    /// than once
+    #[arg(long = "exclude-plug", env = "EXCLUDE_PLUGS", value_delimiter = ',')]
+    exclude_plugs: Vec<PlugMatcher>,
+}
+
+/// The devices to read from, along with the credentials given for every device.
+pub struct ResolvedDevices {
+    pub devices: Vec<DeviceConfig>,
+    pub alias_mode: AliasMode,
+    pub plug_filter: PlugFilter,
+    pub sources: DeviceSources,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl ListenOptions {
+    /// Picks where to listen as given as options, falling back to the configuration file.
+    pub fn resolve(&self, matches: &ArgMatches, settings: &ServerConfig) -> ListenOptions {
+        ListenOptions {
+            port: merge(matches, "port", self.port, settings.port),
+            bind_address: merge(
+                matches,
+                "bind_address",
+                self.bind_address,
+                settings.bind_address,
+            ),
+            unix_socket: merge(
+                matches,
+                "unix_socket",
+                self.unix_socket.clone(),
+                settings.unix_socket.clone().map(Some),
+            ),
+            health_port: merge(
+                matches,
+                "health_port",
+                self.health_port,
+                settings.health_port.map(Some),
+            ),
+            health_bind_address: merge(
+                matches,
+                "health_bind_address",
+                self.health_bind_address,
+                settings.health_bind_address.map(Some),
+            ),
+        }
+    }
+}
+
+impl PushGatewayOptions {
+    /// Creates a pusher for the Pushgateway given as options, falling back to the one in the
+    /// configuration file, if any.
+    pub fn resolve(
+        &self,
+        matches: &ArgMatches,
+        settings: &ServerConfig,
+    ) -> Result<Option<Pushgateway>, AppError> {
+        let url = merge(
+            matches,
+            "push_gateway_url",
+            self.push_gateway_url.clone(),
+            settings.push_gateway_url.clone().map(Some),
+        );
+        let username = merge(
+            matches,
+            "push_gateway_username",
+            self.push_gateway_username.clone(),
+            settings.push_gateway_username.clone().map(Some),
+        );
+        let password = merge(
+            matches,
+            "push_gateway_password",
+            self.push_gateway_password.clone(),
+            settings.push_gateway_password.clone().map(Some),
+        );
+        let job = merge(
+            matches,
+            "push_job",
+            self.push_job.clone(),
+            settings.push_job.clone(),
+        );
+        let grouping = merge(
+            matches,
+            "push_grouping_labels",
+            self.push_grouping_labels.iter().cloned().collect(),
+            (!settings.push_grouping_labels.is_empty())
+                .then(|| settings.push_grouping_labels.clone()),
+        );
+
+        let credentials = match (username, password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            (Some(_), None) => return Err(AppError::missing_option("push-gateway-password")),
+            (None, Some(_)) => return Err(AppError::missing_option("push-gateway-username")),
+            (None, None) => None,
+        };
+        match url {
+            Some(url) if !matches!(url.scheme(), "http" | "https") => Err(AppError::Config(
+                format!("Invalid Pushgateway URL {url}: only http and https are supported"),
+            )),
+            _ if job.is_empty() => Err(AppError::Config(
+                "The Pushgateway job can't be empty".to_string(),
+            )),
+            _ if grouping.contains_key("job") => Err(AppError::Config(
+                "The Pushgateway job is given by push_job, not as a grouping label".to_string(),
+            )),
+            Some(url) => Ok(Some(Pushgateway::new(&url, &job, &grouping, credentials))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl DeviceOptions {
+    /// Reads the configuration file, if one was given.
+    pub fn config_file(&self) -> Result<ConfigFile, AppError> {
+        match &self.config {
+            Some(path) => config::load(path).map_err(AppError::Config),
+            None => Ok(ConfigFile::default()),
+        }
+    }
+
+    /// Picks the devices and credentials given as options, falling back to those in the
+    /// configuration file, failing if the devices or credentials can't be read.
+    pub fn resolve(
+        &self,
+        matches: &ArgMatches,
+        settings: &ServerConfig,
+        config_devices: Vec<DeviceConfig>,
+    ) -> Result<ResolvedDevices, AppError> {
+        let username = merge_secret(
+            matches,
+            "username",
+            self.username.clone(),
+            self.username_file.clone(),
+            settings.username.clone(),
+            settings.username_file.clone(),
+        );
+        let password = merge_secret(
+            matches,
+            "password",
+            self.password.clone(),
+            self.password_file.clone(),
+            settings.password.clone(),
+            settings.password_file.clone(),
+        );
+        let (username, password) = match (username, password) {
+            (Ok(username), Ok(password)) => (username, password),
+            (Err(e), _) | (_, Err(e)) => return Err(AppError::Config(e)),
+        };
+        let devices_file = merge(
+            matches,
+            "devices_file",
+            self.devices_file.clone(),
+            settings.devices_file.clone().map(Some),
+        );
+        let sources = DeviceSources {
+            addresses: match matches.value_source("device_addresses") {
+                None | Some(ValueSource::DefaultValue) => None,
+                _ => Some(
+                    merge_device_addresses(&self.device_addresses)
+                        .into_iter()
+                        .map(|device| DeviceConfig {
+                            address: device.address,
+                            model: device.model,
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+            },
+            config_file: self.config.clone(),
+            devices_file,
+            aliases: match matches.value_source("device_aliases") {
+                None | Some(ValueSource::DefaultValue) => BTreeMap::new(),
+                _ => self.device_aliases.iter().cloned().collect(),
+            },
+        };
+        let devices = sources.resolve(config_devices).map_err(AppError::Config)?;
+
+        Ok(ResolvedDevices {
+            devices,
+            alias_mode: merge(matches, "alias_mode", self.alias_mode, settings.alias_mode),
+            plug_filter: PlugFilter::new(
+                merge(
+                    matches,
+                    "include_plugs",
+                    self.include_plugs.clone(),
+                    settings.include_plugs.clone(),
+                ),
+                merge(
+                    matches,
+                    "exclude_plugs",
+                    self.exclude_plugs.clone(),
+                    settings.exclude_plugs.clone(),
+                ),
+            ),
+            sources,
+            username,
+            password,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DeviceOptions;
+    use crate::address::merge_device_addresses;
+    use clap::{CommandFactory, Parser};
+
+    #[derive(Parser)]
+    struct Command {
+        #[command(flatten)]
+        devices: DeviceOptions,
+    }
+
+    fn device_addresses(args: &[&str]) -> Result<Vec<String>, clap::Error> {
+        let command = Command::try_parse_from(["exporter"].iter().chain(args))?;
+        Ok(merge_device_addresses(&command.devices.device_addresses)
+            .into_iter()
+            .map(|d| d.address)
+            .collect())
+    }
+
+    #[test]
+    fn repeated_device_addresses() {
+        assert_eq!(
+            device_addresses(&[
+                "--device-addresses",
+                "10.0.0.5",
+                "-d",
+                "10.0.0.6",
+                "--device-addresses",
+                "10.0.0.5",
+            ])
+            .unwrap(),
+            ["10.0.0.5", "10.0.0.6"]
+        );
+    }
+
+    #[test]
+    fn device_address_lists() {
+        assert_eq!(
+            device_addresses(&[
+                "--device-addresses",
+                "10.0.0.5, 10.0.0.6",
+                "--device-addresses",
+                "Power-Strip.local 10.0.0.7",
+                "--device-addresses",
+                "power-strip.local",
+            ])
+            .unwrap(),
+            ["10.0.0.5", "10.0.0.6", "Power-Strip.local", "10.0.0.7"]
+        );
+
+        let e = device_addresses(&["--device-addresses", "10.0.0.5,,10.0.0.6"]).unwrap_err();
+        assert!(e.to_string().contains("empty entry"), "{e}");
+        assert!(device_addresses(&["--device-addresses", "http://10.0.0.5"]).is_err());
+    }
+
+    #[test]
+    fn device_addresses_from_environment() {
+        // Setting the variable would leak into the other tests parsing the command line, so this
+        // checks where it's read from and that its value is parsed like the option's
+        let command = Command::command();
+        let argument = command
+            .get_arguments()
+            .find(|a| a.get_id() == "device_addresses")
+            .unwrap();
+        assert_eq!(argument.get_env(), Some(std::ffi::OsStr::new("IP_ADDRESS")));
+
+        assert_eq!(
+            device_addresses(&["--device-addresses", "10.0.0.5,10.0.0.6 10.0.0.7"]).unwrap(),
+            ["10.0.0.5", "10.0.0.6", "10.0.0.7"]
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use crate::exporter::Inventory;
+use crate::version::VERSION;
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::{Counter, Gauge, MeterProvider};
+use opentelemetry_otlp::{MetricExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::metrics::data::ResourceMetrics;
+use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+use opentelemetry_sdk::metrics::reader::MetricReader;
+use opentelemetry_sdk::metrics::{
+    InstrumentKind, ManualReader, Pipeline, SdkMeterProvider, Temporality,
+};
+use prometheus_client::metrics::counter::Counter as ErrorCounter;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// How metrics are sent to the OTLP endpoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+pub enum OtlpProtocol {
+    /// Protobuf posted over HTTP
+    #[default]
+    #[value(name = "http/protobuf")]
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+    /// gRPC
+    #[cfg(feature = "otlp-grpc")]
+    #[serde(rename = "grpc")]
+    Grpc,
+}
+
+/// Pushes the metrics read from the devices to an OTLP endpoint, as gauges matching those served
+/// at `/metrics` and named with the same prefix, along with the energy used by each plug as a sum if it's being kept.
+pub struct OtlpExporter {
+    // Kept so the instruments stay registered
+    _providers: [SdkMeterProvider; 2],
+    readers: [Arc<ManualReader>; 2],
+    exporter: MetricExporter,
+    power_use: Gauge<u64>,
+    device_info: Gauge<u64>,
+    energy_total: Counter<f64>,
+    /// The energy total last recorded for each plug, by the ID of the device it's part of and its
+    /// own ID.
+    energy_recorded: Mutex<HashMap<(String, String), f64>>,
+    errors: ErrorCounter,
+}
+
+impl OtlpExporter {
+    /// Creates an exporter for the endpoint, which is the full URL metrics are posted to over
+    /// HTTP, e.g. `http://localhost:4318/v1/metrics`, or the address of the collector over gRPC,
+    /// e.g. `http://localhost:4317`. The instruments' names start with the prefix, followed by an
+    /// underscore.
+    pub fn new(
+        endpoint: &Url,
+        protocol: OtlpProtocol,
+        prefix: &str,
+    ) -> Result<Self, opentelemetry_otlp::ExporterBuildError> {
+        let exporter = match protocol {
+            OtlpProtocol::HttpProtobuf => MetricExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint.as_str())
+                .with_timeout(Duration::from_secs(10))
+                .build()?,
+            #[cfg(feature = "otlp-grpc")]
+            OtlpProtocol::Grpc => MetricExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint.as_str())
+                .with_timeout(Duration::from_secs(10))
+                .build()?,
+        };
+
+        // Only what was recorded since the last push is sent, so devices that are removed or
+        // couldn't be read stop being reported rather than repeating their last reading
+        let (provider, reader) = meter_provider(Temporality::Delta);
+        // Whereas the energy totals are sent as they are, so carrying them over from before the
+        // exporter restarted isn't counted as using it all again
+        let (energy_provider, energy_reader) = meter_provider(Temporality::Cumulative);
+
+        let meter = provider.meter(env!("CARGO_PKG_NAME"));
+        let power_use = meter
+            .u64_gauge(format!("{prefix}_power_use_watts"))
+            .with_description("Current power use in watts")
+            .with_unit("W")
+            .build();
+        let device_info = meter
+            .u64_gauge(format!("{prefix}_device_info"))
+            .with_description("Device information")
+            .build();
+        let energy_total = energy_provider
+            .meter(env!("CARGO_PKG_NAME"))
+            .f64_counter(format!("{prefix}_energy_total"))
+            .with_description("Energy used by the plug in watt-hours")
+            .with_unit("Wh")
+            .build();
+
+        Ok(OtlpExporter {
+            _providers: [provider, energy_provider],
+            readers: [reader, energy_reader],
+            exporter,
+            power_use,
+            device_info,
+            energy_total,
+            energy_recorded: Mutex::new(HashMap::new()),
+            errors: ErrorCounter::default(),
+        })
+    }
+
+    /// Counts the pushes that failed.
+    pub fn errors(&self) -> ErrorCounter {
+        self.errors.clone()
+    }
+
+    /// Records what was last read from a device, to be sent on the next push.
+    pub fn record(&self, address: &str, inventory: &Inventory) {
+        let info = &inventory.device_info;
+        let mut device_attributes = vec![
+            KeyValue::new("power_strip_id", info.power_strip_id.clone()),
+            KeyValue::new("ip_address", address.to_string()),
+        ];
+
+        let mut info_attributes = device_attributes.clone();
+        info_attributes.extend([
+            KeyValue::new("model", info.model.clone()),
+            KeyValue::new("firmware_version", info.firmware_version.clone()),
+            KeyValue::new("hardware_version", info.hardware_version.clone()),
+            KeyValue::new("mac_address", info.mac_address.clone()),
+        ]);
+        if let Some(account) = info.account.0.as_ref() {
+            info_attributes.push(KeyValue::new("account", account.clone()));
+        }
+        self.device_info.record(1, &info_attributes);
+
+        for child in inventory.children.iter() {
+            let Some(watts) = inventory.power_watts.get(&child.device_id) else {
+                continue;
+            };
+
+            device_attributes.truncate(1);
+            device_attributes.extend([
+                KeyValue::new(
+                    "ip_address",
+                    inventory.labelled_address(address).to_string(),
+                ),
+                KeyValue::new("device_id", child.device_id.clone()),
+                KeyValue::new("nickname", child.nickname.clone()),
+                KeyValue::new("position", i64::from(child.position)),
+            ]);
+            self.power_use.record(*watts, &device_attributes);
+        }
+    }
+
+    /// Records the energy a plug has used in watt-hours, as its total has grown since it was last
+    /// recorded.
+    pub fn record_energy(&self, power_strip_id: &str, device_id: &str, watt_hours: f64) {
+        let mut recorded = self.energy_recorded.lock().unwrap();
+        let last = recorded
+            .entry((power_strip_id.to_string(), device_id.to_string()))
+            .or_default();
+        if watt_hours > *last {
+            self.energy_total.add(
+                watt_hours - *last,
+                &[
+                    KeyValue::new("power_strip_id", power_strip_id.to_string()),
+                    KeyValue::new("device_id", device_id.to_string()),
+                ],
+            );
+            *last = watt_hours;
+        }
+    }
+
+    /// Sends everything recorded since the last push.
+    pub async fn push(&self) -> OTelSdkResult {
+        let result = self.send().await;
+        if result.is_err() {
+            self.errors.inc();
+        }
+        result
+    }
+
+    async fn send(&self) -> OTelSdkResult {
+        for reader in self.readers.iter() {
+            let mut metrics = ResourceMetrics::default();
+            reader.collect(&mut metrics)?;
+            // Nothing to send when energy totals aren't being kept
+            if metrics.scope_metrics().next().is_some() {
+                self.exporter.export(&metrics).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A meter provider whose metrics are collected from the reader when pushing, described as coming
+/// from this version of the exporter.
+fn meter_provider(temporality: Temporality) -> (SdkMeterProvider, Arc<ManualReader>) {
+    let reader = Arc::new(
+        ManualReader::builder()
+            .with_temporality(temporality)
+            .build(),
+    );
+    let provider = SdkMeterProvider::builder()
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .with_attribute(KeyValue::new("service.version", VERSION))
+                .build(),
+        )
+        .with_reader(SharedReader(reader.clone()))
+        .build();
+    (provider, reader)
+}
+
+/// Lets the reader be collected from directly while the meter provider also holds it.
+#[derive(Debug)]
+struct SharedReader(Arc<ManualReader>);
+
+impl MetricReader for SharedReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> OTelSdkResult {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OtlpExporter, OtlpProtocol};
+    use crate::exporter::{AccountLabel, ChildDevice, DeviceInfo, Inventory};
+    use axum::Router;
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::routing::post;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn push_power_use() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let collector = Router::new()
+            .route(
+                "/v1/metrics",
+                post(
+                    |State(tx): State<mpsc::Sender<(HeaderMap, Bytes)>>,
+                     headers: HeaderMap,
+                     body: Bytes| async move {
+                        tx.send((headers, body)).await.unwrap();
+                    },
+                ),
+            )
+            .with_state(tx);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, collector).await.unwrap() });
+
+        let exporter = OtlpExporter::new(
+            &format!("http://{address}/v1/metrics").parse().unwrap(),
+            OtlpProtocol::HttpProtobuf,
+            "tapo",
+        )
+        .unwrap();
+        exporter.record(
+            "10.0.0.1",
+            &Inventory {
+                device_info: DeviceInfo {
+                    power_strip_id: "123".to_string(),
+                    ip_address: "10.0.0.1".to_string(),
+                    model: "P304M".to_string(),
+                    firmware_version: "1.0".to_string(),
+                    hardware_version: "1.0".to_string(),
+                    mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+                    account: AccountLabel::default(),
+                    group: String::new(),
+                    master_on: None,
+                },
+                alias: None,
+                given_mac: None,
+                sensors: vec![],
+                children: vec![ChildDevice {
+                    device_id: "456".to_string(),
+                    nickname: "Living room".to_string(),
+                    device_on: true,
+                    on_time_seconds: None,
+                    overloaded: None,
+                    position: 1,
+                }],
+                power_watts: HashMap::from([("456".to_string(), 45)]),
+            },
+        );
+        exporter.record_energy("123", "456", 12.5);
+        exporter.push().await.unwrap();
+
+        let mut body = vec![];
+        for _ in 0..2 {
+            let (headers, bytes) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(headers[CONTENT_TYPE], "application/x-protobuf");
+            body.extend_from_slice(&bytes);
+        }
+        // Strings are kept as-is in the protobuf encoding
+        for expected in [
+            "tapo_power_use_watts",
+            "tapo_device_info",
+            "Living room",
+            "tapo_energy_total",
+            "service.version",
+        ] {
+            assert!(
+                body.windows(expected.len())
+                    .any(|w| w == expected.as_bytes()),
+                "{expected}"
+            );
+        }
+    }
+}
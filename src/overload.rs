@@ -0,0 +1,75 @@
+use crate::exporter::PowerUse;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::registry::Registry;
+use std::collections::HashMap;
+
+/// Counts how many times each plug's overload protection has tripped. Plugs only report whether
+/// they're overloaded rather than how many times they have been, so each time one is read as
+/// overloaded having not been before is counted, which misses any that start and end between reads.
+#[derive(Default)]
+pub struct OverloadEvents {
+    events: Family<PowerUse, Counter>,
+    /// Whether each plug was overloaded when last read.
+    overloaded: HashMap<PowerUse, bool>,
+}
+
+impl OverloadEvents {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "overload_events",
+            "Number of times the plug has been read as overloaded having not been before",
+            self.events.clone(),
+        );
+    }
+
+    /// Records whether the plug is overloaded, counting it if it wasn't when last read.
+    pub fn record(&mut self, labels: &PowerUse, overloaded: bool) {
+        let events = self.events.get_or_create(labels);
+        let was_overloaded = self.overloaded.insert(labels.clone(), overloaded);
+        if overloaded && was_overloaded != Some(true) {
+            events.inc();
+        }
+    }
+
+    pub fn remove(&mut self, labels: &PowerUse) {
+        self.overloaded.remove(labels);
+        self.events.remove(labels);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OverloadEvents;
+    use crate::exporter::{AliasLabel, PowerUse};
+
+    #[test]
+    fn counts_each_overload() {
+        let mut overloads = OverloadEvents::default();
+        let labels = PowerUse {
+            power_strip_id: "123".to_string(),
+            ip_address: "10.0.0.1".to_string(),
+            device_id: "456".to_string(),
+            nickname: "Heater".to_string(),
+            group: String::new(),
+            position: 1,
+            strip_alias: AliasLabel::default(),
+        };
+        let count = |overloads: &OverloadEvents| overloads.events.get(&labels).unwrap().get();
+
+        overloads.record(&labels, false);
+        assert_eq!(count(&overloads), 0);
+        overloads.record(&labels, true);
+        overloads.record(&labels, true);
+        assert_eq!(count(&overloads), 1);
+        overloads.record(&labels, false);
+        overloads.record(&labels, true);
+        assert_eq!(count(&overloads), 2);
+
+        overloads.remove(&labels);
+        assert!(overloads.events.get(&labels).is_none());
+        // Counted again if it's still overloaded when it's read again
+        overloads.record(&labels, true);
+        assert_eq!(count(&overloads), 1);
+    }
+}
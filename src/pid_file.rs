@@ -0,0 +1,52 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// A file holding the process's ID for process managers to track it by, which is deleted when
+/// dropped, as happens when the server stops.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the process's ID to the file, replacing any left behind by a previous run.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to delete {}: {e}", self.path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PidFile;
+
+    #[test]
+    fn deleted_when_dropped() {
+        let dir = std::env::temp_dir().join(format!("pid-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("exporter.pid");
+        std::fs::write(&path, "1\n").unwrap();
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+
+        assert!(PidFile::create(&dir.join("missing").join("exporter.pid")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
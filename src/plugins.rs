@@ -0,0 +1,373 @@
+use crate::address::url_host;
+use crate::config::Credentials;
+use crate::connect::KnownModel;
+use crate::exporter::{
+    H100Client, PlugClient, PlugNonEmClient, PowerStripClient, PowerStripNonEmClient, TapoClient,
+};
+use async_trait::async_trait;
+use std::sync::{Arc, LazyLock, RwLock};
+use tapo::{ApiClient, Error, GenericDeviceHandler};
+
+/// Connects to the models of device it supports, so devices can be read from without changing
+/// how the exporter picks a client for each model.
+#[async_trait]
+pub trait DevicePlugin: Send + Sync {
+    /// The models the plugin connects to, as the devices report them.
+    fn supported_models(&self) -> &[&str];
+
+    /// Connects to the device at the address, which is an IP address or DNS name.
+    async fn build_client(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error>;
+
+    /// Connects to the device over the session it was asked for its model on, so it isn't logged
+    /// in to twice. Plugins that can't read from it over that session connect to it afresh.
+    async fn take_over(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+        _device: GenericDeviceHandler,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+        self.build_client(credentials, address).await
+    }
+}
+
+/// Which of the tapo crate's handlers connects to a model, which decides the client it's read with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handler {
+    P100,
+    P105,
+    P110,
+    P115,
+    P300,
+    P304,
+    P306,
+    H100,
+}
+
+/// The handler for each model that can be read from, in the order they're listed as supported.
+/// Models the tapo crate has no handler of their own for use the one for a model they're
+/// compatible with.
+const HANDLERS: &[(KnownModel, Handler)] = &[
+    (KnownModel::P304M, Handler::P304),
+    (KnownModel::P110M, Handler::P110),
+    (KnownModel::P115, Handler::P115),
+    // The EP25 is an outdoor P110
+    (KnownModel::EP25, Handler::P110),
+    (KnownModel::P100, Handler::P100),
+    (KnownModel::P105, Handler::P105),
+    (KnownModel::P300, Handler::P300),
+    (KnownModel::P306, Handler::P306),
+    (KnownModel::H100, Handler::H100),
+];
+
+impl Handler {
+    /// Connects to the device with the handler, wrapped in the client that reads from it.
+    async fn connect(
+        self,
+        credentials: &Credentials,
+        address: &str,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+        let device = ApiClient::new(&credentials.username, &credentials.password)
+            .generic_device(url_host(address))
+            .await?;
+        Ok(self.take_over(credentials, address, device))
+    }
+
+    /// Reads from the device over the session it's already logged in to, wrapped in the client
+    /// for the handler.
+    fn take_over(
+        self,
+        credentials: &Credentials,
+        address: &str,
+        device: GenericDeviceHandler,
+    ) -> Box<dyn TapoClient + Send + Sync> {
+        let address = address.to_string();
+        let account = credentials.account.clone();
+
+        match self {
+            Handler::P304 => Box::new(PowerStripClient {
+                address,
+                account,
+                client: device.into(),
+            }),
+            Handler::P110 | Handler::P115 => Box::new(PlugClient {
+                address,
+                account,
+                client: device.into(),
+            }),
+            Handler::P100 | Handler::P105 => Box::new(PlugNonEmClient {
+                address,
+                account,
+                client: device.into(),
+            }),
+            Handler::P300 | Handler::P306 => Box::new(PowerStripNonEmClient {
+                address,
+                account,
+                client: device.into(),
+            }),
+            Handler::H100 => Box::new(H100Client {
+                address,
+                account,
+                client: device.into(),
+            }),
+        }
+    }
+}
+
+/// Connects to one of the models the exporter supports itself.
+pub struct BuiltinPlugin {
+    handler: Handler,
+    names: [&'static str; 1],
+}
+
+impl BuiltinPlugin {
+    pub fn new(model: KnownModel, handler: Handler) -> Self {
+        BuiltinPlugin {
+            handler,
+            names: [model.name()],
+        }
+    }
+}
+
+#[async_trait]
+impl DevicePlugin for BuiltinPlugin {
+    fn supported_models(&self) -> &[&str] {
+        &self.names
+    }
+
+    async fn build_client(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+        self.handler.connect(credentials, address).await
+    }
+
+    async fn take_over(
+        &self,
+        credentials: &Credentials,
+        address: &str,
+        device: GenericDeviceHandler,
+    ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+        Ok(self.handler.take_over(credentials, address, device))
+    }
+}
+
+/// The plugins consulted for a client for each model, with those registered later taking
+/// precedence for the models they support.
+#[derive(Default)]
+pub struct PluginRegistry {
+    plugins: Vec<Arc<dyn DevicePlugin>>,
+}
+
+impl PluginRegistry {
+    /// A registry with a plugin for each model the exporter supports itself.
+    pub fn with_builtins() -> Self {
+        let mut registry = PluginRegistry::default();
+        for (model, handler) in HANDLERS {
+            registry.register(Arc::new(BuiltinPlugin::new(*model, *handler)));
+        }
+        registry
+    }
+
+    pub fn register(&mut self, plugin: Arc<dyn DevicePlugin>) {
+        self.plugins.push(plugin);
+    }
+
+    /// The plugin for the model, ignoring case and any region, along with the model as the plugin
+    /// names it.
+    pub fn plugin_for(&self, model: &str) -> Option<(Arc<dyn DevicePlugin>, String)> {
+        let model = normalize_model(model);
+        self.plugins.iter().rev().find_map(|plugin| {
+            let name = plugin
+                .supported_models()
+                .iter()
+                .find(|m| m.eq_ignore_ascii_case(model))?
+                .to_string();
+            Some((plugin.clone(), name))
+        })
+    }
+
+    /// Every supported model, in the order they were registered, for listing in errors.
+    pub fn supported_models(&self) -> Vec<String> {
+        let mut models: Vec<String> = Vec::new();
+        for model in self.plugins.iter().flat_map(|p| p.supported_models()) {
+            if !models.iter().any(|m| m.eq_ignore_ascii_case(model)) {
+                models.push(model.to_string());
+            }
+        }
+        models
+    }
+}
+
+/// The model without the region devices can report along with it, e.g. `P110M` for `P110M(EU)`.
+pub fn normalize_model(model: &str) -> &str {
+    let model = model.trim();
+    let end = model
+        .find(|c: char| c == '(' || c.is_whitespace())
+        .unwrap_or(model.len());
+    &model[..end]
+}
+
+static REGISTRY: LazyLock<RwLock<PluginRegistry>> =
+    LazyLock::new(|| RwLock::new(PluginRegistry::with_builtins()));
+
+/// Adds a plugin to those consulted when connecting to devices, taking precedence over the
+/// built-in ones for the models it supports.
+pub fn register(plugin: Arc<dyn DevicePlugin>) {
+    REGISTRY.write().unwrap().register(plugin);
+}
+
+/// The plugin for the model, ignoring case and any region, along with the model as the plugin
+/// names it.
+pub fn plugin_for(model: &str) -> Option<(Arc<dyn DevicePlugin>, String)> {
+    REGISTRY.read().unwrap().plugin_for(model)
+}
+
+/// Checks a model has a plugin, returning it as the plugin names it.
+pub fn parse_model(value: &str) -> Result<String, String> {
+    plugin_for(value).map(|(_, model)| model).ok_or_else(|| {
+        format!(
+            "{value} is not a supported model, expected one of {}",
+            supported_models().join(", ")
+        )
+    })
+}
+
+/// Every model a plugin is registered for.
+pub fn supported_models() -> Vec<String> {
+    REGISTRY.read().unwrap().supported_models()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        DevicePlugin, HANDLERS, Handler, PluginRegistry, normalize_model, register,
+        supported_models,
+    };
+    use crate::config::Credentials;
+    use crate::connect::KnownModel;
+    use crate::connect::client_for_device;
+    use crate::exporter::TapoClient;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use tapo::Error;
+
+    struct TestPlugin {
+        models: Vec<&'static str>,
+    }
+
+    #[async_trait]
+    impl DevicePlugin for TestPlugin {
+        fn supported_models(&self) -> &[&str] {
+            &self.models
+        }
+
+        async fn build_client(
+            &self,
+            _: &Credentials,
+            address: &str,
+        ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+            Err(Error::Other(anyhow::anyhow!(
+                "Built by a plugin for {address}"
+            )))
+        }
+    }
+
+    #[test]
+    fn later_plugins_take_precedence() {
+        let mut registry = PluginRegistry::with_builtins();
+        assert_eq!(
+            registry.supported_models(),
+            [
+                "P304M", "P110M", "P115", "EP25", "P100", "P105", "P300", "P306", "H100"
+            ]
+        );
+        assert!(registry.plugin_for("EP40").is_none());
+
+        let (_, name) = registry.plugin_for("p304m").unwrap();
+        assert_eq!(name, "P304M");
+
+        let plugin: Arc<dyn DevicePlugin> = Arc::new(TestPlugin {
+            models: vec!["p304m", "KP125M"],
+        });
+        registry.register(plugin.clone());
+        let (found, name) = registry.plugin_for("P304M").unwrap();
+        assert!(Arc::ptr_eq(&found, &plugin));
+        assert_eq!(name, "p304m");
+        assert_eq!(
+            registry.supported_models(),
+            [
+                "P304M", "P110M", "P115", "EP25", "P100", "P105", "P300", "P306", "H100", "KP125M"
+            ]
+        );
+    }
+
+    #[test]
+    fn handlers() {
+        let handler_for = |model| {
+            HANDLERS
+                .iter()
+                .find(|(m, _)| *m == model)
+                .map(|(_, handler)| *handler)
+        };
+        assert_eq!(handler_for(KnownModel::P304M), Some(Handler::P304));
+        assert_eq!(handler_for(KnownModel::EP25), Some(Handler::P110));
+        assert_eq!(handler_for(KnownModel::P105), Some(Handler::P105));
+        assert_eq!(handler_for(KnownModel::H100), Some(Handler::H100));
+        assert_eq!(handler_for(KnownModel::EP40), None);
+
+        // Each model is listed once
+        for (i, (model, _)) in HANDLERS.iter().enumerate() {
+            assert!(
+                HANDLERS[i + 1..].iter().all(|(m, _)| m != model),
+                "{model} is listed twice"
+            );
+        }
+    }
+
+    #[test]
+    fn normalized_models() {
+        for (reported, model) in [
+            ("P110M", "P110M"),
+            ("P110M(EU)", "P110M"),
+            ("P110M(UK)", "P110M"),
+            ("P115(EU)", "P115"),
+            ("P304M(UK)", "P304M"),
+            ("P110M (EU)", "P110M"),
+            (" p115 ", "p115"),
+            ("P115(EU) V1", "P115"),
+            ("", ""),
+        ] {
+            assert_eq!(normalize_model(reported), model, "{reported:?}");
+        }
+
+        let registry = PluginRegistry::with_builtins();
+        let (_, name) = registry.plugin_for("P115(EU)").unwrap();
+        assert_eq!(name, "P115");
+        let (_, name) = registry.plugin_for("p110m (uk)").unwrap();
+        assert_eq!(name, "P110M");
+    }
+
+    #[tokio::test]
+    async fn connects_with_registered_plugin() {
+        register(Arc::new(TestPlugin {
+            models: vec!["TEST1"],
+        }));
+        assert!(supported_models().contains(&"TEST1".to_string()));
+
+        let credentials = Credentials {
+            username: "me".to_string(),
+            password: "secret".to_string(),
+            account: None,
+        };
+        let e = client_for_device(&credentials, "10.0.0.1", Some("test1"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "Built by a plugin for 10.0.0.1");
+    }
+}
@@ -0,0 +1,247 @@
+use crate::exporter::ChildDevice;
+use std::fmt;
+use std::str::FromStr;
+use tracing::warn;
+
+/// Picks out plugs by their nickname, position or ID, given as `position:2`, `id:ABC` or
+/// `nickname:Kettle*`, with anything else matching whichever of them it can.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum PlugMatcher {
+    /// A nickname glob, where `*` matches any run of characters and `?` any one, or an ID or
+    /// position equal to it
+    Any(String),
+    /// Nicknames matching a glob
+    Nickname(String),
+    Position(u8),
+    Id(String),
+}
+
+impl PlugMatcher {
+    pub fn matches(&self, child: &ChildDevice) -> bool {
+        match self {
+            PlugMatcher::Any(value) => {
+                glob_match(value, &child.nickname)
+                    || *value == child.device_id
+                    || value.parse() == Ok(child.position)
+            }
+            PlugMatcher::Nickname(glob) => glob_match(glob, &child.nickname),
+            PlugMatcher::Position(position) => *position == child.position,
+            PlugMatcher::Id(id) => *id == child.device_id,
+        }
+    }
+}
+
+impl FromStr for PlugMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let matcher = if let Some(position) = s.strip_prefix("position:") {
+            PlugMatcher::Position(
+                position
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("{s} doesn't give a plug position"))?,
+            )
+        } else if let Some(id) = s.strip_prefix("id:") {
+            PlugMatcher::Id(id.trim().to_string())
+        } else if let Some(nickname) = s.strip_prefix("nickname:") {
+            PlugMatcher::Nickname(nickname.trim().to_string())
+        } else {
+            PlugMatcher::Any(s.to_string())
+        };
+
+        match &matcher {
+            PlugMatcher::Any(v) | PlugMatcher::Nickname(v) | PlugMatcher::Id(v) if v.is_empty() => {
+                Err(format!("{s} doesn't give anything to match plugs on"))
+            }
+            _ => Ok(matcher),
+        }
+    }
+}
+
+impl TryFrom<String> for PlugMatcher {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for PlugMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlugMatcher::Any(value) => write!(f, "{value}"),
+            PlugMatcher::Nickname(glob) => write!(f, "nickname:{glob}"),
+            PlugMatcher::Position(position) => write!(f, "position:{position}"),
+            PlugMatcher::Id(id) => write!(f, "id:{id}"),
+        }
+    }
+}
+
+/// Whether the text matches the glob, where `*` matches any run of characters and `?` any one.
+fn glob_match(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut g, mut t) = (0, 0);
+    // Where to resume from if what followed the last `*` stops matching
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, t));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, from)) => {
+                    g = star + 1;
+                    t = from + 1;
+                    backtrack = Some((star, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+/// Which plugs are read from, skipping those matching an exclude filter unless they also match
+/// an include filter. When only include filters are given, only plugs matching one are read.
+#[derive(Clone, Debug, Default)]
+pub struct PlugFilter {
+    include: Vec<PlugMatcher>,
+    exclude: Vec<PlugMatcher>,
+    /// Whether each include, then each exclude, filter has matched a plug
+    matched: Vec<bool>,
+    warned: bool,
+}
+
+impl PlugFilter {
+    pub fn new(include: Vec<PlugMatcher>, exclude: Vec<PlugMatcher>) -> Self {
+        PlugFilter {
+            matched: vec![false; include.len() + exclude.len()],
+            include,
+            exclude,
+            warned: false,
+        }
+    }
+
+    /// Whether the plug should be read from.
+    pub fn keeps(&mut self, child: &ChildDevice) -> bool {
+        let mut included = false;
+        let mut excluded = false;
+        for (i, matcher) in self.include.iter().chain(self.exclude.iter()).enumerate() {
+            if matcher.matches(child) {
+                self.matched[i] = true;
+                if i < self.include.len() {
+                    included = true;
+                } else {
+                    excluded = true;
+                }
+            }
+        }
+
+        included || (!excluded && (self.include.is_empty() || !self.exclude.is_empty()))
+    }
+
+    /// Warns about any filter that hasn't matched a plug so far, only the first time it's called.
+    pub fn warn_unmatched(&mut self) {
+        if self.warned {
+            return;
+        }
+        self.warned = true;
+
+        for (i, matcher) in self.include.iter().chain(self.exclude.iter()).enumerate() {
+            if !self.matched[i] {
+                warn!("Plug filter {matcher} doesn't match any plug");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PlugFilter, PlugMatcher, glob_match};
+    use crate::exporter::ChildDevice;
+
+    fn plug(position: u8, nickname: &str) -> ChildDevice {
+        ChildDevice {
+            device_id: format!("id-{position}"),
+            nickname: nickname.to_string(),
+            device_on: true,
+            on_time_seconds: None,
+            overloaded: None,
+            position,
+        }
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("Kettle", "Kettle"));
+        assert!(glob_match("Ket*", "Kettle"));
+        assert!(glob_match("*tle", "Kettle"));
+        assert!(glob_match("K*t*e", "Kettle"));
+        assert!(glob_match("K?ttle", "Kettle"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("Kettle", "kettle"));
+        assert!(!glob_match("K?tle", "Kettle"));
+        assert!(!glob_match("*x*", "Kettle"));
+    }
+
+    #[test]
+    fn matchers() {
+        let matcher = |s: &str| s.parse::<PlugMatcher>().unwrap();
+
+        assert_eq!(matcher("position:2"), PlugMatcher::Position(2));
+        assert_eq!(matcher("id:abc"), PlugMatcher::Id("abc".to_string()));
+        assert_eq!(
+            matcher("nickname:3"),
+            PlugMatcher::Nickname("3".to_string())
+        );
+        assert!("position:first".parse::<PlugMatcher>().is_err());
+        assert!("id:".parse::<PlugMatcher>().is_err());
+        assert!("".parse::<PlugMatcher>().is_err());
+
+        // Without a prefix, any of the nickname, ID or position can match
+        assert!(matcher("Router*").matches(&plug(1, "Router and modem")));
+        assert!(matcher("id-1").matches(&plug(1, "Router")));
+        assert!(matcher("1").matches(&plug(1, "Router")));
+        assert!(!matcher("nickname:1").matches(&plug(1, "Router")));
+        assert!(!matcher("position:2").matches(&plug(1, "Router")));
+    }
+
+    #[test]
+    fn filters() {
+        let matchers = |list: &[&str]| list.iter().map(|s| s.parse().unwrap()).collect();
+
+        let mut filter = PlugFilter::default();
+        assert!(filter.keeps(&plug(1, "Router")));
+
+        let mut filter = PlugFilter::new(vec![], matchers(&["Router", "position:2"]));
+        assert!(!filter.keeps(&plug(1, "Router")));
+        assert!(!filter.keeps(&plug(2, "NAS")));
+        assert!(filter.keeps(&plug(3, "Kettle")));
+
+        let mut filter = PlugFilter::new(matchers(&["Kettle"]), vec![]);
+        assert!(!filter.keeps(&plug(1, "Router")));
+        assert!(filter.keeps(&plug(3, "Kettle")));
+
+        // Include wins over exclude
+        let mut filter = PlugFilter::new(matchers(&["Kettle"]), matchers(&["*"]));
+        assert!(!filter.keeps(&plug(1, "Router")));
+        assert!(filter.keeps(&plug(3, "Kettle")));
+
+        let mut filter = PlugFilter::new(matchers(&["Toaster"]), matchers(&["Router"]));
+        assert!(filter.keeps(&plug(3, "Kettle")));
+        assert_eq!(filter.matched, [false, false]);
+        filter.warn_unmatched();
+        assert!(filter.warned);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::exporter::PowerUse;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::exemplar::HistogramWithExemplars;
+use prometheus_client::metrics::family::{Family, MetricConstructor};
+use prometheus_client::registry::Registry;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The upper bounds of the buckets power use readings are counted in, in watts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerBuckets(Arc<Vec<f64>>);
+
+impl MetricConstructor<HistogramWithExemplars<Reading>> for PowerBuckets {
+    fn new_metric(&self) -> HistogramWithExemplars<Reading> {
+        HistogramWithExemplars::new(self.0.iter().copied())
+    }
+}
+
+/// The exemplar of the last reading counted in each bucket, saying when the devices were read so
+/// a reading can be found among those of the other plugs read at the same time. There's no
+/// `trace_id`, as reads aren't traced with OpenTelemetry.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Reading {
+    /// Seconds since the Unix epoch.
+    pub scrape_timestamp: String,
+}
+
+/// Parses bucket upper bounds separated by commas, such as `0,50,100,200`, which must be in
+/// ascending order.
+pub fn parse_buckets(list: &str) -> Result<PowerBuckets, String> {
+    let buckets = list
+        .split(',')
+        .map(|bound| {
+            let bound = bound.trim();
+            match bound.parse::<f64>() {
+                Ok(watts) if watts.is_finite() => Ok(watts),
+                _ => Err(format!("{bound:?} isn't a number of watts")),
+            }
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+
+    if !buckets.windows(2).all(|pair| pair[0] < pair[1]) {
+        return Err(format!("{list} isn't in ascending order"));
+    }
+    Ok(PowerBuckets(Arc::new(buckets)))
+}
+
+/// Counts each plug's power use readings in buckets, so their distribution over time can be
+/// queried with `histogram_quantile`.
+pub struct PowerHistogram {
+    readings: Family<PowerUse, HistogramWithExemplars<Reading>, PowerBuckets>,
+}
+
+impl PowerHistogram {
+    pub fn new(buckets: PowerBuckets) -> Self {
+        PowerHistogram {
+            readings: Family::new_with_constructor(buckets),
+        }
+    }
+
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "power_readings_watts",
+            "Distribution of the power use read from the plug in watts",
+            self.readings.clone(),
+        );
+    }
+
+    /// Counts a reading taken at the given time, which is kept as the exemplar of its bucket.
+    pub fn observe(&self, labels: &PowerUse, watts: u64, read_at: SystemTime) {
+        let reading = Reading {
+            scrape_timestamp: read_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+                .to_string(),
+        };
+        self.readings
+            .get_or_create(labels)
+            .observe(watts as f64, Some(reading), Some(read_at));
+    }
+
+    pub fn remove(&self, labels: &PowerUse) {
+        self.readings.remove(labels);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_buckets;
+
+    #[test]
+    fn buckets() {
+        let bounds = |list: &str| parse_buckets(list).map(|b| b.0.to_vec());
+
+        assert_eq!(
+            bounds("0,50, 100,2000.5"),
+            Ok(vec![0.0, 50.0, 100.0, 2000.5])
+        );
+        assert_eq!(bounds("100"), Ok(vec![100.0]));
+
+        let e = bounds("0,100,50").unwrap_err();
+        assert!(e.contains("ascending"), "{e}");
+        assert!(bounds("0,50,50").is_err());
+        assert!(bounds("0,,50").is_err());
+        assert!(bounds("0,lots").is_err());
+        assert!(bounds("0,inf").is_err());
+    }
+}
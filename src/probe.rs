@@ -0,0 +1,376 @@
+use crate::address::{parse_device_address, resolves_within};
+use crate::config::Credentials;
+use crate::connect::client_for_device;
+use crate::error::{DeviceContext, ExporterError, Operation};
+use crate::exporter::{
+    DeviceInfo, OPENMETRICS_CONTENT_TYPE, PowerUse, TapoClient, encode_metrics, error_response,
+    update_device,
+};
+use async_trait::async_trait;
+use axum::Router;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use ipnet::IpNet;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant};
+use tapo::Error;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+type SharedClient = Arc<Mutex<Box<dyn TapoClient + Send + Sync>>>;
+
+/// Creates clients for devices on demand.
+#[async_trait]
+pub trait Connector {
+    async fn connect(&self, address: &str) -> Result<Box<dyn TapoClient + Send + Sync>, Error>;
+}
+
+/// Connects to targets with the credentials given for every device, failing if there aren't any.
+pub(crate) struct TapoConnector {
+    pub credentials: Option<Credentials>,
+}
+
+#[async_trait]
+impl Connector for TapoConnector {
+    async fn connect(&self, address: &str) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+        match &self.credentials {
+            Some(credentials) => client_for_device(credentials, address, None).await,
+            None => Err(Error::Validation {
+                field: "username".to_string(),
+                message: "No username and password given for probing".to_string(),
+            }),
+        }
+    }
+}
+
+struct CachedClient {
+    client: SharedClient,
+    last_used: Instant,
+}
+
+/// Collects metrics from a target supplied by the scraper, in the style of the blackbox exporter.
+pub struct Prober {
+    connector: Box<dyn Connector + Send + Sync>,
+    allowed: Vec<IpNet>,
+    client_ttl: Duration,
+    timeout: Duration,
+    metric_prefix: String,
+    clients: Mutex<HashMap<String, CachedClient>>,
+}
+
+impl Prober {
+    pub fn new(
+        connector: Box<dyn Connector + Send + Sync>,
+        allowed: Vec<IpNet>,
+        client_ttl: Duration,
+        timeout: Duration,
+        metric_prefix: String,
+    ) -> Self {
+        Prober {
+            connector,
+            allowed,
+            client_ttl,
+            timeout,
+            metric_prefix,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks every address the target resolves to is within the allowlist. Nothing is allowed
+    /// when no allowlist has been configured, so the server's credentials aren't sent to any host.
+    async fn is_allowed(&self, target: &str) -> bool {
+        !self.allowed.is_empty() && resolves_within(target, &self.allowed).await
+    }
+
+    async fn client(&self, target: &str) -> Result<SharedClient, Error> {
+        {
+            let mut clients = self.clients.lock().await;
+            clients.retain(|_, c| c.last_used.elapsed() < self.client_ttl);
+
+            if let Some(cached) = clients.get_mut(target) {
+                cached.last_used = Instant::now();
+                return Ok(cached.client.clone());
+            }
+        }
+
+        // Connect without holding the lock so a slow target doesn't hold up probes of the others
+        let client = Arc::new(Mutex::new(self.connector.connect(target).await?));
+        self.clients.lock().await.insert(
+            target.to_string(),
+            CachedClient {
+                client: client.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(client)
+    }
+
+    async fn evict(&self, target: &str) {
+        self.clients.lock().await.remove(target);
+    }
+
+    /// Probes the target, returning a registry containing its metrics along with whether the
+    /// probe succeeded.
+    async fn probe(&self, target: &str) -> Registry {
+        let mut registry = Registry::default();
+        let power_use = Family::<PowerUse, Gauge>::default();
+        let device_info = Family::<DeviceInfo, Gauge>::default();
+        let success = Gauge::<i64>::default();
+        let duration = Gauge::<f64, AtomicU64>::default();
+
+        // Only the target's metrics are prefixed, as the probe's own are named like the blackbox
+        // exporter's
+        let target_registry = registry.sub_registry_with_prefix(&self.metric_prefix);
+        target_registry.register(
+            "power_use_watts",
+            "Current power use in watts",
+            power_use.clone(),
+        );
+        target_registry.register("device_info", "Device information", device_info.clone());
+        registry.register(
+            "probe_success",
+            "Whether the probe of the device succeeded",
+            success.clone(),
+        );
+        registry.register(
+            "probe_duration_seconds",
+            "How long the probe took to complete in seconds",
+            duration.clone(),
+        );
+
+        let start = Instant::now();
+        let result = tokio::time::timeout(self.timeout, async {
+            let device = DeviceContext::new(target, None);
+            let client = self
+                .client(target)
+                .await
+                .map_err(|e| ExporterError::device(&device, Operation::Connect, e))?;
+            let mut client = client.lock().await;
+            client
+                .refresh_session()
+                .await
+                .map_err(|e| ExporterError::device(&device, Operation::Refresh, e))?;
+            update_device(
+                client.as_mut(),
+                &power_use,
+                &device_info,
+                None,
+                None,
+                "",
+                None,
+                None,
+                None,
+                None,
+            )
+            .await
+        })
+        .await;
+
+        match result {
+            Ok(Ok(_)) => {
+                success.set(1);
+            }
+            Ok(Err(e)) => {
+                warn!("Failed to probe: {e}");
+                self.evict(target).await;
+            }
+            Err(_) => {
+                warn!("Timed out probing {target}");
+                self.evict(target).await;
+            }
+        }
+        duration.set(start.elapsed().as_secs_f64());
+
+        registry
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ProbeParams {
+    target: Option<String>,
+}
+
+async fn probe_handler(
+    State(prober): State<Arc<Prober>>,
+    Query(params): Query<ProbeParams>,
+) -> Response {
+    let target = match params.target {
+        Some(target) if !target.is_empty() => target,
+        _ => return (StatusCode::BAD_REQUEST, "Target parameter is missing").into_response(),
+    };
+    let target = match parse_device_address(&target) {
+        Ok(target) => target,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    if !prober.is_allowed(&target).await {
+        return (
+            StatusCode::FORBIDDEN,
+            format!("Target {target} is not allowed"),
+        )
+            .into_response();
+    }
+
+    let registry = prober.probe(&target).await;
+
+    match encode_metrics(&registry) {
+        Ok(body) => ([(CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)], body).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+pub fn router(prober: Prober) -> Router {
+    Router::new()
+        .route("/probe", get(probe_handler))
+        .with_state(Arc::new(prober))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Connector, Prober, router};
+    use crate::exporter::TapoClient;
+    use crate::fake::FakeClient;
+    use async_trait::async_trait;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::Request;
+    use axum::http::StatusCode;
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tapo::Error;
+    use tower::ServiceExt;
+
+    struct TestConnector {
+        connections: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Connector for TestConnector {
+        async fn connect(&self, address: &str) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+            self.connections.fetch_add(1, Ordering::SeqCst);
+            match address {
+                "10.0.0.1" => Ok(Box::new(FakeClient::new(address))),
+                _ => Err(Error::DeviceNotFound),
+            }
+        }
+    }
+
+    fn test_router(allowed: &[&str], connections: Arc<AtomicUsize>) -> Router {
+        router(Prober::new(
+            Box::new(TestConnector { connections }),
+            allowed.iter().map(|a| a.parse().unwrap()).collect(),
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+            "tapo".to_string(),
+        ))
+    }
+
+    async fn probe(router: Router, uri: &str) -> (StatusCode, String) {
+        let response = router
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        (
+            status,
+            str::from_utf8(body_bytes.as_ref()).unwrap().to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn probe_target() {
+        let router = test_router(&["10.0.0.0/8"], Arc::new(AtomicUsize::new(0)));
+
+        let (status, body) = probe(router, "/probe?target=10.0.0.1").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(
+            "tapo_power_use_watts{power_strip_id=\"123\",ip_address=\"10.0.0.1\",device_id=\"456\",nickname=\"\",group=\"\",position=\"1\"} 45\n"
+        ));
+        assert!(body.contains("probe_success 1\n"));
+    }
+
+    #[tokio::test]
+    async fn probe_unreachable_target() {
+        let router = test_router(&["10.0.0.0/8"], Arc::new(AtomicUsize::new(0)));
+
+        let (status, body) = probe(router, "/probe?target=10.0.0.2").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("probe_success 0\n"));
+        assert!(!body.contains("tapo_power_use_watts{"));
+    }
+
+    #[tokio::test]
+    async fn probe_reuses_client() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let router = test_router(&["10.0.0.0/8"], connections.clone());
+
+        probe(router.clone(), "/probe?target=10.0.0.1").await;
+        probe(router, "/probe?target=10.0.0.1").await;
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn probe_disallowed_target() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let router = test_router(&["192.168.0.0/24"], connections.clone());
+
+        let (status, _) = probe(router, "/probe?target=10.0.0.1").await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn probe_without_allowlist() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let router = test_router(&[], connections.clone());
+
+        let (status, _) = probe(router, "/probe?target=10.0.0.1").await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn probe_allowed_target() {
+        let router = test_router(&["10.0.0.0/8"], Arc::new(AtomicUsize::new(0)));
+
+        let (status, body) = probe(router, "/probe?target=10.0.0.1").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("probe_success 1\n"));
+    }
+
+    #[tokio::test]
+    async fn probe_invalid_target() {
+        let router = test_router(&["10.0.0.0/8"], Arc::new(AtomicUsize::new(0)));
+
+        let (status, _) = probe(router, "/probe?target=fe80::1%25eth0").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn probe_without_target() {
+        let router = test_router(&["10.0.0.0/8"], Arc::new(AtomicUsize::new(0)));
+
+        let (status, _) = probe(router, "/probe").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
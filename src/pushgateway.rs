@@ -0,0 +1,247 @@
+use crate::exporter::OPENMETRICS_CONTENT_TYPE;
+use crate::labels::is_valid_label_name;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE;
+use prometheus_client::metrics::counter::Counter;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{StatusCode, Url};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The job the metrics are grouped under on the Pushgateway, unless given another.
+pub const DEFAULT_JOB: &str = "tapo_exporter";
+
+#[derive(Debug, thiserror::Error)]
+pub enum PushError {
+    #[error("{0}")]
+    Send(#[from] reqwest::Error),
+    #[error("the Pushgateway answered {status}: {body}")]
+    Rejected { status: StatusCode, body: String },
+}
+
+/// Pushes the metrics, as served at `/metrics`, to a Prometheus Pushgateway, for when Prometheus
+/// can't reach the exporter to scrape it.
+pub struct Pushgateway {
+    client: reqwest::Client,
+    url: Url,
+    /// The username and password to push with, if the Pushgateway needs them.
+    credentials: Option<(String, String)>,
+    errors: Counter,
+}
+
+impl Pushgateway {
+    /// Creates a pusher for the Pushgateway at the base URL, e.g. `http://localhost:9091`, grouping
+    /// the metrics under the job and any other labels.
+    pub fn new(
+        base_url: &Url,
+        job: &str,
+        grouping: &BTreeMap<String, String>,
+        credentials: Option<(String, String)>,
+    ) -> Self {
+        let mut url = base_url.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .expect("Pushgateway URLs are HTTP URLs, which have paths");
+            segments.pop_if_empty().push("metrics");
+            for (name, value) in [("job", job)]
+                .into_iter()
+                .chain(grouping.iter().map(|(n, v)| (n.as_str(), v.as_str())))
+            {
+                // Values that can't be a path segment of their own are given in base64 instead
+                match value.is_empty() || value.contains('/') {
+                    true => segments.extend([
+                        format!("{name}@base64"),
+                        // An empty value is encoded as a lone padding character
+                        match value.is_empty() {
+                            true => "=".to_string(),
+                            false => URL_SAFE.encode(value),
+                        },
+                    ]),
+                    false => segments.extend([name, value]),
+                };
+            }
+        }
+
+        Pushgateway {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            url,
+            credentials,
+            errors: Counter::default(),
+        }
+    }
+
+    /// Counts the pushes that failed.
+    pub fn errors(&self) -> Counter {
+        self.errors.clone()
+    }
+
+    /// Replaces the metrics last pushed with these, so devices that are removed stop being
+    /// reported.
+    pub async fn push(&self, metrics: String) -> Result<(), PushError> {
+        let result = self.send(metrics).await;
+        if result.is_err() {
+            self.errors.inc();
+        }
+        result
+    }
+
+    async fn send(&self, metrics: String) -> Result<(), PushError> {
+        let mut request = self
+            .client
+            .put(self.url.clone())
+            .header(CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)
+            .body(metrics);
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(PushError::Rejected {
+                status,
+                body: response.text().await.unwrap_or_default(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Parses a label to group the metrics under on the Pushgateway, as its name and value separated
+/// by `=`, e.g. `instance=kitchen`.
+pub fn parse_grouping_label(value: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = value.split_once('=') else {
+        return Err(format!(
+            "{value} isn't a label name and value separated by ="
+        ));
+    };
+    match name {
+        "job" => Err("job is given by --push-job".to_string()),
+        name if !is_valid_label_name(name) => Err(format!("{name} isn't a valid label name")),
+        name => Ok((name.to_string(), value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DEFAULT_JOB, PushError, Pushgateway, parse_grouping_label};
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::put;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn push_metrics() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let gateway = Router::new()
+            .route(
+                "/prefix/metrics/job/tapo_exporter",
+                put(
+                    |State(tx): State<mpsc::Sender<(HeaderMap, String)>>,
+                     headers: HeaderMap,
+                     body: String| async move {
+                        tx.send((headers, body)).await.unwrap();
+                    },
+                ),
+            )
+            .route(
+                "/forbidden/metrics/job/tapo_exporter",
+                put(|| async { (StatusCode::FORBIDDEN, "not allowed") }),
+            )
+            .with_state(tx);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, gateway).await.unwrap() });
+
+        let pushgateway = Pushgateway::new(
+            &format!("http://{address}/prefix/").parse().unwrap(),
+            DEFAULT_JOB,
+            &BTreeMap::new(),
+            Some(("me".to_string(), "secret".to_string())),
+        );
+        pushgateway
+            .push("tapo_device_reachable 1\n# EOF\n".to_string())
+            .await
+            .unwrap();
+
+        let (headers, body) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, "tapo_device_reachable 1\n# EOF\n");
+        assert!(
+            headers[CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with("application/openmetrics-text")
+        );
+        // `me:secret` in base64
+        assert_eq!(headers[AUTHORIZATION], "Basic bWU6c2VjcmV0");
+
+        let pushgateway = Pushgateway::new(
+            &format!("http://{address}/forbidden").parse().unwrap(),
+            DEFAULT_JOB,
+            &BTreeMap::new(),
+            None,
+        );
+        // The Pushgateway's explanation is kept
+        match pushgateway.push(String::new()).await {
+            Err(PushError::Rejected { status, body }) => {
+                assert_eq!(status, StatusCode::FORBIDDEN);
+                assert_eq!(body, "not allowed");
+            }
+            result => panic!("{result:?}"),
+        }
+    }
+
+    #[test]
+    fn grouping_urls() {
+        let url = |job, grouping: &[(&str, &str)]| {
+            let grouping = grouping
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect();
+            Pushgateway::new(
+                &"http://localhost:9091".parse().unwrap(),
+                job,
+                &grouping,
+                None,
+            )
+            .url
+            .to_string()
+        };
+
+        assert_eq!(
+            url("cron", &[("instance", "sbc 1"), ("site", "a&b?")]),
+            "http://localhost:9091/metrics/job/cron/instance/sbc%201/site/a&b%3F"
+        );
+        // Slashes and empty values can't be path segments
+        assert_eq!(
+            url("tapo/cron", &[("room", "")]),
+            "http://localhost:9091/metrics/job@base64/dGFwby9jcm9u/room@base64/="
+        );
+    }
+
+    #[test]
+    fn grouping_labels() {
+        assert_eq!(
+            parse_grouping_label("instance=kitchen=1"),
+            Ok(("instance".to_string(), "kitchen=1".to_string()))
+        );
+        assert_eq!(
+            parse_grouping_label("instance=").map(|(_, v)| v),
+            Ok(String::new())
+        );
+        assert!(parse_grouping_label("instance").is_err());
+        assert!(parse_grouping_label("job=cron").is_err());
+        assert!(parse_grouping_label("1st=a").is_err());
+    }
+}
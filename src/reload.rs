@@ -0,0 +1,371 @@
+use crate::config::{Credentials, DeviceConfig, DeviceSources};
+#[cfg(feature = "watch-config")]
+use crate::config_watch;
+use crate::error::AppError;
+use crate::exporter::{AliasMode, Devices, device_aliases, device_groups, device_labels};
+use crate::mac::ResolvedMacs;
+use crate::management::DeviceConnector;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// What a device is connected to with, so reloading can tell when it needs connecting to again.
+#[derive(Debug, PartialEq)]
+pub struct Connection {
+    model: Option<String>,
+    credentials: Result<Credentials, String>,
+}
+
+/// What each device is connected to with, by address, reading any credentials files again.
+pub fn device_connections(
+    devices: &[DeviceConfig],
+    username: Option<&str>,
+    password: Option<&str>,
+) -> BTreeMap<String, Connection> {
+    devices
+        .iter()
+        .map(|d| {
+            let connection = Connection {
+                model: d.model.clone(),
+                credentials: d.credentials(username, password),
+            };
+            (d.address.clone(), connection)
+        })
+        .collect()
+}
+
+/// Asks for the devices to be reloaded whenever the process is sent `SIGHUP`.
+pub async fn forward_hangups(reloads: mpsc::Sender<()>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP, so devices can't be reloaded with it: {e}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        if reloads.send(()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Watches the configuration file for changes until dropped.
+#[cfg(feature = "watch-config")]
+pub type ConfigWatcher = notify::RecommendedWatcher;
+#[cfg(not(feature = "watch-config"))]
+pub type ConfigWatcher = std::convert::Infallible;
+
+/// Starts watching the configuration file, failing if there isn't one or it can't be watched.
+#[cfg(feature = "watch-config")]
+pub fn watch_config_file(
+    sources: &DeviceSources,
+    reloads: mpsc::Sender<()>,
+) -> Result<ConfigWatcher, AppError> {
+    let Some(path) = sources.config_file.as_ref() else {
+        return Err(AppError::missing_option("config"));
+    };
+    config_watch::watch_config(path, reloads)
+        .map_err(|e| AppError::Runtime(format!("Failed to watch {}: {e}", path.display())))
+}
+
+#[cfg(not(feature = "watch-config"))]
+pub fn watch_config_file(
+    _: &DeviceSources,
+    _: mpsc::Sender<()>,
+) -> Result<ConfigWatcher, AppError> {
+    Err(AppError::Config(
+        "--watch-config needs the exporter to be built with the watch-config feature".to_string(),
+    ))
+}
+
+/// Where the devices are reloaded from, and what they're connected to with.
+pub struct DeviceReloader {
+    pub sources: DeviceSources,
+    pub alias_mode: AliasMode,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub macs: ResolvedMacs,
+    pub connector: Arc<dyn DeviceConnector + Send + Sync>,
+}
+
+/// Re-reads the devices from the files they were given in whenever asked to, connecting to those
+/// added in the background and dropping those removed. Devices whose model or credentials changed
+/// are connected to again, while those that haven't changed are left alone, so their sessions are
+/// kept. Devices given by MAC address that have `moved` to another IP address are connected to at
+/// the new one, without reading the files again.
+pub async fn reload_devices(
+    mut reloads: mpsc::Receiver<()>,
+    mut moved: mpsc::Receiver<()>,
+    reloader: DeviceReloader,
+    mut loaded: Vec<DeviceConfig>,
+    mut configured: BTreeMap<String, Connection>,
+    devices: Devices,
+) {
+    let DeviceReloader {
+        sources,
+        alias_mode,
+        username,
+        password,
+        macs,
+        connector,
+    } = reloader;
+
+    loop {
+        tokio::select! {
+            reload = reloads.recv() => {
+                if reload.is_none() {
+                    return;
+                }
+                if !sources.reloadable() {
+                    warn!("Not reloading devices as they weren't read from a file");
+                    continue;
+                }
+
+                loaded = match sources.load() {
+                    Ok(reloaded) => reloaded,
+                    Err(e) => {
+                        warn!("Failed to reload devices: {e}");
+                        continue;
+                    }
+                };
+            }
+            Some(()) = moved.recv() => {}
+        }
+        let reloaded = macs.substitute(&loaded);
+
+        let connections = device_connections(&reloaded, username.as_deref(), password.as_deref());
+        let removed: Vec<&String> = configured
+            .keys()
+            .filter(|address| !connections.contains_key(*address))
+            .collect();
+        let changed: Vec<&String> = connections
+            .iter()
+            .filter(|(address, c)| configured.get(*address).is_some_and(|old| old != *c))
+            .map(|(address, _)| address)
+            .collect();
+
+        for address in removed.iter().chain(changed.iter()) {
+            devices.remove(address).await;
+        }
+        devices.set_labels(device_labels(&reloaded)).await;
+        devices
+            .set_aliases(device_aliases(&reloaded, alias_mode))
+            .await;
+        devices.set_groups(device_groups(&reloaded)).await;
+        devices.set_given_macs(macs.found()).await;
+        let added: Vec<&DeviceConfig> = reloaded
+            .iter()
+            // Includes any that changed, and any that failed to connect last time
+            .filter(|d| !devices.contains(&d.address))
+            .collect();
+
+        info!(
+            "Reloaded devices, connecting to {:?} and removing {:?}, having changed {:?}",
+            added.iter().map(|d| &d.address).collect::<Vec<_>>(),
+            removed,
+            changed
+        );
+
+        for device in added.into_iter().cloned() {
+            let devices = devices.clone();
+            let connector = connector.clone();
+            let credentials = connections[&device.address].credentials.clone();
+
+            tokio::spawn(async move {
+                let client = match credentials {
+                    Ok(credentials) => connector
+                        .connect(&credentials, &device.address, device.model.as_deref())
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e),
+                };
+                match client {
+                    Ok(client) => devices.add(client).await,
+                    Err(e) => warn!("Failed to add {}: {e}", device.address),
+                }
+            });
+        }
+
+        configured = connections;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::device_connections;
+    use crate::config::DeviceConfig;
+
+    #[test]
+    fn changed_device_connections() {
+        let dir = std::env::temp_dir().join(format!("connections-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("credentials.toml");
+        std::fs::write(&path, "username = \"me\"\npassword = \"old\"\n").unwrap();
+        let devices = vec![
+            DeviceConfig {
+                address: "10.0.0.1".to_string(),
+                ..DeviceConfig::default()
+            },
+            DeviceConfig {
+                address: "10.0.0.2".to_string(),
+                credentials_file: Some(path.clone()),
+                ..DeviceConfig::default()
+            },
+        ];
+
+        let before = device_connections(&devices, Some("me"), Some("secret"));
+        std::fs::write(&path, "username = \"me\"\npassword = \"new\"\n").unwrap();
+        let after = device_connections(&devices, Some("me"), Some("secret"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Only the device whose credentials file changed needs connecting to again
+        assert_eq!(before["10.0.0.1"], after["10.0.0.1"]);
+        assert_ne!(before["10.0.0.2"], after["10.0.0.2"]);
+
+        let mut devices = devices;
+        devices[0].model = Some("P110M".to_string());
+        let declared = device_connections(&devices, Some("me"), Some("secret"));
+        assert_ne!(after["10.0.0.1"], declared["10.0.0.1"]);
+    }
+
+    #[cfg(feature = "watch-config")]
+    mod watched_config {
+        use super::super::{DeviceReloader, device_connections, reload_devices};
+        use crate::config::{Credentials, DeviceSources};
+        use crate::config_watch;
+        use crate::exporter::{
+            AliasMode, AppConfig, ChildDevice, DeviceInfo, TapoClient, split_app,
+        };
+        use crate::mac::ResolvedMacs;
+        use crate::management::{DeviceConnector, Management, router};
+        use async_trait::async_trait;
+        use axum::body::Body;
+        use axum::http::Request;
+        use http_body_util::BodyExt;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tapo::Error;
+        use tapo::responses::CurrentPowerResult;
+        use tokio::sync::mpsc;
+        use tower::ServiceExt;
+
+        /// Stands in for connecting to a device, as it's only listed rather than read from.
+        struct ListedConnector {}
+
+        #[async_trait]
+        impl DeviceConnector for ListedConnector {
+            async fn connect(
+                &self,
+                _: &Credentials,
+                address: &str,
+                _: Option<&str>,
+            ) -> Result<Box<dyn TapoClient + Send + Sync>, Error> {
+                Ok(Box::new(ListedClient {
+                    address: address.to_string(),
+                }))
+            }
+        }
+
+        struct ListedClient {
+            address: String,
+        }
+
+        #[async_trait]
+        impl TapoClient for ListedClient {
+            fn address(&self) -> &str {
+                &self.address
+            }
+
+            async fn refresh_session(&mut self) -> Result<(), Error> {
+                Err(Error::Other(anyhow::anyhow!("not a device")))
+            }
+
+            async fn device_info(&self) -> Result<DeviceInfo, Error> {
+                Err(Error::Other(anyhow::anyhow!("not a device")))
+            }
+
+            async fn child_devices(&self) -> Result<Vec<ChildDevice>, Error> {
+                Err(Error::Other(anyhow::anyhow!("not a device")))
+            }
+
+            async fn get_power_for_plug(&self, _: &str) -> Result<CurrentPowerResult, Error> {
+                Err(Error::Other(anyhow::anyhow!("not a device")))
+            }
+        }
+
+        #[tokio::test]
+        async fn devices_added_to_the_file_are_listed() {
+            let dir = std::env::temp_dir().join(format!("watched-config-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("config.toml");
+            std::fs::write(&path, "[[devices]]\naddress = \"10.0.0.1\"\n").unwrap();
+
+            let (_, _, devices) = split_app(Vec::new(), AppConfig::default());
+            let sources = DeviceSources {
+                addresses: None,
+                config_file: Some(path.clone()),
+                devices_file: None,
+                aliases: Default::default(),
+            };
+            let loaded = sources.load().unwrap();
+            let (reload_tx, reloads) = mpsc::channel(1);
+            let watcher = config_watch::watch_config(&path, reload_tx).unwrap();
+            let (_moved_tx, moved) = mpsc::channel(1);
+            tokio::spawn(reload_devices(
+                reloads,
+                moved,
+                DeviceReloader {
+                    sources,
+                    alias_mode: AliasMode::default(),
+                    username: Some("user".to_string()),
+                    password: Some("secret".to_string()),
+                    macs: ResolvedMacs::default(),
+                    connector: Arc::new(ListedConnector {}),
+                },
+                loaded.clone(),
+                device_connections(&loaded, Some("user"), Some("secret")),
+                devices.clone(),
+            ));
+            let app = router(Management::new(
+                devices,
+                Box::new(ListedConnector {}),
+                None,
+                Vec::new(),
+                None,
+            ));
+
+            std::fs::write(
+                &path,
+                "[[devices]]\naddress = \"10.0.0.1\"\n[[devices]]\naddress = \"10.0.0.2\"\n",
+            )
+            .unwrap();
+
+            // Devices are only added once the file's been reloaded
+            let listed = tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let response = app
+                        .clone()
+                        .oneshot(Request::get("/api/v1/devices").body(Body::empty()).unwrap())
+                        .await
+                        .unwrap();
+                    let body = response.into_body().collect().await.unwrap().to_bytes();
+                    let body = String::from_utf8(body.to_vec()).unwrap();
+                    if body.contains("10.0.0.2") {
+                        return body;
+                    }
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .expect("the added device should have been listed");
+            assert!(listed.contains("10.0.0.1"), "{listed}");
+
+            drop(watcher);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use crate::retry;
+use prometheus_client::metrics::counter::Counter;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::{StatusCode, Url};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The version of the remote write protocol the requests are made with.
+const REMOTE_WRITE_VERSION: &str = "0.1.0";
+
+/// How many times to try sending each request before giving up on it.
+const ATTEMPTS: u32 = 3;
+
+/// A request to write samples, as defined by Prometheus' `prompb/remote.proto`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+/// The samples of a series, identified by its labels, including its name as `__name__`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+/// A value of a series, at a time in milliseconds since the Unix epoch.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+/// How to authenticate with the remote write endpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RemoteWriteAuth {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RemoteWriteError {
+    #[error("Failed to compress the metrics: {0}")]
+    Compress(#[from] snap::Error),
+    #[error("Failed to send the metrics: {0}")]
+    Send(#[from] reqwest::Error),
+}
+
+impl RemoteWriteError {
+    /// Whether sending the request again could succeed. The endpoint rejecting the request, other
+    /// than for being sent too many, would only happen again.
+    fn is_retryable(&self) -> bool {
+        match self {
+            RemoteWriteError::Compress(_) => false,
+            RemoteWriteError::Send(e) => e.status().is_none_or(|status| {
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            }),
+        }
+    }
+}
+
+/// Writes the metrics, as served at `/metrics`, to a Prometheus remote write endpoint such as
+/// Thanos or Cortex, for when there's no Prometheus to scrape the exporter.
+pub struct RemoteWriter {
+    client: reqwest::Client,
+    url: Url,
+    auth: Option<RemoteWriteAuth>,
+    retry_delay: Duration,
+    errors: Counter,
+}
+
+impl RemoteWriter {
+    /// Creates a writer for the endpoint, which is the full URL samples are posted to, e.g.
+    /// `http://localhost:10908/api/v1/receive`.
+    pub fn new(url: &Url, auth: Option<RemoteWriteAuth>) -> Self {
+        RemoteWriter {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            url: url.clone(),
+            auth,
+            retry_delay: Duration::from_secs(1),
+            errors: Counter::default(),
+        }
+    }
+
+    /// Counts the requests that failed to send, having been tried again.
+    pub fn errors(&self) -> Counter {
+        self.errors.clone()
+    }
+
+    /// Writes a sample of each series in the metrics, all taken at the given time, trying again
+    /// with a growing delay if the endpoint can't be reached or fails to handle it.
+    pub async fn write(&self, metrics: &str, at: SystemTime) -> Result<(), RemoteWriteError> {
+        let timestamp = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let request = WriteRequest {
+            timeseries: parse_metrics(metrics, timestamp),
+        };
+        // The protocol only accepts snappy's block format, rather than its framed format
+        let body =
+            snap::raw::Encoder::new().compress_vec(&prost::Message::encode_to_vec(&request))?;
+
+        let result = retry::with_backoff(
+            &format!("remote write to {}", self.url),
+            ATTEMPTS,
+            self.retry_delay,
+            RemoteWriteError::is_retryable,
+            || self.send(body.clone()),
+        )
+        .await;
+        if result.is_err() {
+            self.errors.inc();
+        }
+        result
+    }
+
+    async fn send(&self, body: Vec<u8>) -> Result<(), RemoteWriteError> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header(CONTENT_ENCODING, "snappy")
+            .header("X-Prometheus-Remote-Write-Version", REMOTE_WRITE_VERSION)
+            .body(body);
+        request = match &self.auth {
+            Some(RemoteWriteAuth::Bearer(token)) => request.bearer_auth(token),
+            Some(RemoteWriteAuth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            None => request,
+        };
+
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Parses metrics encoded as they're served, in the text format of
+/// [`OPENMETRICS_CONTENT_TYPE`](crate::exporter::OPENMETRICS_CONTENT_TYPE),
+/// into a series for each sample, with their labels sorted by name as remote write requires.
+/// Lines that can't be parsed are left out.
+pub fn parse_metrics(metrics: &str, timestamp: i64) -> Vec<TimeSeries> {
+    metrics
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (mut labels, value) = parse_sample(line)?;
+            labels.sort_by(|a, b| a.name.cmp(&b.name));
+            Some(TimeSeries {
+                labels,
+                samples: vec![Sample { value, timestamp }],
+            })
+        })
+        .collect()
+}
+
+/// Parses a sample line, e.g. `tapo_power_use_watts{device_id="456"} 45`, into its labels,
+/// including its name, and value.
+fn parse_sample(line: &str) -> Option<(Vec<Label>, f64)> {
+    let name_end = line.find(['{', ' '])?;
+    let mut labels = vec![Label {
+        name: "__name__".to_string(),
+        value: line[..name_end].to_string(),
+    }];
+
+    let mut rest = &line[name_end..];
+    if let Some(label_set) = rest.strip_prefix('{') {
+        let mut chars = label_set.char_indices();
+        loop {
+            let mut name = String::new();
+            let end = loop {
+                match chars.next()? {
+                    (i, '}') if name.is_empty() => break Some(i),
+                    (_, '=') => break None,
+                    (_, c) => name.push(c),
+                }
+            };
+            if let Some(end) = end {
+                rest = &label_set[end + 1..];
+                break;
+            }
+
+            if chars.next()?.1 != '"' {
+                return None;
+            }
+            let mut value = String::new();
+            loop {
+                match chars.next()?.1 {
+                    '"' => break,
+                    '\\' => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    c => value.push(c),
+                }
+            }
+            labels.push(Label { name, value });
+
+            match chars.next()? {
+                (_, ',') => {}
+                (end, '}') => {
+                    rest = &label_set[end + 1..];
+                    break;
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    // Any timestamp after the value is left for the time the metrics are written at
+    let value = rest.split_whitespace().next()?.parse().ok()?;
+    Some((labels, value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_metrics;
+    use super::{Label, RemoteWriteAuth, RemoteWriter, Sample, TimeSeries, WriteRequest};
+    use axum::Router;
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use prost::Message;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio::sync::mpsc;
+
+    fn series(labels: &[(&str, &str)], value: f64) -> TimeSeries {
+        TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            samples: vec![Sample {
+                value,
+                timestamp: 1_700_000_000_000,
+            }],
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let metrics = "# HELP tapo_power_use_watts Current power use in watts.\n\
+            # TYPE tapo_power_use_watts gauge\n\
+            tapo_power_use_watts{power_strip_id=\"123\",nickname=\"Living \\\"room\\\", upstairs\",position=\"1\"} 45\n\
+            tapo_scrape_errors_total{ip_address=\"10.0.0.1\"} 2 1700000000\n\
+            tapo_power_readings_watts_bucket{le=\"+Inf\"} 3\n\
+            tapo_device_count 2\n\
+            not a sample\n\
+            # EOF\n";
+
+        assert_eq!(
+            parse_metrics(metrics, 1_700_000_000_000),
+            [
+                series(
+                    &[
+                        ("__name__", "tapo_power_use_watts"),
+                        ("nickname", "Living \"room\", upstairs"),
+                        ("position", "1"),
+                        ("power_strip_id", "123"),
+                    ],
+                    45.0
+                ),
+                series(
+                    &[
+                        ("__name__", "tapo_scrape_errors_total"),
+                        ("ip_address", "10.0.0.1"),
+                    ],
+                    2.0
+                ),
+                series(
+                    &[
+                        ("__name__", "tapo_power_readings_watts_bucket"),
+                        ("le", "+Inf"),
+                    ],
+                    3.0
+                ),
+                series(&[("__name__", "tapo_device_count")], 2.0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn write_metrics() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let endpoint = Router::new()
+            .route(
+                "/api/v1/receive",
+                post(
+                    |State(tx): State<mpsc::Sender<(HeaderMap, Bytes)>>,
+                     headers: HeaderMap,
+                     body: Bytes| async move {
+                        tx.send((headers, body)).await.unwrap();
+                    },
+                ),
+            )
+            .route("/forbidden", post(|| async { StatusCode::FORBIDDEN }))
+            .with_state(tx);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, endpoint).await.unwrap() });
+
+        let writer = RemoteWriter::new(
+            &format!("http://{address}/api/v1/receive").parse().unwrap(),
+            Some(RemoteWriteAuth::Bearer("token".to_string())),
+        );
+        writer
+            .write(
+                "tapo_device_count 2\n# EOF\n",
+                UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            )
+            .await
+            .unwrap();
+
+        let (headers, body) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(headers[CONTENT_TYPE], "application/x-protobuf");
+        assert_eq!(headers[CONTENT_ENCODING], "snappy");
+        assert_eq!(headers["x-prometheus-remote-write-version"], "0.1.0");
+        assert_eq!(headers[AUTHORIZATION], "Bearer token");
+
+        let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        assert_eq!(
+            WriteRequest::decode(body.as_slice()).unwrap(),
+            WriteRequest {
+                timeseries: vec![series(&[("__name__", "tapo_device_count")], 2.0)],
+            }
+        );
+
+        let writer = RemoteWriter::new(
+            &format!("http://{address}/forbidden").parse().unwrap(),
+            Some(RemoteWriteAuth::Basic {
+                username: "me".to_string(),
+                password: "secret".to_string(),
+            }),
+        );
+        assert!(writer.write("", UNIX_EPOCH).await.is_err());
+        assert_eq!(writer.errors().get(), 1);
+    }
+
+    #[test]
+    fn golden_payload() {
+        let request = WriteRequest {
+            timeseries: parse_metrics("tapo_device_count 2\n# EOF\n", 1_700_000_000_000),
+        };
+
+        assert_eq!(
+            request.encode_to_vec(),
+            b"\x0a1\x0a\x1d\x0a\x08__name__\x12\x11tapo_device_count\x12\x10\x09\x00\x00\x00\x00\x00\x00\x00@\x10\x80\xd0\x95\xff\xbc1"
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_failed_writes() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let endpoint = Router::new()
+            // Unavailable for the first two requests
+            .route(
+                "/unavailable",
+                post(|State(calls): State<Arc<AtomicU32>>| async move {
+                    match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 | 1 => StatusCode::SERVICE_UNAVAILABLE,
+                        _ => StatusCode::NO_CONTENT,
+                    }
+                }),
+            )
+            .route(
+                "/rejected",
+                post(|State(calls): State<Arc<AtomicU32>>| async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::BAD_REQUEST
+                }),
+            )
+            .with_state(calls.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, endpoint).await.unwrap() });
+        let writer = |path: &str| RemoteWriter {
+            retry_delay: Duration::from_millis(1),
+            ..RemoteWriter::new(&format!("http://{address}{path}").parse().unwrap(), None)
+        };
+
+        let unavailable = writer("/unavailable");
+        unavailable
+            .write("tapo_device_count 2\n", UNIX_EPOCH)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(unavailable.errors().get(), 0);
+
+        // A request the endpoint rejects would only be rejected again
+        calls.store(0, Ordering::SeqCst);
+        let rejected = writer("/rejected");
+        assert!(
+            rejected
+                .write("tapo_device_count 2\n", UNIX_EPOCH)
+                .await
+                .is_err()
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(rejected.errors().get(), 1);
+    }
+}
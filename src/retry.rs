@@ -0,0 +1,107 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+/// Runs the operation until it succeeds, up to `attempts` times, waiting `delay` before the first
+/// retry and twice as long before each one after that. Errors `retryable` rejects, such as those
+/// that would only happen again, are returned without retrying, as is the error of the last attempt.
+pub async fn with_backoff<T, E, F, Fut>(
+    description: &str,
+    attempts: u32,
+    delay: Duration,
+    retryable: impl Fn(&E) -> bool,
+    mut operation: F,
+) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts && retryable(&e) => {
+                warn!(
+                    "Attempt {attempt} of {attempts} to {description} failed: {e}, retrying in {}",
+                    humantime::format_duration(delay)
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::with_backoff;
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let calls = Cell::new(0);
+        let start = Instant::now();
+        let result = with_backoff(
+            "connect",
+            5,
+            Duration::from_millis(10),
+            |_: &String| true,
+            || async {
+                calls.set(calls.get() + 1);
+                match calls.get() {
+                    3 => Ok(calls.get()),
+                    _ => Err("rebooting".to_string()),
+                }
+            },
+        )
+        .await;
+
+        assert_eq!(result, Ok(3));
+        // Waited 10ms then 20ms
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let calls = Cell::new(0);
+        let result: Result<(), String> = with_backoff(
+            "connect",
+            3,
+            Duration::from_millis(1),
+            |_| true,
+            || async {
+                calls.set(calls.get() + 1);
+                Err(format!("attempt {}", calls.get()))
+            },
+        )
+        .await;
+
+        assert_eq!(result, Err("attempt 3".to_string()));
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_errors_that_would_happen_again() {
+        let calls = Cell::new(0);
+        let result: Result<(), String> = with_backoff(
+            "connect",
+            3,
+            Duration::from_millis(1),
+            |e: &String| e != "unsupported",
+            || async {
+                calls.set(calls.get() + 1);
+                Err("unsupported".to_string())
+            },
+        )
+        .await;
+
+        assert_eq!(result, Err("unsupported".to_string()));
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,404 @@
+use crate::plugins;
+use async_trait::async_trait;
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// The port devices listen on for discovery packets.
+pub const DISCOVERY_PORT: u16 = 20002;
+
+/// Length of the header before the JSON of both discovery packets and their responses.
+const HEADER_LENGTH: usize = 16;
+
+/// The value of a packet's checksum while the checksum is worked out.
+const INITIAL_CHECKSUM: u32 = 0x5a6b7c8d;
+
+/// Key sent with discovery packets for devices to encrypt their handshake with. Devices won't
+/// respond without one, but nothing they encrypt with it is read, so it's not kept secret.
+const PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----\n\
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQCr8azTZZtDxubjVwVDVhi3lxVt\n\
+m3QRPx42jn6F05c2Fl0degUR9BpzBT9lRrgys76OmLPDhL3Xd4SDA/hT0fxgtZYJ\n\
+bQPohYkpsSKfjhuL+slCn7P16bYMfOe7xS+vOonwT8ZiFkaSf8Br5GiFUTB7MVMV\n\
+7Ch7pSJ6X41ICOz2pQIDAQAB\n\
+-----END PUBLIC KEY-----\n";
+
+/// Sends discovery packets and receives the responses to them.
+#[async_trait]
+pub trait Transport {
+    async fn send_to(&self, packet: &[u8], target: SocketAddr) -> io::Result<()>;
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+#[async_trait]
+impl Transport for UdpSocket {
+    async fn send_to(&self, packet: &[u8], target: SocketAddr) -> io::Result<()> {
+        UdpSocket::send_to(self, packet, target).await.map(|_| ())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+}
+
+/// Binds a socket that can broadcast discovery packets.
+pub async fn bind() -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    socket.set_broadcast(true)?;
+    Ok(socket)
+}
+
+/// A device that responded to a discovery packet.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FoundDevice {
+    pub address: String,
+    pub mac: String,
+    pub model: String,
+}
+
+impl FoundDevice {
+    /// The model without its region, e.g. `P110M` for `P110M(UK)`, if it's one that's supported.
+    fn supported_model(&self) -> Option<String> {
+        plugins::plugin_for(&self.model).map(|(_, model)| model)
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    result: ResponseResult,
+}
+
+#[derive(Deserialize)]
+struct ResponseResult {
+    ip: Option<String>,
+    mac: String,
+    device_model: String,
+}
+
+/// The broadcast address of each IPv4 network interface, other than loopback, falling back to the
+/// limited broadcast address if none are found.
+pub fn broadcast_addresses() -> Vec<IpAddr> {
+    let interfaces = if_addrs::get_if_addrs().unwrap_or_else(|e| {
+        warn!("Failed to list network interfaces: {e}");
+        Vec::new()
+    });
+
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    for interface in interfaces.iter().filter(|i| !i.is_loopback()) {
+        if let if_addrs::IfAddr::V4(addr) = &interface.addr {
+            if let Some(broadcast) = addr.broadcast {
+                if !addresses.contains(&IpAddr::V4(broadcast)) {
+                    addresses.push(IpAddr::V4(broadcast));
+                }
+            }
+        }
+    }
+
+    if addresses.is_empty() {
+        addresses.push(IpAddr::V4(Ipv4Addr::BROADCAST));
+    }
+    addresses
+}
+
+/// Every host address within the subnets, for networks that don't pass on broadcasts.
+pub fn subnet_hosts(subnets: &[Ipv4Net]) -> Vec<IpAddr> {
+    subnets
+        .iter()
+        .flat_map(Ipv4Net::hosts)
+        .map(IpAddr::V4)
+        .collect()
+}
+
+/// Sends a discovery packet to each target, then collects the devices that respond within the
+/// duration, in the order they responded.
+pub async fn discover(
+    transport: &(dyn Transport + Send + Sync),
+    targets: &[IpAddr],
+    duration: Duration,
+) -> io::Result<Vec<FoundDevice>> {
+    let packet = request_packet(std::process::id());
+
+    // One interface being down shouldn't stop the others being searched
+    let mut sent = false;
+    let mut last_error = None;
+    for target in targets {
+        match transport
+            .send_to(&packet, SocketAddr::new(*target, DISCOVERY_PORT))
+            .await
+        {
+            Ok(()) => sent = true,
+            Err(e) => {
+                debug!("Failed to send discovery packet to {target}: {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+    if let (false, Some(e)) = (sent, last_error) {
+        return Err(e);
+    }
+
+    let deadline = Instant::now() + duration;
+    let mut found: Vec<FoundDevice> = Vec::new();
+    let mut buf = [0; 4096];
+    while let Ok(received) = tokio::time::timeout_at(deadline, transport.recv_from(&mut buf)).await
+    {
+        let (len, from) = received?;
+        match parse_response(&buf[..len], from) {
+            Some(device) if !found.iter().any(|d| d.address == device.address) => {
+                found.push(device)
+            }
+            Some(_) => {}
+            None => debug!("Ignoring unrecognised discovery response from {from}"),
+        }
+    }
+
+    Ok(found)
+}
+
+/// Builds the discovery packet: a header followed by the JSON holding the public key, with a
+/// checksum of the whole packet in the header.
+fn request_packet(serial: u32) -> Vec<u8> {
+    let payload = serde_json::json!({"params": {"rsa_key": PUBLIC_KEY}}).to_string();
+
+    let mut packet = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    packet.push(2); // version
+    packet.push(0); // message type
+    packet.extend_from_slice(&1u16.to_be_bytes()); // operation
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.push(17); // flags
+    packet.push(0); // padding
+    packet.extend_from_slice(&serial.to_be_bytes());
+    packet.extend_from_slice(&INITIAL_CHECKSUM.to_be_bytes());
+    packet.extend_from_slice(payload.as_bytes());
+
+    let checksum = crc32fast::hash(&packet);
+    packet[12..HEADER_LENGTH].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// Reads the device from a response, using the address it was sent from if it doesn't give one.
+fn parse_response(packet: &[u8], from: SocketAddr) -> Option<FoundDevice> {
+    let response: Response = serde_json::from_slice(packet.get(HEADER_LENGTH..)?).ok()?;
+
+    Some(FoundDevice {
+        address: response.result.ip.unwrap_or_else(|| from.ip().to_string()),
+        mac: response.result.mac.replace('-', ":"),
+        model: response.result.device_model,
+    })
+}
+
+/// Formats the devices as a devices file, commenting out those with models that aren't supported.
+pub fn devices_file(devices: &[FoundDevice]) -> String {
+    let mut out = String::from("# Found by the discover subcommand\n");
+    for device in devices {
+        match device.supported_model() {
+            Some(model) => out.push_str(&format!("{} {model}  # {}\n", device.address, device.mac)),
+            None => out.push_str(&format!(
+                "# {}  # {} isn't supported\n",
+                device.address, device.model
+            )),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        DISCOVERY_PORT, FoundDevice, HEADER_LENGTH, INITIAL_CHECKSUM, Transport, devices_file,
+        discover, request_packet, subnet_hosts,
+    };
+    use async_trait::async_trait;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    struct TestTransport {
+        sent: Mutex<Vec<(Vec<u8>, SocketAddr)>>,
+        responses: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+        unreachable: Option<IpAddr>,
+    }
+
+    impl TestTransport {
+        fn new(responses: Vec<(Vec<u8>, &str)>) -> Self {
+            TestTransport {
+                sent: Mutex::new(Vec::new()),
+                responses: Mutex::new(
+                    responses
+                        .into_iter()
+                        .map(|(packet, from)| (packet, from.parse().unwrap()))
+                        .collect(),
+                ),
+                unreachable: None,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Transport for TestTransport {
+        async fn send_to(&self, packet: &[u8], target: SocketAddr) -> io::Result<()> {
+            if self.unreachable == Some(target.ip()) {
+                return Err(io::Error::from(io::ErrorKind::NetworkUnreachable));
+            }
+            self.sent.lock().unwrap().push((packet.to_vec(), target));
+            Ok(())
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let response = self.responses.lock().unwrap().pop_front();
+            match response {
+                Some((packet, from)) => {
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    Ok((packet.len(), from))
+                }
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    fn response(json: &str) -> Vec<u8> {
+        let mut packet = vec![0; HEADER_LENGTH];
+        packet.extend_from_slice(json.as_bytes());
+        packet
+    }
+
+    fn found(address: &str, mac: &str, model: &str) -> FoundDevice {
+        FoundDevice {
+            address: address.to_string(),
+            mac: mac.to_string(),
+            model: model.to_string(),
+        }
+    }
+
+    #[test]
+    fn request_packet_checksum() {
+        let packet = request_packet(42);
+
+        assert_eq!(packet[..4], [2, 0, 0, 1]);
+        assert_eq!(
+            u16::from_be_bytes([packet[4], packet[5]]) as usize,
+            packet.len() - HEADER_LENGTH
+        );
+        assert_eq!(packet[8..12], 42u32.to_be_bytes());
+
+        let mut unsummed = packet.clone();
+        unsummed[12..HEADER_LENGTH].copy_from_slice(&INITIAL_CHECKSUM.to_be_bytes());
+        assert_eq!(
+            packet[12..HEADER_LENGTH],
+            crc32fast::hash(&unsummed).to_be_bytes()
+        );
+
+        let payload: serde_json::Value = serde_json::from_slice(&packet[HEADER_LENGTH..]).unwrap();
+        assert!(payload["params"]["rsa_key"].is_string());
+    }
+
+    #[tokio::test]
+    async fn discover_devices() {
+        let transport = TestTransport::new(vec![
+            (
+                response(
+                    r#"{"error_code":0,"result":{"ip":"192.168.0.10","mac":"AA-BB-CC-DD-EE-01","device_model":"P304M(UK)"}}"#,
+                ),
+                "192.168.0.10:20002",
+            ),
+            (b"not a response".to_vec(), "192.168.0.99:20002"),
+            (
+                response(
+                    r#"{"error_code":0,"result":{"mac":"AA-BB-CC-DD-EE-02","device_model":"P110M(EU)"}}"#,
+                ),
+                "192.168.0.11:20002",
+            ),
+            // Responding to the broadcast on each interface
+            (
+                response(
+                    r#"{"error_code":0,"result":{"ip":"192.168.0.10","mac":"AA-BB-CC-DD-EE-01","device_model":"P304M(UK)"}}"#,
+                ),
+                "192.168.0.10:20002",
+            ),
+        ]);
+
+        let targets = [
+            "192.168.0.255".parse().unwrap(),
+            "10.0.0.255".parse().unwrap(),
+        ];
+        let devices = discover(&transport, &targets, Duration::from_millis(50))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            devices,
+            vec![
+                found("192.168.0.10", "AA:BB:CC:DD:EE:01", "P304M(UK)"),
+                found("192.168.0.11", "AA:BB:CC:DD:EE:02", "P110M(EU)"),
+            ]
+        );
+
+        let sent = transport.sent.lock().unwrap();
+        assert_eq!(
+            sent.iter().map(|(_, target)| *target).collect::<Vec<_>>(),
+            targets
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, DISCOVERY_PORT))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn discover_without_responses() {
+        let mut transport = TestTransport::new(Vec::new());
+        transport.unreachable = Some("10.0.0.255".parse().unwrap());
+
+        let targets = [
+            "192.168.0.255".parse().unwrap(),
+            "10.0.0.255".parse().unwrap(),
+        ];
+        let devices = discover(&transport, &targets, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert!(devices.is_empty());
+
+        // Only fails when the packet couldn't be sent anywhere
+        let e = discover(&transport, &targets[1..], Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NetworkUnreachable);
+    }
+
+    #[test]
+    fn subnet_scan_hosts() {
+        let hosts = subnet_hosts(&[
+            "192.168.0.0/30".parse().unwrap(),
+            "10.0.0.8/31".parse().unwrap(),
+        ]);
+
+        assert_eq!(
+            hosts,
+            ["192.168.0.1", "192.168.0.2", "10.0.0.8", "10.0.0.9"]
+                .map(|ip| ip.parse::<IpAddr>().unwrap())
+        );
+    }
+
+    #[test]
+    fn register_devices() {
+        let devices = [
+            found("192.168.0.10", "AA:BB:CC:DD:EE:01", "P304M(UK)"),
+            found("192.168.0.11", "AA:BB:CC:DD:EE:02", "P110M"),
+            found("192.168.0.12", "AA:BB:CC:DD:EE:03", "KP115(UK)"),
+        ];
+
+        assert_eq!(
+            devices_file(&devices),
+            "# Found by the discover subcommand\n\
+            192.168.0.10 P304M  # AA:BB:CC:DD:EE:01\n\
+            192.168.0.11 P110M  # AA:BB:CC:DD:EE:02\n\
+            # 192.168.0.12  # KP115(UK) isn't supported\n"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::labels::escape_label_value;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+
+/// What a sensor connected to a device, such as a T310 connected to an H100 hub, last reported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorReading {
+    pub device_id: String,
+    pub nickname: String,
+    /// The sensor's model, e.g. `T310`.
+    pub sensor_type: String,
+    pub temperature_celsius: Option<f64>,
+    pub humidity_percent: Option<f64>,
+    /// Whether the sensor's battery needs replacing, as sensors don't report its level.
+    pub battery_low: Option<bool>,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Sensor {
+    pub hub_id: String,
+    pub device_id: String,
+    pub nickname: String,
+    pub sensor_type: String,
+}
+
+/// Reports the readings of the sensors connected to each device, such as the temperature and
+/// humidity sensors connected to a hub.
+#[derive(Default)]
+pub struct SensorMetrics {
+    temperature: Family<Sensor, Gauge<f64, AtomicU64>>,
+    humidity: Family<Sensor, Gauge<f64, AtomicU64>>,
+    battery_low: Family<Sensor, Gauge>,
+    /// The labels last recorded for each device's sensors, by the device's address.
+    recorded: HashMap<String, Vec<Sensor>>,
+}
+
+impl SensorMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "temperature_celsius",
+            "Temperature reported by the sensor in degrees Celsius",
+            self.temperature.clone(),
+        );
+        registry.register(
+            "humidity_percent",
+            "Relative humidity reported by the sensor as a percentage",
+            self.humidity.clone(),
+        );
+        registry.register(
+            "battery_low",
+            "Whether the sensor's battery is low",
+            self.battery_low.clone(),
+        );
+    }
+
+    /// Records the readings of the device's sensors, no longer serving those of sensors it stopped
+    /// reporting.
+    pub fn record(&mut self, address: &str, hub_id: &str, readings: &[SensorReading]) {
+        self.remove(address);
+
+        let mut recorded = Vec::with_capacity(readings.len());
+        for reading in readings {
+            let labels = Sensor {
+                hub_id: escape_label_value(hub_id),
+                device_id: escape_label_value(&reading.device_id),
+                nickname: escape_label_value(&reading.nickname),
+                sensor_type: escape_label_value(&reading.sensor_type),
+            };
+            if let Some(celsius) = reading.temperature_celsius {
+                self.temperature.get_or_create(&labels).set(celsius);
+            }
+            if let Some(percent) = reading.humidity_percent {
+                self.humidity.get_or_create(&labels).set(percent);
+            }
+            if let Some(low) = reading.battery_low {
+                self.battery_low.get_or_create(&labels).set(low as i64);
+            }
+            recorded.push(labels);
+        }
+        if !recorded.is_empty() {
+            self.recorded.insert(address.to_string(), recorded);
+        }
+    }
+
+    /// Stops serving the readings last recorded for the device's sensors.
+    pub fn remove(&mut self, address: &str) {
+        for labels in self.recorded.remove(address).unwrap_or_default() {
+            self.temperature.remove(&labels);
+            self.humidity.remove(&labels);
+            self.battery_low.remove(&labels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Sensor, SensorMetrics, SensorReading};
+
+    #[test]
+    fn sensors_stop_being_served_when_no_longer_reported() {
+        let mut sensors = SensorMetrics::default();
+        let reading = |device_id: &str| SensorReading {
+            device_id: device_id.to_string(),
+            nickname: "Bedroom".to_string(),
+            sensor_type: "T310".to_string(),
+            temperature_celsius: Some(21.5),
+            humidity_percent: Some(45.0),
+            battery_low: Some(false),
+        };
+        let labels = |device_id: &str| Sensor {
+            hub_id: "123".to_string(),
+            device_id: device_id.to_string(),
+            nickname: "Bedroom".to_string(),
+            sensor_type: "T310".to_string(),
+        };
+
+        sensors.record("10.0.0.1", "123", &[reading("1"), reading("2")]);
+        assert_eq!(sensors.temperature.get(&labels("1")).unwrap().get(), 21.5);
+        assert_eq!(sensors.humidity.get(&labels("2")).unwrap().get(), 45.0);
+        assert_eq!(sensors.battery_low.get(&labels("2")).unwrap().get(), 0);
+
+        sensors.record("10.0.0.1", "123", &[reading("2")]);
+        assert!(sensors.temperature.get(&labels("1")).is_none());
+        assert!(sensors.temperature.get(&labels("2")).is_some());
+
+        sensors.remove("10.0.0.1");
+        assert!(sensors.temperature.get(&labels("2")).is_none());
+        assert!(sensors.battery_low.get(&labels("2")).is_none());
+    }
+}
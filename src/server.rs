@@ -0,0 +1,1019 @@
+use crate::alert::AlertConfig;
+use crate::cloud::{self, DEFAULT_CLOUD_URL};
+use crate::config::{
+    Credentials, device_credentials, merge, parse_metric_prefix, parse_mode, parse_origin,
+};
+use crate::connect::{TapoClientFactory, build_clients, client_for_device};
+use crate::discovery::Discovery;
+use crate::energy::EnergyTotals;
+use crate::error::AppError;
+use crate::exporter::{
+    AppConfig, DEFAULT_METRIC_PREFIX, Devices, ReadinessPolicy, split_app_with_routes,
+};
+use crate::influx::InfluxWriter;
+use crate::mac::{self, ResolvedMacs};
+use crate::management::{self, ClientConnector, Management};
+use crate::options::{DeviceOptions, ListenOptions, PushGatewayOptions, ResolvedDevices};
+use crate::otlp::{OtlpExporter, OtlpProtocol};
+use crate::pid_file::PidFile;
+use crate::power_histogram::{PowerBuckets, parse_buckets};
+use crate::probe::{self, Prober, TapoConnector};
+use crate::pushgateway::Pushgateway;
+use crate::reload::{
+    DeviceReloader, device_connections, forward_hangups, reload_devices, watch_config_file,
+};
+use crate::remote_write::{RemoteWriteAuth, RemoteWriter};
+use crate::statsd::StatsdSender;
+use crate::systemd::{self, ActivatedListener, Notifier};
+use axum::http::HeaderValue;
+use clap::{ArgMatches, Args};
+use ipnet::IpNet;
+use std::fs::Permissions;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn};
+
+/// Options for running the server, which are overridden by those in the configuration file unless
+/// given.
+#[derive(Args)]
+pub struct ServerOptions {
+    #[command(flatten)]
+    devices: DeviceOptions,
+
+    /// Add devices announcing themselves over mDNS on the local network as they're found
+    #[arg(long, env = "DISCOVER_MDNS")]
+    discover_mdns: bool,
+
+    /// Also read from the supported devices on the Tapo account, found on the local network by
+    /// their MAC addresses
+    #[arg(long, env = "DISCOVER_FROM_CLOUD")]
+    discover_from_cloud: bool,
+
+    /// URL of the TP-Link cloud's API to sign in to with `--discover-from-cloud`, which differs
+    /// for accounts in some regions
+    #[arg(long, env = "CLOUD_URL", default_value = DEFAULT_CLOUD_URL)]
+    cloud_url: reqwest::Url,
+
+    /// Fail to start if a device is a model that isn't supported, rather than only serving its
+    /// device information
+    #[arg(long, env = "STRICT_MODELS")]
+    strict_models: bool,
+
+    /// Reload the devices whenever the configuration file changes, as well as on `SIGHUP`,
+    /// which needs the `watch-config` feature
+    #[arg(long, env = "WATCH_CONFIG")]
+    watch_config: bool,
+
+    /// How many times to try connecting to each device when starting, in case it's rebooting
+    #[arg(long, env = "CONNECT_ATTEMPTS", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    connect_attempts: u32,
+
+    /// How long to wait before trying to connect to a device again, doubling after each attempt
+    #[arg(long, env = "CONNECT_RETRY_DELAY", default_value = "1s", value_parser = humantime::parse_duration)]
+    connect_retry_delay: Duration,
+
+    /// How often to look for the IP addresses of devices given by MAC address that haven't
+    /// been found, or that are failing to be read from in case they've moved
+    #[arg(long, env = "MAC_RESOLVE_INTERVAL", default_value = "5m", value_parser = humantime::parse_duration)]
+    mac_resolve_interval: Duration,
+
+    /// Serve `/api/v1/devices` for adding and removing devices while running, which anyone who
+    /// can reach the server can use unless `--management-api-token` is given
+    #[arg(long, env = "ENABLE_MANAGEMENT_API")]
+    enable_management_api: bool,
+
+    /// Bearer token that requests to the management API must be made with
+    #[arg(long, env = "MANAGEMENT_API_TOKEN", hide_env_values = true)]
+    management_api_token: Option<String>,
+
+    /// Networks that targets of the probe endpoint must be within, refusing every target if unset
+    #[arg(long, env = "PROBE_ALLOW_CIDR", value_delimiter = ',')]
+    probe_allow_cidr: Vec<IpNet>,
+
+    /// How long an unused probe target's client is kept before reconnecting
+    #[arg(long, env = "PROBE_CLIENT_TTL", default_value = "5m", value_parser = humantime::parse_duration)]
+    probe_client_ttl: Duration,
+
+    /// Maximum time to spend probing a target before reporting it as failed
+    #[arg(long, env = "PROBE_TIMEOUT", default_value = "10s", value_parser = humantime::parse_duration)]
+    probe_timeout: Duration,
+
+    /// Maximum time to spend handling a request that reads from the devices
+    #[arg(long, env = "HTTP_TIMEOUT", default_value = "30s", value_parser = humantime::parse_duration)]
+    http_timeout: Duration,
+
+    /// Maximum number of requests that read from the devices to handle at once
+    #[arg(long, env = "HTTP_MAX_CONCURRENT", default_value_t = 4)]
+    http_max_concurrent: usize,
+
+    /// Minimum time between reading metrics from the devices, serving the last metrics read to
+    /// scrapes in between
+    #[arg(long, env = "MIN_SCRAPE_INTERVAL", default_value = "5s", value_parser = humantime::parse_duration, visible_alias = "power-refresh-interval")]
+    min_scrape_interval: Duration,
+
+    /// Minimum time between reading each device's information, such as its model and firmware
+    /// version, with what was last read used in between
+    #[arg(long, env = "INFO_REFRESH_INTERVAL", default_value = "5m", value_parser = humantime::parse_duration)]
+    info_refresh_interval: Duration,
+
+    /// Maximum time to spend reading from every device each time they're read, answering the
+    /// scrape as unavailable if it's taken longer
+    #[arg(long, env = "SCRAPE_TIMEOUT", default_value = "30s", value_parser = humantime::parse_duration)]
+    scrape_timeout: Duration,
+
+    /// Origins, or `*` for any, that browsers are allowed to fetch the metrics from
+    #[arg(long, env = "CORS_ALLOWED_ORIGINS", value_delimiter = ',', value_parser = parse_origin)]
+    cors_allowed_origins: Vec<HeaderValue>,
+
+    /// URL to POST a JSON alert to when a plug's power use goes over the alert threshold
+    #[arg(long, env = "ALERT_WEBHOOK_URL")]
+    alert_webhook_url: Option<reqwest::Url>,
+
+    /// Power use in watts over which a plug is alerted on, until it drops below 90% of this
+    #[arg(long, env = "ALERT_THRESHOLD_WATTS")]
+    alert_threshold_watts: Option<u64>,
+
+    /// URL of an OTLP endpoint, e.g. `http://localhost:4318/v1/metrics` over HTTP or
+    /// `http://localhost:4317` over gRPC, to push metrics to as well as serving them
+    #[arg(long, env = "OTLP_ENDPOINT")]
+    otlp_endpoint: Option<reqwest::Url>,
+
+    /// How metrics are sent to the OTLP endpoint
+    #[arg(long, env = "OTLP_PROTOCOL", value_enum, default_value_t = OtlpProtocol::HttpProtobuf)]
+    otlp_protocol: OtlpProtocol,
+
+    /// Address, as `host:port`, of a StatsD server to send metrics to as gauges as well as serving
+    /// them
+    #[arg(long, env = "STATSD_ADDRESS")]
+    statsd_address: Option<String>,
+
+    /// Send metrics to StatsD with DogStatsD tags rather than putting identifiers in their names
+    #[arg(long, env = "STATSD_TAGS")]
+    statsd_tags: bool,
+
+    #[command(flatten)]
+    push_gateway: PushGatewayOptions,
+
+    /// URL of a Prometheus remote write endpoint, e.g. `http://localhost:10908/api/v1/receive`,
+    /// to write the metrics to as well as serving them
+    #[arg(long, env = "REMOTE_WRITE_URL")]
+    remote_write_url: Option<reqwest::Url>,
+
+    /// Bearer token to write to the remote write endpoint with
+    #[arg(
+        long,
+        env = "REMOTE_WRITE_BEARER_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "remote_write_username"
+    )]
+    remote_write_bearer_token: Option<String>,
+
+    /// Username to write to the remote write endpoint with
+    #[arg(long, env = "REMOTE_WRITE_USERNAME")]
+    remote_write_username: Option<String>,
+
+    /// Password to write to the remote write endpoint with
+    #[arg(long, env = "REMOTE_WRITE_PASSWORD", hide_env_values = true)]
+    remote_write_password: Option<String>,
+
+    /// Base URL of an InfluxDB server, e.g. `http://localhost:8086`, to write the plugs' power
+    /// and energy use to as line protocol as well as serving them
+    #[arg(long, env = "INFLUX_URL")]
+    influx_url: Option<reqwest::Url>,
+
+    /// Bucket to write to on the InfluxDB server
+    #[arg(long, env = "INFLUX_BUCKET")]
+    influx_bucket: Option<String>,
+
+    /// Organization the bucket is in, if the InfluxDB server needs one
+    #[arg(long, env = "INFLUX_ORG")]
+    influx_org: Option<String>,
+
+    /// API token to write to the InfluxDB server with
+    #[arg(long, env = "INFLUX_TOKEN", hide_env_values = true)]
+    influx_token: Option<String>,
+
+    /// Only push the metrics to the Pushgateway, remote write endpoint or InfluxDB, without
+    /// listening for requests
+    #[arg(long, env = "NO_LISTEN")]
+    no_listen: bool,
+
+    /// How often to read from the devices and push the metrics to OTLP, StatsD, the remote
+    /// write endpoint, the Pushgateway or InfluxDB
+    #[arg(long, env = "PUSH_INTERVAL", default_value = "60s", value_parser = humantime::parse_duration, visible_aliases = ["remote-write-interval", "influx-interval"])]
+    push_interval: Duration,
+
+    /// How many readings of each plug to keep for reporting how its power use is changing
+    #[arg(long, env = "HISTORY_SIZE", default_value_t = 10)]
+    history_size: usize,
+
+    /// Upper bounds of the buckets to count each plug's power use readings in, in watts and
+    /// separated by commas, e.g. `0,50,100,200,500,1000,2000`, serving
+    /// `tapo_power_readings_watts` as a histogram when given
+    #[arg(long, env = "POWER_HISTOGRAM_BUCKETS", value_parser = parse_buckets)]
+    power_histogram_buckets: Option<PowerBuckets>,
+
+    /// Maximum number of label combinations to record power use with, leaving out plugs that
+    /// would go over it
+    #[arg(long, env = "MAX_LABEL_CARDINALITY", default_value_t = 1000)]
+    max_label_cardinality: usize,
+
+    /// JSON file to keep the energy used by each plug in, saved each time the devices are read
+    /// so the totals carry on after restarting
+    #[arg(long, env = "STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// What the name of every metric starts with, followed by an underscore
+    #[arg(long, env = "METRIC_PREFIX", default_value = DEFAULT_METRIC_PREFIX, value_parser = parse_metric_prefix)]
+    metric_prefix: String,
+
+    /// Path of a file to write the process's ID to for process managers, deleted on stopping
+    #[arg(long, env = "PID_FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// How many devices must have been read recently for the server to report itself as ready
+    #[arg(long, env = "READINESS_POLICY", value_enum, default_value_t = ReadinessPolicy::Any)]
+    readiness_policy: ReadinessPolicy,
+
+    /// How recently a device must have been read to count towards the server being ready
+    #[arg(long, env = "READINESS_WINDOW", default_value = "5m", value_parser = humantime::parse_duration)]
+    readiness_window: Duration,
+
+    /// Maximum time to wait for in-flight requests to complete when shutting down
+    #[arg(long, env = "SHUTDOWN_TIMEOUT", default_value = "10s", value_parser = humantime::parse_duration)]
+    shutdown_timeout: Duration,
+
+    /// Permissions, in octal, given to the Unix domain socket
+    #[arg(long, env, default_value = "660", value_parser = parse_mode)]
+    unix_socket_mode: u32,
+}
+
+impl ServerOptions {
+    /// Connects to the devices and serves their metrics until the process is asked to stop.
+    /// `matches` are what the options were parsed from, and `listen_matches` what `listen` was, so
+    /// that options given in neither fall back to the configuration file.
+    pub async fn run(
+        &self,
+        matches: &ArgMatches,
+        listen: &ListenOptions,
+        listen_matches: &ArgMatches,
+    ) -> Result<(), AppError> {
+        let file = self.devices.config_file()?;
+        let settings = file.server;
+        // Before the settings are taken apart below
+        let pushgateway = self.push_gateway.resolve(matches, &settings)?;
+
+        let ListenOptions {
+            port,
+            bind_address,
+            unix_socket,
+            health_port,
+            health_bind_address,
+        } = listen.resolve(listen_matches, &settings);
+        let ResolvedDevices {
+            devices,
+            alias_mode,
+            plug_filter,
+            sources: device_sources,
+            username,
+            password,
+        } = self.devices.resolve(matches, &settings, file.devices)?;
+        let discover_mdns = merge(
+            matches,
+            "discover_mdns",
+            self.discover_mdns,
+            settings.discover_mdns,
+        );
+        let discover_from_cloud = merge(
+            matches,
+            "discover_from_cloud",
+            self.discover_from_cloud,
+            settings.discover_from_cloud,
+        );
+        let cloud_url = merge(
+            matches,
+            "cloud_url",
+            self.cloud_url.clone(),
+            settings.cloud_url,
+        );
+        let strict_models = merge(
+            matches,
+            "strict_models",
+            self.strict_models,
+            settings.strict_models,
+        );
+        let watch_config = merge(
+            matches,
+            "watch_config",
+            self.watch_config,
+            settings.watch_config,
+        );
+        let connect_attempts = merge(
+            matches,
+            "connect_attempts",
+            self.connect_attempts,
+            settings.connect_attempts,
+        );
+        let connect_retry_delay = merge(
+            matches,
+            "connect_retry_delay",
+            self.connect_retry_delay,
+            settings.connect_retry_delay,
+        );
+        let mac_resolve_interval = merge(
+            matches,
+            "mac_resolve_interval",
+            self.mac_resolve_interval,
+            settings.mac_resolve_interval,
+        );
+        let enable_management_api = merge(
+            matches,
+            "enable_management_api",
+            self.enable_management_api,
+            settings.enable_management_api,
+        );
+        let management_api_token = merge(
+            matches,
+            "management_api_token",
+            self.management_api_token.clone(),
+            settings.management_api_token.map(Some),
+        );
+        let probe_allow_cidr = merge(
+            matches,
+            "probe_allow_cidr",
+            self.probe_allow_cidr.clone(),
+            settings.probe_allow_cidr,
+        );
+        // Devices are only connected to with the server's credentials if they could be probed
+        let management_networks = probe_allow_cidr.clone();
+        let probe_client_ttl = merge(
+            matches,
+            "probe_client_ttl",
+            self.probe_client_ttl,
+            settings.probe_client_ttl,
+        );
+        let probe_timeout = merge(
+            matches,
+            "probe_timeout",
+            self.probe_timeout,
+            settings.probe_timeout,
+        );
+        let http_timeout = merge(
+            matches,
+            "http_timeout",
+            self.http_timeout,
+            settings.http_timeout,
+        );
+        let http_max_concurrent = merge(
+            matches,
+            "http_max_concurrent",
+            self.http_max_concurrent,
+            settings.http_max_concurrent,
+        );
+        let min_scrape_interval = merge(
+            matches,
+            "min_scrape_interval",
+            self.min_scrape_interval,
+            settings.min_scrape_interval,
+        );
+        let info_refresh_interval = merge(
+            matches,
+            "info_refresh_interval",
+            self.info_refresh_interval,
+            settings.info_refresh_interval,
+        );
+        let scrape_timeout = merge(
+            matches,
+            "scrape_timeout",
+            self.scrape_timeout,
+            settings.scrape_timeout,
+        );
+        let cors_allowed_origins = merge(
+            matches,
+            "cors_allowed_origins",
+            self.cors_allowed_origins.clone(),
+            settings.cors_allowed_origins,
+        );
+        let alert_webhook_url = merge(
+            matches,
+            "alert_webhook_url",
+            self.alert_webhook_url.clone(),
+            settings.alert_webhook_url.map(Some),
+        );
+        let alert_threshold_watts = merge(
+            matches,
+            "alert_threshold_watts",
+            self.alert_threshold_watts,
+            settings.alert_threshold_watts.map(Some),
+        );
+        let alert = match (alert_webhook_url, alert_threshold_watts) {
+            (Some(webhook_url), Some(threshold_watts)) => Some(AlertConfig {
+                webhook_url,
+                threshold_watts,
+            }),
+            (Some(_), None) => return Err(AppError::missing_option("alert-threshold-watts")),
+            (None, Some(_)) => return Err(AppError::missing_option("alert-webhook-url")),
+            (None, None) => None,
+        };
+        let otlp_endpoint = merge(
+            matches,
+            "otlp_endpoint",
+            self.otlp_endpoint.clone(),
+            settings.otlp_endpoint.map(Some),
+        );
+        let otlp_protocol = merge(
+            matches,
+            "otlp_protocol",
+            self.otlp_protocol,
+            settings.otlp_protocol,
+        );
+        let statsd_address = merge(
+            matches,
+            "statsd_address",
+            self.statsd_address.clone(),
+            settings.statsd_address.map(Some),
+        );
+        let statsd_tags = merge(
+            matches,
+            "statsd_tags",
+            self.statsd_tags,
+            settings.statsd_tags,
+        );
+        let push_interval = merge(
+            matches,
+            "push_interval",
+            self.push_interval,
+            settings.push_interval,
+        );
+        let metric_prefix = merge(
+            matches,
+            "metric_prefix",
+            self.metric_prefix.clone(),
+            settings.metric_prefix,
+        );
+        let otlp = otlp_endpoint
+            .map(|endpoint| {
+                OtlpExporter::new(&endpoint, otlp_protocol, &metric_prefix)
+                    .map_err(|e| AppError::Config(format!("Invalid OTLP endpoint {endpoint}: {e}")))
+            })
+            .transpose()?;
+        let statsd = statsd_address
+            .map(|address| {
+                StatsdSender::new(&address, statsd_tags, &metric_prefix)
+                    .map_err(|e| AppError::Config(format!("Invalid StatsD address {address}: {e}")))
+            })
+            .transpose()?;
+        let remote_write_url = merge(
+            matches,
+            "remote_write_url",
+            self.remote_write_url.clone(),
+            settings.remote_write_url.map(Some),
+        );
+        let remote_write_bearer_token = merge(
+            matches,
+            "remote_write_bearer_token",
+            self.remote_write_bearer_token.clone(),
+            settings.remote_write_bearer_token.map(Some),
+        );
+        let remote_write_username = merge(
+            matches,
+            "remote_write_username",
+            self.remote_write_username.clone(),
+            settings.remote_write_username.map(Some),
+        );
+        let remote_write_password = merge(
+            matches,
+            "remote_write_password",
+            self.remote_write_password.clone(),
+            settings.remote_write_password.map(Some),
+        );
+        let remote_write_auth = match (
+            remote_write_bearer_token,
+            remote_write_username,
+            remote_write_password,
+        ) {
+            (Some(_), Some(_), _) => {
+                return Err(AppError::Config(
+                    "Only one of --remote-write-bearer-token and --remote-write-username can be given"
+                        .to_string(),
+                ));
+            }
+            (Some(token), None, _) => Some(RemoteWriteAuth::Bearer(token)),
+            (None, Some(username), Some(password)) => {
+                Some(RemoteWriteAuth::Basic { username, password })
+            }
+            (None, Some(_), None) => return Err(AppError::missing_option("remote-write-password")),
+            (None, None, Some(_)) => return Err(AppError::missing_option("remote-write-username")),
+            (None, None, None) => None,
+        };
+        let remote_writer = match remote_write_url {
+            Some(url) if !matches!(url.scheme(), "http" | "https") => {
+                return Err(AppError::Config(format!(
+                    "Invalid remote write URL {url}: only http and https are supported"
+                )));
+            }
+            Some(url) => Some(RemoteWriter::new(&url, remote_write_auth)),
+            None => None,
+        };
+        let influx_url = merge(
+            matches,
+            "influx_url",
+            self.influx_url.clone(),
+            settings.influx_url.map(Some),
+        );
+        let influx_bucket = merge(
+            matches,
+            "influx_bucket",
+            self.influx_bucket.clone(),
+            settings.influx_bucket.map(Some),
+        );
+        let influx_org = merge(
+            matches,
+            "influx_org",
+            self.influx_org.clone(),
+            settings.influx_org.map(Some),
+        );
+        let influx_token = merge(
+            matches,
+            "influx_token",
+            self.influx_token.clone(),
+            settings.influx_token.map(Some),
+        );
+        let influx = match (influx_url, influx_bucket) {
+            (Some(url), _) if !matches!(url.scheme(), "http" | "https") => {
+                return Err(AppError::Config(format!(
+                    "Invalid InfluxDB URL {url}: only http and https are supported"
+                )));
+            }
+            (Some(url), Some(bucket)) => Some(InfluxWriter::new(
+                &url,
+                &bucket,
+                influx_org.as_deref(),
+                influx_token,
+            )),
+            (Some(_), None) => return Err(AppError::missing_option("influx-bucket")),
+            (None, _) => None,
+        };
+        let no_listen = merge(matches, "no_listen", self.no_listen, settings.no_listen);
+        if no_listen && pushgateway.is_none() && remote_writer.is_none() && influx.is_none() {
+            return Err(AppError::missing(
+                "--no-listen needs one of --push-gateway-url, --remote-write-url or --influx-url \
+                 to be given, either as an option or in the configuration file",
+            ));
+        }
+        let push = otlp.is_some()
+            || statsd.is_some()
+            || pushgateway.is_some()
+            || remote_writer.is_some()
+            || influx.is_some();
+        let history_size = merge(
+            matches,
+            "history_size",
+            self.history_size,
+            settings.history_size,
+        );
+        let power_histogram_buckets = merge(
+            matches,
+            "power_histogram_buckets",
+            self.power_histogram_buckets.clone(),
+            settings.power_histogram_buckets.map(Some),
+        );
+        let max_label_cardinality = merge(
+            matches,
+            "max_label_cardinality",
+            self.max_label_cardinality,
+            settings.max_label_cardinality,
+        );
+        let state_file = merge(
+            matches,
+            "state_file",
+            self.state_file.clone(),
+            settings.state_file.map(Some),
+        );
+        let energy_totals = state_file
+            .map(|path| EnergyTotals::load(&path).map_err(AppError::Config))
+            .transpose()?;
+        let readiness_policy = merge(
+            matches,
+            "readiness_policy",
+            self.readiness_policy,
+            settings.readiness_policy,
+        );
+        let readiness_window = merge(
+            matches,
+            "readiness_window",
+            self.readiness_window,
+            settings.readiness_window,
+        );
+        let shutdown_timeout = merge(
+            matches,
+            "shutdown_timeout",
+            self.shutdown_timeout,
+            settings.shutdown_timeout,
+        );
+        let unix_socket_mode = merge(
+            matches,
+            "unix_socket_mode",
+            self.unix_socket_mode,
+            settings.unix_socket_mode,
+        );
+        let pid_file = merge(
+            matches,
+            "pid_file",
+            self.pid_file.clone(),
+            settings.pid_file.map(Some),
+        );
+
+        let global_credentials = match (&username, &password) {
+            (Some(username), Some(password)) => Some(Credentials {
+                username: username.clone(),
+                password: password.clone(),
+                account: None,
+            }),
+            _ => None,
+        };
+        // Devices on the Tapo account or discovered can only be read with the credentials given
+        // for every device
+        let missing_credentials = || match username {
+            None => AppError::missing_option("username"),
+            Some(_) => AppError::missing_option("password"),
+        };
+
+        // Devices given by MAC address are read from at the IP address found for them
+        let macs = ResolvedMacs::default();
+        let given_devices = devices;
+        let devices = mac::find_devices(&given_devices, &macs).await;
+
+        let cloud_devices = match (discover_from_cloud, &global_credentials) {
+            (true, Some(credentials)) => {
+                cloud::devices_to_read(&cloud_url, credentials, &devices).await
+            }
+            (true, None) => return Err(missing_credentials()),
+            (false, _) => Vec::new(),
+        };
+
+        let all_devices = [devices.clone(), cloud_devices].concat();
+        let credentials =
+            device_credentials(&all_devices, username.as_deref(), password.as_deref())
+                .map_err(AppError::Config)?;
+        let clients = build_clients(
+            &TapoClientFactory,
+            &all_devices,
+            credentials,
+            strict_models,
+            connect_attempts,
+            connect_retry_delay,
+        )
+        .await?;
+
+        let prober = Prober::new(
+            Box::new(TapoConnector {
+                credentials: global_credentials.clone(),
+            }),
+            probe_allow_cidr,
+            probe_client_ttl,
+            probe_timeout,
+            metric_prefix.clone(),
+        );
+
+        let health_listener = match health_port {
+            Some(health_port) => {
+                let address =
+                    SocketAddr::new(health_bind_address.unwrap_or(bind_address), health_port);
+                let listener = tokio::net::TcpListener::bind(address)
+                    .await
+                    .map_err(|e| AppError::listen(address, e))?;
+
+                info!(
+                    "Health checks are being served on {}",
+                    listener
+                        .local_addr()
+                        .map_err(|e| AppError::listen(address, e))?
+                );
+                Some(listener)
+            }
+            None => None,
+        };
+
+        let config = AppConfig {
+            http_timeout,
+            http_max_concurrent,
+            readiness_policy,
+            readiness_window,
+            min_scrape_interval,
+            info_refresh_interval,
+            cors_allowed_origins,
+            plug_filter,
+            alert,
+            statsd,
+            remote_write_errors: remote_writer.as_ref().map(RemoteWriter::errors),
+            influx_write_errors: influx.as_ref().map(InfluxWriter::errors),
+            otlp_push_errors: otlp.as_ref().map(OtlpExporter::errors),
+            pushgateway_push_errors: pushgateway.as_ref().map(Pushgateway::errors),
+            history_size,
+            power_histogram_buckets,
+            max_label_cardinality,
+            device_timeout: None,
+            scrape_timeout: Some(scrape_timeout),
+            energy_totals,
+            metric_prefix,
+            ..AppConfig::default()
+        }
+        .with_devices(&devices, alias_mode, &macs);
+        let (router, health_router, added_devices) =
+            split_app_with_routes(clients, config, probe::router(prober));
+        let (router, health_app) = match health_listener {
+            Some(listener) => (router, Some((listener, health_router))),
+            None => (router.merge(health_router), None),
+        };
+        let router = match enable_management_api {
+            true => router.merge(management::router(Management::new(
+                added_devices.clone(),
+                Box::new(ClientConnector {}),
+                global_credentials.clone(),
+                management_networks,
+                management_api_token,
+            ))),
+            false => router,
+        };
+
+        let (reload_tx, reloads) = mpsc::channel(1);
+        // Kept until the server stops, as dropping it stops watching
+        let _watcher = match watch_config {
+            true => Some(watch_config_file(&device_sources, reload_tx.clone())?),
+            false => None,
+        };
+        tokio::spawn(forward_hangups(reload_tx));
+        let (moved_tx, moved) = mpsc::channel(1);
+        tokio::spawn(mac::resolve_devices(
+            added_devices.clone(),
+            macs.clone(),
+            mac_resolve_interval,
+            moved_tx,
+        ));
+        tokio::spawn(reload_devices(
+            reloads,
+            moved,
+            DeviceReloader {
+                sources: device_sources,
+                alias_mode,
+                username: username.clone(),
+                password: password.clone(),
+                macs,
+                connector: Arc::new(ClientConnector {}),
+            },
+            given_devices,
+            device_connections(&devices, username.as_deref(), password.as_deref()),
+            added_devices.clone(),
+        ));
+
+        if push {
+            tokio::spawn(push_metrics(
+                added_devices.clone(),
+                otlp,
+                pushgateway,
+                remote_writer,
+                influx,
+                push_interval,
+            ));
+        }
+
+        if discover_mdns {
+            let credentials = global_credentials.ok_or_else(missing_credentials)?;
+            tokio::spawn(add_discovered_devices(added_devices, credentials));
+        }
+
+        // Kept until the server stops, as dropping it deletes the file
+        let _pid_file = pid_file
+            .map(|path| {
+                PidFile::create(&path).map_err(|e| {
+                    AppError::Runtime(format!(
+                        "Failed to write the PID file {}: {e}",
+                        path.display()
+                    ))
+                })
+            })
+            .transpose()?;
+        let notifier = Notifier::from_env();
+
+        if no_listen {
+            info!("Not listening for requests, only pushing the metrics");
+            notifier.ready();
+            shutdown_signal(notifier).await;
+            return Ok(());
+        }
+
+        let passed = |e: io::Error| {
+            AppError::Runtime(format!("Failed to use the socket passed by systemd: {e}"))
+        };
+        match systemd::activated_listener().map_err(passed)? {
+            Some(ActivatedListener::Tcp(listener)) => {
+                listener.set_nonblocking(true).map_err(passed)?;
+                let listener = tokio::net::TcpListener::from_std(listener).map_err(passed)?;
+
+                info!(
+                    "Server is listening on {} passed by systemd",
+                    listener.local_addr().map_err(passed)?
+                );
+                serve(listener, router, health_app, notifier, shutdown_timeout).await
+            }
+            Some(ActivatedListener::Unix(listener)) => {
+                listener.set_nonblocking(true).map_err(passed)?;
+                let listener = tokio::net::UnixListener::from_std(listener).map_err(passed)?;
+
+                info!("Server is listening on Unix domain socket passed by systemd");
+                serve(listener, router, health_app, notifier, shutdown_timeout).await
+            }
+            None => match &unix_socket {
+                Some(path) => {
+                    let listener = bind_unix_socket(path, unix_socket_mode)
+                        .map_err(|e| AppError::listen(path.display(), e))?;
+
+                    info!("Server is listening on {}", path.display());
+                    serve(listener, router, health_app, notifier, shutdown_timeout).await
+                }
+                None => {
+                    let address = SocketAddr::new(bind_address, port);
+                    let listener = tokio::net::TcpListener::bind(address)
+                        .await
+                        .map_err(|e| AppError::listen(address, e))?;
+
+                    info!(
+                        "Server is listening on {}",
+                        listener
+                            .local_addr()
+                            .map_err(|e| AppError::listen(address, e))?
+                    );
+                    serve(listener, router, health_app, notifier, shutdown_timeout).await
+                }
+            },
+        }
+    }
+}
+
+/// Starts reading from devices as they announce themselves, skipping any already being read from.
+async fn add_discovered_devices(devices: Devices, credentials: Credentials) {
+    let mut discovery = match Discovery::start() {
+        Ok(discovery) => discovery,
+        Err(e) => {
+            warn!("Failed to start discovery: {e}");
+            return;
+        }
+    };
+
+    while let Some(device) = discovery.next().await {
+        if devices.contains(&device.address) {
+            continue;
+        }
+
+        match client_for_device(&credentials, &device.address, None).await {
+            Ok(client) => {
+                info!("Discovered {} at {}", device.name, device.address);
+                devices.add(client).await;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to connect to {} discovered at {}: {e}",
+                    device.name, device.address
+                );
+                // Tried again when it next announces itself, rather than never being read from
+                discovery.forget(&device.address);
+            }
+        }
+    }
+}
+
+/// Reads from the devices on every interval, pushing the metrics to OTLP, StatsD, a remote write
+/// endpoint, the Pushgateway or InfluxDB for platforms that don't scrape, or can't reach the
+/// exporter to.
+async fn push_metrics(
+    devices: Devices,
+    otlp: Option<OtlpExporter>,
+    pushgateway: Option<Pushgateway>,
+    remote_writer: Option<RemoteWriter>,
+    influx: Option<InfluxWriter>,
+    interval: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+        if let Err(e) = devices
+            .push_metrics(
+                otlp.as_ref(),
+                pushgateway.as_ref(),
+                remote_writer.as_ref(),
+                influx.as_ref(),
+            )
+            .await
+        {
+            warn!("Failed to push metrics: {e}");
+        }
+    }
+}
+
+/// Serves requests, along with health checks if they're served separately, until the process is
+/// asked to stop, letting systemd know once requests can be served and when shutting down. Once
+/// asked to stop, in-flight requests are given until the shutdown timeout to complete.
+async fn serve<L>(
+    listener: L,
+    router: axum::Router,
+    health_app: Option<(tokio::net::TcpListener, axum::Router)>,
+    notifier: Notifier,
+    shutdown_timeout: Duration,
+) -> Result<(), AppError>
+where
+    L: axum::serve::Listener,
+    L::Addr: std::fmt::Debug,
+{
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let shutdown = |mut rx: watch::Receiver<()>| async move {
+        let _ = rx.changed().await;
+    };
+
+    notifier.ready();
+    tokio::spawn(async move {
+        shutdown_signal(notifier).await;
+        let _ = shutdown_tx.send(());
+    });
+
+    let metrics = async {
+        axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown(shutdown_rx.clone()))
+            .await
+    };
+    let health = async {
+        match health_app {
+            Some((listener, health_app)) => {
+                axum::serve(listener, health_app)
+                    .with_graceful_shutdown(shutdown(shutdown_rx.clone()))
+                    .await
+            }
+            None => Ok(()),
+        }
+    };
+
+    let deadline = async {
+        shutdown(shutdown_rx.clone()).await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+
+    tokio::select! {
+        (metrics, health) = async { tokio::join!(metrics, health) } => {
+            metrics.and(health).map_err(|e| AppError::Runtime(format!("Failed to serve requests: {e}")))
+        }
+        _ = deadline => {
+            warn!("Timed out waiting for in-flight requests to complete");
+            Ok(())
+        }
+    }
+}
+
+async fn shutdown_signal(notifier: Notifier) {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM, so only Ctrl-C will stop the server: {e}");
+                std::future::pending().await
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = terminate => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    info!("Shutting down");
+    notifier.stopping();
+}
+
+/// Binds to the Unix domain socket, replacing any socket left behind by a previous run.
+fn bind_unix_socket(path: &Path, mode: u32) -> io::Result<tokio::net::UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+
+    Ok(listener)
+}
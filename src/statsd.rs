@@ -0,0 +1,210 @@
+use crate::exporter::Inventory;
+use prometheus_client::metrics::counter::Counter;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+
+/// Sends metric values as StatsD gauges over UDP, named after the metric prefix, e.g.
+/// `tapo.power_use_watts.123.456:45|g`, or with DogStatsD tags, e.g.
+/// `tapo.power_use_watts:45|g|#power_strip_id:123,device_id:456`.
+#[derive(Clone, Debug)]
+pub struct StatsdSender {
+    socket: Arc<UdpSocket>,
+    tags: bool,
+    prefix: String,
+    errors: Counter,
+}
+
+impl StatsdSender {
+    /// Creates a sender for the `host:port` address, using DogStatsD tags rather than putting
+    /// identifiers in the metric names if `tags` is set. The metric names start with the prefix,
+    /// followed by a dot.
+    pub fn new(address: &str, tags: bool, prefix: &str) -> io::Result<Self> {
+        let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{address} has no address"))
+        })?;
+        let local = match address {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+        // A busy network shouldn't hold up reading from the devices
+        socket.set_nonblocking(true)?;
+
+        Ok(StatsdSender {
+            socket: Arc::new(socket),
+            tags,
+            prefix: prefix.to_string(),
+            errors: Counter::default(),
+        })
+    }
+
+    /// Counts the packets that failed to send.
+    pub fn errors(&self) -> Counter {
+        self.errors.clone()
+    }
+
+    /// Sends the power use of each of the device's plugs.
+    pub fn send_power_use(&self, address: &str, inventory: &Inventory) {
+        let power_strip_id = inventory.device_info.power_strip_id.as_str();
+        let name = format!("{}.power_use_watts", self.prefix);
+        for child in inventory.children.iter() {
+            let Some(watts) = inventory.power_watts.get(&child.device_id) else {
+                continue;
+            };
+
+            let packet = if self.tags {
+                tagged_gauge(
+                    &name,
+                    *watts,
+                    &[
+                        ("power_strip_id", power_strip_id),
+                        ("ip_address", inventory.labelled_address(address)),
+                        ("device_id", &child.device_id),
+                        ("nickname", &child.nickname),
+                        ("position", &child.position.to_string()),
+                    ],
+                )
+            } else {
+                gauge(&name, &[power_strip_id, &child.device_id], *watts)
+            };
+            self.send(&packet);
+        }
+    }
+
+    /// Sends the number of failed attempts to read from the device.
+    pub fn send_scrape_errors(&self, address: &str, count: u64) {
+        let name = format!("{}.scrape_errors", self.prefix);
+        let packet = if self.tags {
+            tagged_gauge(&name, count, &[("ip_address", address)])
+        } else {
+            gauge(&name, &[address], count)
+        };
+        self.send(&packet);
+    }
+
+    /// Sends the packet without waiting to find out whether it arrived, as is usual for StatsD.
+    fn send(&self, packet: &str) {
+        if self.socket.send(packet.as_bytes()).is_err() {
+            self.errors.inc();
+        }
+    }
+}
+
+/// A gauge with the identifiers appended to its name.
+fn gauge(name: &str, ids: &[&str], value: u64) -> String {
+    let mut packet = name.to_string();
+    for id in ids {
+        packet.push('.');
+        packet.push_str(&sanitize(id, &['-', '_']));
+    }
+    packet.push_str(&format!(":{value}|g"));
+    packet
+}
+
+/// A gauge with DogStatsD tags.
+fn tagged_gauge(name: &str, value: u64, tags: &[(&str, &str)]) -> String {
+    let tags = tags
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| format!("{key}:{}", sanitize(value, &['-', '_', '.', ':', ' '])))
+        .collect::<Vec<_>>()
+        .join(",");
+    if tags.is_empty() {
+        format!("{name}:{value}|g")
+    } else {
+        format!("{name}:{value}|g|#{tags}")
+    }
+}
+
+/// Replaces characters StatsD gives meaning to, such as `.` separating a name's parts, with `_`.
+fn sanitize(value: &str, allowed: &[char]) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || allowed.contains(&c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StatsdSender, gauge, tagged_gauge};
+    use crate::exporter::{AccountLabel, ChildDevice, DeviceInfo, Inventory};
+    use std::collections::HashMap;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    #[test]
+    fn gauge_packets() {
+        assert_eq!(
+            gauge("tapo.power_use_watts", &["123", "456"], 45),
+            "tapo.power_use_watts.123.456:45|g"
+        );
+        assert_eq!(
+            gauge("tapo.scrape_errors", &["192.168.0.10"], 3),
+            "tapo.scrape_errors.192_168_0_10:3|g"
+        );
+        assert_eq!(
+            tagged_gauge(
+                "tapo.power_use_watts",
+                45,
+                &[
+                    ("device_id", "456"),
+                    ("nickname", "Kettle|1,2"),
+                    ("room", "")
+                ]
+            ),
+            "tapo.power_use_watts:45|g|#device_id:456,nickname:Kettle_1_2"
+        );
+    }
+
+    #[test]
+    fn send_power_use() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let sender =
+            StatsdSender::new(&receiver.local_addr().unwrap().to_string(), false, "tapo").unwrap();
+
+        sender.send_power_use(
+            "10.0.0.1",
+            &Inventory {
+                device_info: DeviceInfo {
+                    power_strip_id: "123".to_string(),
+                    ip_address: "10.0.0.1".to_string(),
+                    model: "P304M".to_string(),
+                    firmware_version: "1.0".to_string(),
+                    hardware_version: "1.0".to_string(),
+                    mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+                    account: AccountLabel::default(),
+                    group: String::new(),
+                    master_on: None,
+                },
+                alias: None,
+                given_mac: None,
+                sensors: vec![],
+                children: vec![ChildDevice {
+                    device_id: "456".to_string(),
+                    nickname: "Kettle".to_string(),
+                    device_on: true,
+                    on_time_seconds: None,
+                    overloaded: None,
+                    position: 1,
+                }],
+                power_watts: HashMap::from([("456".to_string(), 45)]),
+            },
+        );
+
+        let mut buffer = [0; 512];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"tapo.power_use_watts.123.456:45|g");
+        assert_eq!(sender.errors().get(), 0);
+    }
+}
@@ -0,0 +1,136 @@
+use listenfd::ListenFd;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use tracing::warn;
+
+/// A socket passed to the exporter by systemd socket activation.
+pub enum ActivatedListener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Takes the first socket passed by systemd, if the exporter was started by socket activation.
+pub fn activated_listener() -> io::Result<Option<ActivatedListener>> {
+    let mut fds = ListenFd::from_env();
+    if fds.len() == 0 {
+        return Ok(None);
+    }
+
+    match fds.take_tcp_listener(0) {
+        Ok(listener) => Ok(listener.map(ActivatedListener::Tcp)),
+        Err(_) => Ok(fds.take_unix_listener(0)?.map(ActivatedListener::Unix)),
+    }
+}
+
+/// Tells systemd about changes in the state of the service when it's been asked to via
+/// `NOTIFY_SOCKET`, such as when running as a `Type=notify` service.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    socket: Option<String>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        Notifier {
+            socket: std::env::var("NOTIFY_SOCKET").ok(),
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some(socket) = &self.socket {
+            if let Err(e) = send(socket, state) {
+                warn!("Failed to notify systemd of {state}: {e}");
+            }
+        }
+    }
+}
+
+fn send(socket: &str, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+
+    match socket.strip_prefix('@') {
+        Some(name) => send_abstract(&datagram, name, state),
+        None => datagram.send_to(state.as_bytes(), socket).map(|_| ()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(datagram: &UnixDatagram, name: &str, state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let address = SocketAddr::from_abstract_name(name)?;
+    datagram
+        .send_to_addr(state.as_bytes(), &address)
+        .map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_: &UnixDatagram, _: &str, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Notifier;
+    use std::os::unix::net::UnixDatagram;
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buffer = [0; 64];
+        let length = socket.recv(&mut buffer).unwrap();
+        String::from_utf8(buffer[..length].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn notify_socket_path() {
+        let path = std::env::temp_dir().join(format!("notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier {
+            socket: Some(path.to_str().unwrap().to_string()),
+        };
+        notifier.ready();
+        notifier.stopping();
+
+        assert_eq!(receive(&socket), "READY=1");
+        assert_eq!(receive(&socket), "STOPPING=1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notify_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let name = format!("p304m-notify-{}", std::process::id());
+        let socket =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+
+        let notifier = Notifier {
+            socket: Some(format!("@{name}")),
+        };
+        notifier.ready();
+
+        assert_eq!(receive(&socket), "READY=1");
+    }
+
+    #[test]
+    fn notify_without_socket() {
+        // Nothing to assert beyond this not failing when not run by systemd
+        Notifier { socket: None }.ready();
+    }
+}
@@ -0,0 +1,37 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Writes the file alongside its destination then renames it into place, so it's never seen part
+/// written, as the node_exporter textfile collector needs.
+pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", std::process::id()));
+    let temp = PathBuf::from(temp);
+
+    std::fs::write(&temp, contents)
+        .and_then(|_| std::fs::rename(&temp, path))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp);
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_atomically;
+
+    #[test]
+    fn write_file_atomically() {
+        let dir = std::env::temp_dir().join(format!("collect-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("tapo.prom");
+        std::fs::write(&path, "old").unwrap();
+
+        write_atomically(&path, "new").unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(contents, "new");
+        assert_eq!(files, 1, "the temporary file should have been renamed");
+    }
+}
@@ -0,0 +1,111 @@
+use crate::list::columns;
+use serde::Serialize;
+
+/// The version given when building, such as the release's tag.
+pub const VERSION: &str = match option_env!("VERSION") {
+    Some(version) => version,
+    None => "dev-build",
+};
+
+/// How the build details are printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum VersionFormat {
+    /// A line for each detail
+    #[default]
+    Text,
+    /// A JSON object
+    Json,
+}
+
+/// What the binary was built from, as recorded by the build script.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    /// Whether there were uncommitted changes when built.
+    pub git_dirty: bool,
+    pub build_timestamp: &'static str,
+    pub rustc_version: &'static str,
+    pub target: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        BuildInfo {
+            version: VERSION,
+            git_commit: env!("GIT_COMMIT"),
+            git_dirty: env!("GIT_DIRTY") == "true",
+            build_timestamp: env!("BUILD_TIMESTAMP"),
+            rustc_version: env!("RUSTC_VERSION"),
+            target: env!("BUILD_TARGET"),
+            features: env!("BUILD_FEATURES")
+                .split(',')
+                .filter(|f| !f.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn format(&self, format: VersionFormat) -> String {
+        match format {
+            VersionFormat::Text => {
+                let commit = match self.git_dirty {
+                    true => format!("{} (dirty)", self.git_commit),
+                    false => self.git_commit.to_string(),
+                };
+                let features = match self.features.is_empty() {
+                    true => "none".to_string(),
+                    false => self.features.join(", "),
+                };
+                columns(&[
+                    vec!["Version:".to_string(), self.version.to_string()],
+                    vec!["Commit:".to_string(), commit],
+                    vec!["Built:".to_string(), self.build_timestamp.to_string()],
+                    vec!["Compiler:".to_string(), self.rustc_version.to_string()],
+                    vec!["Target:".to_string(), self.target.to_string()],
+                    vec!["Features:".to_string(), features],
+                ])
+            }
+            VersionFormat::Json => format!("{}\n", serde_json::to_string_pretty(self).unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BuildInfo, VERSION, VersionFormat};
+
+    #[test]
+    fn build_info() {
+        let info = BuildInfo::current();
+        assert_eq!(info.version, VERSION);
+        assert!(!info.git_commit.is_empty());
+        assert!(!info.rustc_version.is_empty());
+        assert!(humantime::parse_rfc3339(info.build_timestamp).is_ok());
+
+        let info = BuildInfo {
+            version: "1.2.3",
+            git_commit: "abc123",
+            git_dirty: true,
+            build_timestamp: "2025-01-02T03:04:05Z",
+            rustc_version: "rustc 1.90.0",
+            target: "x86_64-unknown-linux-gnu",
+            features: vec![],
+        };
+        assert_eq!(
+            info.format(VersionFormat::Text),
+            "Version:   1.2.3\n\
+            Commit:    abc123 (dirty)\n\
+            Built:     2025-01-02T03:04:05Z\n\
+            Compiler:  rustc 1.90.0\n\
+            Target:    x86_64-unknown-linux-gnu\n\
+            Features:  none\n"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&info.format(VersionFormat::Json)).unwrap();
+        assert_eq!(json["git_commit"], "abc123");
+        assert_eq!(json["git_dirty"], true);
+        assert_eq!(json["features"], serde_json::json!([]));
+    }
+}
@@ -0,0 +1,224 @@
+//! Connects the exporter's clients to a device served locally, reading from it through the tapo
+//! crate as the exporter would from a real device.
+
+mod mock_device;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use http_body_util::BodyExt;
+use mock_device::{MockDevice, Plug};
+use p304m_prometheus_exporter::{AccountLabel, AppConfig, ChildDevice, Credentials, DeviceInfo};
+use p304m_prometheus_exporter::{app, client_for_device, is_unsupported_model};
+use std::time::Duration;
+use tapo::{Error, TapoResponseError};
+use tower::ServiceExt;
+
+fn credentials(password: &str) -> Credentials {
+    Credentials {
+        username: "me@example.com".to_string(),
+        password: password.to_string(),
+        account: None,
+    }
+}
+
+fn plugs() -> Vec<Plug> {
+    vec![
+        Plug {
+            device_id: "456".to_string(),
+            nickname: "Kettle".to_string(),
+            position: 1,
+            device_on: true,
+            current_power: 2400,
+        },
+        Plug {
+            device_id: "789".to_string(),
+            nickname: "Lamp".to_string(),
+            position: 2,
+            device_on: false,
+            current_power: 0,
+        },
+    ]
+}
+
+#[tokio::test]
+async fn read_a_power_strip() {
+    let device = MockDevice::p304m("me@example.com", "secret", plugs()).await;
+
+    let mut client = client_for_device(&credentials("secret"), &device.address, None)
+        .await
+        .unwrap();
+    // The model's asked for with a generic client, whose session the client for its model
+    // carries on with rather than logging in again
+    assert_eq!(
+        device.requests(),
+        [
+            "component_nego",
+            "handshake1",
+            "handshake2",
+            "get_device_info",
+        ]
+    );
+    assert_eq!(client.identity().model.as_deref(), Some("P304M(UK)"));
+
+    device.clear_requests();
+    client.refresh_session().await.unwrap();
+    assert_eq!(
+        client.device_info().await.unwrap(),
+        DeviceInfo {
+            power_strip_id: "8022A1B2C3D4E5F6".to_string(),
+            ip_address: device.address.clone(),
+            model: "P304M(UK)".to_string(),
+            firmware_version: "1.0.8 Build 240613 Rel.103305".to_string(),
+            hardware_version: "1.0".to_string(),
+            mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+            account: AccountLabel::default(),
+            group: String::new(),
+            master_on: Some(true),
+        }
+    );
+    assert_eq!(
+        client.child_devices().await.unwrap(),
+        [
+            ChildDevice {
+                device_id: "456".to_string(),
+                nickname: "Kettle".to_string(),
+                device_on: true,
+                on_time_seconds: Some(60),
+                overloaded: Some(false),
+                position: 1,
+            },
+            ChildDevice {
+                device_id: "789".to_string(),
+                nickname: "Lamp".to_string(),
+                device_on: false,
+                on_time_seconds: None,
+                overloaded: Some(false),
+                position: 2,
+            },
+        ]
+    );
+    assert_eq!(
+        client
+            .get_power_for_plug("456")
+            .await
+            .unwrap()
+            .current_power,
+        2400
+    );
+    assert_eq!(
+        client.identity().device_id.as_deref(),
+        Some("8022A1B2C3D4E5F6")
+    );
+
+    assert_eq!(
+        device.requests(),
+        [
+            "handshake1",
+            "handshake2",
+            "get_device_info",
+            "get_child_device_list",
+            // The plug's looked up among the children before it's asked for its power use
+            "get_child_device_list",
+            "control_child get_current_power",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn declared_model_is_not_asked_for() {
+    let device = MockDevice::p304m("me@example.com", "secret", plugs()).await;
+
+    let client = client_for_device(&credentials("secret"), &device.address, Some("P304M"))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        device.requests(),
+        ["component_nego", "handshake1", "handshake2"]
+    );
+    assert_eq!(client.device_info().await.unwrap().model, "P304M(UK)");
+}
+
+#[tokio::test]
+async fn unsupported_model() {
+    let device = MockDevice::with_model("me@example.com", "secret", "L530").await;
+
+    let e = client_for_device(&credentials("secret"), &device.address, None)
+        .await
+        .err()
+        .unwrap();
+    assert!(is_unsupported_model(&e), "{e}");
+}
+
+#[tokio::test]
+async fn wrong_password() {
+    let device = MockDevice::p304m("me@example.com", "secret", plugs()).await;
+
+    let e = client_for_device(&credentials("wrong"), &device.address, Some("P304M"))
+        .await
+        .err()
+        .unwrap();
+    assert!(
+        matches!(e, Error::Tapo(TapoResponseError::InvalidCredentials(_))),
+        "{e}"
+    );
+    assert_eq!(device.requests(), ["component_nego", "handshake1"]);
+}
+
+#[tokio::test]
+async fn expired_session() {
+    let device = MockDevice::p304m("me@example.com", "secret", plugs()).await;
+    let mut client = client_for_device(&credentials("secret"), &device.address, Some("P304M"))
+        .await
+        .unwrap();
+
+    device.expire_sessions();
+    let e = client.device_info().await.unwrap_err();
+    assert!(
+        matches!(e, Error::Tapo(TapoResponseError::SessionTimeout)),
+        "{e}"
+    );
+
+    // Logging in again starts a new session
+    client.refresh_session().await.unwrap();
+    assert_eq!(client.child_devices().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn serve_metrics_read_from_the_device() {
+    let device = MockDevice::p304m("me@example.com", "secret", plugs()).await;
+    let client = client_for_device(&credentials("secret"), &device.address, Some("P304M"))
+        .await
+        .unwrap();
+    let router = app(
+        vec![client],
+        AppConfig {
+            min_scrape_interval: Duration::ZERO,
+            ..AppConfig::default()
+        },
+    );
+
+    let scrape = || async {
+        let response = router
+            .clone()
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    let kettle = format!(
+        "tapo_power_use_watts{{power_strip_id=\"8022A1B2C3D4E5F6\",ip_address=\"{}\",device_id=\"456\",nickname=\"Kettle\",group=\"\",position=\"1\"}}",
+        device.address
+    );
+    let metrics = scrape().await;
+    assert!(metrics.contains(&format!("{kettle} 2400\n")), "{metrics}");
+
+    // Each scrape logs in again, so an expired session isn't noticed
+    device.expire_sessions();
+    device.set_power("456", 0);
+    let metrics = scrape().await;
+    assert!(metrics.contains(&format!("{kettle} 0\n")), "{metrics}");
+}
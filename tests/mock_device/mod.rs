@@ -0,0 +1,421 @@
+//! A Tapo device served on a local port, speaking the KLAP protocol the tapo crate falls back to
+//! when a device turns down its older passthrough protocol, so the exporter's clients can be
+//! connected to it and read from as they would a real device.
+
+use aes::Aes128;
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// What the device responds with for an error, e.g. to a request it doesn't understand.
+const INVALID_REQUEST: i32 = -1002;
+
+/// The code the device turns down the passthrough protocol with, so KLAP is used instead.
+const PASSTHROUGH_UNSUPPORTED: i32 = 1003;
+
+/// One of a power strip's plugs.
+#[derive(Clone, Debug)]
+pub struct Plug {
+    pub device_id: String,
+    pub nickname: String,
+    pub position: u8,
+    pub device_on: bool,
+    pub current_power: u64,
+}
+
+/// A device serving canned responses, which keeps the requests made of it for tests to check.
+pub struct MockDevice {
+    /// The address to connect to the device at, including the port it's served on.
+    pub address: String,
+    state: Arc<Mutex<DeviceState>>,
+    server: JoinHandle<()>,
+}
+
+struct DeviceState {
+    auth_hash: Vec<u8>,
+    device_info: Value,
+    plugs: Vec<Plug>,
+    sessions: HashMap<String, Session>,
+    next_session: u32,
+    requests: Vec<String>,
+}
+
+struct Session {
+    local_seed: Vec<u8>,
+    remote_seed: Vec<u8>,
+    cipher: Option<Cipher>,
+}
+
+impl MockDevice {
+    /// Serves a P304M with the plugs, which only accepts the username and password given.
+    pub async fn p304m(username: &str, password: &str, plugs: Vec<Plug>) -> MockDevice {
+        MockDevice::start(username, password, device_info("P304M(UK)", "P304M"), plugs).await
+    }
+
+    /// Serves a device reporting itself as the model, without any plugs.
+    pub async fn with_model(username: &str, password: &str, model: &str) -> MockDevice {
+        MockDevice::start(username, password, device_info(model, "PLUG"), vec![]).await
+    }
+
+    async fn start(
+        username: &str,
+        password: &str,
+        device_info: Value,
+        plugs: Vec<Plug>,
+    ) -> MockDevice {
+        let state = Arc::new(Mutex::new(DeviceState {
+            auth_hash: auth_hash(username, password),
+            device_info,
+            plugs,
+            sessions: HashMap::new(),
+            next_session: 0,
+            requests: Vec::new(),
+        }));
+
+        let router = Router::new()
+            .route("/app", post(negotiate))
+            .route("/app/handshake1", post(handshake1))
+            .route("/app/handshake2", post(handshake2))
+            .route("/app/request", post(request))
+            .with_state(state.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        });
+
+        MockDevice {
+            address,
+            state,
+            server,
+        }
+    }
+
+    /// The requests made of the device in the order they were made, named by their method, with
+    /// the method asked of a plug following the plug's for requests passed on to one.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Forgets the requests made so far.
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+
+    /// Ends every session, as the device does after a while, so requests are turned down until
+    /// the client logs in again.
+    pub fn expire_sessions(&self) {
+        self.state.lock().unwrap().sessions.clear();
+    }
+
+    /// Changes how much power the plug reports using.
+    pub fn set_power(&self, device_id: &str, watts: u64) {
+        let mut state = self.state.lock().unwrap();
+        let plug = state
+            .plugs
+            .iter_mut()
+            .find(|p| p.device_id == device_id)
+            .unwrap();
+        plug.current_power = watts;
+    }
+}
+
+impl Drop for MockDevice {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// What a device reports about itself, with the fields both the tapo crate's generic and power
+/// strip handlers read.
+fn device_info(model: &str, kind: &str) -> Value {
+    json!({
+        "device_id": "8022A1B2C3D4E5F6",
+        "type": format!("SMART.TAPO{kind}"),
+        "model": model,
+        "hw_id": "hw",
+        "hw_ver": "1.0",
+        "fw_id": "fw",
+        "fw_ver": "1.0.8 Build 240613 Rel.103305",
+        "oem_id": "oem",
+        "mac": "AA-BB-CC-DD-EE-FF",
+        "ip": "127.0.0.1",
+        "ssid": STANDARD.encode("Home"),
+        "signal_level": 3,
+        "rssi": -45,
+        "specs": "",
+        "lang": "en_US",
+        "device_on": true,
+        "on_time": 3600,
+        "nickname": STANDARD.encode("Power strip"),
+        "avatar": "",
+        "has_set_location_info": false,
+        "region": "Europe/London",
+        "time_diff": 0,
+    })
+}
+
+/// How a plug is listed among the power strip's children.
+fn plug_info(plug: &Plug) -> Value {
+    json!({
+        "auto_off_remain_time": 0,
+        "auto_off_status": "off",
+        "avatar": "",
+        "bind_count": 1,
+        "category": "plug.powerstrip.sub-plug",
+        "default_states": { "type": "last_states" },
+        "charging_status": "normal",
+        "device_id": plug.device_id,
+        "device_on": plug.device_on,
+        "fw_id": "",
+        "fw_ver": "1.0.8 Build 240613 Rel.103305",
+        "has_set_location_info": false,
+        "hw_id": "",
+        "hw_ver": "1.0",
+        "is_usb": false,
+        "mac": "AABBCCDDEEFF",
+        "model": "P304M",
+        "nickname": STANDARD.encode(&plug.nickname),
+        "oem_id": "",
+        "on_time": if plug.device_on { 60 } else { 0 },
+        "original_device_id": "8022A1B2C3D4E5F6",
+        "overcurrent_status": "normal",
+        "overheat_status": "normal",
+        "position": plug.position,
+        "power_protection_status": "normal",
+        "region": "Europe/London",
+        "slot_number": 3,
+        "status_follow_edge": true,
+        "type": "SMART.TAPOPLUG",
+    })
+}
+
+/// Turns down the passthrough protocol, which the tapo crate asks about first.
+async fn negotiate(State(state): State<Arc<Mutex<DeviceState>>>) -> Response {
+    state
+        .lock()
+        .unwrap()
+        .requests
+        .push("component_nego".to_string());
+    axum::Json(json!({ "error_code": PASSTHROUGH_UNSUPPORTED })).into_response()
+}
+
+/// Starts a session, proving the device knows the credentials with a hash of them along with both
+/// sides' seeds.
+async fn handshake1(State(state): State<Arc<Mutex<DeviceState>>>, local_seed: Bytes) -> Response {
+    let mut state = state.lock().unwrap();
+    state.requests.push("handshake1".to_string());
+
+    state.next_session += 1;
+    let id = state.next_session;
+    let remote_seed = sha256(&id.to_be_bytes())[..16].to_vec();
+    let server_hash = sha256(&[&local_seed[..], &remote_seed, &state.auth_hash].concat());
+
+    let cookie = format!("{id:08X}");
+    let body = [remote_seed.as_slice(), &server_hash].concat();
+    state.sessions.insert(
+        cookie.clone(),
+        Session {
+            local_seed: local_seed.to_vec(),
+            remote_seed,
+            cipher: None,
+        },
+    );
+
+    (
+        [(
+            header::SET_COOKIE,
+            format!("TP_SESSIONID={cookie};TIMEOUT=86400"),
+        )],
+        body,
+    )
+        .into_response()
+}
+
+/// Completes the session once the client proves it knows the credentials too.
+async fn handshake2(
+    State(state): State<Arc<Mutex<DeviceState>>>,
+    headers: HeaderMap,
+    client_hash: Bytes,
+) -> Response {
+    let mut state = state.lock().unwrap();
+    state.requests.push("handshake2".to_string());
+
+    let auth_hash = state.auth_hash.clone();
+    let Some(session) = session_id(&headers).and_then(|id| state.sessions.get_mut(&id)) else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+
+    let expected = sha256(&[&session.remote_seed[..], &session.local_seed, &auth_hash].concat());
+    if client_hash[..] != expected {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    session.cipher = Some(Cipher::new(
+        &session.local_seed,
+        &session.remote_seed,
+        &auth_hash,
+    ));
+    StatusCode::OK.into_response()
+}
+
+#[derive(Deserialize)]
+struct Sequence {
+    seq: i32,
+}
+
+/// Answers an encrypted request made in a session.
+async fn request(
+    State(state): State<Arc<Mutex<DeviceState>>>,
+    Query(Sequence { seq }): Query<Sequence>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut state = state.lock().unwrap();
+
+    let Some(cipher) = session_id(&headers)
+        .and_then(|id| state.sessions.get(&id))
+        .and_then(|s| s.cipher.clone())
+    else {
+        state.requests.push("expired session".to_string());
+        return StatusCode::FORBIDDEN.into_response();
+    };
+
+    let request: Value = serde_json::from_slice(&cipher.decrypt(seq, &body)).unwrap();
+    let response = state.respond(&request);
+    cipher
+        .encrypt(seq, response.to_string().as_bytes())
+        .into_response()
+}
+
+impl DeviceState {
+    fn respond(&mut self, request: &Value) -> Value {
+        let method = request["method"].as_str().unwrap_or_default();
+        match method {
+            "get_device_info" => {
+                self.requests.push(method.to_string());
+                json!({ "error_code": 0, "result": self.device_info })
+            }
+            "get_child_device_list" => {
+                self.requests.push(method.to_string());
+                let plugs: Vec<Value> = self.plugs.iter().map(plug_info).collect();
+                json!({
+                    "error_code": 0,
+                    "result": {
+                        "child_device_list": plugs,
+                        "start_index": 0,
+                        "sum": plugs.len(),
+                    },
+                })
+            }
+            "control_child" => {
+                let device_id = request["params"]["device_id"].as_str().unwrap_or_default();
+                let child_request = &request["params"]["requestData"]["params"]["requests"][0];
+                let child_method = child_request["method"].as_str().unwrap_or_default();
+                self.requests.push(format!("{method} {child_method}"));
+
+                let Some(plug) = self.plugs.iter().find(|p| p.device_id == device_id) else {
+                    return json!({ "error_code": INVALID_REQUEST });
+                };
+                let response = match child_method {
+                    "get_current_power" => json!({
+                        "method": child_method,
+                        "error_code": 0,
+                        "result": { "current_power": plug.current_power },
+                    }),
+                    _ => json!({ "method": child_method, "error_code": INVALID_REQUEST }),
+                };
+                json!({
+                    "error_code": 0,
+                    "result": {
+                        "responseData": { "result": { "responses": [response] } },
+                    },
+                })
+            }
+            _ => {
+                self.requests.push(method.to_string());
+                json!({ "error_code": INVALID_REQUEST })
+            }
+        }
+    }
+}
+
+/// The session a request was made in, from the cookie handshake1 set.
+fn session_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix("TP_SESSIONID="))
+        .map(str::to_string)
+}
+
+/// The hash of the credentials both sides prove they know.
+fn auth_hash(username: &str, password: &str) -> Vec<u8> {
+    let username = Sha1::digest(username.as_bytes());
+    let password = Sha1::digest(password.as_bytes());
+    sha256(&[&username[..], &password[..]].concat()).to_vec()
+}
+
+fn sha256(value: &[u8]) -> [u8; 32] {
+    Sha256::digest(value).into()
+}
+
+/// Encrypts and decrypts a session's requests, with keys derived from both sides' seeds and the
+/// credentials.
+#[derive(Clone)]
+struct Cipher {
+    key: Vec<u8>,
+    iv: Vec<u8>,
+    sig: Vec<u8>,
+}
+
+impl Cipher {
+    fn new(local_seed: &[u8], remote_seed: &[u8], auth_hash: &[u8]) -> Self {
+        let seeds = [local_seed, remote_seed, auth_hash].concat();
+        let derive = |prefix: &str| sha256(&[prefix.as_bytes(), &seeds].concat());
+        Cipher {
+            key: derive("lsk")[..16].to_vec(),
+            iv: derive("iv")[..12].to_vec(),
+            sig: derive("ldk")[..28].to_vec(),
+        }
+    }
+
+    fn iv_seq(&self, seq: i32) -> Vec<u8> {
+        [self.iv.as_slice(), &seq.to_be_bytes()].concat()
+    }
+
+    /// Decrypts a request, which starts with a signature that isn't checked.
+    fn decrypt(&self, seq: i32, body: &[u8]) -> Vec<u8> {
+        cbc::Decryptor::<Aes128>::new_from_slices(&self.key, &self.iv_seq(seq))
+            .unwrap()
+            .decrypt_padded_vec_mut::<Pkcs7>(&body[32..])
+            .unwrap()
+    }
+
+    /// Encrypts a response, signed as the device signs them.
+    fn encrypt(&self, seq: i32, data: &[u8]) -> Vec<u8> {
+        let encrypted = cbc::Encryptor::<Aes128>::new_from_slices(&self.key, &self.iv_seq(seq))
+            .unwrap()
+            .encrypt_padded_vec_mut::<Pkcs7>(data);
+        let signature = sha256(&[self.sig.as_slice(), &seq.to_be_bytes(), &encrypted].concat());
+        [signature.as_slice(), &encrypted].concat()
+    }
+}